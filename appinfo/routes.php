@@ -23,6 +23,16 @@ return [
 			'url' => '/test/version',
 			'verb' => 'GET',
 		],
+		[
+			'name' => 'config#config',
+			'url' => '/config',
+			'verb' => 'GET',
+		],
+		[
+			'name' => 'endpoint#register',
+			'url' => '/endpoint',
+			'verb' => 'POST',
+		],
 		[
 			'name' => 'Auth#preAuth',
 			'url' => '/pre_auth',
@@ -0,0 +1,36 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use notify_push::connection::{ping_jitter, ping_nonce, ConnectionOptions};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::num::NonZeroUsize;
+
+fn connection_setup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connection_setup");
+
+    // the previous setup, seeding a generator for every connection
+    group.bench_function("small_rng", |b| {
+        b.iter(|| {
+            let opts = ConnectionOptions::new(15, 0);
+            let mut rng = SmallRng::seed_from_u64(0);
+            black_box((opts, rng.gen::<NonZeroUsize>()))
+        })
+    });
+
+    group.bench_function("connection_id_jitter", |b| {
+        b.iter(|| {
+            let opts = ConnectionOptions::new(15, 0);
+            let jitter = ping_jitter(opts.id);
+            black_box((opts, jitter, ping_nonce()))
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, connection_setup);
+criterion_main!(benches);
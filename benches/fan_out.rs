@@ -0,0 +1,57 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use notify_push::connection::ActiveConnections;
+use notify_push::message::PushMessage;
+use notify_push::UserId;
+
+fn fan_out_connections(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out_connections");
+    for count in [1, 4, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            let connections = ActiveConnections::new(None);
+            let user = UserId::from("user");
+            let mut receivers: Vec<_> = (0..count)
+                .map(|_| connections.add(user.clone()).unwrap())
+                .collect();
+            b.iter(|| {
+                connections.send_to_user(&user, PushMessage::Activity);
+                for receiver in receivers.iter_mut() {
+                    receiver.try_recv().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn fan_out_users(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out_users");
+    for count in [10, 100, 1000] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            let connections = ActiveConnections::new(None);
+            let users: Vec<_> = (0..count)
+                .map(|i| UserId::from(format!("user{}", i)))
+                .collect();
+            let mut receivers: Vec<_> = users
+                .iter()
+                .map(|user| connections.add(user.clone()).unwrap())
+                .collect();
+            b.iter(|| {
+                for user in &users {
+                    connections.send_to_user(user, PushMessage::Activity);
+                }
+                for receiver in receivers.iter_mut() {
+                    receiver.try_recv().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out_connections, fan_out_users);
+criterion_main!(benches);
@@ -0,0 +1,137 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Compare the experimental io_uring listener with the default epoll listener.
+//!
+//! Run with `cargo bench --features io-uring --bench io_uring`. For both listeners a push server is started against the
+//! mock services, and the time from publishing an event in redis until every connected client received the message is
+//! measured, together with the number of context switches of all threads in the process during the run.
+//!
+//! Only the listener passed as argument (`epoll` or `io_uring`) is benchmarked if one is given, so the syscalls of a
+//! single listener can be counted by running the benchmark binary under `strace -c -f`.
+
+use futures::future::join_all;
+use futures::StreamExt;
+use notify_push::uring::UringListener;
+use notify_push_test_support::Services;
+use redis::AsyncCommands;
+use std::env::args;
+use std::fs::{read_dir, read_to_string};
+use std::time::{Duration, Instant};
+
+/// Users to send the messages to, the number of connections per user is limited
+const USERS: usize = 10;
+const CLIENTS_PER_USER: usize = 10;
+const ROUNDS: usize = 1000;
+
+/// Context switches of all threads in the process, every wait for io that blocks a thread is one voluntary switch
+#[derive(Debug, Default, Clone, Copy)]
+struct ContextSwitches {
+    voluntary: u64,
+    involuntary: u64,
+}
+
+impl ContextSwitches {
+    fn get() -> Self {
+        let mut switches = ContextSwitches::default();
+        for task in read_dir("/proc/self/task").into_iter().flatten().flatten() {
+            let status = read_to_string(task.path().join("status")).unwrap_or_default();
+            let field = |name: &str| -> u64 {
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or_default()
+            };
+            switches.voluntary += field("voluntary_ctxt_switches:");
+            switches.involuntary += field("nonvoluntary_ctxt_switches:");
+        }
+        switches
+    }
+}
+
+struct BenchResult {
+    p50: Duration,
+    p99: Duration,
+    switches: ContextSwitches,
+}
+
+fn user(index: usize) -> String {
+    format!("user{}", index)
+}
+
+async fn run(services: &Services, io_uring: bool) -> BenchResult {
+    let mut config = services.config();
+    config.io_uring = io_uring;
+    let server = services.spawn_server_with_config(config).await;
+    let users = (0..USERS * CLIENTS_PER_USER)
+        .map(|client| user(client / CLIENTS_PER_USER))
+        .collect::<Vec<_>>();
+    let mut clients = join_all(users.iter().map(|user| server.connect_auth(user, "pass"))).await;
+    let mut redis = services.redis_client().await;
+
+    let before = ContextSwitches::get();
+    let mut latencies = Vec::with_capacity(ROUNDS);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        for index in 0..USERS {
+            redis
+                .publish::<_, _, ()>(
+                    "notify_activity",
+                    format!(r#"{{"user":"{}"}}"#, user(index)),
+                )
+                .await
+                .unwrap();
+        }
+        for client in &mut clients {
+            client.next().await.unwrap().unwrap();
+        }
+        latencies.push(start.elapsed());
+    }
+    let after = ContextSwitches::get();
+
+    latencies.sort();
+    BenchResult {
+        p50: latencies[latencies.len() / 2],
+        p99: latencies[latencies.len() * 99 / 100],
+        switches: ContextSwitches {
+            voluntary: after.voluntary.saturating_sub(before.voluntary),
+            involuntary: after.involuntary.saturating_sub(before.involuntary),
+        },
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = UringListener::bind(([127, 0, 0, 1], 0).into()) {
+        println!("io_uring is not available: {}", e);
+        return;
+    }
+
+    let services = Services::new().await;
+    for index in 0..USERS {
+        services.add_user(&user(index), "pass");
+    }
+
+    println!(
+        "{} rounds of a message to {} users with {} clients each",
+        ROUNDS, USERS, CLIENTS_PER_USER
+    );
+    println!(
+        "{:<10} {:>10} {:>10} {:>12} {:>12}",
+        "listener", "p50", "p99", "voluntary", "involuntary"
+    );
+    let only = args().skip(1).find(|arg| !arg.starts_with('-'));
+    for (name, io_uring) in [("epoll", false), ("io_uring", true)] {
+        if only.as_deref().is_some_and(|only| only != name) {
+            continue;
+        }
+        let result = run(&services, io_uring).await;
+        println!(
+            "{:<10} {:>10.2?} {:>10.2?} {:>12} {:>12}",
+            name, result.p50, result.p99, result.switches.voluntary, result.switches.involuntary
+        );
+    }
+}
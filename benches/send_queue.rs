@@ -0,0 +1,86 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use notify_push::message::{PushMessage, SendQueue, UpdatedFiles};
+use std::time::{Duration, Instant};
+
+fn file_message(ids: impl IntoIterator<Item = u64>) -> PushMessage {
+    PushMessage::File(UpdatedFiles::Known(ids.into_iter().collect()))
+}
+
+fn send_queue(c: &mut Criterion) {
+    let base_time = Instant::now();
+
+    c.bench_function("send_queue_push", |b| {
+        b.iter_batched_ref(
+            SendQueue::new,
+            |queue| {
+                for i in 0..100 {
+                    queue.push(black_box(file_message([i])), base_time);
+                    queue.push(black_box(PushMessage::Activity), base_time);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    c.bench_function("send_queue_drain", |b| {
+        b.iter_batched_ref(
+            || {
+                let mut queue = SendQueue::new();
+                queue.push(file_message(0..16), base_time);
+                queue.push(PushMessage::Activity, base_time);
+                queue.push(PushMessage::Notification, base_time);
+                queue
+            },
+            |queue| {
+                queue
+                    .drain(base_time + Duration::from_secs(120), 100, 15)
+                    .count()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn updated_files_extend(c: &mut Criterion) {
+    let mut group = c.benchmark_group("updated_files_extend");
+    for size in [4, 64, 1024, 10_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let more = UpdatedFiles::Known((size / 2..size + size / 2).collect());
+            b.iter_batched_ref(
+                || UpdatedFiles::Known((0..size).collect()),
+                |files| files.extend(black_box(&more), usize::MAX),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// A large directory changing, every file update is merged into the queued message
+fn send_queue_mass_change(c: &mut Criterion) {
+    let base_time = Instant::now();
+    c.bench_function("send_queue_mass_change_10k", |b| {
+        b.iter_batched_ref(
+            || SendQueue::new().with_max_file_ids(usize::MAX),
+            |queue| {
+                for i in 0..10_000 {
+                    queue.push(black_box(file_message([i])), base_time);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(
+    benches,
+    send_queue,
+    updated_files_extend,
+    send_queue_mass_change
+);
+criterion_main!(benches);
@@ -4,7 +4,10 @@
  */
  
 use nextcloud_appinfo::get_appinfo;
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 fn main() {
     println!("cargo:rerun-if-changed=appinfo/info.xml");
@@ -13,4 +16,87 @@ fn main() {
     let appinfo = get_appinfo(&appinfo_path).expect("Failed to load appinfo");
     println!("cargo:rustc-env=NOTIFY_PUSH_VERSION={}", appinfo.version());
     println!("cargo:rustc-env=CARGO_PKG_VERSION={}", appinfo.version());
+
+    // builds from a source tarball can pass the commit in the environment
+    println!("cargo:rerun-if-env-changed=NOTIFY_PUSH_COMMIT");
+    let commit = env::var("NOTIFY_PUSH_COMMIT")
+        .ok()
+        .or_else(git_commit)
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=NOTIFY_PUSH_COMMIT={}", commit);
+
+    // used to pick the release binary when updating
+    println!(
+        "cargo:rustc-env=NOTIFY_PUSH_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!(
+        "cargo:rustc-env=NOTIFY_PUSH_DEPENDENCIES={}",
+        security_dependencies().join(",")
+    );
+}
+
+/// Crates that handle untrusted input or cryptography, reported in the build info for auditing
+const SECURITY_DEPENDENCIES: &[&str] = &[
+    "redis",
+    "sqlx",
+    "reqwest",
+    "hyper",
+    "tungstenite",
+    "p256",
+    "aes-gcm",
+];
+/// Only compiled in with the `rustls` feature
+const TLS_DEPENDENCIES: &[&str] = &["rustls", "rustls-webpki", "ring", "webpki-roots", "openssl"];
+/// Only compiled in with the `ldap` feature
+const LDAP_DEPENDENCIES: &[&str] = &["ldap3", "lber"];
+
+/// The locked versions of the security relevant dependencies, as `name@version`
+fn security_dependencies() -> Vec<String> {
+    let tls = env::var("CARGO_FEATURE_RUSTLS").is_ok();
+    let ldap = env::var("CARGO_FEATURE_LDAP").is_ok();
+    let Ok(lock) = fs::read_to_string("Cargo.lock") else {
+        return Vec::new();
+    };
+    let mut dependencies = Vec::new();
+    let mut name = None;
+    for line in lock.lines() {
+        if let Some(value) = line.strip_prefix("name = ") {
+            name = Some(value.trim_matches('"'));
+        } else if let (Some(value), Some(package)) = (line.strip_prefix("version = "), name.take())
+        {
+            if SECURITY_DEPENDENCIES.contains(&package)
+                || (tls && TLS_DEPENDENCIES.contains(&package))
+                || (ldap && LDAP_DEPENDENCIES.contains(&package))
+            {
+                dependencies.push(format!("{}@{}", package, value.trim_matches('"')));
+            }
+        }
+    }
+    dependencies
+}
+
+fn git_commit() -> Option<String> {
+    let head = Path::new(".git/HEAD");
+    if !head.exists() {
+        return None;
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(branch) = fs::read_to_string(head).ok()?.trim().strip_prefix("ref: ") {
+        let branch_ref = Path::new(".git").join(branch);
+        if branch_ref.exists() {
+            println!("cargo:rerun-if-changed={}", branch_ref.display());
+        }
+    }
+
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
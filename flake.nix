@@ -66,7 +66,11 @@
       testClientOpts = nearskOpt // {
         cargoBuildOptions = x: x ++ ["-p" "test_client"];
       };
-      buildServer = target: (cross-naersk'.buildPackage target) nearskOpt;
+      # the prebuilt binaries are static musl builds using the size optimized `dist` profile
+      buildServer = target: (cross-naersk'.buildPackage target) (nearskOpt // {
+        release = false;
+        cargoBuildOptions = x: x ++ ["--profile" "dist"];
+      });
       buildTestClient = target: (cross-naersk'.buildPackage target) testClientOpts;
       hostNaersk = cross-naersk'.hostNaersk;
 
@@ -0,0 +1,13 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use notify_push::connection::ClientCommand;
+
+fuzz_target!(|text: &str| {
+    let _ = ClientCommand::parse(text);
+});
@@ -0,0 +1,19 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use notify_push::event::{Event, CHANNELS};
+
+// the first byte selects the channel, the rest is used as payload
+fuzz_target!(|data: &[u8]| {
+    if let Some((channel, payload)) = data.split_first() {
+        let channel = CHANNELS[*channel as usize % CHANNELS.len()];
+        if let Ok(event) = Event::decode(channel, payload) {
+            let _ = event.to_string();
+        }
+    }
+});
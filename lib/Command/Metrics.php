@@ -15,6 +15,9 @@ use Symfony\Component\Console\Input\InputInterface;
 use Symfony\Component\Console\Output\OutputInterface;
 
 class Metrics extends Command {
+	/** Queries answered by every push server instance under `notify_push_<query>_<instance id>` */
+	private const QUERIES = ['metrics', 'uptime', 'version', 'config_hash', 'connections'];
+
 	private $queue;
 
 	public function __construct(
@@ -30,41 +33,110 @@ class Metrics extends Command {
 	protected function configure() {
 		$this
 			->setName('notify_push:metrics')
-			->setDescription('Get the metrics from the push server');
+			->setDescription('Get the metrics from the push server instances');
 		parent::configure();
 	}
 
 	protected function execute(InputInterface $input, OutputInterface $output): int {
-		if ($this->queue instanceof RedisQueue) {
-			$redis = $this->queue->getConnection();
-			$redis->del('notify_push_metrics');
-			$this->queue->push('notify_query', 'metrics');
-			usleep(10 * 1000);
+		if (!$this->queue instanceof RedisQueue) {
+			$output->writeln('<error>Redis is not available</error>');
+			return 1;
+		}
+		$redis = $this->queue->getConnection();
+		$redis->del('notify_push_metrics');
+		foreach (self::QUERIES as $query) {
+			// answers from earlier runs don't expire immediately
+			foreach ($redis->keys("notify_push_{$query}_*") ?: [] as $key) {
+				$redis->del($key);
+			}
+			$this->queue->push('notify_query', $query);
+		}
+		usleep(10 * 1000);
+		$instances = $this->getInstances($redis);
+		if (!$instances || min($instances) < count(self::QUERIES)) {
+			usleep(100 * 1000);
+			$instances = $this->getInstances($redis);
+		}
+		$instances = array_keys($instances);
+		sort($instances);
+
+		if (!$instances) {
+			// push servers from before the per-instance answers only write the metrics
 			$metrics = $redis->get('notify_push_metrics');
 			if (!$metrics) {
-				usleep(100 * 1000);
-				$metrics = $redis->get('notify_push_metrics');
-			}
-			if ($metrics) {
-				$metrics = json_decode($metrics, true);
-				if (!is_array($metrics)) {
-					$output->writeln('<error>Invalid metrics received from push server</error>');
-					return 1;
-				}
-				$output->writeln('Active connection count: ' . $metrics['active_connection_count']);
-				$output->writeln('Active user count: ' . $metrics['active_user_count']);
-				$output->writeln('Total connection count: ' . $metrics['total_connection_count']);
-				$output->writeln('Total database query count: ' . $metrics['mapping_query_count']);
-				$output->writeln('Events received: ' . $metrics['events_received']);
-				$output->writeln('Messages sent: ' . $metrics['messages_sent']);
-				return 0;
-			} else {
 				$output->writeln('<error>No metrics received from push server</error>');
 				return 1;
 			}
-		} else {
-			$output->writeln('<error>Redis is not available</error>');
-			return 1;
+			$metrics = json_decode($metrics, true);
+			if (!is_array($metrics)) {
+				$output->writeln('<error>Invalid metrics received from push server</error>');
+				return 1;
+			}
+			$this->writeMetrics($output, $metrics);
+			return 0;
+		}
+
+		$configHashes = [];
+		foreach ($instances as $instance) {
+			$answers = [];
+			foreach (self::QUERIES as $query) {
+				$answer = $redis->get("notify_push_{$query}_{$instance}");
+				$answers[$query] = $answer ? json_decode($answer, true) : null;
+			}
+			$output->writeln("Instance $instance:");
+			$output->writeln('  Version: ' . ($answers['version'] ?? 'unknown'));
+			$output->writeln('  Uptime: ' . (is_int($answers['uptime']) ? $this->formatUptime($answers['uptime']) : 'unknown'));
+			$output->writeln('  Config hash: ' . ($answers['config_hash'] ?? 'unknown'));
+			if (is_array($answers['connections'])) {
+				$output->writeln('  Active connection count: ' . $answers['connections']['active_connection_count']);
+				$output->writeln('  Active user count: ' . $answers['connections']['active_user_count']);
+				$output->writeln('  Total connection count: ' . $answers['connections']['total_connection_count']);
+			}
+			if (is_array($answers['metrics'])) {
+				$output->writeln('  Total database query count: ' . $answers['metrics']['mapping_query_count']);
+				$output->writeln('  Events received: ' . $answers['metrics']['events_received']);
+				$output->writeln('  Messages sent: ' . $answers['metrics']['messages_sent']);
+			}
+			if (isset($answers['config_hash'])) {
+				$configHashes[$answers['config_hash']] = true;
+			}
 		}
+		if (count($configHashes) > 1) {
+			$output->writeln('<comment>Not all push server instances are running with the same configuration</comment>');
+		}
+		return 0;
+	}
+
+	/**
+	 * Number of answers per instance id, taken from the `notify_push_<query>_<instance id>` keys
+	 *
+	 * @param \Redis|\RedisCluster $redis
+	 * @return array<string, int>
+	 */
+	private function getInstances($redis): array {
+		$instances = [];
+		foreach (self::QUERIES as $query) {
+			$prefix = "notify_push_{$query}_";
+			foreach ($redis->keys($prefix . '*') ?: [] as $key) {
+				$instance = substr($key, strlen($prefix));
+				$instances[$instance] = ($instances[$instance] ?? 0) + 1;
+			}
+		}
+		return $instances;
+	}
+
+	private function writeMetrics(OutputInterface $output, array $metrics): void {
+		$output->writeln('Active connection count: ' . $metrics['active_connection_count']);
+		$output->writeln('Active user count: ' . $metrics['active_user_count']);
+		$output->writeln('Total connection count: ' . $metrics['total_connection_count']);
+		$output->writeln('Total database query count: ' . $metrics['mapping_query_count']);
+		$output->writeln('Events received: ' . $metrics['events_received']);
+		$output->writeln('Messages sent: ' . $metrics['messages_sent']);
+	}
+
+	private function formatUptime(int $seconds): string {
+		$days = intdiv($seconds, 86400);
+		$time = sprintf('%02d:%02d:%02d', intdiv($seconds % 86400, 3600), intdiv($seconds % 3600, 60), $seconds % 60);
+		return $days > 0 ? "$days days, $time" : $time;
 	}
 }
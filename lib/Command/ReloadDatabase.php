@@ -0,0 +1,40 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Command;
+
+use OCA\NotifyPush\Queue\IQueue;
+use Symfony\Component\Console\Command\Command;
+use Symfony\Component\Console\Input\InputInterface;
+use Symfony\Component\Console\Output\OutputInterface;
+
+class ReloadDatabase extends Command {
+	private $queue;
+
+	public function __construct(
+		IQueue $queue,
+	) {
+		parent::__construct();
+		$this->queue = $queue;
+	}
+
+	/**
+	 * @return void
+	 */
+	protected function configure(): void {
+		$this
+			->setName('notify_push:reload-database')
+			->setDescription('Let the push server reload the database credentials from its configuration');
+		parent::configure();
+	}
+
+	protected function execute(InputInterface $input, OutputInterface $output): int {
+		$this->queue->push('notify_config', 'reload_database');
+		return 0;
+	}
+}
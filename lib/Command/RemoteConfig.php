@@ -0,0 +1,70 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Command;
+
+use OCA\NotifyPush\RemoteConfig as Settings;
+use Symfony\Component\Console\Command\Command;
+use Symfony\Component\Console\Input\InputInterface;
+use Symfony\Component\Console\Input\InputOption;
+use Symfony\Component\Console\Output\OutputInterface;
+
+class RemoteConfig extends Command {
+	private $settings;
+
+	public function __construct(
+		Settings $settings,
+	) {
+		parent::__construct();
+		$this->settings = $settings;
+	}
+
+	/**
+	 * @return void
+	 */
+	protected function configure(): void {
+		$this
+			->setName('notify_push:remote-config')
+			->setDescription('Show or change the settings loaded by push servers with REMOTE_CONFIG enabled')
+			->addOption('max-debounce-time', null, InputOption::VALUE_REQUIRED, 'maximum debounce time in seconds for new connections, "none" to use the push server configuration')
+			->addOption('max-connection-time', null, InputOption::VALUE_REQUIRED, 'maximum connection time in seconds for new connections, "none" to use the push server configuration')
+			->addOption('exclude-path', null, InputOption::VALUE_REQUIRED | InputOption::VALUE_IS_ARRAY, 'don\'t send storage updates for paths starting with this prefix, replaces the existing list')
+			->addOption('clear-excluded-paths', null, InputOption::VALUE_NONE, 'send storage updates for all paths again');
+		parent::configure();
+	}
+
+	protected function execute(InputInterface $input, OutputInterface $output): int {
+		$changed = false;
+		foreach (Settings::TIME_SETTINGS as $key) {
+			$value = $input->getOption(str_replace('_', '-', $key));
+			if ($value === null) {
+				continue;
+			}
+			if ($value === 'none') {
+				$this->settings->setTime($key, null);
+			} elseif (ctype_digit($value)) {
+				$this->settings->setTime($key, (int)$value);
+			} else {
+				$output->writeln("<error>Invalid value for $key, expected a number of seconds or \"none\"</error>");
+				return 1;
+			}
+			$changed = true;
+		}
+		$excludedPaths = $input->getOption('exclude-path');
+		if ($excludedPaths || $input->getOption('clear-excluded-paths')) {
+			$this->settings->setExcludedPaths($excludedPaths);
+			$changed = true;
+		}
+
+		if ($changed) {
+			$this->settings->publish();
+		}
+		$output->writeln(json_encode($this->settings->getSettings(), JSON_PRETTY_PRINT | JSON_UNESCAPED_SLASHES));
+		return 0;
+	}
+}
@@ -11,6 +11,7 @@ namespace OCA\NotifyPush\Command;
 use OCA\NotifyPush\Queue\IQueue;
 use Symfony\Component\Console\Command\Command;
 use Symfony\Component\Console\Input\InputInterface;
+use Symfony\Component\Console\Input\InputOption;
 use Symfony\Component\Console\Output\OutputInterface;
 
 class Reset extends Command {
@@ -29,12 +30,18 @@ class Reset extends Command {
 	protected function configure(): void {
 		$this
 			->setName('notify_push:reset')
-			->setDescription('Cancel all active connections to the push server');
+			->setDescription('Cancel all active connections to the push server')
+			->addOption('user', 'u', InputOption::VALUE_REQUIRED, 'Only cancel the connections of this user');
 		parent::configure();
 	}
 
 	protected function execute(InputInterface $input, OutputInterface $output): int {
-		$this->queue->push('notify_signal', 'reset');
+		$user = $input->getOption('user');
+		if ($user) {
+			$this->queue->push('notify_signal', ['disconnect' => ['user' => $user]]);
+		} else {
+			$this->queue->push('notify_signal', 'reset');
+		}
 		return 0;
 	}
 }
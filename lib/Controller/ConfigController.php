@@ -0,0 +1,35 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Controller;
+
+use OCA\NotifyPush\RemoteConfig;
+use OCP\AppFramework\Controller;
+use OCP\AppFramework\Http\DataResponse;
+use OCP\IRequest;
+
+class ConfigController extends Controller {
+	private $remoteConfig;
+
+	public function __construct(
+		IRequest $request,
+		RemoteConfig $remoteConfig,
+	) {
+		parent::__construct('notify_push', $request);
+		$this->remoteConfig = $remoteConfig;
+	}
+
+	/**
+	 * @NoAdminRequired
+	 * @PublicPage
+	 * @NoCSRFRequired
+	 */
+	public function config(): DataResponse {
+		return new DataResponse($this->remoteConfig->getSettings());
+	}
+}
@@ -0,0 +1,53 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Controller;
+
+use OCP\AppFramework\Controller;
+use OCP\AppFramework\Http;
+use OCP\AppFramework\Http\DataResponse;
+use OCP\IConfig;
+use OCP\IRequest;
+
+class EndpointController extends Controller {
+	private $config;
+
+	public function __construct(
+		IRequest $request,
+		IConfig $config,
+	) {
+		parent::__construct('notify_push', $request);
+		$this->config = $config;
+	}
+
+	/**
+	 * Lets a push server with `PUBLIC_URL` set register its url, authenticated with the shared test secret
+	 *
+	 * @NoAdminRequired
+	 * @PublicPage
+	 * @NoCSRFRequired
+	 * @BruteForceProtection(action=notify_push_endpoint)
+	 */
+	public function register(string $endpoint = ''): DataResponse {
+		$secret = $this->config->getAppValue('notify_push', 'test_secret', '');
+		$provided = $this->request->getHeader('x-notify-push-test-secret');
+		if ($secret === '' || !hash_equals($secret, $provided)) {
+			$response = new DataResponse('invalid test secret', Http::STATUS_FORBIDDEN);
+			$response->throttle();
+			return $response;
+		}
+
+		$scheme = parse_url($endpoint, PHP_URL_SCHEME);
+		if (!in_array($scheme, ['http', 'https'], true) || !parse_url($endpoint, PHP_URL_HOST)) {
+			return new DataResponse('invalid endpoint', Http::STATUS_BAD_REQUEST);
+		}
+
+		$this->config->setAppValue('notify_push', 'base_endpoint', rtrim($endpoint, '/'));
+		return new DataResponse('ok');
+	}
+}
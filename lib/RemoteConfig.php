@@ -0,0 +1,68 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush;
+
+use OCA\NotifyPush\Queue\IQueue;
+use OCP\IConfig;
+
+/**
+ * Push server settings managed by the app, loaded by push servers that have `REMOTE_CONFIG` enabled
+ */
+class RemoteConfig {
+	public const TIME_SETTINGS = ['max_debounce_time', 'max_connection_time'];
+
+	public function __construct(
+		private IConfig $config,
+		private IQueue $queue,
+	) {
+	}
+
+	/**
+	 * The settings in the format the push server expects, settings that aren't set are left to the push server configuration
+	 */
+	public function getSettings(): array {
+		$settings = [];
+		foreach (self::TIME_SETTINGS as $key) {
+			$value = $this->config->getAppValue('notify_push', $key, '');
+			$settings[$key] = $value === '' ? null : (int)$value;
+		}
+		$excludedPaths = json_decode($this->config->getAppValue('notify_push', 'excluded_paths', '[]'), true);
+		$settings['excluded_paths'] = is_array($excludedPaths) ? array_values($excludedPaths) : [];
+		return $settings;
+	}
+
+	public function setTime(string $key, ?int $seconds): void {
+		if (!in_array($key, self::TIME_SETTINGS, true)) {
+			throw new \InvalidArgumentException("Unknown setting $key");
+		}
+		if ($seconds === null) {
+			$this->config->deleteAppValue('notify_push', $key);
+		} else {
+			$this->config->setAppValue('notify_push', $key, (string)$seconds);
+		}
+	}
+
+	/**
+	 * @param string[] $paths storage updates for paths starting with any of these prefixes are not sent to the clients
+	 */
+	public function setExcludedPaths(array $paths): void {
+		if (count($paths) === 0) {
+			$this->config->deleteAppValue('notify_push', 'excluded_paths');
+		} else {
+			$this->config->setAppValue('notify_push', 'excluded_paths', json_encode(array_values($paths)));
+		}
+	}
+
+	/**
+	 * Let the push servers load the changed settings
+	 */
+	public function publish(): void {
+		$this->queue->push('notify_config', 'remote_config');
+	}
+}
@@ -36,6 +36,18 @@ class SelfTest {
 		$this->cookie = rand(1, (int)pow(2, 30));
 	}
 
+	/**
+	 * Options for requests to the test endpoints of the push server, including the shared secret if one is configured
+	 */
+	public function getRequestOptions(): array {
+		$options = ['nextcloud' => ['allow_local_address' => true], 'verify' => false];
+		$secret = $this->config->getAppValue('notify_push', 'test_secret', '');
+		if ($secret !== '') {
+			$options['headers'] = ['X-Notify-Push-Test-Secret' => $secret];
+		}
+		return $options;
+	}
+
 	public function test(string $server, OutputInterface $output, bool $ignoreProxyError = false): int {
 		if ($this->queue instanceof RedisQueue) {
 			$output->writeln('<info>✓ redis is configured</info>');
@@ -58,7 +70,7 @@ class SelfTest {
 		$this->config->setAppValue('notify_push', 'cookie', (string)$this->cookie);
 
 		try {
-			$retrievedCookie = (int)$this->client->get($server . '/test/cookie', ['nextcloud' => ['allow_local_address' => true], 'verify' => false])->getBody();
+			$retrievedCookie = (int)$this->client->get($server . '/test/cookie', $this->getRequestOptions())->getBody();
 		} catch (\Exception $e) {
 			$msg = $e->getMessage();
 			$output->writeln("<error>🗴 can't connect to push server: $msg</error>");
@@ -76,7 +88,7 @@ class SelfTest {
 		// test if the push server can load storage mappings from the db
 		[$storageId, $count] = $this->getStorageIdForTest();
 		try {
-			$retrievedCount = (int)$this->client->get($server . '/test/mapping/' . $storageId, ['nextcloud' => ['allow_local_address' => true], 'verify' => false])->getBody();
+			$retrievedCount = (int)$this->client->get($server . '/test/mapping/' . $storageId, $this->getRequestOptions())->getBody();
 		} catch (\Exception $e) {
 			$msg = $e->getMessage();
 			$output->writeln("<error>🗴 can't connect to push server: $msg</error>");
@@ -92,7 +104,7 @@ class SelfTest {
 
 		// test if the push server can reach nextcloud by having it request the cookie
 		try {
-			$response = $this->client->get($server . '/test/reverse_cookie', ['nextcloud' => ['allow_local_address' => true], 'verify' => false])->getBody();
+			$response = $this->client->get($server . '/test/reverse_cookie', $this->getRequestOptions())->getBody();
 			$retrievedCookie = (int)$response;
 
 			if ($this->cookie === $retrievedCookie) {
@@ -112,7 +124,7 @@ class SelfTest {
 
 		// test that the push server is a trusted proxy
 		try {
-			$resolvedRemote = $this->client->get($server . '/test/remote/1.2.3.4', ['nextcloud' => ['allow_local_address' => true], 'verify' => false])->getBody();
+			$resolvedRemote = $this->client->get($server . '/test/remote/1.2.3.4', $this->getRequestOptions())->getBody();
 		} catch (\Exception $e) {
 			$msg = $e->getMessage();
 			$output->writeln("<error>🗴 can't connect to push server: $msg</error>");
@@ -180,7 +192,7 @@ class SelfTest {
 		// test that the binary is up to date
 		try {
 			$this->queue->getConnection()->del('notify_push_version');
-			$response = $this->client->post($server . '/test/version', ['nextcloud' => ['allow_local_address' => true], 'verify' => false]);
+			$response = $this->client->post($server . '/test/version', $this->getRequestOptions());
 			if ($response === 'error') {
 				$output->writeln('<error>🗴 failed to get binary version, check the push server output for more information</error>');
 				return self::ERROR_OTHER;
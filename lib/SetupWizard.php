@@ -181,7 +181,7 @@ class SetupWizard {
 
 	private function isBinaryRunningAt(string $address): bool {
 		try {
-			$result = $this->client->get($address . '/test/cookie', ['nextcloud' => ['allow_local_address' => true], 'verify' => false]);
+			$result = $this->client->get($address . '/test/cookie', $this->test->getRequestOptions());
 			return is_numeric($result->getBody());
 		} catch (\Exception $e) {
 			return false;
@@ -0,0 +1,86 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Activity of the connected users and connections.
+//!
+//! Most connections are parked clients that hardly ever receive a message, so the number of connected users says
+//! little about how much the push server is actually used. Every user and connection keeps the time of its last
+//! message, from which the number of recently active users and idle connections is counted periodically.
+
+use crate::metrics::METRICS;
+use crate::App;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// Users that received a message within this time are counted as active
+pub const ACTIVE_USER_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Connections without messages for longer than this are counted as idle
+pub const IDLE_CONNECTION_TIME: Duration = Duration::from_secs(60 * 60);
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(30);
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Seconds since the start of the process
+pub fn activity_clock() -> u64 {
+    START.elapsed().as_secs()
+}
+
+/// Time of the last activity, with a resolution of a second so it can be updated for every message
+#[derive(Debug)]
+pub struct ActivityTime(AtomicU64);
+
+impl ActivityTime {
+    const NEVER: u64 = u64::MAX;
+
+    pub fn now() -> Self {
+        ActivityTime(AtomicU64::new(activity_clock()))
+    }
+
+    pub fn never() -> Self {
+        ActivityTime(AtomicU64::new(Self::NEVER))
+    }
+
+    pub fn touch(&self) {
+        self.0.store(activity_clock(), Ordering::Relaxed);
+    }
+
+    /// Whether there was any activity since `window` before `now`
+    pub fn is_active(&self, now: u64, window: Duration) -> bool {
+        let last = self.0.load(Ordering::Relaxed);
+        last != Self::NEVER && now.saturating_sub(last) <= window.as_secs()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ActivityStats {
+    /// Connected users that received a message in the last [`ACTIVE_USER_WINDOW`]
+    pub active_users: usize,
+    /// Connections that didn't send a message in the last [`IDLE_CONNECTION_TIME`]
+    pub idle_connections: usize,
+}
+
+/// Periodically update the activity gauges in the metrics
+pub async fn activity_loop(app: Arc<App>) {
+    let mut interval = interval(ACTIVITY_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        METRICS.set_activity(app.connections.activity());
+    }
+}
+
+#[test]
+fn test_activity_time() {
+    let time = ActivityTime(AtomicU64::new(100));
+    assert!(time.is_active(100, ACTIVE_USER_WINDOW));
+    assert!(time.is_active(400, ACTIVE_USER_WINDOW));
+    assert!(!time.is_active(401, ACTIVE_USER_WINDOW));
+    // clocks read before the last update was stored
+    assert!(time.is_active(99, ACTIVE_USER_WINDOW));
+    assert!(!ActivityTime::never().is_active(0, ACTIVE_USER_WINDOW));
+}
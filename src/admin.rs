@@ -0,0 +1,222 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Endpoints for operators, authenticated with the admin token from the config.
+//!
+//! Without an admin token configured all admin endpoints respond with a 404, except for `POST /admin/push` which
+//! also accepts the separate push token.
+
+use crate::event::RelayedMessage;
+use crate::http::WebSocketUpgrade;
+use crate::monitor::handle_monitor_socket;
+use crate::roundtrip::Roundtrip;
+use crate::storage_stats::StorageActivity;
+use crate::user_stats::UserActivity;
+use crate::{constant_time_eq, App, UserId};
+use axum::extract::{RawQuery, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Number of entries returned by the top-n endpoints if no limit is given
+const DEFAULT_LIMIT: usize = 10;
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// The `limit` query parameter of the top-n endpoints
+fn limit(query: Option<String>) -> usize {
+    query_param(query.as_deref(), "limit")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectedUser {
+    pub user: String,
+    /// Number of open websocket connections
+    pub connections: usize,
+    /// Messages waiting to be picked up by the connections of the user
+    pub queued_messages: usize,
+    /// Whether the user has a long-polling client
+    pub polling: bool,
+}
+
+/// The connected users with the most connections first, or only the user from the `user` query parameter
+async fn connections(
+    State(app): State<Arc<App>>,
+    RawQuery(query): RawQuery,
+) -> Json<Vec<ConnectedUser>> {
+    let name = |user: &UserId| user.name().unwrap_or_else(|| String::from("unknown user"));
+    let mut users: Vec<ConnectedUser> = app
+        .connections
+        .user_stats()
+        .into_iter()
+        .map(|(user, connections, queued_messages)| ConnectedUser {
+            polling: app.connections.polls().is_polling(&user),
+            user: name(&user),
+            connections,
+            queued_messages,
+        })
+        .collect();
+    for user in app.connections.polls().polling_users() {
+        let user = name(&user);
+        if !users.iter().any(|connected| connected.user == user) {
+            users.push(ConnectedUser {
+                user,
+                connections: 0,
+                queued_messages: 0,
+                polling: true,
+            });
+        }
+    }
+    if let Some(filter) = query_param(query.as_deref(), "user") {
+        users.retain(|connected| connected.user == filter);
+    }
+    users.sort_by(|a, b| {
+        b.connections
+            .cmp(&a.connections)
+            .then_with(|| a.user.cmp(&b.user))
+    });
+    Json(users)
+}
+
+/// The storages with the most recent storage updates
+async fn storages(
+    State(app): State<Arc<App>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<StorageActivity>>, (StatusCode, &'static str)> {
+    match &app.storage_stats {
+        Some(stats) => Ok(Json(stats.top(limit(query)))),
+        None => Err((StatusCode::NOT_FOUND, "storage stats are not enabled")),
+    }
+}
+
+/// The users that received the most messages within the configured window
+async fn users(
+    State(app): State<Arc<App>>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<Vec<UserActivity>>, (StatusCode, &'static str)> {
+    match app.connections.message_stats() {
+        Some(stats) => Ok(Json(stats.top(limit(query)))),
+        None => Err((StatusCode::NOT_FOUND, "user message stats are not enabled")),
+    }
+}
+
+/// Verify the path from nextcloud through redis to the push server, responds with a 503 if any leg failed
+async fn roundtrip(State(app): State<Arc<App>>) -> (StatusCode, Json<Roundtrip>) {
+    let roundtrip = app.verify_roundtrip().await;
+    let status = if roundtrip.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(roundtrip))
+}
+
+/// Live stream of the metadata of all push messages
+async fn monitor(ws: WebSocketUpgrade, State(app): State<Arc<App>>) -> Response {
+    let events = app.connections.monitor().subscribe();
+    ws.on_upgrade(move |socket| handle_monitor_socket(socket, events))
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRequest {
+    user: String,
+    message: String,
+    #[serde(default)]
+    body: Box<Value>,
+}
+
+/// Send a custom message to a user, like a `notify_custom` event published in redis
+///
+/// The message is relayed to the other instances, since they don't receive it over redis.
+async fn push(State(app): State<Arc<App>>, Json(request): Json<PushRequest>) -> StatusCode {
+    let message = RelayedMessage::Custom {
+        message: request.message,
+        body: request.body,
+    };
+    match app.relay(&request.user, message).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(e) => {
+            // the connections to this instance already received the message
+            log::warn!(
+                "Failed to relay pushed message to the other instances: {:#}",
+                e
+            );
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// Check the bearer token of the request against the configured tokens, 404 if none are configured
+fn check_token<'a>(
+    headers: &HeaderMap,
+    tokens: impl IntoIterator<Item = &'a Option<String>>,
+) -> Result<(), StatusCode> {
+    let mut tokens = tokens.into_iter().flatten().peekable();
+    if tokens.peek().is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+    match token {
+        Some(token) if tokens.any(|expected| constant_time_eq(token, expected)) => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+async fn authenticate(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    match check_token(&headers, [&app.admin_token]) {
+        Ok(()) => next.run(request).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+async fn authenticate_push(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    match check_token(&headers, [&app.push_token, &app.admin_token]) {
+        Ok(()) => next.run(request).await,
+        Err(status) => status.into_response(),
+    }
+}
+
+pub fn admin_routes(app: Arc<App>) -> Router<Arc<App>> {
+    let push_routes =
+        Router::new()
+            .route("/admin/push", post(push))
+            .route_layer(middleware::from_fn_with_state(
+                app.clone(),
+                authenticate_push,
+            ));
+    Router::new()
+        .route("/admin/storages", get(storages))
+        .route("/admin/users", get(users))
+        .route("/admin/connections", get(connections))
+        .route("/admin/monitor", get(monitor))
+        .route("/admin/roundtrip", get(roundtrip))
+        .route_layer(middleware::from_fn_with_state(app, authenticate))
+        .merge(push_routes)
+}
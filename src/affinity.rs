@@ -0,0 +1,59 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Routing keys that let sticky-session load balancers send all connections of a user to the same instance.
+//!
+//! A client asks for the key of its user with `request_affinity` once authenticated, and passes it as the `affinity`
+//! query parameter when it reconnects. Load balancers can hash on the parameter, or on the header with the same value
+//! that is sent back in the handshake response.
+
+/// Response header of the websocket handshake containing the routing key the client passed
+pub const AFFINITY_HEADER: &str = "x-notify-push-affinity";
+
+/// Query parameter of the websocket url the client passes its routing key in
+pub const AFFINITY_QUERY: &str = "affinity";
+
+/// Hash of the user name the routing key is formed from, the same on every instance and version of the push server
+///
+/// This uses FNV-1a instead of the hasher of the standard library, which doesn't guarantee stable output between releases.
+pub fn affinity_hash(user: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let hash = user.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    });
+    hash
+}
+
+/// Get the routing key from the query of a websocket request, if it's well-formed
+pub fn requested_affinity(query: Option<&str>) -> Option<String> {
+    url::form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == AFFINITY_QUERY)
+        .map(|(_, value)| value.into_owned())
+        .filter(|value| value.len() == 16 && value.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+use crate::UserId;
+
+#[test]
+fn test_affinity_key() {
+    // changing the key would move users to different instances during an update
+    assert_eq!(0xdcb27518fed9d577, affinity_hash("foo"));
+    assert_ne!(affinity_hash("foo"), affinity_hash("bar"));
+
+    let key = UserId::new("foo").affinity_key();
+    assert_eq!("dcb27518fed9d577", key);
+    assert_eq!(
+        Some(key.clone()),
+        requested_affinity(Some(&format!("affinity={}", key)))
+    );
+    assert_eq!(
+        None,
+        requested_affinity(Some("affinity=foo\r\nx-injected: 1"))
+    );
+    assert_eq!(None, requested_affinity(Some("other=1")));
+    assert_eq!(None, requested_affinity(None));
+}
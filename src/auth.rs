@@ -0,0 +1,389 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Backends that verify the credentials a client sends when opening a websocket.
+//!
+//! Next to the Nextcloud login, consumers without a Nextcloud account (like dashboards) can authenticate
+//! with a token from a static file or with a bind to an LDAP server. The providers are tried in the configured order.
+
+use crate::config::Config;
+use crate::error::{AuthenticationError, ConfigError};
+use crate::service_account::{service_account_id, ServiceAccount, ServiceScope};
+use crate::{nc, UserId};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parse_display::{Display, FromStr};
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The credentials send by a client
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub forwarded_for: &'a [IpAddr],
+}
+
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Verify the credentials, returning [`AuthenticationError::Invalid`] lets the next provider try them
+    fn verify<'a>(
+        &'a self,
+        credentials: Credentials<'a>,
+    ) -> BoxFuture<'a, Result<UserId, AuthenticationError>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+#[display(style = "snake_case")]
+pub enum AuthProviderKind {
+    /// Login to Nextcloud with the username and (app) password
+    Nextcloud,
+    /// Tokens from the file configured with `AUTH_TOKEN_FILE`
+    TokenFile,
+    /// Bind to the LDAP server configured with `LDAP_URL` and `LDAP_BIND_DN`
+    Ldap,
+}
+
+impl AuthProvider for nc::Client {
+    fn name(&self) -> &'static str {
+        "nextcloud"
+    }
+
+    fn verify<'a>(
+        &'a self,
+        credentials: Credentials<'a>,
+    ) -> BoxFuture<'a, Result<UserId, AuthenticationError>> {
+        self.verify_credentials(
+            credentials.username,
+            credentials.password,
+            credentials.forwarded_for.to_vec(),
+        )
+        .boxed()
+    }
+}
+
+/// A line of the token file
+#[derive(Debug, PartialEq)]
+struct Token {
+    user: String,
+    token: String,
+    /// Users and groups a service account receives the messages for
+    scope: Option<ServiceScope>,
+}
+
+/// Static tokens for service accounts, read from a file with a `username:token` pair per line
+///
+/// The token can be followed by `users=<user>,...` and `groups=<group>,...` to create a service account
+/// that receives the messages for those users instead.
+pub struct TokenFile {
+    tokens: Vec<Token>,
+}
+
+impl TokenFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = read_to_string(path).map_err(|error| ConfigError::AuthTokenFile {
+            path: path.into(),
+            error,
+        })?;
+        Self::parse(&content).map_err(|line| ConfigError::AuthToken {
+            path: path.into(),
+            line,
+        })
+    }
+
+    /// Parse the tokens, returning the number of the first invalid line on error
+    fn parse(content: &str) -> Result<Self, usize> {
+        let tokens = content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| Self::parse_line(line).ok_or(number))
+            .collect::<Result<_, _>>()?;
+        Ok(TokenFile { tokens })
+    }
+
+    fn parse_line(line: &str) -> Option<Token> {
+        let (user, rest) = line.split_once(':')?;
+        let mut parts = rest.split_whitespace();
+        let token = parts.next()?;
+        if user.is_empty() {
+            return None;
+        }
+        let mut scope: Option<ServiceScope> = None;
+        for part in parts {
+            let (key, list) = part.split_once('=')?;
+            let list = list
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(String::from);
+            let scope = scope.get_or_insert_with(Default::default);
+            match key {
+                "users" => scope.users.extend(list),
+                "groups" => scope.groups.extend(list),
+                _ => return None,
+            }
+        }
+        Some(Token {
+            user: user.into(),
+            token: token.into(),
+            scope,
+        })
+    }
+
+    /// The service accounts with their scopes
+    pub fn service_accounts(&self) -> Vec<ServiceAccount> {
+        self.tokens
+            .iter()
+            .filter_map(|token| Some(ServiceAccount::new(&token.user, token.scope.clone()?)))
+            .collect()
+    }
+}
+
+impl AuthProvider for TokenFile {
+    fn name(&self) -> &'static str {
+        "token file"
+    }
+
+    fn verify<'a>(
+        &'a self,
+        credentials: Credentials<'a>,
+    ) -> BoxFuture<'a, Result<UserId, AuthenticationError>> {
+        // check every token for the user, so the timing doesn't tell which of them matched
+        let matched = self
+            .tokens
+            .iter()
+            .filter(|token| token.user == credentials.username)
+            .fold(None, |matched, token| {
+                if crate::constant_time_eq(&token.token, credentials.password) {
+                    Some(token)
+                } else {
+                    matched
+                }
+            });
+        let result = match matched {
+            Some(Token { scope: Some(_), .. }) => Ok(service_account_id(credentials.username)),
+            Some(_) => Ok(UserId::new(credentials.username)),
+            None => Err(AuthenticationError::Invalid),
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+/// Verify the credentials by binding to an LDAP server as the user
+#[cfg(feature = "ldap")]
+pub struct Ldap {
+    url: url::Url,
+    /// DN to bind with, `{user}` is replaced by the escaped username
+    bind_dn: String,
+}
+
+#[cfg(feature = "ldap")]
+impl Ldap {
+    pub fn new(url: url::Url, bind_dn: String) -> Self {
+        Ldap { url, bind_dn }
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> Result<(), AuthenticationError> {
+        use ldap3::{LdapConnAsync, LdapConnSettings};
+        use std::time::Duration;
+
+        // an empty password would be an anonymous bind, which always succeeds
+        if password.is_empty() {
+            return Err(AuthenticationError::Invalid);
+        }
+        let dn = self.bind_dn.replace("{user}", &ldap3::dn_escape(username));
+        let settings = LdapConnSettings::new().set_conn_timeout(Duration::from_secs(5));
+        let (connection, mut ldap) = LdapConnAsync::with_settings(settings, self.url.as_str())
+            .await
+            .map_err(|e| AuthenticationError::Ldap(e.to_string()))?;
+        ldap3::drive!(connection);
+        let result = ldap
+            .simple_bind(&dn, password)
+            .await
+            .map_err(|e| AuthenticationError::Ldap(e.to_string()))?;
+        ldap.unbind().await.ok();
+        match result.rc {
+            0 => Ok(()),
+            // invalidCredentials
+            49 => Err(AuthenticationError::Invalid),
+            _ => Err(AuthenticationError::Ldap(result.to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "ldap")]
+impl AuthProvider for Ldap {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    fn verify<'a>(
+        &'a self,
+        credentials: Credentials<'a>,
+    ) -> BoxFuture<'a, Result<UserId, AuthenticationError>> {
+        async move {
+            self.bind(credentials.username, credentials.password)
+                .await
+                .map(|()| UserId::new(credentials.username))
+        }
+        .boxed()
+    }
+}
+
+/// The configured providers, in the order they are tried
+pub struct AuthProviders {
+    providers: Vec<Arc<dyn AuthProvider>>,
+    /// Service accounts from the token file
+    service_accounts: Vec<ServiceAccount>,
+}
+
+impl AuthProviders {
+    pub fn new(providers: Vec<Arc<dyn AuthProvider>>) -> Self {
+        AuthProviders {
+            providers,
+            service_accounts: Vec::new(),
+        }
+    }
+
+    pub fn from_config(config: &Config, nc_client: Arc<nc::Client>) -> Result<Self, ConfigError> {
+        let mut service_accounts = Vec::new();
+        let providers = config
+            .auth_providers
+            .iter()
+            .map(|kind| -> Result<Arc<dyn AuthProvider>, ConfigError> {
+                match kind {
+                    AuthProviderKind::Nextcloud => Ok(nc_client.clone()),
+                    AuthProviderKind::TokenFile => {
+                        let path = config
+                            .auth_token_file
+                            .as_deref()
+                            .ok_or(ConfigError::AuthProviderOption(*kind, "AUTH_TOKEN_FILE"))?;
+                        let token_file = TokenFile::load(path)?;
+                        service_accounts.extend(token_file.service_accounts());
+                        Ok(Arc::new(token_file))
+                    }
+                    #[cfg(feature = "ldap")]
+                    AuthProviderKind::Ldap => {
+                        let url = config
+                            .ldap_url
+                            .clone()
+                            .ok_or(ConfigError::AuthProviderOption(*kind, "LDAP_URL"))?;
+                        let bind_dn = config
+                            .ldap_bind_dn
+                            .clone()
+                            .ok_or(ConfigError::AuthProviderOption(*kind, "LDAP_BIND_DN"))?;
+                        Ok(Arc::new(Ldap::new(url, bind_dn)))
+                    }
+                    #[cfg(not(feature = "ldap"))]
+                    AuthProviderKind::Ldap => Err(ConfigError::LdapDisabled),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AuthProviders {
+            providers,
+            service_accounts,
+        })
+    }
+
+    pub fn service_accounts(&self) -> &[ServiceAccount] {
+        &self.service_accounts
+    }
+
+    /// Try the credentials with every provider until one accepts them
+    ///
+    /// If none of them do, the first error other than invalid credentials is returned,
+    /// so clients can tell a backend that's down from a wrong password.
+    pub async fn verify(
+        &self,
+        credentials: Credentials<'_>,
+    ) -> Result<UserId, AuthenticationError> {
+        let mut error = None;
+        for provider in &self.providers {
+            match provider.verify(credentials).await {
+                Ok(user) => {
+                    log::debug!(
+                        "Authenticated {} using {}",
+                        credentials.username,
+                        provider.name()
+                    );
+                    return Ok(user);
+                }
+                Err(AuthenticationError::Invalid) => {}
+                Err(e) => {
+                    log::debug!(
+                        "Failed to verify credentials for {} using {}: {}",
+                        credentials.username,
+                        provider.name(),
+                        e
+                    );
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or(AuthenticationError::Invalid))
+    }
+}
+
+#[test]
+fn test_parse_token_file() {
+    let file = TokenFile::parse(
+        "# dashboards\n\
+         dashboard:secret\n\
+         \n\
+         dashboard:rotated:with:colons\n\
+         monitoring:other\n\
+         signage:token users=alice,bob groups=signage",
+    )
+    .unwrap();
+    let token = |user: &str, token: &str, scope: Option<ServiceScope>| Token {
+        user: user.into(),
+        token: token.into(),
+        scope,
+    };
+    assert_eq!(
+        vec![
+            token("dashboard", "secret", None),
+            token("dashboard", "rotated:with:colons", None),
+            token("monitoring", "other", None),
+            token(
+                "signage",
+                "token",
+                Some(ServiceScope {
+                    users: vec!["alice".into(), "bob".into()],
+                    groups: vec!["signage".into()],
+                })
+            ),
+        ],
+        file.tokens
+    );
+    assert_eq!(1, file.service_accounts().len());
+    assert_eq!(Some(3), TokenFile::parse("a:b\n\nc\n").err());
+    assert_eq!(Some(1), TokenFile::parse(":b").err());
+    assert_eq!(Some(1), TokenFile::parse("a:b owner=c").err());
+}
+
+#[tokio::test]
+async fn test_auth_provider_stack() {
+    let tokens =
+        |content: &str| -> Arc<dyn AuthProvider> { Arc::new(TokenFile::parse(content).unwrap()) };
+    let providers = AuthProviders::new(vec![tokens("foo:first"), tokens("foo:second")]);
+    let verify = |password: &'static str| {
+        providers.verify(Credentials {
+            username: "foo",
+            password,
+            forwarded_for: &[],
+        })
+    };
+    assert_eq!(UserId::new("foo"), verify("first").await.unwrap());
+    assert_eq!(UserId::new("foo"), verify("second").await.unwrap());
+    assert!(matches!(
+        verify("third").await,
+        Err(AuthenticationError::Invalid)
+    ));
+}
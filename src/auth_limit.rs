@@ -0,0 +1,189 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Protection against brute forcing credentials through the push server.
+//!
+//! Failed logins are counted per client address and per username, until no attempts failed for the cooldown time.
+//! Once an address goes over the threshold, further attempts from it are rejected without asking nextcloud.
+//! Rejecting every attempt for a username would let anyone lock out a known user, so those are only delayed instead.
+
+use crate::error::AuthenticationError;
+use crate::UserId;
+use dashmap::DashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Stop tracking failures that are over the cooldown once this many addresses or usernames are tracked
+const MAX_TRACKED: usize = 10_000;
+/// Delay for the first attempt for a username that went over the threshold, doubled for every further failure
+const USER_DELAY: Duration = Duration::from_secs(1);
+const MAX_USER_DELAY: Duration = Duration::from_secs(30);
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+pub struct AuthLimiter {
+    threshold: u32,
+    cooldown: Duration,
+    addresses: DashMap<IpAddr, Failures>,
+    users: DashMap<String, Failures>,
+}
+
+impl AuthLimiter {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        AuthLimiter {
+            threshold,
+            cooldown,
+            addresses: DashMap::default(),
+            users: DashMap::default(),
+        }
+    }
+
+    fn is_blocked<K: Eq + Hash>(
+        &self,
+        failures: &DashMap<K, Failures>,
+        key: &K,
+        now: Instant,
+    ) -> bool {
+        failures.get(key).is_some_and(|failures| {
+            failures.count >= self.threshold && now.duration_since(failures.last) < self.cooldown
+        })
+    }
+
+    /// Whether attempts from this address are currently rejected
+    pub fn is_limited(&self, client: Option<IpAddr>, now: Instant) -> bool {
+        client.is_some_and(|client| self.is_blocked(&self.addresses, &client, now))
+    }
+
+    /// How long to wait before verifying an attempt for this username
+    pub fn user_delay(&self, username: &str, now: Instant) -> Option<Duration> {
+        let failures = self.users.get(username)?;
+        if failures.count < self.threshold || now.duration_since(failures.last) >= self.cooldown {
+            return None;
+        }
+        let doublings = (failures.count - self.threshold).min(5);
+        Some((USER_DELAY * 2u32.pow(doublings)).min(MAX_USER_DELAY))
+    }
+
+    fn add_failure<K: Eq + Hash + Clone>(
+        &self,
+        failures: &DashMap<K, Failures>,
+        key: &K,
+        now: Instant,
+    ) -> bool {
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, failures| now.duration_since(failures.last) < self.cooldown);
+        }
+        let mut entry = failures.entry(key.clone()).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        // failures from before the last cooldown are forgotten
+        if now.duration_since(entry.last) >= self.cooldown {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        entry.count == self.threshold
+    }
+
+    pub fn record_failure(&self, client: Option<IpAddr>, username: &str, now: Instant) {
+        if let Some(client) = client {
+            if self.add_failure(&self.addresses, &client, now) {
+                log::warn!(
+                    "Too many failed login attempts from {}, rejecting logins for {}s",
+                    client,
+                    self.cooldown.as_secs()
+                );
+            }
+        }
+        if !username.is_empty() && self.add_failure(&self.users, &username.to_string(), now) {
+            log::warn!(
+                "Too many failed login attempts for {}, delaying logins for {}s",
+                username,
+                self.cooldown.as_secs()
+            );
+        }
+    }
+
+    /// Forget the failures of a user after a successful login, failures of the address are kept
+    pub fn record_success(&self, username: &str) {
+        self.users.remove(username);
+    }
+
+    /// Run the verification of the credentials unless the client is limited, and count the result
+    pub async fn limit(
+        &self,
+        client: Option<IpAddr>,
+        username: &str,
+        verify: impl Future<Output = Result<UserId, AuthenticationError>>,
+    ) -> Result<UserId, AuthenticationError> {
+        if self.is_limited(client, Instant::now()) {
+            return Err(AuthenticationError::TooManyAttempts);
+        }
+        if let Some(delay) = self.user_delay(username, Instant::now()) {
+            log::debug!("Delaying login attempt for {} by {:?}", username, delay);
+            sleep(delay).await;
+        }
+        let result = verify.await;
+        match &result {
+            Ok(_) => self.record_success(username),
+            Err(AuthenticationError::Invalid) => {
+                self.record_failure(client, username, Instant::now())
+            }
+            // errors talking to nextcloud or ldap aren't the client's fault
+            Err(_) => {}
+        }
+        result
+    }
+}
+
+#[test]
+fn test_auth_limit() {
+    let limiter = AuthLimiter::new(3, Duration::from_secs(60));
+    let client: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+    let other: Option<IpAddr> = Some("10.0.0.2".parse().unwrap());
+    let start = Instant::now();
+
+    for _ in 0..2 {
+        limiter.record_failure(client, "foo", start);
+    }
+    assert!(!limiter.is_limited(client, start));
+    assert_eq!(None, limiter.user_delay("foo", start));
+    limiter.record_failure(client, "foo", start);
+    assert!(limiter.is_limited(client, start));
+    assert!(!limiter.is_limited(other, start));
+    assert!(!limiter.is_limited(None, start));
+    // the username is only delayed, so others can't lock out the user
+    assert_eq!(
+        Some(Duration::from_secs(1)),
+        limiter.user_delay("foo", start)
+    );
+    assert_eq!(None, limiter.user_delay("bar", start));
+    limiter.record_failure(other, "foo", start);
+    assert_eq!(
+        Some(Duration::from_secs(2)),
+        limiter.user_delay("foo", start)
+    );
+
+    let later = start + Duration::from_secs(60);
+    assert!(!limiter.is_limited(client, later));
+    assert_eq!(None, limiter.user_delay("foo", later));
+    // the count starts over after the cooldown
+    limiter.record_failure(client, "foo", later);
+    assert!(!limiter.is_limited(client, later));
+
+    for _ in 0..10 {
+        limiter.record_failure(other, "foo", later);
+    }
+    assert_eq!(Some(MAX_USER_DELAY), limiter.user_delay("foo", later));
+    limiter.record_success("foo");
+    assert_eq!(None, limiter.user_delay("foo", later));
+}
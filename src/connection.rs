@@ -3,36 +3,232 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
 
-use crate::error::{AuthenticationError, WebSocketError};
-use crate::message::{PushMessage, SendQueue};
+use crate::activity::{
+    activity_clock, ActivityStats, ActivityTime, ACTIVE_USER_WINDOW, IDLE_CONNECTION_TIME,
+};
+use crate::auth::Credentials;
+use crate::error::{AuthenticationError, WebSocketError, WebSocketErrorKind};
+use crate::forwarded::Forwarded;
+use crate::http::WebSocket;
+use crate::message::{
+    CustomDebounce, MergeTime, PushMessage, Reply, SendQueue, Subprotocol, DEFAULT_MAX_FILE_IDS,
+};
 use crate::metrics::METRICS;
+use crate::monitor::Monitor;
 use crate::passthru_hasher::PassthruHasher;
+use crate::poll::PollBuffers;
+use crate::presence::{PresenceState, PresenceWebhook};
+use crate::supervisor::spawn_supervised;
+use crate::trace::{Stage, Tracer};
+use crate::user_stats::UserMessageStats;
 use crate::Result;
 use crate::{App, UserId};
+use ahash::RandomState;
 use dashmap::mapref::entry::Entry;
-use dashmap::DashMap;
-use futures::{future::select, pin_mut, SinkExt, StreamExt};
-use rand::{Rng, SeedableRng};
+use dashmap::{DashMap, DashSet};
+use futures::{future::select, pin_mut, Sink, SinkExt, StreamExt};
+use parse_display::Display;
+use rand::Rng;
+use std::cmp::min;
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
-use tokio::sync::broadcast;
-use tokio::time::timeout;
-use warp::filters::ws::{Message, WebSocket};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep_until, timeout};
+use tokio_tungstenite::tungstenite::Message;
 
 const USER_CONNECTION_LIMIT: usize = 64;
+const MAX_CLIENT_ID_LENGTH: usize = 64;
 const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// The first ping is sent up to this much earlier, so connections opened at the same time don't all ping at once
+const PING_JITTER: Duration = Duration::from_secs(10);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Request to close open connections, broadcast to every connection
+#[derive(Debug, Clone)]
+pub enum Reset {
+    All,
+    User(UserId),
+}
+
+impl Reset {
+    pub fn applies_to(&self, user: &UserId) -> bool {
+        match self {
+            Reset::All => true,
+            Reset::User(reset_user) => reset_user == user,
+        }
+    }
+}
+
+struct UserConnections {
+    sender: broadcast::Sender<PushMessage>,
+    /// Messages for the delivery task of the user, if messages are debounced per user
+    inbox: Option<mpsc::UnboundedSender<PushMessage>>,
+    last_message: ActivityTime,
+}
+
+impl UserConnections {
+    fn send(&self, msg: PushMessage) {
+        match &self.inbox {
+            Some(inbox) => {
+                inbox.send(msg).ok();
+            }
+            None => {
+                self.sender.send(msg).ok();
+            }
+        }
+    }
+}
+
+/// Debounces the messages for a user once and forwards them to all connections of the user
+///
+/// The task stops once the user is removed from the active connections.
+async fn deliver_to_user(
+    mut inbox: mpsc::UnboundedReceiver<PushMessage>,
+    sender: broadcast::Sender<PushMessage>,
+    max_debounce_time: usize,
+    mut send_queue: SendQueue,
+) {
+    loop {
+        let next_flush = send_queue.next_flush(debounce_connection_count(), max_debounce_time);
+        tokio::select! {
+            msg = inbox.recv() => {
+                let Some(msg) = msg else {
+                    return;
+                };
+                if let Some(msg) = send_queue.push(msg, Instant::now()) {
+                    sender.send(msg).ok();
+                }
+            }
+            _ = sleep_until(next_flush.unwrap_or_else(Instant::now).into()), if next_flush.is_some() => {
+                for msg in send_queue.drain(Instant::now(), debounce_connection_count(), max_debounce_time) {
+                    sender.send(msg).ok();
+                }
+            }
+        }
+    }
+}
+
+/// The connection count used to scale the debounce time
+fn debounce_connection_count() -> usize {
+    METRICS.active_connection_count() + 50000
+}
 
 #[derive(Default)]
-pub struct ActiveConnections(DashMap<UserId, broadcast::Sender<PushMessage>, PassthruHasher>);
+pub struct ActiveConnections {
+    users: DashMap<UserId, UserConnections, PassthruHasher>,
+    /// Time of the last message sent over each connection, by connection id
+    connection_activity: DashMap<u64, Arc<ActivityTime>, RandomState>,
+    presence: Option<PresenceWebhook>,
+    /// Users that were connected before a restart
+    resumed: DashSet<UserId, PassthruHasher>,
+    resumed_until: OnceLock<Instant>,
+    /// Maximum debounce time for the per user delivery tasks, if enabled
+    user_delivery: Option<usize>,
+    /// Number of messages sent to each user, if enabled
+    message_stats: Option<UserMessageStats>,
+    tracer: Tracer,
+    monitor: Monitor,
+    /// Recent messages for the users with long-polling clients
+    polls: PollBuffers,
+    /// Maximum number of file ids collected into a single message
+    max_file_ids: usize,
+    /// Custom messages that are debounced like the built-in messages
+    custom_debounce: Vec<CustomDebounce>,
+    merge_time: MergeTime,
+}
 
 impl ActiveConnections {
+    pub fn new(presence: Option<PresenceWebhook>) -> Self {
+        ActiveConnections {
+            users: DashMap::default(),
+            connection_activity: DashMap::default(),
+            presence,
+            resumed: DashSet::default(),
+            resumed_until: OnceLock::new(),
+            user_delivery: None,
+            message_stats: None,
+            tracer: Tracer::default(),
+            monitor: Monitor::default(),
+            polls: PollBuffers::default(),
+            max_file_ids: DEFAULT_MAX_FILE_IDS,
+            custom_debounce: Vec::new(),
+            merge_time: MergeTime::default(),
+        }
+    }
+
+    pub fn with_max_file_ids(mut self, max_file_ids: usize) -> Self {
+        self.max_file_ids = max_file_ids;
+        self
+    }
+
+    pub fn max_file_ids(&self) -> usize {
+        self.max_file_ids
+    }
+
+    pub fn with_custom_debounce(mut self, custom_debounce: Vec<CustomDebounce>) -> Self {
+        self.custom_debounce = custom_debounce;
+        self
+    }
+
+    pub fn with_merge_time(mut self, merge_time: MergeTime) -> Self {
+        self.merge_time = merge_time;
+        self
+    }
+
+    pub fn merge_time(&self) -> MergeTime {
+        self.merge_time
+    }
+
+    /// Create an empty queue to debounce the messages for a user with
+    pub fn send_queue(&self) -> SendQueue {
+        SendQueue::new()
+            .with_max_file_ids(self.max_file_ids)
+            .with_custom_debounce(&self.custom_debounce)
+            .with_merge_time(self.merge_time)
+    }
+
+    /// Count the messages sent to each user
+    pub fn with_message_stats(mut self, stats: UserMessageStats) -> Self {
+        self.message_stats = Some(stats);
+        self
+    }
+
+    pub fn message_stats(&self) -> Option<&UserMessageStats> {
+        self.message_stats.as_ref()
+    }
+
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    pub fn polls(&self) -> &PollBuffers {
+        &self.polls
+    }
+
+    /// Debounce the messages once per user in a separate task instead of in every connection,
+    /// the connections then send every message they receive right away
+    pub fn with_user_delivery(mut self, max_debounce_time: usize) -> Self {
+        self.user_delivery = Some(max_debounce_time);
+        self
+    }
+
+    /// Whether the messages are already debounced before they are send to the connections
+    pub fn is_debounced_per_user(&self) -> bool {
+        self.user_delivery.is_some()
+    }
+
     pub fn add(&self, user: UserId) -> Result<broadcast::Receiver<PushMessage>> {
-        match self.0.entry(user) {
+        match self.users.entry(user) {
             Entry::Occupied(entry) => {
-                let sender = entry.get();
+                let sender = &entry.get().sender;
                 if sender.receiver_count() > USER_CONNECTION_LIMIT {
                     Err(AuthenticationError::LimitExceeded.into())
                 } else {
@@ -41,87 +237,326 @@ impl ActiveConnections {
             }
             Entry::Vacant(entry) => {
                 METRICS.add_user();
+                if let Some(presence) = &self.presence {
+                    presence.notify(entry.key(), PresenceState::Connected);
+                }
                 let (tx, rx) = broadcast::channel(4);
-                entry.insert(tx);
+                let inbox = self.user_delivery.map(|max_debounce_time| {
+                    let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+                    spawn_supervised(
+                        "user delivery",
+                        deliver_to_user(inbox_rx, tx.clone(), max_debounce_time, self.send_queue()),
+                    );
+                    inbox_tx
+                });
+                entry.insert(UserConnections {
+                    sender: tx,
+                    inbox,
+                    last_message: ActivityTime::never(),
+                });
                 Ok(rx)
             }
         }
     }
 
+    #[cfg_attr(feature = "otel", tracing::instrument(skip(self, msg), fields(message = %msg)))]
     pub fn send_to_user(&self, user: &UserId, msg: PushMessage) {
-        if let Some(tx) = self.0.get(user) {
-            tx.send(msg).ok();
+        let connections = self.users.get(user);
+        let polled = self.polls.send_to_user(user, &msg);
+        self.monitor
+            .record(Some(user), connections.is_some() || polled, &msg);
+        if connections.is_some() || polled {
+            if let Some(stats) = &self.message_stats {
+                stats.record(user);
+            }
+        }
+        if let Some(connections) = connections {
+            connections.last_message.touch();
+            connections.send(msg);
+        }
+    }
+
+    pub fn send_to_all(&self, msg: PushMessage) {
+        self.monitor.record(None, true, &msg);
+        self.polls.send_to_all(&msg);
+        for connections in self.users.iter() {
+            connections.last_message.touch();
+            connections.send(msg.clone());
+        }
+    }
+
+    /// Whether the user has any websocket connections or polling clients
+    pub fn is_connected(&self, user: &UserId) -> bool {
+        self.users.contains_key(user) || self.polls.is_polling(user)
+    }
+
+    /// All users with websocket connections
+    pub fn users(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.users
+            .iter()
+            .map(|connections| connections.key().clone())
+    }
+
+    /// Mark users as connected before a restart, their sessions are resumed if they reconnect before the deadline
+    pub fn resume(&self, users: impl IntoIterator<Item = UserId>, until: Instant) {
+        if self.resumed_until.set(until).is_ok() {
+            for user in users {
+                self.resumed.insert(user);
+            }
+        }
+    }
+
+    /// Check if the user was connected before a restart
+    pub fn is_resumed(&self, user: &UserId) -> bool {
+        match self.resumed_until.get() {
+            Some(until) if *until > Instant::now() => self.resumed.contains(user),
+            Some(_) => {
+                self.resumed.clear();
+                false
+            }
+            None => false,
         }
     }
 
+    /// Number of open connections and queued messages for every connected user
+    pub fn user_stats(&self) -> Vec<(UserId, usize, usize)> {
+        self.users
+            .iter()
+            .map(|connections| {
+                (
+                    connections.key().clone(),
+                    connections.sender.receiver_count(),
+                    connections.sender.len(),
+                )
+            })
+            .collect()
+    }
+
+    /// Start keeping the time of the last message sent over a connection
+    pub fn track_activity(&self, connection_id: u64) -> Arc<ActivityTime> {
+        let activity = Arc::new(ActivityTime::now());
+        self.connection_activity
+            .insert(connection_id, activity.clone());
+        activity
+    }
+
+    pub fn untrack_activity(&self, connection_id: u64) {
+        self.connection_activity.remove(&connection_id);
+    }
+
+    /// Count the recently active users and the idle connections
+    pub fn activity(&self) -> ActivityStats {
+        self.activity_at(activity_clock())
+    }
+
+    fn activity_at(&self, now: u64) -> ActivityStats {
+        ActivityStats {
+            active_users: self
+                .users
+                .iter()
+                .filter(|connections| connections.last_message.is_active(now, ACTIVE_USER_WINDOW))
+                .count(),
+            idle_connections: self
+                .connection_activity
+                .iter()
+                .filter(|activity| !activity.is_active(now, IDLE_CONNECTION_TIME))
+                .count(),
+        }
+    }
+
+    pub fn resumed_count(&self) -> usize {
+        self.resumed.len()
+    }
+
     pub fn remove(&self, user: &UserId) {
-        if let Entry::Occupied(e) = self.0.entry(user.clone()) {
-            if e.get().receiver_count() == 1 {
+        if let Entry::Occupied(e) = self.users.entry(user.clone()) {
+            if e.get().sender.receiver_count() == 1 {
                 log::debug!("Removing {} from active connections", user);
                 METRICS.remove_user();
+                if let Some(presence) = &self.presence {
+                    presence.notify(user, PresenceState::Disconnected);
+                }
                 e.remove();
             }
         }
     }
 }
 
+/// Limits the rate of new connections for a while after startup,
+/// so reconnecting clients don't all hit the nextcloud authentication at once
+pub struct ConnectionRamp {
+    rate: u32,
+    until: Instant,
+    /// Start of the current one second window and the connections accepted in it
+    window: Mutex<(Instant, u32)>,
+}
+
+impl ConnectionRamp {
+    pub fn new(rate: u32, duration: Duration) -> Self {
+        let now = Instant::now();
+        ConnectionRamp {
+            rate,
+            until: now + duration,
+            window: Mutex::new((now, 0)),
+        }
+    }
+
+    pub fn try_accept(&self) -> bool {
+        let now = Instant::now();
+        if now >= self.until {
+            return true;
+        }
+        let mut window = self.window.lock().unwrap();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 < self.rate {
+            window.1 += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct ConnectionOptions {
+    pub id: u64,
     pub listen_file_id: AtomicBool,
     pub max_debounce_time: usize,
     pub max_connection_time: Duration,
+    /// Name and version the client identified itself with, e.g. `desktop/3.14.0`
+    pub client_id: OnceLock<String>,
+    /// Framing of the messages, negotiated during the upgrade
+    pub subprotocol: Subprotocol,
+    /// Time to wait for more messages to merge with before sending
+    pub merge_time: MergeTime,
 }
 
 impl ConnectionOptions {
     pub fn new(max_debounce_time: usize, max_connection_time: usize) -> Self {
         ConnectionOptions {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
             max_debounce_time,
             max_connection_time: Duration::from_secs(max_connection_time as u64),
             ..ConnectionOptions::default()
         }
     }
+
+    pub fn client(&self) -> &str {
+        self.client_id
+            .get()
+            .map(String::as_str)
+            .unwrap_or("unidentified client")
+    }
+}
+
+/// Commands a client can send over an authenticated connection
+#[derive(Debug, PartialEq, Display)]
+pub enum ClientCommand {
+    #[display("listen notify_file_id")]
+    ListenFileId,
+    #[display("client_id {0}")]
+    ClientId(String),
+    #[display("request_transfer_token")]
+    RequestTransferToken,
+    #[display("request_affinity")]
+    RequestAffinity,
+}
+
+impl ClientCommand {
+    pub fn parse(text: &str) -> Option<Self> {
+        if text == "listen notify_file_id" {
+            Some(ClientCommand::ListenFileId)
+        } else if let Some(client_id) = text.strip_prefix("client_id ") {
+            Some(ClientCommand::ClientId(
+                client_id
+                    .trim()
+                    .chars()
+                    .take(MAX_CLIENT_ID_LENGTH)
+                    .collect(),
+            ))
+        } else if text == "request_transfer_token" {
+            Some(ClientCommand::RequestTransferToken)
+        } else if text == "request_affinity" {
+            Some(ClientCommand::RequestAffinity)
+        } else {
+            None
+        }
+    }
 }
 
 pub async fn handle_user_socket(
     mut ws: WebSocket,
     app: Arc<App>,
-    forwarded_for: Vec<IpAddr>,
+    forwarded: Forwarded,
     opts: ConnectionOptions,
 ) {
     let user_id = match timeout(
         Duration::from_secs(15),
-        socket_auth(&mut ws, forwarded_for, &app),
+        socket_auth(&mut ws, forwarded, &app),
     )
     .await
     {
         Ok(Ok(user_id)) => user_id,
         Ok(Err(e)) => {
             log::warn!("{}", e);
-            ws.send(Message::text(format!("err: {}", e))).await.ok();
+            ws.send(Reply::Error(e.to_string()).into_message(opts.subprotocol))
+                .await
+                .ok();
             return;
         }
         Err(_) => {
-            ws.send(Message::text("Authentication timeout".to_string()))
-                .await
-                .ok();
+            let reply = Reply::UnprefixedError("Authentication timeout".into());
+            ws.send(reply.into_message(opts.subprotocol)).await.ok();
             return;
         }
     };
 
     log::info!("new websocket authenticated as {}", user_id);
-    ws.send(Message::text("authenticated")).await.ok();
+    ws.send(Reply::Authenticated.into_message(opts.subprotocol))
+        .await
+        .ok();
 
+    app.shards
+        .run(
+            &user_id,
+            serve_connection(ws, app.clone(), user_id.clone(), opts),
+        )
+        .await;
+}
+
+/// Send the messages for the authenticated user until the connection is closed
+async fn serve_connection(
+    mut ws: WebSocket,
+    app: Arc<App>,
+    user_id: UserId,
+    opts: ConnectionOptions,
+) {
     let mut rx = match app.connections.add(user_id.clone()) {
         Ok(rx) => rx,
         Err(e) => {
-            ws.send(Message::text(e.to_string())).await.ok();
+            ws.send(Reply::UnprefixedError(e.to_string()).into_message(opts.subprotocol))
+                .await
+                .ok();
             return;
         }
     };
 
-    let (mut user_ws_tx, mut user_ws_rx) = ws.split();
+    if app.connections.is_resumed(&user_id) {
+        // any events between the shutdown and the restart were lost, tell the client to check for updates
+        log::debug!("Resuming session for {} after restart", user_id);
+        for msg in PushMessage::resync() {
+            ws.feed(msg.into_message(&opts)).await.ok();
+        }
+        ws.flush().await.ok();
+    }
+
+    let (user_ws_tx, mut user_ws_rx) = ws.split();
 
     METRICS.add_connection();
+    let activity = app.connections.track_activity(opts.id);
+    let activity = &activity;
 
     // Every time we send a ping, we set this to a random non-zero value
     // when a pong is returned, we check it against the expected value and reset this to 0
@@ -130,72 +565,119 @@ pub async fn handle_user_socket(
     let expect_pong = AtomicUsize::default();
     let expect_pong = &expect_pong;
 
-    let transmit = async {
-        // Use faster random generator for generating ping messages, they dont need to be
-        // cryptographically secure. It is also OK to use same sequence for every connection.
-        let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+    // replies to commands sent by the client
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Message>(1);
 
-        let mut send_queue = SendQueue::default();
+    let transmit = async {
+        let mut writer = FrameWriter::new(user_ws_tx);
+        let debounced = app.connections.is_debounced_per_user();
+        let mut send_queue = app
+            .connections
+            .send_queue()
+            .with_merge_time(opts.merge_time);
 
         let mut reset = app.reset_rx();
 
         let connection_start_time = Instant::now();
-        let mut last_send = connection_start_time - PING_INTERVAL;
+        let mut last_send = connection_start_time - ping_jitter(opts.id);
+        let connection_deadline = (opts.max_connection_time != Duration::ZERO)
+            .then(|| connection_start_time + opts.max_connection_time);
+
+        // set when the server closes the connection itself, as opposed to the connection being lost
+        let close_reason = 'tx_loop: loop {
+            // instead of polling, we sleep until the next queued message has to be sent or a ping is due,
+            // so idle connections don't cause any wakeups in between
+            let connection_count = debounce_connection_count();
+            let next_wakeup = [
+                send_queue.next_flush(connection_count, opts.max_debounce_time),
+                connection_deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .fold(last_send + PING_INTERVAL, min);
 
-        'tx_loop: loop {
             tokio::select! {
-                msg = timeout(Duration::from_millis(500), rx.recv()) => {
+                msg = rx.recv() => {
+                    let mut msg = match msg {
+                        Ok(msg) => Some(msg),
+                        // we dont care about dropped messages
+                        Err(broadcast::error::RecvError::Lagged(_)) => None,
+                        Err(broadcast::error::RecvError::Closed) => break 'tx_loop None,
+                    };
                     let now = Instant::now();
-                    match msg {
-                        Ok(Ok(msg)) => {
-                            if let Some(msg) = send_queue.push(msg, now) {
-                                log::debug!(target: "notify_push::send", "Sending {} to {}", msg, user_id);
-                                METRICS.add_message();
-                                last_send = now;
-                                user_ws_tx.send(msg.into_message(&opts)).await.ok();
-                            }
+                    while let Some(current) = msg {
+                        let current = if debounced {
+                            Some(current)
+                        } else {
+                            send_queue.push(current, now)
+                        };
+                        if let Some(current) = current {
+                            log::debug!(target: "notify_push::send", "Sending {} to {} ({})", current, user_id, opts.client());
+                            app.connections.tracer().record(&user_id, Stage::Sent, now, &current);
+                            METRICS.add_message();
+                            activity.touch();
+                            last_send = now;
+                            writer.feed(current.into_message(&opts)).await;
                         }
-                        Err(_timout) => {
-                            if opts.max_connection_time != Duration::ZERO && now - connection_start_time > opts.max_connection_time {
-                                user_ws_tx.close().await.ok();
-                                log::debug!("Connection closed by exceeding maximum connection time");
-                                break 'tx_loop;
-                            }
+                        // messages that are already waiting are written together with this one
+                        msg = try_recv_queued(&mut rx);
+                    }
+                    writer.flush().await;
+                },
+                _ = sleep_until(next_wakeup.into()) => {
+                    let now = Instant::now();
+                    if connection_deadline.is_some_and(|deadline| now >= deadline) {
+                        break 'tx_loop Some("exceeding maximum connection time");
+                    }
 
-                            for msg in send_queue.drain(now, METRICS.active_connection_count() + 50000, opts.max_debounce_time) {
-                                last_send = now;
-                                METRICS.add_message();
-                                log::debug!(target: "notify_push::send", "Sending debounced {} to {}", msg, user_id);
-                                user_ws_tx.feed(msg.into_message(&opts)).await.ok();
-                            }
+                    for msg in send_queue.drain(now, connection_count, opts.max_debounce_time) {
+                        last_send = now;
+                        METRICS.add_message();
+                        activity.touch();
+                        log::debug!(target: "notify_push::send", "Sending debounced {} to {} ({})", msg, user_id, opts.client());
+                        app.connections.tracer().record(&user_id, Stage::Sent, now, &msg);
+                        writer.feed(msg.into_message(&opts)).await;
+                    }
 
-                            if now.duration_since(last_send) > PING_INTERVAL {
-                                let data = rng.gen::<NonZeroUsize>().into();
-                                let last_ping = expect_pong.swap(data, Ordering::SeqCst);
-                                if last_ping > 0 {
-                                    log::info!("{} didn't reply to ping, closing", user_id);
-                                    break;
-                                }
-                                log::debug!(target: "notify_push::send", "Sending ping to {}", user_id);
-                                last_send = now;
-                                user_ws_tx
-                                    .feed(Message::ping(data.to_le_bytes()))
-                                    .await
-                                    .ok();
-                            }
-                            user_ws_tx.flush().await.ok();
-                        }
-                        Ok(Err(_)) => {
-                            // we dont care about dropped messages
+                    if now.duration_since(last_send) >= PING_INTERVAL {
+                        let data = ping_nonce().into();
+                        let last_ping = expect_pong.swap(data, Ordering::SeqCst);
+                        if last_ping > 0 {
+                            log::info!("{} ({}) didn't reply to ping, closing", user_id, opts.client());
+                            break None;
                         }
+                        log::debug!(target: "notify_push::send", "Sending ping to {} ({})", user_id, opts.client());
+                        last_send = now;
+                        writer.feed(Message::Ping(data.to_le_bytes().to_vec().into())).await;
                     }
+                    writer.flush().await;
+                },
+                Some(reply) = reply_rx.recv() => {
+                    writer.feed(reply).await;
+                    writer.flush().await;
                 },
-                _ = reset.recv() => {
-                    user_ws_tx.close().await.ok();
-                    log::debug!("Connection closed by reset request");
-                    break 'tx_loop;
+                reset = reset.recv() => match reset {
+                    Ok(reset) if !reset.applies_to(&user_id) => {},
+                    Ok(Reset::User(_)) => break 'tx_loop Some("disconnect request"),
+                    // a lagging connection might have missed a reset, so it's closed to be sure
+                    _ => break 'tx_loop Some("reset request"),
                 },
             };
+        };
+
+        if let Some(reason) = close_reason {
+            // messages still waiting for their debounce time are sent now instead of being lost with the connection
+            let now = Instant::now();
+            for msg in send_queue.take_pending() {
+                METRICS.add_message();
+                log::debug!(target: "notify_push::send", "Sending pending {} to {} ({}) before closing", msg, user_id, opts.client());
+                app.connections
+                    .tracer()
+                    .record(&user_id, Stage::Sent, now, &msg);
+                writer.feed(msg.into_message(&opts)).await;
+            }
+            writer.close().await;
+            log::debug!("Connection closed by {}", reason);
         }
     };
 
@@ -205,26 +687,59 @@ pub async fn handle_user_socket(
             match result {
                 Ok(msg) if msg.is_pong() => {
                     let expected = expect_pong.swap(0, Ordering::SeqCst);
-                    if msg.as_bytes() != expected.to_le_bytes() {
+                    if msg.into_data() != expected.to_le_bytes().as_slice() {
                         log::info!("received wrong pong, closing");
                         break;
                     }
                 }
-                Ok(msg) if msg.is_text() => {
-                    let text = msg.to_str().unwrap_or_default();
-                    if text == "listen notify_file_id" {
+                Ok(Message::Text(text)) => match ClientCommand::parse(text.as_str()) {
+                    Some(ClientCommand::ListenFileId) => {
                         opts.listen_file_id.store(true, Ordering::Relaxed);
                     }
-                }
+                    Some(ClientCommand::ClientId(client_id)) if opts.client_id.get().is_none() => {
+                        opts.client_id.set(client_id).ok();
+                        log::info!("{} identified as {}", user_id, opts.client());
+                        METRICS.add_client_connection(opts.client());
+                    }
+                    Some(ClientCommand::RequestTransferToken) => {
+                        let reply = if !app.transfer_tokens {
+                            log::debug!(
+                                "{} requested a transfer token, but they are disabled",
+                                user_id
+                            );
+                            Reply::Error("Failed to create transfer token".into())
+                        } else {
+                            match app.create_transfer_token(&user_id).await {
+                                Ok(token) => Reply::TransferToken(token),
+                                Err(e) => {
+                                    log::warn!("Failed to create transfer token: {:#}", e);
+                                    Reply::Error("Failed to create transfer token".into())
+                                }
+                            }
+                        };
+                        reply_tx
+                            .send(reply.into_message(opts.subprotocol))
+                            .await
+                            .ok();
+                    }
+                    Some(ClientCommand::RequestAffinity) => {
+                        reply_tx
+                            .send(
+                                Reply::Affinity(user_id.affinity_key())
+                                    .into_message(opts.subprotocol),
+                            )
+                            .await
+                            .ok();
+                    }
+                    _ => {}
+                },
                 Ok(_) => {}
                 Err(e) => {
-                    let formatted = e.to_string();
-                    // hack while warp only has opaque error types
-                    match formatted.as_str() {
-                        "WebSocket protocol error: Connection reset without closing handshake"
-                        | "IO error: Connection reset by peer (os error 104)" => {
-                            log::debug!("websocket error: {}", e)
-                        }
+                    let e = WebSocketError::from(e);
+                    let kind = e.kind();
+                    METRICS.add_websocket_error(kind);
+                    match kind {
+                        WebSocketErrorKind::Reset => log::debug!("websocket error: {}", e),
                         _ => log::warn!("websocket error: {}", e),
                     };
                     break;
@@ -239,9 +754,75 @@ pub async fn handle_user_socket(
     select(transmit, receive).await;
 
     METRICS.remove_connection();
+    app.connections.untrack_activity(opts.id);
+    if let Some(client_id) = opts.client_id.get() {
+        METRICS.remove_client_connection(client_id);
+    }
+    log::debug!("Connection for {} ({}) closed", user_id, opts.client());
     app.connections.remove(&user_id);
 }
 
+/// Buffers websocket frames until they are flushed,
+/// so all frames from the same wake-up are written to the socket together
+struct FrameWriter<S> {
+    sink: S,
+    pending: usize,
+}
+
+impl<S: Sink<Message> + Unpin> FrameWriter<S> {
+    fn new(sink: S) -> Self {
+        FrameWriter { sink, pending: 0 }
+    }
+
+    async fn feed(&mut self, message: Message) {
+        if self.sink.feed(message).await.is_ok() {
+            self.pending += 1;
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.pending > 0 {
+            self.sink.flush().await.ok();
+            METRICS.add_flush(self.pending);
+            self.pending = 0;
+        }
+    }
+
+    async fn close(&mut self) {
+        self.flush().await;
+        self.sink.close().await.ok();
+    }
+}
+
+/// Take the next message that is already waiting for the connection, without waiting for new ones
+fn try_recv_queued(rx: &mut broadcast::Receiver<PushMessage>) -> Option<PushMessage> {
+    loop {
+        match rx.try_recv() {
+            Ok(msg) => return Some(msg),
+            Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Deterministic offset for the first ping of a connection, derived from the connection id
+///
+/// This only needs to spread the pings out, so a cheap hash is enough.
+pub fn ping_jitter(connection_id: u64) -> Duration {
+    // splitmix64 finalizer
+    let mut hash = connection_id.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    Duration::from_millis(hash % PING_JITTER.as_millis() as u64)
+}
+
+/// Random payload for a ping, taken from the cryptographically secure generator of the current worker thread
+/// so clients can't predict the expected pong
+pub fn ping_nonce() -> NonZeroUsize {
+    rand::thread_rng().gen()
+}
+
 async fn read_socket_auth_message(rx: &mut WebSocket) -> Result<Message, WebSocketError> {
     match rx.next().await {
         Some(Ok(msg)) => Ok(msg),
@@ -250,20 +831,39 @@ async fn read_socket_auth_message(rx: &mut WebSocket) -> Result<Message, WebSock
     }
 }
 
+/// Only text frames are accepted for the credentials
+fn message_text(msg: &Message) -> Option<&str> {
+    match msg {
+        Message::Text(text) => Some(text.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg_attr(feature = "otel", tracing::instrument(skip_all, err))]
 async fn socket_auth(
     rx: &mut WebSocket,
-    forwarded_for: Vec<IpAddr>,
+    forwarded: Forwarded,
     app: &App,
 ) -> Result<UserId, AuthenticationError> {
     let username_msg = read_socket_auth_message(rx).await?;
-    let username = username_msg
-        .to_str()
-        .map_err(|_| AuthenticationError::InvalidMessage)?;
+    let username = message_text(&username_msg).ok_or(AuthenticationError::InvalidMessage)?;
     let password_msg = read_socket_auth_message(rx).await?;
-    let password = password_msg
-        .to_str()
-        .map_err(|_| AuthenticationError::InvalidMessage)?;
+    let password = message_text(&password_msg).ok_or(AuthenticationError::InvalidMessage)?;
+
+    app.limit_auth(
+        &forwarded,
+        username,
+        verify_socket_credentials(username, password, &forwarded.hops, app),
+    )
+    .await
+}
 
+async fn verify_socket_credentials(
+    username: &str,
+    password: &str,
+    forwarded_for: &[IpAddr],
+    app: &App,
+) -> Result<UserId, AuthenticationError> {
     // cleanup all pre_auth tokens older than 15s
     let cutoff = Instant::now() - Duration::from_secs(15);
     app.pre_auth.retain(|_, (time, _)| *time > cutoff);
@@ -277,11 +877,94 @@ async fn socket_auth(
     }
 
     if !username.is_empty() {
-        Ok(app
-            .nc_client
-            .verify_credentials(username, password, forwarded_for)
-            .await?)
+        app.auth
+            .verify(Credentials {
+                username,
+                password,
+                forwarded_for,
+            })
+            .await
     } else {
-        Err(AuthenticationError::Invalid)
+        match app.redeem_transfer_token(password).await {
+            Ok(Some(user)) => {
+                log::debug!("Authenticated socket for {} using transfer token", user);
+                Ok(user)
+            }
+            Ok(None) => Err(AuthenticationError::Invalid),
+            Err(e) => {
+                log::warn!("Failed to check transfer token: {:#}", e);
+                Err(AuthenticationError::Invalid)
+            }
+        }
     }
 }
+
+#[test]
+fn test_parse_client_command() {
+    assert_eq!(
+        Some(ClientCommand::ListenFileId),
+        ClientCommand::parse("listen notify_file_id")
+    );
+    assert_eq!(
+        Some(ClientCommand::ClientId("desktop/3.14.0".into())),
+        ClientCommand::parse("client_id  desktop/3.14.0 ")
+    );
+    assert_eq!(
+        Some(ClientCommand::ClientId("a".repeat(MAX_CLIENT_ID_LENGTH))),
+        ClientCommand::parse(&format!("client_id {}", "a".repeat(100)))
+    );
+    assert_eq!(
+        Some(ClientCommand::RequestAffinity),
+        ClientCommand::parse("request_affinity")
+    );
+    assert_eq!(None, ClientCommand::parse("listen notify_file"));
+}
+
+#[test]
+fn test_connection_ramp() {
+    let ramp = ConnectionRamp::new(2, Duration::from_secs(60));
+    assert!(ramp.try_accept());
+    assert!(ramp.try_accept());
+    assert!(!ramp.try_accept());
+
+    // a new window allows new connections
+    ramp.window.lock().unwrap().0 -= Duration::from_secs(1);
+    assert!(ramp.try_accept());
+
+    // no limit after the ramp is over
+    let ramp = ConnectionRamp::new(0, Duration::ZERO);
+    assert!(ramp.try_accept());
+}
+
+#[test]
+fn test_ping_jitter() {
+    assert_eq!(ping_jitter(1), ping_jitter(1));
+    assert!((0..1000).all(|id| ping_jitter(id) < PING_JITTER));
+    // consecutive connections are spread out
+    let distinct = (0..100)
+        .map(|id| ping_jitter(id).as_secs())
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(PING_JITTER.as_secs() as usize, distinct.len());
+}
+
+#[test]
+fn test_activity() {
+    let connections = ActiveConnections::default();
+    let user = UserId::new("activity");
+    let _rx = connections.add(user.clone()).unwrap();
+    let activity = connections.track_activity(1);
+    assert_eq!(ActivityStats::default(), connections.activity());
+
+    connections.send_to_user(&user, PushMessage::Activity);
+    assert_eq!(1, connections.activity().active_users);
+
+    // an hour later without any messages
+    let later = activity_clock() + IDLE_CONNECTION_TIME.as_secs() + 1;
+    let stats = connections.activity_at(later);
+    assert_eq!(0, stats.active_users);
+    assert_eq!(1, stats.idle_connections);
+
+    drop(activity);
+    connections.untrack_activity(1);
+    assert_eq!(0, connections.activity_at(later).idle_connections);
+}
@@ -0,0 +1,96 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Panic reporting.
+//!
+//! Panics are logged as a single record containing the message, location, backtrace and build info,
+//! so they end up in the same place as the rest of the logs instead of only on stderr.
+
+use crate::metrics::BuildInfo;
+use serde::Serialize;
+use std::backtrace::Backtrace;
+use std::fs::{create_dir_all, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize)]
+pub struct CrashReport {
+    pub message: String,
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    /// Seconds since the unix epoch
+    pub time: u64,
+    pub build: BuildInfo,
+    pub backtrace: String,
+}
+
+impl CrashReport {
+    fn new(message: String, location: Option<String>) -> Self {
+        CrashReport {
+            message,
+            location,
+            thread: std::thread::current().name().map(String::from),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            build: BuildInfo::get(),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// Write the report as json to a new file in `dir`, returning the path of the file
+    pub fn write_to(&self, dir: &Path) -> io::Result<PathBuf> {
+        create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "notify_push-crash-{}-{}.json",
+            self.time,
+            std::process::id()
+        ));
+        write(&path, serde_json::to_vec_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+/// Replace the panic hook with one that logs a crash report, and optionally writes it to `crash_dump_dir`
+///
+/// This should only be installed once the logger is set up.
+pub fn install_panic_hook(crash_dump_dir: Option<PathBuf>) {
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic"));
+        let report = CrashReport::new(message, info.location().map(ToString::to_string));
+
+        match serde_json::to_string(&report) {
+            Ok(json) => log::error!(target: "notify_push::panic", "Panic: {}", json),
+            Err(_) => log::error!(target: "notify_push::panic", "Panic: {:?}", report),
+        }
+        if let Some(dir) = &crash_dump_dir {
+            match report.write_to(dir) {
+                Ok(path) => log::error!("Crash report written to {}", path.display()),
+                Err(e) => log::error!("Failed to write crash report to {}: {}", dir.display(), e),
+            }
+        }
+    }));
+}
+
+#[test]
+fn test_write_crash_report() {
+    let dir = std::env::temp_dir().join(format!("notify_push_crash_test_{}", std::process::id()));
+    let report = CrashReport::new(String::from("oops"), Some(String::from("src/lib.rs:1:1")));
+    let path = report.write_to(&dir).unwrap();
+    let written: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!("oops", written["message"]);
+    assert_eq!("src/lib.rs:1:1", written["location"]);
+    assert_eq!(env!("NOTIFY_PUSH_VERSION"), written["build"]["version"]);
+    assert!(!written["backtrace"].as_str().unwrap().is_empty());
+}
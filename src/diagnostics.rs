@@ -0,0 +1,75 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Report of the internal state of the push server, logged on `SIGQUIT` to help debugging hangs.
+
+use crate::metrics::METRICS;
+use crate::App;
+use std::cmp::Reverse;
+use std::fmt::Write;
+use tokio::runtime::Handle;
+
+/// Number of users with the most connections to include in the report
+const TOP_USERS: usize = 10;
+
+impl App {
+    pub fn diagnostics(&self) -> String {
+        let mut users = self.connections.user_stats();
+        let connection_count: usize = users.iter().map(|(_, connections, _)| connections).sum();
+        let queued_count: usize = users.iter().map(|(_, _, queued)| queued).sum();
+        let max_queued = users
+            .iter()
+            .map(|(_, _, queued)| *queued)
+            .max()
+            .unwrap_or(0);
+
+        let mut report = String::from("Diagnostics report\n");
+        let _ = writeln!(
+            &mut report,
+            "  users: {}, connections: {}, resumed sessions: {}",
+            users.len(),
+            connection_count,
+            self.connections.resumed_count()
+        );
+        let _ = writeln!(
+            &mut report,
+            "  queued messages: {} (at most {} for a single user)",
+            queued_count, max_queued
+        );
+        let _ = writeln!(
+            &mut report,
+            "  storage mapping cache: {}, pre-auth tokens: {}",
+            self.storage_mapping.cache_size(),
+            self.pre_auth.len()
+        );
+        let _ = writeln!(
+            &mut report,
+            "  events received: {}, messages sent: {}",
+            METRICS.events_received(),
+            METRICS.messages_sent()
+        );
+        if let Ok(handle) = Handle::try_current() {
+            let metrics = handle.metrics();
+            let _ = writeln!(
+                &mut report,
+                "  workers: {}, tasks: {}, scheduler queue: {}",
+                metrics.num_workers(),
+                metrics.num_alive_tasks(),
+                metrics.global_queue_depth()
+            );
+        }
+
+        users.sort_unstable_by_key(|(_, connections, _)| Reverse(*connections));
+        let _ = writeln!(&mut report, "  users with the most connections:");
+        for (user, connections, queued) in users.iter().take(TOP_USERS) {
+            let _ = writeln!(
+                &mut report,
+                "    {}: {} connections, {} queued messages",
+                user, connections, queued
+            );
+        }
+        report
+    }
+}
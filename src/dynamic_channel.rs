@@ -0,0 +1,131 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Redis channels that apps register at runtime with `add_channel` config events.
+//!
+//! Every event on such a channel is forwarded to the user as a custom message with the name set in the rule.
+//! The rules are only kept in memory, apps need to register their channels again after the push server restarts.
+
+use crate::event::{ChannelEvent, Custom, Event, MessageDecodeError, CHANNELS};
+use dashmap::DashMap;
+use redis::aio::PubSubSink;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+#[derive(Debug, Error)]
+pub enum ChannelRuleError {
+    #[error("channel name can't be empty")]
+    Empty,
+    #[error("{0} is a built-in channel")]
+    BuiltIn(String),
+}
+
+#[derive(Default)]
+pub struct DynamicChannels {
+    /// Name of the custom message for every registered channel
+    rules: DashMap<String, String>,
+    /// Subscription of the current redis connection, `None` while disconnected
+    sink: Mutex<Option<PubSubSink>>,
+}
+
+impl DynamicChannels {
+    /// Register a channel, or change the message of an already registered one
+    pub async fn add(&self, channel: String, message: String) -> Result<(), ChannelRuleError> {
+        if channel.is_empty() {
+            return Err(ChannelRuleError::Empty);
+        }
+        if CHANNELS.contains(&channel.as_str()) {
+            return Err(ChannelRuleError::BuiltIn(channel));
+        }
+        log::info!("Forwarding events on {} as {} messages", channel, message);
+        if self.rules.insert(channel.clone(), message).is_none() {
+            if let Some(sink) = self.sink.lock().await.as_mut() {
+                if let Err(e) = sink.subscribe(&channel).await {
+                    log::warn!("Failed to subscribe to {}: {}", channel, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, channel: &str) {
+        if self.rules.remove(channel).is_none() {
+            log::debug!("Channel {} to remove was never added", channel);
+            return;
+        }
+        log::info!("No longer forwarding events on {}", channel);
+        if let Some(sink) = self.sink.lock().await.as_mut() {
+            if let Err(e) = sink.unsubscribe(channel).await {
+                log::warn!("Failed to unsubscribe from {}: {}", channel, e);
+            }
+        }
+    }
+
+    /// Subscribe a new redis connection to all registered channels
+    pub async fn attach(&self, mut sink: PubSubSink) -> redis::RedisResult<()> {
+        let mut current = self.sink.lock().await;
+        // collect first, so the map isn't locked while waiting for redis
+        let channels: Vec<String> = self.rules.iter().map(|rule| rule.key().clone()).collect();
+        for channel in channels {
+            sink.subscribe(channel).await?;
+        }
+        *current = Some(sink);
+        Ok(())
+    }
+
+    pub async fn detach(&self) {
+        *self.sink.lock().await = None;
+    }
+
+    /// Decode an event on a registered channel, `None` if the channel isn't registered
+    pub fn decode(
+        &self,
+        channel: &str,
+        payload: &[u8],
+    ) -> Option<Result<Event, MessageDecodeError>> {
+        let message = self.rules.get(channel)?.value().clone();
+        Some(
+            serde_json::from_slice(payload)
+                .map(|ChannelEvent { user, body }| {
+                    Event::Custom(Custom {
+                        user,
+                        message,
+                        body,
+                    })
+                })
+                .map_err(MessageDecodeError::from),
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_channel_rules() {
+    let channels = DynamicChannels::default();
+    assert!(channels.decode("notify_deck", b"{}").is_none());
+    assert!(matches!(
+        channels.add("notify_custom".into(), "custom".into()).await,
+        Err(ChannelRuleError::BuiltIn(_))
+    ));
+
+    channels
+        .add("notify_deck".into(), "deck_update".into())
+        .await
+        .unwrap();
+    match channels.decode("notify_deck", br#"{"user":"foo","body":{"board":1}}"#) {
+        Some(Ok(Event::Custom(custom))) => {
+            assert_eq!(crate::UserId::new("foo"), custom.user);
+            assert_eq!("deck_update", custom.message);
+            assert_eq!(r#"{"board":1}"#, custom.body.to_string());
+        }
+        _ => panic!("expected a custom event"),
+    }
+    assert!(matches!(
+        channels.decode("notify_deck", b"{}"),
+        Some(Err(MessageDecodeError::Json(_)))
+    ));
+
+    channels.remove("notify_deck").await;
+    assert!(channels.decode("notify_deck", b"{}").is_none());
+}
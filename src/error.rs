@@ -3,19 +3,28 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
  
+use crate::auth::AuthProviderKind;
+use crate::config::TlsVersion;
 use flexi_logger::FlexiLoggerError;
 use miette::Diagnostic;
 use redis::RedisError;
 use reqwest::StatusCode;
-use std::net::AddrParseError;
+use std::io::ErrorKind;
+use std::net::{AddrParseError, SocketAddr};
 use std::num::ParseIntError;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum Error {
     #[error(transparent)]
+    #[diagnostic(transparent)]
     Database(#[from] DatabaseError),
     #[error(transparent)]
+    #[diagnostic(
+        code(notify_push::redis),
+        help("Make sure the push server is configured with the same redis server as nextcloud and that it's reachable from the push server")
+    )]
     Redis(#[from] RedisError),
     #[error(transparent)]
     #[diagnostic(transparent)]
@@ -23,14 +32,38 @@ pub enum Error {
     #[error("Error while running self test: {0}")]
     #[diagnostic(transparent)]
     SelfTest(#[from] SelfTestError),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Setup(#[from] SetupError),
+    #[error("{0} tasks panicked within a minute")]
+    TaskPanics(usize),
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Update(#[from] UpdateError),
     #[error("Failed to set signal hook: {0}")]
     SignalHook(#[source] std::io::Error),
+    #[error("Failed to start the worker processes: {0}")]
+    Workers(#[source] std::io::Error),
+    #[error("Failed to start the runtime shards: {0}")]
+    RuntimeShards(#[source] std::io::Error),
+    #[error("Failed to read or write the persisted metrics: {0}")]
+    MetricsStore(#[source] std::io::Error),
+    #[error("Failed to setup the OpenTelemetry exporter: {0}")]
+    Otel(String),
     #[error("Failed to listen to socket: {0}")]
+    #[diagnostic(transparent)]
     Socket(#[from] SocketError),
+    #[cfg(feature = "rustls")]
+    #[error("Failed to setup TLS: {0}")]
+    #[diagnostic(transparent)]
+    Tls(#[from] TlsError),
     #[error("Error while handling authentication: {0}")]
     Authentication(#[from] AuthenticationError),
     #[error("Error while communicating with Nextcloud: {0}")]
+    #[diagnostic(transparent)]
     NextCloud(#[from] NextCloudError),
+    #[error("Error while sending web push message: {0}")]
+    WebPush(#[from] WebPushError),
     #[cfg(feature = "systemd")]
     #[error("Failed to notify SystemD: {0}")]
     SystemD(#[from] std::io::Error),
@@ -43,6 +76,10 @@ pub enum NextCloudError {
     #[error("Invalid nextcloud url: {0}")]
     NextcloudUrl(#[from] url::ParseError),
     #[error("Error while connecting to nextcloud: {0}")]
+    #[diagnostic(
+        code(notify_push::nextcloud::connect),
+        help("Make sure nextcloud can be reached from the push server at the configured url, if nextcloud uses a self-signed certificate either use a local http url or set ALLOW_SELF_SIGNED=true")
+    )]
     NextcloudConnect(#[source] reqwest::Error),
     #[error("Client error: {0}")]
     Client(StatusCode),
@@ -51,16 +88,34 @@ pub enum NextCloudError {
     #[error("Unexpected status code: {0}")]
     Other(StatusCode),
     #[error("{0} is not configured as a trusted domain for the nextcloud server")]
+    #[diagnostic(
+        code(notify_push::nextcloud::untrusted_domain),
+        help("Add the domain to `trusted_domains` in the nextcloud config.php, or set NEXTCLOUD_URL to a url with a domain that is already trusted")
+    )]
     NotATrustedDomain(String),
+    #[error("Too many failed requests to nextcloud, try again later")]
+    Unavailable,
     #[error("Invalid response when getting test cookie: {0}")]
+    #[diagnostic(
+        code(notify_push::nextcloud::malformed_response),
+        help("Make sure the nextcloud url points to the nextcloud instance and that the notify_push app is enabled")
+    )]
     MalformedCookieResponse(#[source] ParseIntError),
     #[error("Invalid response when testing if the push server is a trusted proxy: {0}")]
+    #[diagnostic(
+        code(notify_push::nextcloud::malformed_response),
+        help("Make sure the nextcloud url points to the nextcloud instance and that the notify_push app is enabled")
+    )]
     MalformedRemote(#[source] AddrParseError),
 }
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum DatabaseError {
     #[error("Failed to connect to database: {0}")]
+    #[diagnostic(
+        code(notify_push::database::connect),
+        help("Check the database host and credentials from the nextcloud config.php or DATABASE_URL, the database needs to accept connections from the push server")
+    )]
     Connect(#[source] sqlx::Error),
     #[error("Failed to query database: {0}")]
     Query(#[source] sqlx::Error),
@@ -69,28 +124,125 @@ pub enum DatabaseError {
 #[derive(Debug, Error, Diagnostic)]
 pub enum SelfTestError {
     #[error("Failed to test database access: {0}")]
+    #[diagnostic(transparent)]
     Database(#[from] DatabaseError),
     #[error("Failed to test redis access: {0}")]
+    #[diagnostic(
+        code(notify_push::redis),
+        help("Make sure the push server is configured with the same redis server as nextcloud and that it's reachable from the push server")
+    )]
     Redis(#[from] RedisError),
     #[error("Error while communicating with nextcloud instance: {0}")]
+    #[diagnostic(transparent)]
     NextcloudCommunication(#[from] NextCloudError),
+    #[error("push server (version {server}) is not compatible with the app (version {app})")]
+    #[diagnostic(
+        code(notify_push::version_mismatch),
+        help("Update the push server binary and the notify_push app to the same version")
+    )]
+    VersionMismatch { server: String, app: String },
+    #[error(
+        "the push server uses redis database {configured} but nextcloud writes to database {found}"
+    )]
+    #[diagnostic(
+        code(notify_push::redis_db_mismatch),
+        help("Set REDIS_DB={found} or change the `dbindex` in the redis configuration of the nextcloud config.php so both use the same database")
+    )]
+    RedisDbMismatch { configured: i64, found: i64 },
+    #[error("the app never responded with its version within {seconds} seconds")]
+    #[diagnostic(
+        code(notify_push::no_app_version),
+        help("Make sure the notify_push app is enabled and up to date, and that nextcloud is configured to use redis")
+    )]
+    NoAppVersion { seconds: u64 },
 }
 
 #[derive(Debug, Error, Diagnostic)]
 pub enum SocketError {
     #[error("Failed to bind to socket at {1}: {0}")]
+    #[diagnostic(
+        code(notify_push::socket::bind),
+        help("Make sure no other process is listening on the same port or socket, binding to ports below 1024 requires additional privileges")
+    )]
     Bind(#[source] std::io::Error, String),
     #[error("Failed to set socket permissions: {0}")]
+    #[diagnostic(
+        code(notify_push::socket::permissions),
+        help("The push server needs to own the socket file, make sure it runs as the user that creates the socket")
+    )]
     SocketPermissions(#[source] std::io::Error),
 }
 
+#[derive(Debug, Error, Diagnostic)]
+pub enum SetupError {
+    #[error("The setup needs the path to the nextcloud config.php")]
+    #[diagnostic(
+        code(notify_push::setup::no_config_file),
+        help("Run the setup as `notify_push /path/to/nextcloud/config/config.php setup`")
+    )]
+    NoConfigFile,
+    #[error("No free port found after {0}")]
+    #[diagnostic(
+        code(notify_push::setup::no_free_port),
+        help("Set a different port with `--port`")
+    )]
+    NoFreePort(SocketAddr),
+    #[error("Failed to write {1}: {0}")]
+    #[diagnostic(
+        code(notify_push::setup::write),
+        help("Run the setup as root to install the systemd unit, or pass `--unit-file` with a path you can write to")
+    )]
+    Write(#[source] std::io::Error, String),
+    #[error("Failed to read input: {0}")]
+    Input(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum UpdateError {
+    #[error("Failed to download release: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Unexpected status code {0} while downloading {1}")]
+    Status(StatusCode, String),
+    #[error("The latest release has no binary named {0}")]
+    #[diagnostic(
+        code(notify_push::update::no_asset),
+        help("Update the push server manually, or together with the notify_push app")
+    )]
+    NoAsset(String),
+    #[error(
+        "Checksum of the downloaded binary doesn't match, expected {expected} but got {actual}"
+    )]
+    #[diagnostic(code(notify_push::update::checksum))]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Failed to install update to {1}: {0}")]
+    #[diagnostic(
+        code(notify_push::update::install),
+        help("The push server needs write access to the directory it's installed in to update itself")
+    )]
+    Install(#[source] std::io::Error, String),
+    #[error("Failed to start the updated binary: {0}")]
+    Restart(#[source] std::io::Error),
+}
+
 #[derive(Debug, Error, Diagnostic)]
 pub enum ConfigError {
     #[error("No redis server is configured")]
+    #[diagnostic(
+        code(notify_push::config::no_redis),
+        help("Configure redis in the nextcloud config.php, or set REDIS_URL")
+    )]
     NoRedis,
     #[error("No nextcloud server is configured")]
+    #[diagnostic(
+        code(notify_push::config::no_nextcloud),
+        help("Set `overwrite.cli.url` in the nextcloud config.php, or set NEXTCLOUD_URL")
+    )]
     NoNextcloud,
     #[error("No database server is configured")]
+    #[diagnostic(
+        code(notify_push::config::no_database),
+        help("Pass the path to the nextcloud config.php, or set DATABASE_URL")
+    )]
     NoDatabase,
     #[error("Error while parsing nextcloud config.php")]
     #[diagnostic(transparent)]
@@ -101,11 +253,112 @@ pub enum ConfigError {
         #[source] Box<dyn std::error::Error + Send + Sync>,
     ),
     #[error("socket permissions should be provided in the octal form `0xxx`, got {0}")]
+    #[diagnostic(
+        code(notify_push::config::socket_permissions),
+        help("For example `0660` to allow the owner and group to read and write to the socket")
+    )]
     SocketPermissions(String, Option<ParseIntError>),
     #[error("Failed to parse log level: {0}")]
     LogLevel(#[from] FlexiLoggerError),
     #[error("Failed to parse database configuration: {0:#}")]
     InvalidDatabase(#[from] sqlx::Error),
+    #[error("The web push VAPID key should be a base64url encoded P-256 private key")]
+    VapidKey,
+    #[error("The minimum TLS version ({0}) can't be higher than the maximum TLS version ({1})")]
+    TlsVersions(TlsVersion, TlsVersion),
+    #[error("TLS is configured but this build was compiled without the `rustls` feature")]
+    TlsDisabled,
+    #[error("The maximum header size should be at least 8192 bytes, got {0}")]
+    MaxHeaderSize(usize),
+    #[error("Registering the public url requires a test secret")]
+    #[diagnostic(
+        code(notify_push::config::public_url_without_secret),
+        help("The app uses the test secret to verify the registration, set TEST_SECRET to the secret configured in the app")
+    )]
+    PublicUrlWithoutSecret,
+    #[error("A redis cluster only has database 0, got REDIS_DB={0}")]
+    #[diagnostic(
+        code(notify_push::config::cluster_redis_db),
+        help("Remove the REDIS_DB setting when using a redis cluster")
+    )]
+    ClusterRedisDb(i64),
+    #[error("A redis client certificate needs both REDIS_TLS_CERT and REDIS_TLS_KEY")]
+    RedisClientCertificate,
+    #[error("The {0} authentication provider requires {1} to be set")]
+    AuthProviderOption(AuthProviderKind, &'static str),
+    #[error("Failed to read the authentication tokens from {}: {error}", path.display())]
+    AuthTokenFile {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Invalid authentication token in {} on line {line}", path.display())]
+    #[diagnostic(
+        code(notify_push::config::auth_token),
+        help("Every line of the token file should be `username:token`, lines starting with `#` are ignored")
+    )]
+    AuthToken { path: PathBuf, line: usize },
+    #[error(
+        "LDAP authentication is configured but this build was compiled without the `ldap` feature"
+    )]
+    LdapDisabled,
+    #[error("Multiple workers can't share a unix socket")]
+    #[diagnostic(
+        code(notify_push::config::workers_unix_socket),
+        help("Listen on a tcp port instead, or set WORKERS to 1")
+    )]
+    WorkersUnixSocket,
+    #[error("The io_uring listener is enabled but this build was compiled without the `io-uring` feature")]
+    #[diagnostic(code(notify_push::config::io_uring_disabled))]
+    IoUringDisabled,
+    #[error("The io_uring listener doesn't support {0}")]
+    #[diagnostic(code(notify_push::config::io_uring_unsupported))]
+    IoUringUnsupported(&'static str),
+    #[error(
+        "An OTLP endpoint is configured but this build was compiled without the `otel` feature"
+    )]
+    #[diagnostic(code(notify_push::config::otel_disabled))]
+    OtelDisabled,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Invalid redis url `{url}`: {reason}")]
+#[diagnostic(
+    code(notify_push::config::redis_url),
+    help("Use `redis://host:port/db`, `rediss://` for TLS or `redis+unix:///path/to/redis.sock` for a unix socket, multiple urls can be separated by commas")
+)]
+pub struct RedisUrlError {
+    pub url: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Invalid cpu set `{0}`")]
+#[diagnostic(
+    code(notify_push::config::cpu_set),
+    help("Use a list of cpu numbers and ranges like `0-15,32-47`, multiple sets can be separated by semicolons")
+)]
+pub struct CpuSetError(pub String);
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Invalid metrics label `{0}`")]
+#[diagnostic(
+    code(notify_push::config::metrics_label),
+    help("Use `name=value` with a name of letters, digits and underscores, multiple labels can be separated by commas")
+)]
+pub struct MetricsLabelError(pub String);
+
+#[cfg(feature = "rustls")]
+#[derive(Debug, Error, Diagnostic)]
+pub enum TlsError {
+    #[error("Failed to read {1}: {0}")]
+    Read(#[source] std::io::Error, String),
+    #[error("No private key found in {0}")]
+    NoKey(String),
+    #[error("Unknown or unsupported TLS cipher suite {0}")]
+    UnknownCipherSuite(String),
+    #[error("Invalid TLS configuration: {0}")]
+    Config(#[from] tokio_rustls::rustls::Error),
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -113,7 +366,82 @@ pub enum WebSocketError {
     #[error("Client disconnected unexpectedly")]
     Disconnected,
     #[error(transparent)]
-    Error(#[from] warp::Error),
+    Error(Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+// boxed since the tungstenite error can contain a complete message or http response
+impl From<tokio_tungstenite::tungstenite::Error> for WebSocketError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        WebSocketError::Error(Box::new(e))
+    }
+}
+
+/// Broad categories of websocket errors for logging and metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketErrorKind {
+    /// The client went away without closing the connection, this is expected for mobile clients
+    Reset,
+    /// The client violated the websocket protocol or sent invalid text
+    Protocol,
+    /// A message or frame exceeded the size limits
+    Capacity,
+    /// Any other IO error
+    Io,
+    Other,
+}
+
+impl WebSocketErrorKind {
+    pub const ALL: [WebSocketErrorKind; 5] = [
+        WebSocketErrorKind::Reset,
+        WebSocketErrorKind::Protocol,
+        WebSocketErrorKind::Capacity,
+        WebSocketErrorKind::Io,
+        WebSocketErrorKind::Other,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            WebSocketErrorKind::Reset => "reset",
+            WebSocketErrorKind::Protocol => "protocol",
+            WebSocketErrorKind::Capacity => "capacity",
+            WebSocketErrorKind::Io => "io",
+            WebSocketErrorKind::Other => "other",
+        }
+    }
+}
+
+impl WebSocketError {
+    pub fn kind(&self) -> WebSocketErrorKind {
+        use tokio_tungstenite::tungstenite::error::{Error as WsError, ProtocolError};
+
+        let error = match self {
+            WebSocketError::Disconnected => return WebSocketErrorKind::Reset,
+            WebSocketError::Error(e) => e.as_ref(),
+        };
+        match error {
+            WsError::ConnectionClosed | WsError::AlreadyClosed => WebSocketErrorKind::Reset,
+            WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+                WebSocketErrorKind::Reset
+            }
+            WsError::Io(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::UnexpectedEof
+                ) =>
+            {
+                WebSocketErrorKind::Reset
+            }
+            WsError::Io(_) => WebSocketErrorKind::Io,
+            WsError::Protocol(_) | WsError::Utf8 | WsError::AttackAttempt => {
+                WebSocketErrorKind::Protocol
+            }
+            WsError::Capacity(_) | WsError::WriteBufferFull(_) => WebSocketErrorKind::Capacity,
+            _ => WebSocketErrorKind::Other,
+        }
+    }
 }
 
 #[derive(Debug, Error, Diagnostic)]
@@ -124,8 +452,43 @@ pub enum AuthenticationError {
     InvalidMessage,
     #[error("Error while sending authentication request to nextcloud: {0}")]
     Nextcloud(#[from] NextCloudError),
+    #[error("Error while verifying the credentials with the ldap server: {0}")]
+    Ldap(String),
     #[error("Invalid credentials")]
     Invalid,
+    #[error("Too many failed login attempts")]
+    TooManyAttempts,
     #[error("Connection limit exceeded for user")]
     LimitExceeded,
 }
+
+#[derive(Debug, Error, Diagnostic)]
+pub enum WebPushError {
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Failed to load web push subscriptions: {0}")]
+    Database(#[source] sqlx::Error),
+    #[error("Invalid web push endpoint: {0}")]
+    Endpoint(#[from] url::ParseError),
+    #[error("Invalid key material in web push subscription")]
+    InvalidSubscription,
+    #[error("Failed to encrypt web push message")]
+    Encryption,
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    #[error("Push service returned status {0}")]
+    Status(StatusCode),
+}
+
+#[test]
+fn test_diagnostic_code_forwarded() {
+    let error = Error::from(SelfTestError::from(NextCloudError::NotATrustedDomain(
+        String::from("cloud.example.com"),
+    )));
+    assert_eq!(
+        Some(String::from("notify_push::nextcloud::untrusted_domain")),
+        error.code().map(|code| code.to_string())
+    );
+    assert!(error.help().is_some());
+}
@@ -3,11 +3,13 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
  
+use crate::message::{PushMessage, UpdatedFiles};
 use crate::metrics::METRICS;
 use crate::{Redis, Result, UserId};
 use parse_display::Display;
+use redis::aio::PubSubSink;
 use redis::Msg;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::convert::TryFrom;
 use thiserror::Error;
@@ -52,12 +54,43 @@ pub struct PreAuth {
 pub enum Config {
     LogSpec(String),
     LogRestore,
+    /// Reload the database credentials from the config and switch to a new connection pool
+    ReloadDatabase,
+    /// Load the settings managed by the app again
+    RemoteConfig,
+    /// Log every message for a user in detail for a while
+    TraceUser(TraceUser),
+    /// Forward the events on a new redis channel as custom messages
+    AddChannel(ChannelRule),
+    /// Stop forwarding the events on a channel added with `add_channel`
+    RemoveChannel(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChannelRule {
+    pub channel: String,
+    /// Name of the custom message the events are sent as
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TraceUser {
+    pub user: String,
+    pub minutes: f64,
 }
 
 #[derive(Debug, Deserialize, Display)]
 #[serde(rename_all = "snake_case")]
+#[display(style = "snake_case")]
 pub enum Query {
     Metrics,
+    /// Seconds since the push server was started
+    Uptime,
+    Version,
+    /// Hash of the config, to find instances that are running with a different config
+    ConfigHash,
+    /// Number of active and total connections
+    Connections,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,10 +101,58 @@ pub struct Custom {
     pub body: Box<Value>, // use `Box` to reduce size of `Event` enum from 72 to 48 bytes
 }
 
+/// Event published on a channel added with an `add_channel` config event
+#[derive(Debug, Deserialize)]
+pub struct ChannelEvent {
+    pub user: UserId,
+    #[serde(default)]
+    pub body: Box<Value>,
+}
+
+/// Message published by another push server for its locally originated messages
+#[derive(Debug, Deserialize)]
+pub struct Relay {
+    /// Id of the instance the message originated on, which already delivered it to its own connections
+    pub origin: String,
+    pub user: UserId,
+    pub message: RelayedMessage,
+}
+
+/// Messages that can be relayed between push servers
+#[derive(Debug, Clone, Serialize, Deserialize, Display)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[display(style = "snake_case")]
+pub enum RelayedMessage {
+    File,
+    Activity,
+    Notification,
+    #[display("{message}")]
+    Custom {
+        message: String,
+        #[serde(default)]
+        body: Box<Value>,
+    },
+}
+
+impl From<RelayedMessage> for PushMessage {
+    fn from(message: RelayedMessage) -> Self {
+        match message {
+            RelayedMessage::File => PushMessage::File(UpdatedFiles::Unknown),
+            RelayedMessage::Activity => PushMessage::Activity,
+            RelayedMessage::Notification => PushMessage::Notification,
+            RelayedMessage::Custom { message, body } => PushMessage::Custom(message, body),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Display)]
 #[serde(rename_all = "snake_case")]
 pub enum Signal {
+    /// Close all open connections
     Reset,
+    /// Close the open connections of a single user, e.g. after the account was deleted
+    #[display("Disconnect")]
+    Disconnect { user: UserId },
 }
 
 #[derive(Debug, Display)]
@@ -98,82 +179,89 @@ pub enum Event {
     Query(Query),
     #[display("{0} signal")]
     Signal(Signal),
+    #[display("relayed {0.message} for user {0.user} from {0.origin}")]
+    Relay(Relay),
 }
 
 #[derive(Debug, Error)]
 pub enum MessageDecodeError {
-    #[error("unsupported event type")]
-    UnsupportedEventType,
+    #[error("unsupported event type on channel {channel}")]
+    UnsupportedEventType { channel: String, payload: Vec<u8> },
     #[error("json deserialization error: {0}")]
     Json(#[from] serde_json::Error),
 }
 
+/// The redis channel push servers relay their locally originated messages over
+pub const RELAY_CHANNEL: &str = "notify_push_relay";
+
+/// The redis channels events are published on
+pub const CHANNELS: [&str; 12] = [
+    "notify_storage_update",
+    "notify_group_membership_update",
+    "notify_user_share_created",
+    "notify_test_cookie",
+    "notify_activity",
+    "notify_notification",
+    "notify_pre_auth",
+    "notify_custom",
+    "notify_config",
+    "notify_query",
+    "notify_signal",
+    RELAY_CHANNEL,
+];
+
+impl Event {
+    /// Decode the event from the payload published to a redis channel
+    pub fn decode(channel: &str, payload: &[u8]) -> Result<Self, MessageDecodeError> {
+        match channel {
+            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(payload)?)),
+            "notify_group_membership_update" => {
+                Ok(Event::GroupUpdate(serde_json::from_slice(payload)?))
+            }
+            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(payload)?)),
+            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(payload)?)),
+            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(payload)?)),
+            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(payload)?)),
+            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(payload)?)),
+            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(payload)?)),
+            "notify_config" => Ok(Event::Config(serde_json::from_slice(payload)?)),
+            "notify_query" => Ok(Event::Query(serde_json::from_slice(payload)?)),
+            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(payload)?)),
+            RELAY_CHANNEL => Ok(Event::Relay(serde_json::from_slice(payload)?)),
+            _ => Err(MessageDecodeError::UnsupportedEventType {
+                channel: channel.into(),
+                payload: payload.into(),
+            }),
+        }
+    }
+}
+
 impl TryFrom<Msg> for Event {
     type Error = MessageDecodeError;
 
     fn try_from(msg: Msg) -> Result<Self, Self::Error> {
-        match msg.get_channel_name() {
-            "notify_storage_update" => Ok(Event::StorageUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_group_membership_update" => Ok(Event::GroupUpdate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_user_share_created" => Ok(Event::ShareCreate(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_test_cookie" => Ok(Event::TestCookie(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_activity" => Ok(Event::Activity(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_notification" => Ok(Event::Notification(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_pre_auth" => Ok(Event::PreAuth(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_custom" => Ok(Event::Custom(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_config" => Ok(Event::Config(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_query" => Ok(Event::Query(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            "notify_signal" => Ok(Event::Signal(serde_json::from_slice(
-                msg.get_payload_bytes(),
-            )?)),
-            _ => Err(MessageDecodeError::UnsupportedEventType),
-        }
+        Event::decode(msg.get_channel_name(), msg.get_payload_bytes())
     }
 }
 
+/// Subscribe to the built-in channels, the returned sink can be used to subscribe to more channels later
 pub async fn subscribe(
     client: &Redis,
-) -> Result<impl Stream<Item = Result<Event, MessageDecodeError>>> {
+) -> Result<(
+    PubSubSink,
+    impl Stream<Item = Result<Event, MessageDecodeError>>,
+)> {
     let mut pubsub = client.pubsub().await?;
-    let channels = [
-        "notify_storage_update",
-        "notify_group_membership_update",
-        "notify_user_share_created",
-        "notify_test_cookie",
-        "notify_activity",
-        "notify_notification",
-        "notify_pre_auth",
-        "notify_custom",
-        "notify_config",
-        "notify_query",
-        "notify_signal",
-    ];
-    for channel in channels.iter() {
+    for channel in CHANNELS.iter() {
         pubsub.subscribe(*channel).await?;
     }
 
-    Ok(pubsub.into_on_message().map(|event| {
-        METRICS.add_event();
-        Event::try_from(event)
-    }))
+    let (sink, stream) = pubsub.split();
+    Ok((
+        sink,
+        stream.map(|event| {
+            METRICS.add_event();
+            Event::try_from(event)
+        }),
+    ))
 }
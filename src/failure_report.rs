@@ -0,0 +1,81 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Machine readable reports of startup failures, so the app can show the admin what went wrong.
+
+use miette::Diagnostic;
+use serde::Serialize;
+use std::fs::write;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+pub struct FailureReport {
+    /// The diagnostic code of the error, if known
+    pub code: Option<String>,
+    /// The part of the setup that failed, like `nextcloud`, `redis` or `database`
+    pub subsystem: Option<String>,
+    pub message: String,
+    /// The underlying errors, from outermost to innermost
+    pub causes: Vec<String>,
+    /// What the admin can do to fix the problem
+    pub help: Option<String>,
+    pub version: &'static str,
+}
+
+impl FailureReport {
+    pub fn new(error: &dyn Diagnostic) -> Self {
+        let code = error.code().map(|code| code.to_string());
+        let subsystem = code
+            .as_deref()
+            .and_then(|code| code.split("::").nth(1))
+            .map(String::from);
+        let mut causes = Vec::new();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        FailureReport {
+            code,
+            subsystem,
+            message: error.to_string(),
+            causes,
+            help: error.help().map(|help| help.to_string()),
+            version: env!("NOTIFY_PUSH_VERSION"),
+        }
+    }
+
+    /// Write the report to `target`, or to stdout if `target` is `-`
+    pub fn write_to(&self, target: &Path) -> io::Result<()> {
+        if target == Path::new("-") {
+            println!("{}", serde_json::to_string(self)?);
+            Ok(())
+        } else {
+            write(target, serde_json::to_vec_pretty(self)?)
+        }
+    }
+}
+
+#[test]
+fn test_failure_report() {
+    use crate::error::{ConfigError, Error, NextCloudError, SelfTestError};
+
+    let report = FailureReport::new(&Error::from(SelfTestError::from(
+        NextCloudError::NotATrustedDomain(String::from("cloud.example.com")),
+    )));
+    assert_eq!(
+        Some("notify_push::nextcloud::untrusted_domain"),
+        report.code.as_deref()
+    );
+    assert_eq!(Some("nextcloud"), report.subsystem.as_deref());
+    assert!(report.message.contains("cloud.example.com"));
+    assert!(report.help.is_some());
+
+    let report = FailureReport::new(&ConfigError::MaxHeaderSize(10));
+    assert_eq!(None, report.code);
+    assert_eq!(None, report.subsystem);
+    assert!(report.causes.is_empty());
+}
@@ -0,0 +1,288 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Client information from the headers set by reverse proxies.
+//!
+//! Both the standardized `Forwarded` header from RFC 7239 and the older `X-Forwarded-For` and `X-Real-IP` headers are supported.
+
+use crate::http::ConnectionInfo;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use rfc7239::NodeName;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+/// The addresses a request passed through, starting with the client and ending with the last proxy
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Forwarded {
+    pub hops: Vec<IpAddr>,
+    /// Protocol the client used to connect to the first proxy, or to the push server if there is no proxy
+    pub proto: Option<String>,
+    /// Whether the connection to the push server itself is encrypted
+    pub secure: bool,
+}
+
+impl Forwarded {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        if let Some(forwarded) = header_values(headers, "forwarded") {
+            return parse_forwarded(&forwarded);
+        }
+        // the first proxy in the chain sets the protocol, later proxies might append their own
+        let proto = header_values(headers, "x-forwarded-proto").and_then(|proto| {
+            let proto = proto.split(',').next()?.trim().to_ascii_lowercase();
+            (!proto.is_empty()).then_some(proto)
+        });
+        if let Some(forwarded_for) = header_values(headers, "x-forwarded-for") {
+            return Forwarded {
+                hops: forwarded_for.split(',').filter_map(parse_address).collect(),
+                proto,
+                secure: false,
+            };
+        }
+        Forwarded {
+            hops: headers
+                .get("x-real-ip")
+                .and_then(|ip| parse_address(ip.to_str().ok()?))
+                .into_iter()
+                .collect(),
+            proto,
+            secure: false,
+        }
+    }
+
+    /// Whether the client connection is encrypted, either to the push server or to a TLS terminating proxy
+    ///
+    /// The forwarded protocol can be set by the client itself, so it's only used when the proxies are trusted
+    pub fn is_secure(&self, depth: Option<usize>) -> bool {
+        match depth {
+            Some(_) => matches!(self.proto.as_deref(), Some("https" | "wss")),
+            None => self.secure,
+        }
+    }
+
+    /// The client address, if no proxy information is available this is the remote address itself
+    pub fn client(&self) -> Option<IpAddr> {
+        self.hops.first().copied()
+    }
+
+    /// Only keep the address `depth` hops before the remote address as client,
+    /// addresses further up the chain can be freely set by the client and aren't trusted
+    pub fn at_depth(self, depth: usize) -> Self {
+        Forwarded {
+            hops: self.trusted_client(Some(depth)).into_iter().collect(),
+            proto: self.proto,
+            secure: self.secure,
+        }
+    }
+
+    /// The client address that can't be set by the client itself, the address `depth` hops before the remote address,
+    /// or the remote address if no proxies are trusted
+    ///
+    /// If the chain is shorter than the configured depth, the request didn't pass through all proxies
+    /// and the addresses in it can't be trusted, so the remote address is used instead.
+    pub fn trusted_client(&self, depth: Option<usize>) -> Option<IpAddr> {
+        match depth {
+            Some(depth) => self
+                .hops
+                .len()
+                .checked_sub(depth + 1)
+                .map_or(self.hops.last(), |index| self.hops.get(index))
+                .copied(),
+            None => self.hops.last().copied(),
+        }
+    }
+}
+
+/// Proxies are allowed to send the header multiple times instead of appending to the existing one
+fn header_values(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+/// Parse an RFC 7239 `Forwarded` header, obfuscated or unknown identifiers are skipped
+fn parse_forwarded(header: &str) -> Forwarded {
+    let mut forwarded = Forwarded::default();
+    for (i, element) in rfc7239::parse(header).enumerate() {
+        let element = match element {
+            Ok(element) => element,
+            Err(e) => {
+                log::debug!("Invalid element in forwarded header {:?}: {}", header, e);
+                continue;
+            }
+        };
+        if i == 0 {
+            forwarded.proto = element
+                .protocol
+                .map(|proto| proto.trim_matches('"').to_ascii_lowercase());
+        }
+        if let Some(NodeName::Ip(ip)) = element.forwarded_for.map(|node| node.name) {
+            forwarded.hops.push(ip);
+        }
+    }
+    forwarded
+}
+
+/// Parse a single address from the `X-Forwarded-For` header, some proxies include the client port
+fn parse_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+    address
+        .parse()
+        .or_else(|_| address.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/// The forwarding information for the request, with the remote address added as the last hop
+impl<S: Send + Sync> FromRequestParts<S> for Forwarded {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        let connection = ConnectionInfo::from_extensions(&parts.extensions);
+        let mut forwarded = Forwarded::from_headers(&parts.headers);
+        if let Some(remote) = connection.peer {
+            forwarded.hops.push(remote.ip());
+        }
+        forwarded.secure = connection.secure;
+        if forwarded.proto.is_none() {
+            forwarded.proto = Some(if connection.secure { "https" } else { "http" }.into());
+        }
+        Ok(forwarded)
+    }
+}
+
+#[cfg(test)]
+fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                axum::http::HeaderName::from_static(name),
+                value.parse().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_forwarded_header() {
+    let forwarded = Forwarded::from_headers(&headers(&[(
+        "forwarded",
+        r#"for=192.0.2.60;proto=HTTPS;by=203.0.113.43, for="[2001:db8:cafe::17]:4711", for=unknown, for=_hidden"#,
+    )]));
+    assert_eq!(
+        vec![
+            IpAddr::from([192, 0, 2, 60]),
+            "2001:db8:cafe::17".parse::<IpAddr>().unwrap()
+        ],
+        forwarded.hops
+    );
+    assert_eq!(Some("https"), forwarded.proto.as_deref());
+
+    // multiple headers are combined in order
+    let forwarded = Forwarded::from_headers(&headers(&[
+        ("forwarded", "for=192.0.2.60;proto=http"),
+        ("forwarded", "for=192.0.2.61;proto=https"),
+    ]));
+    assert_eq!(
+        vec![IpAddr::from([192, 0, 2, 60]), IpAddr::from([192, 0, 2, 61])],
+        forwarded.hops
+    );
+    assert_eq!(Some("http"), forwarded.proto.as_deref());
+}
+
+#[test]
+fn test_forwarded_for_header() {
+    let forwarded = Forwarded::from_headers(&headers(&[(
+        "x-forwarded-for",
+        "192.0.2.60, invalid, 192.0.2.61:1234, [2001:db8:cafe::17]:4711",
+    )]));
+    assert_eq!(
+        vec![
+            IpAddr::from([192, 0, 2, 60]),
+            IpAddr::from([192, 0, 2, 61]),
+            "2001:db8:cafe::17".parse::<IpAddr>().unwrap()
+        ],
+        forwarded.hops
+    );
+
+    let forwarded = Forwarded::from_headers(&headers(&[("x-real-ip", "192.0.2.60")]));
+    assert_eq!(vec![IpAddr::from([192, 0, 2, 60])], forwarded.hops);
+    assert_eq!(None, forwarded.proto);
+
+    // the standardized header takes precedence
+    let forwarded = Forwarded::from_headers(&headers(&[
+        ("x-forwarded-for", "192.0.2.60"),
+        ("forwarded", "for=192.0.2.61"),
+    ]));
+    assert_eq!(vec![IpAddr::from([192, 0, 2, 61])], forwarded.hops);
+
+    assert_eq!(
+        Forwarded::default(),
+        Forwarded::from_headers(&HeaderMap::new())
+    );
+}
+
+#[test]
+fn test_at_depth() {
+    let forwarded = Forwarded {
+        hops: vec![
+            IpAddr::from([192, 0, 2, 60]),
+            IpAddr::from([192, 0, 2, 61]),
+            IpAddr::from([10, 0, 0, 1]),
+        ],
+        proto: None,
+        secure: false,
+    };
+    let client = |depth| forwarded.clone().at_depth(depth).hops;
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], client(0));
+    assert_eq!(vec![IpAddr::from([192, 0, 2, 61])], client(1));
+    assert_eq!(vec![IpAddr::from([192, 0, 2, 60])], client(2));
+    // less proxies than configured, the start of the chain is client controlled
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], client(3));
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], client(5));
+    assert!(Forwarded::default().at_depth(1).hops.is_empty());
+
+    // without trusted proxies only the remote address can't be spoofed
+    assert_eq!(
+        Some(IpAddr::from([10, 0, 0, 1])),
+        forwarded.trusted_client(None)
+    );
+    assert_eq!(
+        Some(IpAddr::from([192, 0, 2, 61])),
+        forwarded.trusted_client(Some(1))
+    );
+    // applying the depth twice gives the same client
+    assert_eq!(
+        Some(IpAddr::from([192, 0, 2, 61])),
+        forwarded.clone().at_depth(1).trusted_client(Some(1))
+    );
+}
+
+#[test]
+fn test_forwarded_proto() {
+    let forwarded = Forwarded::from_headers(&headers(&[
+        ("x-forwarded-for", "192.0.2.60"),
+        ("x-forwarded-proto", "HTTPS, http"),
+    ]));
+    assert_eq!(Some("https"), forwarded.proto.as_deref());
+    assert!(forwarded.is_secure(Some(1)));
+    // without trusted proxies the header could be set by the client
+    assert!(!forwarded.is_secure(None));
+
+    let forwarded =
+        Forwarded::from_headers(&headers(&[("forwarded", "for=192.0.2.60;proto=http")]));
+    assert!(!forwarded.is_secure(Some(1)));
+    assert!(!Forwarded::default().is_secure(Some(1)));
+
+    let direct = Forwarded {
+        secure: true,
+        ..Forwarded::default()
+    };
+    assert!(direct.is_secure(None));
+}
@@ -0,0 +1,87 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Periodic checks that the backends the push server depends on are still reachable.
+
+use crate::metrics::METRICS;
+use crate::App;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, MissedTickBehavior};
+
+const HEALTH_CHECK_KEY: &str = "notify_push_health_check";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct HealthStatus {
+    pub database: bool,
+    pub redis: bool,
+    pub nextcloud: bool,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.database && self.redis && self.nextcloud
+    }
+}
+
+impl App {
+    /// A lighter version of the self test, checking that the database, redis and nextcloud can be reached
+    pub async fn health_check(&self) -> HealthStatus {
+        let database = match self.storage_mapping.ping().await {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Health check: database not available: {:#}", e);
+                false
+            }
+        };
+        let redis = match self.check_redis().await {
+            Ok(true) => true,
+            Ok(false) => {
+                log::warn!("Health check: redis returned a different value than was written");
+                false
+            }
+            Err(e) => {
+                log::warn!("Health check: redis not available: {:#}", e);
+                false
+            }
+        };
+        let nextcloud = match self.nc_client.ping().await {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Health check: nextcloud not available: {:#}", e);
+                false
+            }
+        };
+        HealthStatus {
+            database,
+            redis,
+            nextcloud,
+        }
+    }
+
+    async fn check_redis(&self) -> crate::Result<bool> {
+        let value = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string();
+        let mut redis = self.redis.connect().await?;
+        redis.set_ex(HEALTH_CHECK_KEY, &value, 60).await?;
+        Ok(redis.get(HEALTH_CHECK_KEY).await? == value)
+    }
+}
+
+/// Run the health check every `period` and publish the results in the metrics
+pub async fn health_check_loop(app: Arc<App>, period: Duration) {
+    let mut interval = interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let status = app.health_check().await;
+        log::debug!("Health check result: {:?}", status);
+        METRICS.set_health(status);
+    }
+}
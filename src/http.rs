@@ -0,0 +1,584 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Http server shared by the push and metrics listeners.
+//!
+//! Instead of letting axum run the server we run hyper ourselves, so we can limit
+//! how long clients get to send their request headers and how large the requests can be.
+
+use crate::config::HttpLimits;
+use crate::metrics::METRICS;
+use crate::supervisor::spawn_supervised;
+use ahash::RandomState;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::header::{
+    CONNECTION, CONTENT_LENGTH, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use dashmap::DashMap;
+use futures::{pin_mut, stream, Future, Stream, StreamExt};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::time::sleep;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tower::ServiceExt;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// Idle client entries are only cleaned up once there are this many tracked addresses
+const RATE_LIMIT_CLEANUP_SIZE: usize = 1024;
+
+/// An accepted client connection
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// Whether the connection is encrypted by the push server itself
+    fn is_secure(&self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl Connection for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+pub trait Listener: Send + Sync + 'static {
+    type Connection: Connection;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Connection>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Connection = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+impl Listener for UnixListener {
+    type Connection = UnixStream;
+
+    async fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+/// Stream of accepted connections, errors while accepting are logged instead of stopping the server
+pub fn incoming<L: Listener>(listener: L) -> impl Stream<Item = io::Result<L::Connection>> {
+    stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok(connection) => return Some((Ok(connection), listener)),
+                Err(e) => {
+                    log::warn!("Failed to accept connection: {}", e);
+                    // most likely out of file descriptors, give the server some time to close connections
+                    sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    })
+}
+
+/// Information about the client connection, since we run the server ourselves
+/// we pass it along in the request extensions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionInfo {
+    /// Remote address of the client, not available for unix sockets
+    pub peer: Option<SocketAddr>,
+    /// Whether the client connected to the push server over TLS, this doesn't include connections from a TLS terminating proxy
+    pub secure: bool,
+}
+
+impl ConnectionInfo {
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        extensions.get().copied().unwrap_or_default()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ConnectionInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        Ok(ConnectionInfo::from_extensions(&parts.extensions))
+    }
+}
+
+/// Websocket connection after the upgrade
+pub type WebSocket = WebSocketStream<TokioIo<Upgraded>>;
+
+/// Extractor for websocket upgrade requests
+///
+/// We do the upgrade ourselves instead of using the axum websocket, so we get the typed tungstenite errors
+pub struct WebSocketUpgrade {
+    key: HeaderValue,
+    on_upgrade: OnUpgrade,
+    guard: Option<Arc<ClientGuard>>,
+    /// Subprotocols offered by the client, in order of preference
+    requested_protocols: Vec<String>,
+    /// Subprotocol selected by the server, echoed back in the response
+    protocol: Option<&'static str>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.method != Method::GET {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+        }
+        if !header_contains(&parts.headers, CONNECTION, "upgrade") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "connection header did not include 'upgrade'",
+            ));
+        }
+        if !header_contains(&parts.headers, UPGRADE, "websocket") {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "upgrade header did not include 'websocket'",
+            ));
+        }
+        if parts
+            .headers
+            .get(SEC_WEBSOCKET_VERSION)
+            .map_or(true, |version| version != "13")
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "unsupported websocket version, only version 13 is supported",
+            ));
+        }
+        let key = parts
+            .headers
+            .get(SEC_WEBSOCKET_KEY)
+            .cloned()
+            .ok_or((StatusCode::BAD_REQUEST, "missing websocket key"))?;
+        let on_upgrade = parts
+            .extensions
+            .remove::<OnUpgrade>()
+            .ok_or((StatusCode::UPGRADE_REQUIRED, "connection can't be upgraded"))?;
+        let guard = parts.extensions.remove::<Arc<ClientGuard>>();
+        let requested_protocols = parts
+            .headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim().to_string())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        Ok(WebSocketUpgrade {
+            key,
+            on_upgrade,
+            guard,
+            requested_protocols,
+            protocol: None,
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// Subprotocols offered by the client in the `Sec-WebSocket-Protocol` header, in order of preference
+    pub fn requested_protocols(&self) -> impl Iterator<Item = &str> {
+        self.requested_protocols.iter().map(String::as_str)
+    }
+
+    /// Select one of the offered subprotocols, it's send back to the client when accepting the upgrade
+    pub fn protocol(mut self, protocol: &'static str) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Accept the upgrade and run `callback` with the websocket once the upgrade is completed
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let accept = derive_accept_key(self.key.as_bytes());
+        let on_upgrade = self.on_upgrade;
+        let guard = self.guard;
+        let protocol = self.protocol;
+        spawn_supervised("connection", async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let socket = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    callback(socket).await;
+                }
+                Err(e) => log::debug!("Failed to upgrade websocket connection: {}", e),
+            }
+            drop(guard);
+        });
+        let mut response = (
+            StatusCode::SWITCHING_PROTOCOLS,
+            [
+                (CONNECTION, HeaderValue::from_static("upgrade")),
+                (UPGRADE, HeaderValue::from_static("websocket")),
+                (
+                    SEC_WEBSOCKET_ACCEPT,
+                    HeaderValue::from_str(&accept).unwrap(),
+                ),
+            ],
+        )
+            .into_response();
+        if let Some(protocol) = protocol {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+        }
+        response
+    }
+}
+
+/// Check if a comma separated header contains the value, ignoring case
+fn header_contains(headers: &HeaderMap, name: axum::http::HeaderName, expected: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(expected))
+}
+
+/// A request that was rejected by the per ip limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LimitExceeded {
+    RateLimit,
+    Concurrency,
+}
+
+impl LimitExceeded {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LimitExceeded::RateLimit => "rate_limit",
+            LimitExceeded::Concurrency => "concurrency",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    window_start: Instant,
+    requests: u32,
+    active: usize,
+}
+
+/// Requests and open connections for every client ip, shared by all routes of a server
+struct ClientRegistry {
+    rate_limit: u32,
+    max_concurrent: usize,
+    clients: DashMap<IpAddr, ClientState, RandomState>,
+}
+
+impl ClientRegistry {
+    fn new(rate_limit: u32, max_concurrent: usize) -> Self {
+        ClientRegistry {
+            rate_limit,
+            max_concurrent,
+            clients: DashMap::default(),
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ClientGuard, LimitExceeded> {
+        let now = Instant::now();
+        if self.clients.len() > RATE_LIMIT_CLEANUP_SIZE {
+            self.clients.retain(|_, client| {
+                client.active > 0 || now.duration_since(client.window_start) < RATE_LIMIT_WINDOW
+            });
+        }
+        let mut client = self.clients.entry(ip).or_insert(ClientState {
+            window_start: now,
+            requests: 0,
+            active: 0,
+        });
+        if now.duration_since(client.window_start) >= RATE_LIMIT_WINDOW {
+            client.window_start = now;
+            client.requests = 0;
+        }
+        if self.max_concurrent > 0 && client.active >= self.max_concurrent {
+            return Err(LimitExceeded::Concurrency);
+        }
+        client.requests += 1;
+        if self.rate_limit > 0 && client.requests > self.rate_limit {
+            return Err(LimitExceeded::RateLimit);
+        }
+        client.active += 1;
+        Ok(ClientGuard {
+            registry: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Counts towards the concurrency limit of the client until dropped
+///
+/// Websocket upgrades take the guard from the request extensions and keep it for as long as the socket is open
+pub struct ClientGuard {
+    registry: Arc<ClientRegistry>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.registry.clients.get_mut(&self.ip) {
+            client.active = client.active.saturating_sub(1);
+        }
+    }
+}
+
+/// Per ip rate and concurrency limits that can be applied to any group of routes
+///
+/// All routes the limits are applied to share the same counts for a client
+#[derive(Clone)]
+pub struct ClientLimits {
+    registry: Option<Arc<ClientRegistry>>,
+}
+
+impl ClientLimits {
+    pub fn new(limits: &HttpLimits) -> Self {
+        ClientLimits {
+            registry: (limits.rate_limit > 0 || limits.max_concurrent_per_ip > 0).then(|| {
+                Arc::new(ClientRegistry::new(
+                    limits.rate_limit,
+                    limits.max_concurrent_per_ip,
+                ))
+            }),
+        }
+    }
+
+    /// Apply the limits to the routes, rejected requests are counted in the metrics under `route`
+    pub fn apply<S>(&self, router: Router<S>, route: &'static str) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        match &self.registry {
+            Some(registry) => router.route_layer(middleware::from_fn_with_state(
+                (registry.clone(), route),
+                limit_client,
+            )),
+            None => router,
+        }
+    }
+}
+
+async fn limit_client(
+    State((registry, route)): State<(Arc<ClientRegistry>, &'static str)>,
+    connection: ConnectionInfo,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // unix socket connections come from a local proxy and are not limited
+    let Some(remote) = connection.peer else {
+        return next.run(request).await;
+    };
+    match registry.try_acquire(remote.ip()) {
+        Ok(guard) => {
+            let guard = Arc::new(guard);
+            request.extensions_mut().insert(guard.clone());
+            let response = next.run(request).await;
+            drop(guard);
+            response
+        }
+        Err(limit) => {
+            log::debug!(
+                "Rejecting request to {} from {}: {} exceeded",
+                route,
+                remote.ip(),
+                limit.label()
+            );
+            METRICS.add_rejected_request(route, limit);
+            StatusCode::TOO_MANY_REQUESTS.into_response()
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RequestLimits {
+    max_headers: usize,
+    max_body_size: u64,
+}
+
+impl RequestLimits {
+    fn new(limits: &HttpLimits) -> Self {
+        RequestLimits {
+            max_headers: limits.max_headers,
+            max_body_size: limits.max_body_size,
+        }
+    }
+}
+
+async fn limit_requests(
+    State(limits): State<RequestLimits>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.headers().len() > limits.max_headers {
+        return StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE.into_response();
+    }
+    if request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length > limits.max_body_size)
+    {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    next.run(request).await
+}
+
+/// Log every request except websocket upgrades
+async fn log_request(connection: ConnectionInfo, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        log::info!(
+            target: "notify_push::request",
+            "method={} route={} status={} duration_ms={} peer={}",
+            method,
+            path,
+            response.status().as_u16(),
+            start.elapsed().as_millis(),
+            connection
+                .peer
+                .map_or_else(|| String::from("unix"), |peer| peer.to_string())
+        );
+    }
+    response
+}
+
+/// Serve the router on the incoming connections, if `log_requests` is set every request except websocket upgrades is logged
+pub async fn serve_incoming<I, C, Conn>(
+    router: Router,
+    incoming: I,
+    cancel: C,
+    limits: HttpLimits,
+    log_requests: bool,
+) where
+    I: Stream<Item = io::Result<Conn>> + Send + 'static,
+    C: Future<Output = ()> + Send + 'static,
+    Conn: Connection,
+{
+    let router = router.layer(middleware::from_fn_with_state(
+        RequestLimits::new(&limits),
+        limit_requests,
+    ));
+    let router = if log_requests {
+        router.layer(middleware::from_fn(log_request))
+    } else {
+        router
+    };
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_timeout)
+        .max_buf_size(limits.max_header_size);
+    builder
+        .http2()
+        .max_header_list_size(limits.max_header_size as u32);
+
+    let graceful = GracefulShutdown::new();
+    pin_mut!(incoming);
+    pin_mut!(cancel);
+    loop {
+        let connection = tokio::select! {
+            _ = &mut cancel => break,
+            connection = incoming.next() => match connection {
+                Some(Ok(connection)) => connection,
+                Some(Err(e)) => {
+                    log::warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+                None => break,
+            },
+        };
+        let info = ConnectionInfo {
+            peer: connection.peer_addr(),
+            secure: connection.is_secure(),
+        };
+        let router = router.clone();
+        let service =
+            hyper::service::service_fn(move |mut request: Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(info);
+                router.clone().oneshot(request.map(Body::new))
+            });
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(connection), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::debug!("Error while serving http connection: {}", e);
+            }
+        });
+    }
+    graceful.shutdown().await;
+}
+
+#[test]
+fn test_client_rate_limit() {
+    let registry = Arc::new(ClientRegistry::new(2, 0));
+    let ip = IpAddr::from([1, 2, 3, 4]);
+    assert!(registry.try_acquire(ip).is_ok());
+    assert!(registry.try_acquire(ip).is_ok());
+    assert_eq!(
+        Some(LimitExceeded::RateLimit),
+        registry.try_acquire(ip).err()
+    );
+    assert!(registry.try_acquire(IpAddr::from([1, 2, 3, 5])).is_ok());
+
+    // expired windows are reset
+    registry.clients.get_mut(&ip).unwrap().window_start -= RATE_LIMIT_WINDOW;
+    assert!(registry.try_acquire(ip).is_ok());
+}
+
+#[test]
+fn test_client_concurrency_limit() {
+    let registry = Arc::new(ClientRegistry::new(0, 2));
+    let ip = IpAddr::from([1, 2, 3, 4]);
+    let first = registry.try_acquire(ip).unwrap();
+    let _second = registry.try_acquire(ip).unwrap();
+    assert_eq!(
+        Some(LimitExceeded::Concurrency),
+        registry.try_acquire(ip).err()
+    );
+    assert!(registry.try_acquire(IpAddr::from([1, 2, 3, 5])).is_ok());
+
+    drop(first);
+    assert!(registry.try_acquire(ip).is_ok());
+}
@@ -3,92 +3,222 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
  
-use crate::config::{Bind, Config, TlsConfig};
-use crate::connection::{handle_user_socket, ActiveConnections, ConnectionOptions};
+// the config is dumped as json with a single `json!` invocation
+#![recursion_limit = "256"]
+
+use crate::admin::admin_routes;
+use crate::affinity::{requested_affinity, AFFINITY_HEADER};
+use crate::auth::AuthProviders;
+use crate::auth_limit::AuthLimiter;
+use crate::config::{Bind, Config, HttpLimits, Opt, TlsConfig};
+use crate::connection::{
+    handle_user_socket, ActiveConnections, ConnectionOptions, ConnectionRamp, Reset,
+};
+use crate::dynamic_channel::DynamicChannels;
 pub use crate::error::Error;
-use crate::error::{SelfTestError, SocketError};
+use crate::error::{AuthenticationError, SelfTestError, SocketError};
 use crate::event::{
-    Activity, Custom, Event, GroupUpdate, Notification, PreAuth, ShareCreate, StorageUpdate,
+    Activity, ChannelRule, Custom, Event, GroupUpdate, MessageDecodeError, Notification, PreAuth,
+    Relay, RelayedMessage, ShareCreate, StorageUpdate, TraceUser, RELAY_CHANNEL,
 };
-use crate::message::{PushMessage, UpdatedFiles};
+use crate::forwarded::Forwarded;
+use crate::http::{incoming, serve_incoming, ClientLimits, WebSocketUpgrade};
+use crate::message::{PushMessage, Subprotocol, UpdatedFiles};
 use crate::metrics::METRICS;
+use crate::metrics_store::MetricsStore;
+use crate::presence::PresenceWebhook;
+use crate::protocol::protocol;
+use crate::query::Instance;
 use crate::redis::Redis;
+use crate::remote_config::RemoteConfig;
+use crate::roundtrip::TestCookie;
+use crate::service_account::ServiceAccounts;
+use crate::shard::Shards;
 use crate::storage_mapping::StorageMapping;
+use crate::storage_stats::StorageStats;
+use crate::storm::{StormDetector, StormTarget};
+use crate::supervisor::spawn_supervised;
+use crate::trace::{Stage, MAX_TRACE_DURATION};
+use crate::unknown_channel::UnknownChannels;
+use crate::user::keep_user_names;
 pub use crate::user::UserId;
+use crate::user_stats::UserMessageStats;
+use crate::web_push::WebPush;
+use crate::workers::{worker_index, REUSE_PORT};
 use ahash::RandomState;
+use axum::extract::{RawQuery, State};
+use axum::http::header::{AUTHORIZATION, RETRY_AFTER};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
 use dashmap::DashMap;
 use flexi_logger::LoggerHandle;
-use futures::future::{select, Either};
+use futures::future::{select, BoxFuture};
 use futures::StreamExt;
 use futures::{pin_mut, FutureExt};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
 use smallvec::alloc::sync::Arc;
+use sqlx::any::AnyConnectOptions;
 use sqlx::AnyPool;
-use std::convert::Infallible;
 use std::fs;
-use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
+use std::future::{pending, Future};
+use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex as StdMutex, OnceLock, RwLock};
 use std::time::{Duration, Instant};
-use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpSocket, UnixListener};
 use tokio::sync::Mutex;
 use tokio::sync::{broadcast, oneshot};
-use tokio::time::sleep;
-use tokio_stream::wrappers::UnixListenerStream;
-use warp::filters::addr::remote;
-use warp::{Filter, Reply};
-use warp_real_ip::get_forwarded_for;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+#[cfg(feature = "rustls")]
+use tokio_rustls::TlsAcceptor;
+use tower_http::cors::{Any, CorsLayer};
+use url::Url;
 
+pub mod activity;
+pub mod admin;
+pub mod affinity;
+pub mod auth;
+pub mod auth_limit;
 pub mod config;
 pub mod connection;
+pub mod crash;
+pub mod diagnostics;
+pub mod dynamic_channel;
 pub mod error;
 pub mod event;
+pub mod failure_report;
+pub mod forwarded;
+pub mod health;
+pub mod http;
 pub mod message;
 pub mod metrics;
+pub mod metrics_format;
+pub mod metrics_store;
+pub mod monitor;
 pub mod nc;
+#[cfg(feature = "otel")]
+pub mod otel;
 mod passthru_hasher;
+pub mod poll;
+pub mod presence;
+pub mod protocol;
+pub mod proxy_check;
+pub mod query;
 pub mod redis;
+pub mod remote_config;
+pub mod roundtrip;
+pub mod service_account;
+pub mod session;
+pub mod setup;
+pub mod shard;
 pub mod storage_mapping;
+pub mod storage_stats;
+pub mod storm;
+pub mod supervisor;
+#[cfg(feature = "rustls")]
+pub mod tls;
+pub mod trace;
+pub mod unknown_channel;
+pub mod update;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 pub mod user;
+pub mod user_stats;
+pub mod warm_up;
+pub mod web_push;
+pub mod workers;
 
+/// Result type of the library api, errors implement `miette::Diagnostic` but don't require a report handler,
+/// so embedders can handle them like any other error type
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How long a transfer token can be redeemed after it has been created
+const TRANSFER_TOKEN_VALIDITY: Duration = Duration::from_secs(60);
+/// Maximum number of seconds clients are told to wait when their connection is rejected after startup
+const RAMP_MAX_RETRY_AFTER: u32 = 10;
+/// How long the `/test` endpoints are available in production mode after a self test has been started
+const SELF_TEST_WINDOW: Duration = Duration::from_secs(60);
+/// How long to wait for the app to write its version during the self test
+const APP_VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the summary of a user trace is kept in redis
+const TRACE_SUMMARY_EXPIRY: u64 = 7 * 24 * 60 * 60;
+/// Number of resets that can be queued for the connections, enough for a burst of disconnected users
+const RESET_CHANNEL_SIZE: usize = 64;
+/// How often storms are checked for whether they are over
+const STORM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct App {
     connections: ActiveConnections,
-    nc_client: nc::Client,
+    nc_client: Arc<nc::Client>,
+    /// Verifies the credentials of new connections
+    auth: AuthProviders,
+    /// Rejects logins after too many failed attempts
+    auth_limit: Option<AuthLimiter>,
+    service_accounts: ServiceAccounts,
+    /// Runtimes the authenticated connections are handled on, if the connections are sharded
+    shards: Shards,
     storage_mapping: StorageMapping,
     pre_auth: DashMap<String, (Instant, UserId), RandomState>,
-    test_cookie: AtomicU32,
+    test_cookie: TestCookie,
+    /// In production mode the `/test` endpoints are only available while a self test is running
+    production: bool,
+    self_test_until: StdMutex<Option<Instant>>,
+    /// Shared secret nextcloud needs to send to use the `/test` endpoints
+    test_secret: Option<String>,
+    /// Number of trusted proxies in front of the push server
+    forwarded_for_depth: Option<usize>,
+    /// Reject websocket connections that didn't use TLS
+    require_secure: bool,
+    /// Allow connections to request a transfer token for another connection
+    transfer_tokens: bool,
+    /// Log all http requests with their status and duration
+    log_requests: bool,
+    /// Accept the client connections with the experimental io_uring listener
+    io_uring: bool,
+    /// Fail the self test if the app has a different major version
+    strict_version: bool,
+    /// Public url of the push server that is registered with the app
+    public_url: Option<Url>,
+    /// Token for the `/admin` endpoints, they are disabled without it
+    admin_token: Option<String>,
+    /// Token for pushing messages with `POST /admin/push`
+    push_token: Option<String>,
+    /// Event counts for the busiest storages
+    storage_stats: Option<StorageStats>,
+    /// Summarizes the updates for storages and users that receive too many of them
+    storms: Option<StormDetector>,
+    unknown_channels: UnknownChannels,
+    /// Channels added by apps at runtime, forwarded as custom messages
+    dynamic_channels: DynamicChannels,
+    /// Limits new connections after startup
+    connection_ramp: Option<ConnectionRamp>,
+    /// Load settings from the app
+    remote_config_enabled: bool,
+    remote_config: RwLock<RemoteConfig>,
     redis: Redis,
+    instance: Instance,
+    /// Redis key for the session snapshot of this instance
+    session_key: String,
+    web_push: Option<WebPush>,
+    /// Set when the redis subscription is lost, events published in the meantime won't be received
+    redis_disconnected: AtomicBool,
+    /// Arguments the config was loaded from, used to reload the database credentials
+    config_source: OnceLock<Opt>,
     log_handle: Mutex<LoggerHandle>,
-    reset_tx: broadcast::Sender<()>,
-    _reset_rx: broadcast::Receiver<()>,
+    reset_tx: broadcast::Sender<Reset>,
+    _reset_rx: broadcast::Receiver<Reset>,
 }
 
 impl App {
     pub async fn new(config: Config, log_handle: LoggerHandle) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, config.allow_self_signed)?;
-        let test_cookie = AtomicU32::new(0);
-
-        let storage_mapping = StorageMapping::new(config.database, config.database_prefix).await?;
-        let pre_auth = DashMap::default();
-
-        let redis = Redis::new(config.redis)?;
-
-        let (reset_tx, reset_rx) = broadcast::channel(1);
-
-        Ok(App {
-            connections,
-            nc_client,
-            test_cookie,
-            pre_auth,
-            storage_mapping,
-            redis,
-            log_handle: Mutex::new(log_handle),
-            reset_tx,
-            _reset_rx: reset_rx,
-        })
+        let storage_mapping =
+            StorageMapping::new(config.database.clone(), config.database_prefix.clone()).await?;
+        let allow_self_signed = config.allow_self_signed;
+        Self::build(config, storage_mapping, log_handle, allow_self_signed).await
     }
 
     pub async fn with_connection(
@@ -97,24 +227,116 @@ impl App {
         log_handle: LoggerHandle,
         allow_self_signed: bool,
     ) -> Result<Self> {
-        let connections = ActiveConnections::default();
-        let nc_client = nc::Client::new(&config.nextcloud_url, allow_self_signed)?;
-        let test_cookie = AtomicU32::new(0);
+        let storage_mapping =
+            StorageMapping::from_connection(connection, config.database_prefix.clone());
+        Self::build(config, storage_mapping, log_handle, allow_self_signed).await
+    }
 
-        let storage_mapping = StorageMapping::from_connection(connection, config.database_prefix);
+    /// Setup shared by the constructors, which only differ in how the database connection is made
+    async fn build(
+        config: Config,
+        storage_mapping: StorageMapping,
+        log_handle: LoggerHandle,
+        allow_self_signed: bool,
+    ) -> Result<Self> {
+        // transfer tokens, session snapshots and the admin endpoints need the plain user names
+        if config.transfer_tokens || config.resume_sessions || config.admin_token.is_some() {
+            keep_user_names();
+        }
+        let instance = Instance::new(&config);
+        let session_key = session::snapshot_key(config.instance_name.as_deref(), worker_index());
+        let nc_client = Arc::new(nc::Client::new(&config.nextcloud_url, allow_self_signed)?);
+        let auth = AuthProviders::from_config(&config, nc_client.clone())?;
+        let shards = Shards::new(&config.runtime_shards).map_err(Error::RuntimeShards)?;
+        let presence = config
+            .presence_webhook
+            .map(|url| PresenceWebhook::new(url, allow_self_signed))
+            .transpose()?;
+        let connections = ActiveConnections::new(presence)
+            .with_max_file_ids(config.max_file_ids)
+            .with_custom_debounce(config.custom_debounce.clone())
+            .with_merge_time(config.merge_time);
+        let connections = if config.per_user_delivery {
+            connections.with_user_delivery(config.max_debounce_time)
+        } else {
+            connections
+        };
+        let connections = if config.user_message_window.is_zero() {
+            connections
+        } else {
+            connections.with_message_stats(UserMessageStats::new(
+                config.user_message_window,
+                config.user_message_threshold,
+                config.user_message_webhook,
+                allow_self_signed,
+            )?)
+        };
+        let web_push = config
+            .web_push
+            .map(|web_push| {
+                WebPush::new(
+                    web_push,
+                    storage_mapping.connection().clone(),
+                    config.database_prefix,
+                    allow_self_signed,
+                )
+            })
+            .transpose()?;
         let pre_auth = DashMap::default();
+        let service_accounts = ServiceAccounts::new(auth.service_accounts().to_vec());
+        if let Err(e) = service_accounts.load_groups(&storage_mapping).await {
+            log::error!("Failed to load the groups of the service accounts: {:#}", e);
+        }
 
-        let redis = Redis::new(config.redis)?;
+        let redis = Redis::new(config.redis)?
+            .with_command_servers(config.redis_command)
+            .with_certificates(
+                config.redis_tls_cert.as_deref(),
+                config.redis_tls_key.as_deref(),
+                config.redis_tls_ca.as_deref(),
+            )?;
 
-        let (reset_tx, reset_rx) = broadcast::channel(1);
+        let (reset_tx, reset_rx) = broadcast::channel(RESET_CHANNEL_SIZE);
 
         Ok(App {
             connections,
             nc_client,
-            test_cookie,
+            auth,
+            auth_limit: (config.auth_failure_limit > 0)
+                .then(|| AuthLimiter::new(config.auth_failure_limit, config.auth_failure_cooldown)),
+            shards,
+            service_accounts,
+            test_cookie: TestCookie::default(),
+            production: config.production,
+            self_test_until: StdMutex::new(None),
+            test_secret: config.test_secret,
+            forwarded_for_depth: config.forwarded_for_depth,
+            require_secure: config.require_secure,
+            transfer_tokens: config.transfer_tokens,
+            log_requests: config.log_requests,
+            io_uring: config.io_uring,
+            strict_version: config.strict_version,
+            public_url: config.public_url,
+            admin_token: config.admin_token,
+            push_token: config.push_token,
+            storage_stats: (config.storage_stats > 0)
+                .then(|| StorageStats::new(config.storage_stats)),
+            storms: config.storm_threshold.map(StormDetector::new),
+            unknown_channels: UnknownChannels::new(config.unknown_channels),
+            dynamic_channels: DynamicChannels::default(),
+            remote_config_enabled: config.remote_config,
+            remote_config: RwLock::default(),
+            connection_ramp: (config.connection_ramp_rate > 0).then(|| {
+                ConnectionRamp::new(config.connection_ramp_rate, config.connection_ramp_duration)
+            }),
             pre_auth,
             storage_mapping,
             redis,
+            instance,
+            session_key,
+            web_push,
+            redis_disconnected: AtomicBool::new(false),
+            config_source: OnceLock::new(),
             log_handle: Mutex::new(log_handle),
             reset_tx,
             _reset_rx: reset_rx,
@@ -126,11 +348,38 @@ impl App {
             .storage_mapping
             .get_users_for_storage_path(1, "")
             .await?;
+        if self.redis.separate_commands() {
+            log::info!("Not checking the app version, the push server can't read the keys of the app from the command redis");
+            return Ok(());
+        }
         let mut redis = self.redis.connect().await?;
         redis.del("notify_push_app_version").await?;
+        // left over versions in other databases would be mistaken for a database mismatch
+        let mut other_databases = self.redis.other_databases().await?;
+        for (_, connection) in &mut other_databases {
+            connection.del("notify_push_app_version").await?;
+        }
+        // subscribe before requesting the version, so the write can't happen before we're listening
+        let mut written = self.redis.watch_key("notify_push_app_version").await?;
         self.nc_client.request_app_version().await?;
-        match redis.get("notify_push_app_version").await {
+        let version = match redis.get_optional("notify_push_app_version").await {
+            Ok(Some(version)) => Ok(version),
+            _ => {
+                // without keyspace notifications enabled on the redis server this waits for the full timeout
+                timeout(APP_VERSION_TIMEOUT, written.on_message().next())
+                    .await
+                    .ok();
+                redis.get("notify_push_app_version").await
+            }
+        };
+        match version {
             Ok(version) if version == env!("NOTIFY_PUSH_VERSION") => {}
+            Ok(version) if self.strict_version && !same_major_version(&version) => {
+                return Err(SelfTestError::VersionMismatch {
+                    server: env!("NOTIFY_PUSH_VERSION").into(),
+                    app: version,
+                });
+            }
             Ok(version) => {
                 log::warn!(
                     "push server (version {}) is not the same version as the app (version {})",
@@ -138,51 +387,198 @@ impl App {
                     version
                 );
             }
-            Err(_) => {}
+            // the app didn't write its version to our database, either it's configured with a different redis
+            // database which means no events will arrive, or it never received the request
+            Err(_) => {
+                for (found, connection) in &mut other_databases {
+                    if connection.exists("notify_push_app_version").await? {
+                        return Err(SelfTestError::RedisDbMismatch {
+                            configured: self.redis.db(),
+                            found: *found,
+                        });
+                    }
+                }
+                return Err(SelfTestError::NoAppVersion {
+                    seconds: APP_VERSION_TIMEOUT.as_secs(),
+                });
+            }
         }
 
         Ok(())
     }
 
+    /// Remember the arguments the config was loaded from, so the config can be reloaded later on
+    pub fn set_config_source(&self, opt: Opt) {
+        self.config_source.set(opt).ok();
+    }
+
+    /// Switch to a new database connection, without interrupting running queries
+    pub async fn replace_database(&self, options: AnyConnectOptions) -> Result<()> {
+        Ok(self.storage_mapping.connection().replace(options).await?)
+    }
+
+    /// Load the config again and switch to the database credentials from it
+    pub async fn reload_database(&self) -> Result<()> {
+        let Some(opt) = self.config_source.get() else {
+            log::warn!("Can't reload database credentials, config source not known");
+            return Ok(());
+        };
+        let config = Config::from_opt(opt.clone())?;
+        self.replace_database(config.database).await
+    }
+
+    /// Store the connected users in redis so their sessions can be resumed after a restart
+    pub async fn save_sessions(&self) -> Result<usize> {
+        session::save_snapshot(&self.redis, &self.session_key, &self.connections).await
+    }
+
+    /// Load the users that were connected before the restart
+    pub async fn resume_sessions(&self) -> Result<usize> {
+        session::load_snapshot(&self.redis, &self.session_key, &self.connections).await
+    }
+
+    /// Store the cumulative metrics so they can be continued after a restart
+    pub async fn save_metrics(&self, store: &MetricsStore) -> Result<()> {
+        store.save(&self.redis).await
+    }
+
+    /// Continue the cumulative metrics of the previous instance, returns whether any were stored
+    pub async fn load_metrics(&self, store: &MetricsStore) -> Result<bool> {
+        store.load(&self.redis).await
+    }
+
+    /// Store the most recently used storages in redis so the cache can be warmed up after a restart
+    pub async fn save_recent_storages(&self, count: usize) -> Result<usize> {
+        warm_up::save_recent_storages(&self.redis, &self.storage_mapping, count).await
+    }
+
+    /// Load the storage mappings that were most recently used before the restart
+    pub async fn warm_up_storage_cache(&self) -> Result<usize> {
+        warm_up::warm_up(&self.redis, &self.storage_mapping).await
+    }
+
+    /// Create a short-lived token that can be used to authenticate another connection as the same user
+    pub async fn create_transfer_token(&self, user: &UserId) -> Result<String> {
+        let name = user.name().ok_or(AuthenticationError::Invalid)?;
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+        let mut redis = self.redis.connect().await?;
+        redis
+            .set_ex(
+                &format!("notify_push_transfer_token_{}", token),
+                &name,
+                TRANSFER_TOKEN_VALIDITY.as_secs(),
+            )
+            .await?;
+        Ok(token)
+    }
+
+    /// Verify the credentials of a client, rejecting them without verification after too many failed attempts
+    ///
+    /// Attempts are counted for the trusted client address, forwarding headers beyond the trusted proxies are ignored
+    /// since the client can change them on every attempt.
+    pub(crate) async fn limit_auth(
+        &self,
+        forwarded: &Forwarded,
+        username: &str,
+        verify: impl Future<Output = Result<UserId, AuthenticationError>>,
+    ) -> Result<UserId, AuthenticationError> {
+        match &self.auth_limit {
+            Some(limit) => {
+                let client = forwarded.trusted_client(self.forwarded_for_depth);
+                limit.limit(client, username, verify).await
+            }
+            None => verify.await,
+        }
+    }
+
+    /// Get the user for a transfer token, tokens can only be redeemed once
+    pub async fn redeem_transfer_token(&self, token: &str) -> Result<Option<UserId>> {
+        if !self.transfer_tokens {
+            return Ok(None);
+        }
+        let key = format!("notify_push_transfer_token_{}", token);
+        let mut redis = self.redis.connect().await?;
+        // read and remove the token in one command so concurrent redemptions can't both succeed
+        let user = redis.get_del(&key).await?;
+        Ok(user.map(UserId::from))
+    }
+
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all, fields(event = %event)))]
     async fn handle_event(&self, event: Event) {
+        let received = Instant::now();
         match event {
             Event::StorageUpdate(StorageUpdate {
                 storage,
                 path,
                 file_id,
             }) => {
+                if let Some(stats) = &self.storage_stats {
+                    stats.record(storage);
+                }
+                if self.remote_config.read().unwrap().is_excluded(&path) {
+                    log::debug!("Ignoring storage update for excluded path {}", path);
+                    return;
+                }
+                let target = StormTarget::Storage(storage);
+                let storm = self
+                    .storms
+                    .as_ref()
+                    .is_some_and(|storms| storms.record(&target));
                 match self
                     .storage_mapping
                     .get_users_for_storage_path(storage, &path)
                     .await
                 {
+                    Ok(users) if storm => self.send_storm_summary(&target, users),
                     Ok(users) => {
                         for user in users {
-                            self.connections
-                                .send_to_user(&user, PushMessage::File(file_id.into()));
+                            self.send_file_update(&user, file_id.into(), received);
                         }
                     }
                     Err(e) => log::error!("{:#}", e),
                 }
             }
-            Event::GroupUpdate(GroupUpdate { user, .. }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::File(UpdatedFiles::Unknown));
+            Event::GroupUpdate(GroupUpdate { user, group }) => {
+                if self.service_accounts.watches_group(&group) {
+                    if let Err(e) = self
+                        .service_accounts
+                        .load_groups(&self.storage_mapping)
+                        .await
+                    {
+                        log::error!("Failed to load the groups of the service accounts: {:#}", e);
+                    }
+                }
+                self.send_file_update(&user, UpdatedFiles::Unknown, received);
             }
             Event::ShareCreate(ShareCreate { user }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::File(UpdatedFiles::Unknown));
+                self.send_file_update(&user, UpdatedFiles::Unknown, received);
             }
             Event::TestCookie(cookie) => {
-                self.test_cookie.store(cookie, Ordering::SeqCst);
+                // besides the roundtrip check, only nextcloud sends the test cookie, so this is the start of a self test
+                if self.test_cookie.receive(cookie) && self.production {
+                    *self.self_test_until.lock().unwrap() = Some(Instant::now() + SELF_TEST_WINDOW);
+                }
             }
             Event::Activity(Activity { user }) => {
-                self.connections.send_to_user(&user, PushMessage::Activity);
-            }
-            Event::Notification(Notification { user }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::Notification);
+                self.send_to_user(&user, PushMessage::Activity, received);
             }
+            Event::Notification(Notification { user }) => match &self.web_push {
+                Some(web_push) if !self.connections.is_connected(&user) => {
+                    self.connections.tracer().record(
+                        &user,
+                        Stage::Received,
+                        received,
+                        &"notification, sending web push",
+                    );
+                    if let Err(e) = web_push.send(&user, "notify_notification").await {
+                        log::warn!("{:#}", e);
+                    }
+                    self.send_to_service_accounts(&user, &PushMessage::Notification);
+                }
+                _ => {
+                    self.send_to_user(&user, PushMessage::Notification, received);
+                }
+            },
             Event::PreAuth(PreAuth { user, token }) => {
                 self.pre_auth.insert(token, (Instant::now(), user));
             }
@@ -191,8 +587,7 @@ impl App {
                 message,
                 body,
             }) => {
-                self.connections
-                    .send_to_user(&user, PushMessage::Custom(message, body));
+                self.send_to_user(&user, PushMessage::Custom(message, body), received);
             }
             Event::Config(event::Config::LogSpec(spec)) => {
                 match self.log_handle.lock().await.parse_and_push_temp_spec(&spec) {
@@ -204,30 +599,254 @@ impl App {
                 self.log_handle.lock().await.pop_temp_spec();
                 log::info!("Restored log level");
             }
-            Event::Query(event::Query::Metrics) => match self.redis.connect().await {
+            Event::Config(event::Config::ReloadDatabase) => match self.reload_database().await {
+                Ok(()) => log::info!("Switched to new database connection"),
+                Err(e) => log::error!("Failed to reload database connection: {:#}", e),
+            },
+            Event::Config(event::Config::RemoteConfig) if self.remote_config_enabled => {
+                if let Err(e) = self.load_remote_config().await {
+                    log::error!("Failed to load settings from the app: {:#}", e);
+                }
+            }
+            Event::Config(event::Config::RemoteConfig) => {
+                log::debug!(
+                    "Ignoring remote config update, loading settings from the app is disabled"
+                );
+            }
+            Event::Config(event::Config::TraceUser(TraceUser { user, minutes })) => {
+                self.trace_user(user, minutes).await;
+            }
+            Event::Config(event::Config::AddChannel(ChannelRule { channel, message })) => {
+                if let Err(e) = self.dynamic_channels.add(channel, message).await {
+                    log::warn!("Failed to add channel: {}", e);
+                }
+            }
+            Event::Config(event::Config::RemoveChannel(channel)) => {
+                self.dynamic_channels.remove(&channel).await;
+            }
+            Event::Query(query) => match self.redis.shared().await {
                 Ok(mut redis) => {
-                    if let Err(e) = redis
-                        .set(
-                            "notify_push_metrics",
-                            &serde_json::to_string(&METRICS).unwrap(),
-                        )
-                        .await
-                    {
-                        log::warn!("Failed to set metrics: {}", e);
+                    if let Err(e) = self.instance.write_answer(&mut redis, &query).await {
+                        log::warn!("Failed to answer {} query: {}", query, e);
+                        self.redis.reset_shared().await;
                     }
                 }
-                Err(e) => log::warn!("Failed to set metrics: {}", e),
+                Err(e) => log::warn!("Failed to answer {} query: {}", query, e),
             },
+            Event::Relay(relay) if relay.origin == self.instance.id => {}
+            Event::Relay(Relay { user, message, .. }) => {
+                self.send_to_user(&user, message.into(), received);
+            }
             Event::Signal(event::Signal::Reset) => {
                 log::info!("Stopping all open connections");
-                if let Err(e) = self.reset_tx.send(()) {
+                if let Err(e) = self.reset_tx.send(Reset::All) {
                     log::warn!("Failed to send reset command to all connections: {}", e);
                 }
             }
+            Event::Signal(event::Signal::Disconnect { user }) => {
+                log::info!("Stopping all open connections for {}", user);
+                if let Err(e) = self.reset_tx.send(Reset::User(user)) {
+                    log::warn!(
+                        "Failed to send disconnect command to the connections: {}",
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Send a message that didn't come in over redis to the connections of a user on every instance
+    ///
+    /// The message is delivered to the connections of this instance directly and published for the other instances.
+    pub async fn relay(&self, user: &str, message: RelayedMessage) -> Result<()> {
+        self.send_to_user(&UserId::new(user), message.clone().into(), Instant::now());
+
+        let payload = serde_json::json!({
+            "origin": self.instance.id,
+            "user": user,
+            "message": message,
+        })
+        .to_string();
+        let mut redis = self.redis.shared().await?;
+        if let Err(e) = redis.publish(RELAY_CHANNEL, &payload).await {
+            self.redis.reset_shared().await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Send a message to the connections of a user, logging each stage if the user is traced
+    fn send_to_user(&self, user: &UserId, msg: PushMessage, received: Instant) {
+        let tracer = self.connections.tracer();
+        if tracer.is_traced(user) {
+            let resolved = Instant::now();
+            tracer.record(user, Stage::Received, received, &msg);
+            let stage = if self.connections.is_connected(user) {
+                Stage::Queued
+            } else {
+                Stage::Dropped
+            };
+            tracer.record(user, stage, resolved, &msg);
+        }
+        self.send_to_service_accounts(user, &msg);
+        self.connections.send_to_user(user, msg);
+    }
+
+    /// Send a file update to a user, unless the updates for the user are being summarized
+    fn send_file_update(&self, user: &UserId, files: UpdatedFiles, received: Instant) {
+        if let Some(storms) = &self.storms {
+            let target = StormTarget::User(user.clone());
+            if storms.record(&target) {
+                self.send_storm_summary(&target, [user.clone()]);
+                return;
+            }
+        }
+        self.send_to_user(user, PushMessage::File(files), received);
+    }
+
+    /// Let the users affected by a storm know that their files changed, once for every storm
+    fn send_storm_summary(&self, target: &StormTarget, users: impl IntoIterator<Item = UserId>) {
+        let Some(storms) = &self.storms else {
+            return;
+        };
+        let now = Instant::now();
+        for user in storms.summarize(target, users) {
+            self.send_to_user(&user, PushMessage::File(UpdatedFiles::Unknown), now);
+            self.send_to_user(&user, target.message(), now);
+        }
+    }
+
+    /// End the storms that calmed down, the users get one more update for the changes made during the storm
+    async fn storm_loop(&self) {
+        let Some(storms) = &self.storms else {
+            return pending().await;
+        };
+        let mut interval = interval(STORM_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for storm in storms.expire() {
+                for user in storm.users {
+                    self.send_to_user(
+                        &user,
+                        PushMessage::File(UpdatedFiles::Unknown),
+                        Instant::now(),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Copy a message to the service accounts that have the user in their scope
+    fn send_to_service_accounts(&self, user: &UserId, msg: &PushMessage) {
+        for service_account in self.service_accounts.watchers(user) {
+            self.connections.send_to_user(&service_account, msg.clone());
+        }
+    }
+
+    /// Trace all messages for a user for a while, and store a summary in redis once done
+    async fn trace_user(&self, user: String, minutes: f64) {
+        let duration = match Duration::try_from_secs_f64(minutes * 60.0) {
+            Ok(duration) => duration.min(MAX_TRACE_DURATION),
+            Err(_) => {
+                log::warn!(
+                    "Ignoring trace for {} with invalid duration {}",
+                    user,
+                    minutes
+                );
+                return;
+            }
+        };
+        log::info!("Tracing messages for {} for {}s", user, duration.as_secs());
+        let id = self.connections.tracer().start(&user);
+        sleep(duration).await;
+
+        let Some(summary) = self.connections.tracer().finish(&user, id) else {
+            return;
+        };
+        log::info!(
+            "Finished tracing messages for {}, {} messages received and {} sent",
+            user,
+            summary.received.count,
+            summary.sent.count
+        );
+        let key = format!("notify_push_trace_{}", user);
+        let summary = serde_json::to_string(&summary).unwrap_or_default();
+        let result = match self.redis.connect().await {
+            Ok(mut redis) => redis.set_ex(&key, &summary, TRACE_SUMMARY_EXPIRY).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to store trace summary for {}: {:#}", user, e);
+        }
+    }
+
+    /// Whether the `/test` endpoints can currently be used with the provided secret
+    pub fn test_endpoints_enabled(&self, secret: Option<&str>) -> bool {
+        if let Some(expected) = &self.test_secret {
+            if !secret.is_some_and(|secret| constant_time_eq(secret, expected)) {
+                return false;
+            }
+        }
+        !self.production
+            || self
+                .self_test_until
+                .lock()
+                .unwrap()
+                .is_some_and(|until| until > Instant::now())
+    }
+
+    /// The version check is the last step of the self test, close the test endpoints again
+    #[cfg(feature = "test-endpoints")]
+    fn self_test_done(&self) {
+        *self.self_test_until.lock().unwrap() = None;
+    }
+
+    /// Register the public url of the push server with the app, does nothing unless configured
+    pub async fn register_public_url(&self) -> Result<()> {
+        let (Some(public_url), Some(secret)) = (&self.public_url, &self.test_secret) else {
+            return Ok(());
+        };
+        self.nc_client
+            .register_endpoint(public_url.as_str(), secret)
+            .await?;
+        log::info!("Registered {} as push server endpoint", public_url);
+        Ok(())
+    }
+
+    /// Load the settings managed by the app, does nothing unless enabled in the config
+    pub async fn load_remote_config(&self) -> Result<()> {
+        if !self.remote_config_enabled {
+            return Ok(());
+        }
+        let config = self.nc_client.get_remote_config().await?;
+        log::info!("Loaded settings from the app: {:?}", config);
+        *self.remote_config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Options for a new connection, the settings from the app take precedence over the local config
+    fn connection_options(
+        &self,
+        max_debounce_time: usize,
+        max_connection_time: usize,
+    ) -> ConnectionOptions {
+        let remote = self.remote_config.read().unwrap();
+        ConnectionOptions {
+            merge_time: self.connections.merge_time(),
+            ..ConnectionOptions::new(
+                remote.max_debounce_time.unwrap_or(max_debounce_time),
+                remote.max_connection_time.unwrap_or(max_connection_time),
+            )
         }
     }
 
-    pub fn reset_rx(&self) -> broadcast::Receiver<()> {
+    /// Id used to tag the answers of this instance to queries from the app
+    pub fn instance_id(&self) -> &str {
+        &self.instance.id
+    }
+
+    pub fn reset_rx(&self) -> broadcast::Receiver<Reset> {
         self.reset_tx.subscribe()
     }
 }
@@ -237,155 +856,303 @@ pub fn serve(
     bind: Bind,
     cancel: oneshot::Receiver<()>,
     tls: Option<&TlsConfig>,
+    http_limits: &HttpLimits,
     max_debounce_time: usize,
     max_connection_time: usize,
 ) -> Result<impl Future<Output = ()> + Send> {
-    let app = warp::any().map(move || app.clone());
-
-    let cors = warp::cors().allow_any_origin();
+    let log_requests = app.log_requests;
+    let tcp = TcpOptions {
+        reuse_port: REUSE_PORT.load(Ordering::Relaxed),
+        io_uring: app.io_uring,
+    };
+    let limits = ClientLimits::new(http_limits);
 
     // GET /ws -> websocket upgrade
-    let socket = warp::path!("ws")
-        // The `ws()` filter will prepare Websocket handshake...
-        .and(warp::ws())
-        .and(app.clone())
-        .and(remote())
-        .and(get_forwarded_for())
-        .map(
-            move |ws: warp::ws::Ws,
-                  app,
-                  remote: Option<SocketAddr>,
-                  mut forwarded_for: Vec<IpAddr>| {
-                if let Some(remote) = remote {
-                    forwarded_for.push(remote.ip());
-                }
-                log::debug!("new websocket connection from {:?}", forwarded_for.first());
-                let opts = ConnectionOptions::new(max_debounce_time, max_connection_time);
-                ws.on_upgrade(move |socket| handle_user_socket(socket, app, forwarded_for, opts))
-            },
+    let socket = get(
+        move |ws: WebSocketUpgrade,
+              State(app): State<Arc<App>>,
+              forwarded: Forwarded,
+              RawQuery(query): RawQuery| async move {
+            let affinity = requested_affinity(query.as_deref());
+            handle_socket_request(
+                ws,
+                app,
+                forwarded,
+                affinity,
+                max_debounce_time,
+                max_connection_time,
+            )
+        },
+    )
+    .layer(CorsLayer::new().allow_origin(Any));
+
+    // GET /poll -> long-polling for clients that can't use websockets
+    let poll = get(poll::poll).layer(
+        CorsLayer::new()
+            .allow_origin(Any)
+            .allow_headers([AUTHORIZATION]),
+    );
+
+    let routes = limits
+        .apply(Router::new().route("/ws", socket), "ws")
+        .merge(limits.apply(Router::new().route("/poll", poll), "poll"))
+        .route("/protocol", get(protocol))
+        .merge(limits.apply(test_routes(app.clone()), "test"))
+        .merge(limits.apply(admin_routes(app.clone()), "admin"));
+
+    let routes = routes.clone().nest("/push", routes).with_state(app);
+
+    serve_at(routes, bind, cancel, tls, http_limits, log_requests, tcp)
+}
+
+fn handle_socket_request(
+    ws: WebSocketUpgrade,
+    app: Arc<App>,
+    mut forwarded: Forwarded,
+    affinity: Option<String>,
+    max_debounce_time: usize,
+    max_connection_time: usize,
+) -> Response {
+    if let Some(depth) = app.forwarded_for_depth {
+        forwarded = forwarded.at_depth(depth);
+    }
+    let secure = forwarded.is_secure(app.forwarded_for_depth);
+    log::debug!(
+        "new websocket connection from {:?} over {}",
+        forwarded.client(),
+        forwarded.proto.as_deref().unwrap_or("unknown protocol")
+    );
+    if !secure {
+        if app.require_secure {
+            log::info!(
+                "Rejecting insecure websocket connection from {:?}",
+                forwarded.client()
+            );
+            return (StatusCode::FORBIDDEN, "websocket connections require TLS").into_response();
+        }
+        METRICS.add_insecure_connection();
+    }
+    if !app
+        .connection_ramp
+        .as_ref()
+        .map_or(true, ConnectionRamp::try_accept)
+    {
+        // spread the retries of the rejected clients
+        let retry_after = rand::thread_rng().gen_range(1..=RAMP_MAX_RETRY_AFTER);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, retry_after.to_string())],
+            "server is starting, retry later",
         )
-        .with(cors);
-
-    let cookie_test = warp::path!("test" / "cookie")
-        .and(app.clone())
-        .map(|app: Arc<App>| {
-            let cookie = app.test_cookie.load(Ordering::SeqCst);
-            log::debug!("current test cookie is {}", cookie);
-            cookie.to_string()
-        });
-
-    let reverse_cookie_test = warp::path!("test" / "reverse_cookie")
-        .and(app.clone())
-        .and_then(|app: Arc<App>| async move {
-            let response = match app.nc_client.get_test_cookie().await {
-                Ok(cookie) => {
-                    log::debug!("got remote test cookie {}", cookie);
-                    cookie.to_string()
-                }
-                Err(e) => {
-                    log::warn!("Error while trying to get cookie from Nextcloud {:#}", e);
-                    format!("{:#}", e)
-                }
-            };
+            .into_response();
+    }
+    let mut opts = app.connection_options(max_debounce_time, max_connection_time);
+    let ws = match Subprotocol::negotiate(ws.requested_protocols()) {
+        Some(subprotocol) => {
+            opts.subprotocol = subprotocol;
+            ws.protocol(subprotocol.name())
+        }
+        None => ws,
+    };
+    let mut response =
+        ws.on_upgrade(move |socket| handle_user_socket(socket, app, forwarded, opts));
+    // echoed for load balancers that learn the sticky sessions from the responses
+    if let Some(affinity) = affinity.and_then(|affinity| HeaderValue::from_str(&affinity).ok()) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(AFFINITY_HEADER), affinity);
+    }
+    response
+}
 
-            Result::<_, Infallible>::Ok(response)
-        });
-
-    let mapping_test = warp::path!("test" / "mapping" / u32)
-        .and(app.clone())
-        .and_then(|storage_id: u32, app: Arc<App>| async move {
-            let access = app
-                .storage_mapping
-                .get_users_for_storage_path(storage_id, "")
-                .await
-                .map(|access| {
-                    let count = access.count();
-                    log::debug!("storage mapping count for {} = {}", storage_id, count);
-                    count
-                })
-                .map_err(|err| {
-                    log::error!(
-                        "error while getting mapping count for {}: {:#}",
-                        storage_id,
-                        err
-                    );
+/// Endpoints used by `occ notify_push:setup` and the self test to verify the setup
+#[cfg(feature = "test-endpoints")]
+fn test_routes(app: Arc<App>) -> Router<Arc<App>> {
+    use axum::extract::{Path, Request};
+    use axum::http::HeaderMap;
+    use axum::middleware::{self, Next};
+    use axum::routing::post;
+    use std::net::IpAddr;
+
+    async fn cookie_test(State(app): State<Arc<App>>) -> String {
+        let cookie = app.test_cookie.get();
+        log::debug!("current test cookie is {}", cookie);
+        cookie.to_string()
+    }
+
+    async fn reverse_cookie_test(State(app): State<Arc<App>>) -> String {
+        match app.nc_client.get_test_cookie().await {
+            Ok(cookie) => {
+                log::debug!("got remote test cookie {}", cookie);
+                cookie.to_string()
+            }
+            Err(e) => {
+                log::warn!("Error while trying to get cookie from Nextcloud {:#}", e);
+                format!("{:#}", e)
+            }
+        }
+    }
+
+    async fn mapping_test(Path(storage_id): Path<u32>, State(app): State<Arc<App>>) -> String {
+        let access = app
+            .storage_mapping
+            .get_users_for_storage_path(storage_id, "")
+            .await
+            .map(|access| {
+                let count = access.count();
+                log::debug!("storage mapping count for {} = {}", storage_id, count);
+                count
+            })
+            .map_err(|err| {
+                log::error!(
+                    "error while getting mapping count for {}: {:#}",
+                    storage_id,
                     err
-                })
-                .unwrap_or(0);
-            Result::<_, Infallible>::Ok(access.to_string())
-        });
-
-    let remote_test = warp::path!("test" / "remote" / IpAddr)
-        .and(app.clone())
-        .and_then(|remote: IpAddr, app: Arc<App>| async move {
-            let result = app
-                .nc_client
-                .test_set_remote(remote)
-                .await
-                .map(|remote| remote.to_string())
-                .unwrap_or_else(|e| e.to_string());
-            log::debug!("got remote {} when trying to set remote {}", result, remote);
-            Result::<_, Infallible>::Ok(result)
-        });
-
-    let version = warp::path!("test" / "version")
-        .and(warp::post())
-        .and(app)
-        .and_then(|app: Arc<App>| async move {
-            Result::<_, Infallible>::Ok(match app.redis.connect().await {
-                Ok(mut client) => {
-                    client
-                        .set("notify_push_version", env!("NOTIFY_PUSH_VERSION"))
-                        .await
-                        .ok();
-                    "set"
-                }
-                Err(e) => {
-                    log::warn!("Failed to get redis connection for version set: {:#}", e);
-                    "error"
-                }
+                );
             })
-        });
+            .unwrap_or(0);
+        access.to_string()
+    }
 
-    let routes = socket
-        .or(cookie_test)
-        .or(reverse_cookie_test)
-        .or(mapping_test)
-        .or(remote_test)
-        .or(version);
+    async fn remote_test(Path(remote): Path<IpAddr>, State(app): State<Arc<App>>) -> String {
+        let result = app
+            .nc_client
+            .test_set_remote(remote)
+            .await
+            .map(|remote| remote.to_string())
+            .unwrap_or_else(|e| e.to_string());
+        log::debug!("got remote {} when trying to set remote {}", result, remote);
+        result
+    }
+
+    /// The client addresses the push server forwards to nextcloud when authenticating a websocket from this request
+    async fn forwarded_test(State(app): State<Arc<App>>, forwarded: Forwarded) -> String {
+        let forwarded = match app.forwarded_for_depth {
+            Some(depth) => forwarded.at_depth(depth),
+            None => forwarded,
+        };
+        forwarded
+            .hops
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    async fn version(State(app): State<Arc<App>>) -> &'static str {
+        match app.redis.connect().await {
+            Ok(mut client) => {
+                client
+                    .set("notify_push_version", env!("NOTIFY_PUSH_VERSION"))
+                    .await
+                    .ok();
+                app.self_test_done();
+                "set"
+            }
+            Err(e) => {
+                log::warn!("Failed to get redis connection for version set: {:#}", e);
+                "error"
+            }
+        }
+    }
 
-    let routes = routes.clone().or(warp::path!("push" / ..).and(routes));
+    async fn enabled(
+        State(app): State<Arc<App>>,
+        headers: HeaderMap,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let secret = headers
+            .get("x-notify-push-test-secret")
+            .and_then(|secret| secret.to_str().ok());
+        if app.test_endpoints_enabled(secret) {
+            next.run(request).await
+        } else {
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
 
-    serve_at(routes, bind, cancel, tls)
+    Router::new()
+        .route("/test/cookie", get(cookie_test))
+        .route("/test/reverse_cookie", get(reverse_cookie_test))
+        .route("/test/mapping/{storage_id}", get(mapping_test))
+        .route("/test/remote/{remote}", get(remote_test))
+        .route("/test/forwarded", get(forwarded_test))
+        .route("/test/version", post(version))
+        .route_layer(middleware::from_fn_with_state(app, enabled))
 }
 
-fn serve_at<F, C>(
-    filter: F,
+/// Builds without the test endpoints can't be verified by the setup, every request to them is rejected
+#[cfg(not(feature = "test-endpoints"))]
+fn test_routes(_app: Arc<App>) -> Router<Arc<App>> {
+    Router::new()
+}
+
+/// How the tcp listener of a server is set up
+#[derive(Debug, Default, Clone, Copy)]
+struct TcpOptions {
+    /// Set `SO_REUSEPORT`, so the other workers can bind to the same port
+    reuse_port: bool,
+    /// Use the experimental io_uring listener
+    io_uring: bool,
+}
+
+fn serve_at<C>(
+    router: Router,
     bind: Bind,
     cancel: C,
     tls: Option<&TlsConfig>,
-) -> Result<impl Future<Output = ()> + Send>
+    limits: &HttpLimits,
+    log_requests: bool,
+    tcp: TcpOptions,
+) -> Result<BoxFuture<'static, ()>>
 where
     C: Future + Send + Sync + 'static,
-    F: Filter + Clone + Send + Sync + 'static,
-    F::Extract: Reply,
 {
     let cancel = cancel.map(|_| ());
-    let server = warp::serve(filter);
-    match (bind, tls) {
-        (Bind::Tcp(addr), Some(tls)) => {
-            let (_, server) = server
-                .tls()
-                .cert_path(&tls.cert)
-                .key_path(&tls.key)
-                .bind_with_graceful_shutdown(addr, cancel);
-            Ok(Either::Left(Either::Left(server)))
-        }
-        (Bind::Tcp(addr), None) => {
-            let (_, server) = server.bind_with_graceful_shutdown(addr, cancel);
-            Ok(Either::Left(Either::Right(server)))
+    let limits = limits.clone();
+    match bind {
+        Bind::Tcp(addr) => {
+            #[cfg(feature = "rustls")]
+            let acceptor = tls
+                .map(|tls| {
+                    tls::server_config(tls).map(|config| TlsAcceptor::from(Arc::new(config)))
+                })
+                .transpose()?;
+            #[cfg(not(feature = "rustls"))]
+            if tls.is_some() {
+                return Err(crate::error::ConfigError::TlsDisabled.into());
+            }
+
+            #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+            if tcp.io_uring {
+                return Err(crate::error::ConfigError::IoUringDisabled.into());
+            }
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            if tcp.io_uring {
+                let listener = uring::UringListener::bind(addr)
+                    .map_err(|e| SocketError::Bind(e, addr.to_string()))?;
+                return Ok(serve_incoming(
+                    router,
+                    incoming(listener),
+                    cancel,
+                    limits,
+                    log_requests,
+                )
+                .boxed());
+            }
+
+            let listener = bind_tcp(addr, tcp.reuse_port)
+                .map_err(|e| SocketError::Bind(e, addr.to_string()))?;
+
+            #[cfg(feature = "rustls")]
+            if let Some(acceptor) = acceptor {
+                let incoming = tls::incoming(listener, acceptor);
+                return Ok(serve_incoming(router, incoming, cancel, limits, log_requests).boxed());
+            }
+            Ok(serve_incoming(router, incoming(listener), cancel, limits, log_requests).boxed())
         }
-        (Bind::Unix(socket_path, permissions), tls) => {
+        Bind::Unix(socket_path, permissions) => {
             if tls.is_some() {
                 log::warn!("Serving with TLS over a unix socket is not supported");
             }
@@ -396,36 +1163,79 @@ where
             fs::set_permissions(&socket_path, PermissionsExt::from_mode(permissions))
                 .map_err(SocketError::SocketPermissions)?;
 
-            let stream = UnixListenerStream::new(listener);
-            Ok(Either::Right(
-                server
-                    .serve_incoming_with_graceful_shutdown(stream, cancel)
+            Ok(
+                serve_incoming(router, incoming(listener), cancel, limits, log_requests)
                     .map(move |_| {
                         fs::remove_file(socket_path).ok();
-                    }),
-            ))
+                    })
+                    .boxed(),
+            )
         }
     }
 }
 
+/// Bind a tcp listener, with `SO_REUSEPORT` set if the port is shared with other workers
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if reuse_port {
+        socket.set_reuseport(true)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Whether the app version has the same major version as the push server
+fn same_major_version(app_version: &str) -> bool {
+    let major = |version: &str| version.split('.').next().unwrap_or_default().to_string();
+    major(app_version) == major(env!("NOTIFY_PUSH_VERSION"))
+}
+
+/// Compare the secrets without leaking the position of the first difference through the timing
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 pub async fn listen_loop(app: Arc<App>, cancel: oneshot::Receiver<()>) {
+    let storms = {
+        let app = app.clone();
+        async move { app.storm_loop().await }
+    };
     let loop_ = async move {
         loop {
             if let Err(e) = listen(app.clone()).await {
                 log::error!("Failed to setup redis subscription: {:#}", e);
             }
             log::warn!("Redis server disconnected, reconnecting in 1s");
+            app.redis_disconnected.store(true, Ordering::SeqCst);
             sleep(Duration::from_secs(1)).await;
         }
     };
     pin_mut!(loop_);
-    select(cancel, loop_).await;
+    pin_mut!(storms);
+    select(cancel, select(loop_, storms)).await;
 }
 
 pub async fn listen(app: Arc<App>) -> Result<()> {
-    let mut event_stream = event::subscribe(&app.redis).await?;
+    let (sink, mut event_stream) = event::subscribe(&app.redis).await?;
+    app.dynamic_channels.attach(sink).await?;
 
-    let handle = move |event: Event| {
+    if app.redis_disconnected.swap(false, Ordering::SeqCst) {
+        log::info!("Redis connection restored, asking clients to check for missed updates");
+        for msg in PushMessage::resync() {
+            app.connections.send_to_all(msg);
+        }
+    }
+
+    let handle = |event: Event| {
         // todo: any way to do this without cloning the arc every event (scoped?)
         let app = app.clone();
         async move {
@@ -441,10 +1251,23 @@ pub async fn listen(app: Arc<App>) -> Result<()> {
                     "Received {}",
                     event
                 );
-                tokio::spawn(handle(event));
+                spawn_supervised("event", handle(event));
+            }
+            Err(MessageDecodeError::UnsupportedEventType { channel, payload }) => {
+                match app.dynamic_channels.decode(&channel, &payload) {
+                    Some(Ok(event)) => {
+                        spawn_supervised("event", handle(event));
+                    }
+                    Some(Err(e)) => log::warn!("Invalid event on {}: {:#}", channel, e),
+                    None => {
+                        app.unknown_channels
+                            .handle(&channel, &payload, app.connections.monitor())
+                    }
+                }
             }
             Err(e) => log::warn!("{:#}", e),
         }
     }
+    app.dynamic_channels.detach().await;
     Ok(())
 }
@@ -5,18 +5,34 @@
  
 use clap::Parser;
 use flexi_logger::{detailed_format, AdaptiveFormat, Logger, LoggerHandle};
-use miette::{IntoDiagnostic, Result, WrapErr};
-use notify_push::config::{Config, Opt};
-use notify_push::error::ConfigError;
+use miette::{Diagnostic, IntoDiagnostic, Result, WrapErr};
+use notify_push::activity::activity_loop;
+use notify_push::config::{Command, Config, Opt};
+use notify_push::crash::install_panic_hook;
+use notify_push::error::{ConfigError, SelfTestError, UpdateError};
+use notify_push::failure_report::FailureReport;
+use notify_push::health::health_check_loop;
 use notify_push::message::DEBOUNCE_ENABLE;
-use notify_push::metrics::serve_metrics;
+use notify_push::metrics::{serve_metrics, Sbom, METRICS};
+use notify_push::setup::setup;
+use notify_push::supervisor::{spawn_supervised, SUPERVISOR};
+use notify_push::update::{update_check_loop, Updater, UPDATE_INSTALLED};
+use notify_push::workers::{parent_exited, run_workers, worker_bind, worker_index, REUSE_PORT};
 use notify_push::{listen_loop, serve, App, Error};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command as Process;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::oneshot;
 use tokio::task::spawn;
+use tokio::time::sleep;
+
+/// Upper limit for the delay between attempts while waiting for the backends
+const MAX_WAIT_DELAY: Duration = Duration::from_secs(30);
 
 fn main() -> Result<()> {
     miette::set_panic_hook();
@@ -24,15 +40,44 @@ fn main() -> Result<()> {
     let _ = dotenvy::dotenv();
 
     let opt: Opt = Opt::parse();
+    let failure_report = opt.failure_report.clone();
+    let result = start(opt);
+    if let (Err(e), Some(target)) = (&result, &failure_report) {
+        write_failure_report(target, e.as_ref());
+    }
+    result
+}
+
+fn start(opt: Opt) -> Result<()> {
+    if opt.version && opt.verbose {
+        print!("{}", Sbom::get());
+        return Ok(());
+    }
     if opt.version {
         println!("notify_push {}", env!("NOTIFY_PUSH_VERSION"));
         return Ok(());
     }
     let dump_config = opt.dump_config;
-    let config = Config::from_opt(opt)?;
+    let dump_json = opt.json;
+    let config = Config::from_opt(opt.clone())?;
 
     if dump_config {
-        println!("{:#?}", config);
+        let config = config.redacted();
+        if dump_json {
+            println!("{:#}", config.to_json());
+        } else {
+            println!("{:#?}", config);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Setup(setup_opt)) = opt.command.clone() {
+        let config_file = opt.resolved_config_file();
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(setup(config, config_file, setup_opt))?;
         return Ok(());
     }
 
@@ -50,16 +95,41 @@ fn main() -> Result<()> {
     .start()
     .into_diagnostic()
     .wrap_err("Failed to initialize log handler")?;
+    install_panic_hook(config.crash_dump_dir.clone());
 
-    tokio::runtime::Builder::new_multi_thread()
+    if config.workers > 1 && worker_index().is_none() {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(run_workers(config.workers))?;
+        return Ok(());
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(cpus) = &config.cpu_set {
+        cpus.pin(&mut runtime);
+    }
+    runtime
         .enable_all()
         .build()
         .unwrap()
-        .block_on(run(config, log_handle))?;
+        .block_on(run(config, opt, log_handle))?;
     Ok(())
 }
 
-async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
+/// Failing to write the report shouldn't hide the original error
+fn write_failure_report(target: &Path, error: &dyn Diagnostic) {
+    if let Err(e) = FailureReport::new(error).write_to(target) {
+        eprintln!(
+            "Failed to write failure report to {}: {}",
+            target.display(),
+            e
+        );
+    }
+}
+
+async fn run(config: Config, opt: Opt, log_handle: LoggerHandle) -> Result<()> {
     let (serve_cancel, serve_cancel_handle) = oneshot::channel();
     let (metrics_cancel, metrics_cancel_handle) = oneshot::channel();
     let (listen_cancel, listen_cancel_handle) = oneshot::channel();
@@ -74,14 +144,96 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         DEBOUNCE_ENABLE.store(false, Ordering::Relaxed);
     }
 
+    // the workers share the listening port, the metrics are served separately by every worker
+    let worker = worker_index();
+    #[cfg(feature = "otel")]
+    let otel = config
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| notify_push::otel::OtelExporter::start(endpoint, worker))
+        .transpose()?;
+    if worker.is_some() {
+        REUSE_PORT.store(true, Ordering::Relaxed);
+    }
+
     let bind = config.bind.clone();
     let tls = config.tls.clone();
-    let metrics_bind = config.metrics_bind.clone();
+    let metrics_tls = config.metrics_tls.clone();
+    let http_limits = config.http_limits.clone();
+    let log_requests = config.log_requests;
+    let metrics_labels = config.metrics_labels.clone();
+    let metrics_bind = match worker {
+        Some(index) => config
+            .metrics_bind
+            .clone()
+            .map(|bind| worker_bind(bind, index)),
+        None => config.metrics_bind.clone(),
+    };
     let max_debounce_time = config.max_debounce_time;
     let max_connection_time = config.max_connection_time;
-    let app = Arc::new(App::new(config, log_handle).await?);
-    if let Err(e) = app.self_test().await {
-        log::error!("Self test failed: {:#}", e);
+    let resume_sessions = config.resume_sessions;
+    let persist_metrics = match worker {
+        Some(index) => config
+            .persist_metrics
+            .clone()
+            .map(|store| store.for_worker(index)),
+        None => config.persist_metrics.clone(),
+    };
+    let health_check_interval = config.health_check_interval;
+    let wait_for_backends = config.wait_for_backends;
+    let pre_stop_delay = config.pre_stop_delay;
+    let warm_up_storages = config.warm_up_storages;
+    let max_task_panics = config.max_task_panics;
+    let verify_proxy = config.verify_proxy;
+    // only the first worker checks for updates, replacing the binary is left to the service manager
+    let update_check_interval = match worker {
+        Some(index) if index > 0 => Duration::ZERO,
+        _ => config.update_check_interval,
+    };
+    let self_update = config.self_update && worker.is_none();
+    SUPERVISOR.set_max_panics(max_task_panics);
+    let app = Arc::new(
+        start_app(
+            config,
+            log_handle,
+            wait_for_backends,
+            opt.failure_report.as_deref(),
+        )
+        .await?,
+    );
+    app.set_config_source(opt);
+
+    if resume_sessions {
+        match app.resume_sessions().await {
+            Ok(count) => log::info!("Resuming sessions for {} users", count),
+            Err(e) => log::warn!("Failed to load session snapshot: {:#}", e),
+        }
+    }
+
+    if let Some(store) = &persist_metrics {
+        match app.load_metrics(store).await {
+            Ok(true) => log::info!("Continuing the metrics stored in {}", store),
+            Ok(false) => log::debug!("No metrics stored in {}", store),
+            Err(e) => log::warn!("Failed to load the persisted metrics: {:#}", e),
+        }
+    }
+
+    if let Err(e) = app.load_remote_config().await {
+        log::warn!(
+            "Failed to load settings from the app, using the local config: {:#}",
+            e
+        );
+    }
+
+    if warm_up_storages > 0 {
+        // load the mappings in the background, so we don't delay accepting connections
+        let app = app.clone();
+        spawn_supervised("warm up", async move {
+            match app.warm_up_storage_cache().await {
+                Ok(count) => log::info!("Warmed up storage mapping cache with {} storages", count),
+                Err(e) => log::warn!("Failed to warm up storage mapping cache: {:#}", e),
+            }
+        });
     }
 
     log::trace!("Listening on {}", bind);
@@ -90,6 +242,7 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         bind,
         serve_cancel_handle,
         tls.as_ref(),
+        &http_limits,
         max_debounce_time,
         max_connection_time,
     )?);
@@ -99,28 +252,115 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
         spawn(serve_metrics(
             metrics_bind,
             metrics_cancel_handle,
-            tls.as_ref(),
+            metrics_tls.as_ref(),
+            &http_limits,
+            log_requests,
+            metrics_labels,
         )?);
     }
 
     // tell SystemD that sockets have been bound to their addresses
     #[cfg(feature = "systemd")]
-    sd_notify::notify(true, &[sd_notify::NotifyState::Ready]).map_err(Error::SystemD)?;
+    if worker.is_none() {
+        sd_notify::notify(true, &[sd_notify::NotifyState::Ready]).map_err(Error::SystemD)?;
+    }
+
+    spawn_supervised("listen", listen_loop(app.clone(), listen_cancel_handle));
+
+    if verify_proxy {
+        // runs after the server is started, the check goes through our own public url
+        let app = app.clone();
+        spawn_supervised("proxy check", async move {
+            match app.verify_proxy().await {
+                Ok(check) => check.log(),
+                Err(e) => log::warn!("Failed to verify the trusted proxy setup: {:#}", e),
+            }
+        });
+    }
+
+    let mut quit = signal(SignalKind::quit()).map_err(Error::SignalHook)?;
+    let diagnostics_app = app.clone();
+    spawn(async move {
+        while quit.recv().await.is_some() {
+            log::warn!("{}", diagnostics_app.diagnostics());
+        }
+    });
+
+    spawn_supervised("activity", activity_loop(app.clone()));
 
-    spawn(listen_loop(app, listen_cancel_handle));
+    if !health_check_interval.is_zero() {
+        spawn_supervised(
+            "health check",
+            health_check_loop(app.clone(), health_check_interval),
+        );
+    }
+
+    // resolved before starting the check, the path of the running binary changes once it's replaced
+    let binary = std::env::current_exe()
+        .unwrap_or_else(|_| PathBuf::from(std::env::args().next().unwrap_or_default()));
+    if !update_check_interval.is_zero() {
+        match Updater::new(binary.clone()) {
+            Ok(updater) => {
+                if self_update {
+                    log::warn!(
+                        "Self update only checks new releases against their published checksum, not a signature"
+                    );
+                }
+                spawn_supervised(
+                    "update check",
+                    update_check_loop(updater, update_check_interval, self_update),
+                );
+            }
+            Err(e) => log::warn!("Failed to setup the update check: {:#}", e),
+        }
+    }
 
     // wait for either a sigint or sigterm
     let mut term = signal(SignalKind::terminate()).map_err(Error::SignalHook)?;
     let mut int = signal(SignalKind::interrupt()).map_err(Error::SignalHook)?;
 
-    select! {
-        _ = term.recv() => (),
-        _ = int.recv() => (),
+    let shutdown = select! {
+        _ = term.recv() => Shutdown::Signal,
+        _ = int.recv() => Shutdown::Signal,
+        _ = parent_exited() => Shutdown::Signal,
+        _ = SUPERVISOR.panic_limit_reached() => Shutdown::TaskPanics,
+        _ = UPDATE_INSTALLED.notified() => Shutdown::Update,
     };
 
     // then send cancel events to all of our spawned tasks
 
-    log::info!("shutdown signal received, shutting down");
+    if shutdown == Shutdown::Signal {
+        METRICS.set_stopping();
+        if pre_stop_delay.is_zero() {
+            log::info!("shutdown signal received, shutting down");
+        } else {
+            log::info!(
+                "shutdown signal received, shutting down in {}s",
+                pre_stop_delay.as_secs()
+            );
+            // a second signal skips the remaining delay
+            select! {
+                _ = sleep(pre_stop_delay) => {},
+                _ = term.recv() => {},
+                _ = int.recv() => {},
+            }
+            log::info!("shutting down");
+        }
+    }
+
+    if resume_sessions {
+        match app.save_sessions().await {
+            Ok(count) => log::info!("Saved sessions for {} users", count),
+            Err(e) => log::warn!("Failed to save session snapshot: {:#}", e),
+        }
+    }
+
+    if warm_up_storages > 0 {
+        match app.save_recent_storages(warm_up_storages).await {
+            Ok(count) => log::info!("Saved {} recently used storages", count),
+            Err(e) => log::warn!("Failed to save recently used storages: {:#}", e),
+        }
+    }
 
     serve_cancel.send(()).ok();
     metrics_cancel.send(()).ok();
@@ -129,7 +369,92 @@ async fn run(config: Config, log_handle: LoggerHandle) -> Result<()> {
     server
         .await
         .into_diagnostic()
-        .wrap_err("Error while running warp server")?;
+        .wrap_err("Error while running push server")?;
 
-    Ok(())
+    // saved once the connections are closed, so the messages sent while closing are included
+    if let Some(store) = &persist_metrics {
+        match app.save_metrics(store).await {
+            Ok(()) => log::info!("Saved the metrics to {}", store),
+            Err(e) => log::warn!("Failed to save the metrics: {:#}", e),
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    if let Some(otel) = otel {
+        otel.shutdown().await;
+    }
+
+    match shutdown {
+        Shutdown::Signal => Ok(()),
+        // exit with an error so the service manager restarts us
+        Shutdown::TaskPanics => Err(Error::TaskPanics(max_task_panics).into()),
+        // replace the process with the new binary, only returns if starting it failed
+        Shutdown::Update => {
+            let e = Process::new(&binary)
+                .args(std::env::args_os().skip(1))
+                .exec();
+            Err(Error::from(UpdateError::Restart(e)).into())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Shutdown {
+    Signal,
+    TaskPanics,
+    Update,
+}
+
+/// Create the app and run the self test
+///
+/// If `wait` is set, failures are retried with an increasing delay until the time is up,
+/// so the push server can be started before the database, redis or nextcloud are ready.
+/// A self test failure that isn't fatal is still written to the `failure_report`, if set.
+async fn start_app(
+    config: Config,
+    log_handle: LoggerHandle,
+    wait: Duration,
+    failure_report: Option<&Path>,
+) -> Result<App> {
+    let deadline = Instant::now() + wait;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let error = match App::new(config.clone(), log_handle.clone()).await {
+            Ok(app) => match app.self_test().await {
+                Ok(()) => {
+                    if let Err(e) = app.register_public_url().await {
+                        log::warn!("Failed to register the public url with the app: {:#}", e);
+                    }
+                    return Ok(app);
+                }
+                Err(e @ SelfTestError::VersionMismatch { .. }) => return Err(e.into()),
+                Err(e) if wait.is_zero() => {
+                    log::error!("Self test failed: {:#}", e);
+                    if let Some(target) = failure_report {
+                        write_failure_report(target, &e);
+                    }
+                    return Ok(app);
+                }
+                Err(e) => Error::from(e),
+            },
+            Err(e) if wait.is_zero() => return Err(e.into()),
+            Err(e) => e,
+        };
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(miette::Report::new(error).wrap_err(format!(
+                "Backends still not available after waiting {}s",
+                wait.as_secs()
+            )));
+        }
+        let retry_in = delay.min(deadline - now);
+        log::warn!(
+            "Backends not available yet, retrying in {:.1}s: {:#}",
+            retry_in.as_secs_f32(),
+            error
+        );
+        sleep(retry_in).await;
+        delay = (delay * 2).min(MAX_WAIT_DELAY);
+    }
 }
@@ -4,30 +4,128 @@
  */
  
 use crate::connection::ConnectionOptions;
-use parse_display::Display;
+use ahash::RandomState;
+use parse_display::{Display, FromStr};
+use serde::{Serialize, Serializer};
 use serde_json::Value;
 use smallvec::{smallvec, SmallVec};
 use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Write;
+use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Instant;
 use tokio::time::Duration;
-use warp::ws::Message;
+use tokio_tungstenite::tungstenite::Message;
+
+/// How queued custom messages with the same name are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+#[display(style = "snake_case")]
+pub enum MergePolicy {
+    /// Only the last message is sent
+    Last,
+    /// The bodies of all messages are sent as a json array
+    Append,
+}
+
+/// Custom messages with this name are debounced instead of sent right away
+#[derive(Debug, Clone, PartialEq, Eq, Display, FromStr)]
+#[display("{name}={policy}")]
+pub struct CustomDebounce {
+    pub name: String,
+    pub policy: MergePolicy,
+}
+
+/// Number of ids above which a set is kept to check for duplicate ids
+const INDEX_THRESHOLD: usize = 32;
+
+/// Ids of changed files in the order they were added, without duplicates
+///
+/// Small lists are searched linearly, larger lists keep a set of the ids next to the list,
+/// so merging the messages for a large directory doesn't take quadratic time.
+#[derive(Debug, Clone, Default)]
+pub struct FileIds {
+    ids: SmallVec<[u64; 4]>,
+    index: Option<HashSet<u64, RandomState>>,
+}
+
+impl FileIds {
+    pub fn insert(&mut self, id: u64) {
+        if self.index.is_none() && self.ids.len() > INDEX_THRESHOLD {
+            self.index = Some(self.ids.iter().copied().collect());
+        }
+        match &mut self.index {
+            Some(index) => {
+                if index.insert(id) {
+                    self.ids.push(id);
+                }
+            }
+            None => {
+                if !self.ids.contains(&id) {
+                    self.ids.push(id);
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for FileIds {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids == other.ids
+    }
+}
+
+impl Deref for FileIds {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        &self.ids
+    }
+}
+
+impl Serialize for FileIds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.ids.serialize(serializer)
+    }
+}
+
+impl From<SmallVec<[u64; 4]>> for FileIds {
+    fn from(ids: SmallVec<[u64; 4]>) -> Self {
+        FileIds { ids, index: None }
+    }
+}
+
+impl From<Vec<u64>> for FileIds {
+    fn from(ids: Vec<u64>) -> Self {
+        SmallVec::from(ids).into()
+    }
+}
+
+impl FromIterator<u64> for FileIds {
+    fn from_iter<T: IntoIterator<Item = u64>>(iter: T) -> Self {
+        SmallVec::from_iter(iter).into()
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum UpdatedFiles {
     Unknown,
-    Known(SmallVec<[u64; 4]>),
+    Known(FileIds),
 }
 
+/// Default for the maximum number of file ids in a single message
+pub const DEFAULT_MAX_FILE_IDS: usize = 1000;
+
 impl UpdatedFiles {
-    pub fn extend(&mut self, more: &UpdatedFiles) {
-        match (self, more) {
+    /// Add the ids from `more`, once there are more than `max_ids` ids we only tell the client that files changed
+    pub fn extend(&mut self, more: &UpdatedFiles, max_ids: usize) {
+        match (&mut *self, more) {
             (UpdatedFiles::Known(items), UpdatedFiles::Known(b)) => {
-                for id in b {
-                    if !items.contains(id) {
-                        items.push(*id);
-                    }
+                for id in b.iter() {
+                    items.insert(*id);
+                }
+                if items.len() > max_ids {
+                    *self = UpdatedFiles::Unknown;
                 }
             }
             (self_, _) => *self_ = UpdatedFiles::Unknown,
@@ -37,7 +135,7 @@ impl UpdatedFiles {
 
 impl From<u64> for UpdatedFiles {
     fn from(id: u64) -> Self {
-        UpdatedFiles::Known(smallvec![id])
+        UpdatedFiles::Known(FileIds::from(smallvec![id]))
     }
 }
 
@@ -54,9 +152,37 @@ pub enum PushMessage {
 }
 
 impl PushMessage {
-    pub fn merge(&mut self, other: &PushMessage) {
+    /// Messages telling the client to check for every type of update, for when events might have been lost
+    pub fn resync() -> [PushMessage; 3] {
+        [
+            PushMessage::File(UpdatedFiles::Unknown),
+            PushMessage::Activity,
+            PushMessage::Notification,
+        ]
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            PushMessage::File(_) => MessageType::File,
+            PushMessage::Activity => MessageType::Activity,
+            PushMessage::Notification => MessageType::Notification,
+            PushMessage::Custom(..) => MessageType::Custom,
+        }
+    }
+
+    pub fn merge(&mut self, other: &PushMessage, max_file_ids: usize) {
         if let (PushMessage::File(a), PushMessage::File(b)) = (self, other) {
-            a.extend(b)
+            a.extend(b, max_file_ids)
+        }
+    }
+
+    /// Combine a custom message with a newer message of the same name
+    fn merge_custom(&mut self, other: PushMessage, policy: MergePolicy) {
+        if let (PushMessage::Custom(_, body), PushMessage::Custom(_, new)) = (self, other) {
+            match (policy, body.as_mut()) {
+                (MergePolicy::Append, Value::Array(bodies)) => bodies.push(*new),
+                _ => *body = new,
+            }
         }
     }
 
@@ -68,13 +194,134 @@ impl PushMessage {
             PushMessage::File(_) => Duration::from_secs(time as u64),
             PushMessage::Activity => Duration::from_secs(time as u64),
             PushMessage::Notification => Duration::from_secs(1),
-            PushMessage::Custom(..) => Duration::from_millis(1), // no debouncing for custom messages
+            // only custom messages with a configured merge policy are queued
+            PushMessage::Custom(..) => Duration::from_secs(1),
+        }
+    }
+}
+
+/// Framing of the messages send to the client, negotiated with the `Sec-WebSocket-Protocol` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Subprotocol {
+    /// Plain text messages, used if the client doesn't request a subprotocol
+    #[default]
+    Text,
+    /// Json objects with a `type` field, the ids of changed files are always included
+    Json,
+    /// The same objects as the json protocol, encoded as cbor in binary frames
+    Cbor,
+}
+
+impl Subprotocol {
+    pub const ALL: [Subprotocol; 3] = [Subprotocol::Text, Subprotocol::Json, Subprotocol::Cbor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subprotocol::Text => "notify_push.text",
+            Subprotocol::Json => "notify_push.json",
+            Subprotocol::Cbor => "notify_push.cbor",
+        }
+    }
+
+    /// Encode a message for the json or cbor protocol
+    fn encode(&self, message: &StructuredMessage) -> Message {
+        match self {
+            Subprotocol::Cbor => {
+                let mut data = Vec::new();
+                // writing into a vec can't fail and all messages can be represented as cbor
+                ciborium::into_writer(message, &mut data).ok();
+                Message::binary(data)
+            }
+            _ => Message::text(serde_json::to_string(message).unwrap_or_default()),
+        }
+    }
+
+    /// The first of the subprotocols requested by the client that we support
+    pub fn negotiate<'a>(requested: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        requested.into_iter().find_map(|name| {
+            Subprotocol::ALL
+                .into_iter()
+                .find(|protocol| protocol.name() == name)
+        })
+    }
+}
+
+/// Messages in the json and cbor protocols
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StructuredMessage<'a> {
+    Authenticated,
+    TransferToken { token: &'a str },
+    Affinity { key: &'a str },
+    Error { message: &'a str },
+    NotifyFile,
+    NotifyFileId { file_ids: &'a [u64] },
+    NotifyActivity,
+    NotifyNotification,
+    Custom { message: &'a str, body: &'a Value },
+}
+
+/// Frames send to the client other than push messages
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Authenticated,
+    TransferToken(String),
+    /// The routing key of the user, see [`crate::affinity`]
+    Affinity(String),
+    /// An error, prefixed with `err: ` in the text protocol
+    Error(String),
+    /// An error that is send without prefix in the text protocol, for compatibility with existing clients
+    UnprefixedError(String),
+}
+
+impl Reply {
+    pub fn into_message(self, protocol: Subprotocol) -> Message {
+        match protocol {
+            Subprotocol::Text => Message::text(match self {
+                Reply::Authenticated => String::from("authenticated"),
+                Reply::TransferToken(token) => format!("transfer_token {}", token),
+                Reply::Affinity(key) => format!("affinity {}", key),
+                Reply::Error(error) => format!("err: {}", error),
+                Reply::UnprefixedError(error) => error,
+            }),
+            protocol => protocol.encode(&match &self {
+                Reply::Authenticated => StructuredMessage::Authenticated,
+                Reply::TransferToken(token) => StructuredMessage::TransferToken { token },
+                Reply::Affinity(key) => StructuredMessage::Affinity { key },
+                Reply::Error(message) | Reply::UnprefixedError(message) => {
+                    StructuredMessage::Error { message }
+                }
+            }),
         }
     }
 }
 
 impl PushMessage {
     pub fn into_message(self, opts: &ConnectionOptions) -> Message {
+        match opts.subprotocol {
+            Subprotocol::Text => self.into_text_message(opts),
+            protocol => protocol.encode(&self.structured()),
+        }
+    }
+
+    /// The message as json object, like it's sent with the json protocol
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self.structured()).unwrap_or_default()
+    }
+
+    fn structured(&self) -> StructuredMessage<'_> {
+        match self {
+            PushMessage::File(UpdatedFiles::Known(ids)) => {
+                StructuredMessage::NotifyFileId { file_ids: ids }
+            }
+            PushMessage::File(UpdatedFiles::Unknown) => StructuredMessage::NotifyFile,
+            PushMessage::Activity => StructuredMessage::NotifyActivity,
+            PushMessage::Notification => StructuredMessage::NotifyNotification,
+            PushMessage::Custom(message, body) => StructuredMessage::Custom { message, body },
+        }
+    }
+
+    fn into_text_message(self, opts: &ConnectionOptions) -> Message {
         match self {
             PushMessage::File(ids) => match ids {
                 UpdatedFiles::Known(ids) if opts.listen_file_id.load(Ordering::Relaxed) => {
@@ -102,6 +349,103 @@ impl PushMessage {
 
 pub static DEBOUNCE_ENABLE: AtomicBool = AtomicBool::new(true);
 
+/// Default time to wait for more messages to merge with before sending
+pub const DEFAULT_MERGE_TIME: Duration = Duration::from_millis(100);
+
+/// Type of message a merge time applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr, Serialize)]
+#[display(style = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    File,
+    Activity,
+    Notification,
+    Custom,
+}
+
+/// A merge time in milliseconds, either for all messages or for a single type of message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+pub enum MergeTimeSetting {
+    #[display("{0}")]
+    All(u64),
+    #[display("{0}={1}")]
+    Type(MessageType, u64),
+}
+
+/// Messages received shortly after each other are merged before sending,
+/// a message is held back until no new message of the same type was received for this long
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MergeTime {
+    #[serde(serialize_with = "serialize_millis")]
+    pub file: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub activity: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub notification: Duration,
+    #[serde(serialize_with = "serialize_millis")]
+    pub custom: Duration,
+}
+
+pub(crate) fn serialize_millis<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+impl Default for MergeTime {
+    fn default() -> Self {
+        MergeTime {
+            file: DEFAULT_MERGE_TIME,
+            activity: DEFAULT_MERGE_TIME,
+            notification: DEFAULT_MERGE_TIME,
+            custom: DEFAULT_MERGE_TIME,
+        }
+    }
+}
+
+impl MergeTime {
+    /// Apply the settings in order on top of the default merge time
+    pub fn from_settings(settings: &[MergeTimeSetting]) -> Self {
+        let mut merge_time = MergeTime::default();
+        for setting in settings {
+            match *setting {
+                MergeTimeSetting::All(ms) => {
+                    let time = Duration::from_millis(ms);
+                    merge_time = MergeTime {
+                        file: time,
+                        activity: time,
+                        notification: time,
+                        custom: time,
+                    };
+                }
+                MergeTimeSetting::Type(ty, ms) => {
+                    *merge_time.get_mut(ty) = Duration::from_millis(ms);
+                }
+            }
+        }
+        merge_time
+    }
+
+    fn get_mut(&mut self, ty: MessageType) -> &mut Duration {
+        match ty {
+            MessageType::File => &mut self.file,
+            MessageType::Activity => &mut self.activity,
+            MessageType::Notification => &mut self.notification,
+            MessageType::Custom => &mut self.custom,
+        }
+    }
+
+    pub fn for_message(&self, message: &PushMessage) -> Duration {
+        match message {
+            PushMessage::File(_) => self.file,
+            PushMessage::Activity => self.activity,
+            PushMessage::Notification => self.notification,
+            PushMessage::Custom(..) => self.custom,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct SendQueueItem {
     received: Instant,
@@ -119,9 +463,24 @@ impl Default for SendQueueItem {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct SendQueue {
     items: [SendQueueItem; 3],
+    /// Slots for the custom messages that are debounced, by message name
+    custom: BTreeMap<String, (MergePolicy, SendQueueItem)>,
+    max_file_ids: usize,
+    merge_time: MergeTime,
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        SendQueue {
+            items: Default::default(),
+            custom: BTreeMap::new(),
+            max_file_ids: DEFAULT_MAX_FILE_IDS,
+            merge_time: MergeTime::default(),
+        }
+    }
 }
 
 impl SendQueue {
@@ -129,6 +488,31 @@ impl SendQueue {
         SendQueue::default()
     }
 
+    /// Limit the number of file ids that are collected for a single message
+    pub fn with_max_file_ids(mut self, max_file_ids: usize) -> Self {
+        self.max_file_ids = max_file_ids;
+        self
+    }
+
+    pub fn with_merge_time(mut self, merge_time: MergeTime) -> Self {
+        self.merge_time = merge_time;
+        self
+    }
+
+    /// Debounce the custom messages with the configured names, other custom messages are still sent right away
+    pub fn with_custom_debounce(mut self, custom: &[CustomDebounce]) -> Self {
+        self.custom = custom
+            .iter()
+            .map(|debounce| {
+                (
+                    debounce.name.clone(),
+                    (debounce.policy, SendQueueItem::default()),
+                )
+            })
+            .collect();
+        self
+    }
+
     fn item_mut(&mut self, message: &PushMessage) -> Option<&mut SendQueueItem> {
         match message {
             PushMessage::File(_) => Some(&mut self.items[0]),
@@ -142,6 +526,25 @@ impl SendQueue {
         if !DEBOUNCE_ENABLE.load(Ordering::Relaxed) {
             return Some(message);
         }
+        if let PushMessage::Custom(name, body) = message {
+            let Some((policy, item)) = self.custom.get_mut(&name) else {
+                return Some(PushMessage::Custom(name, body));
+            };
+            match &mut item.message {
+                Some(queued) => queued.merge_custom(PushMessage::Custom(name, body), *policy),
+                opt => {
+                    let body = match policy {
+                        MergePolicy::Append => Box::new(Value::Array(vec![*body])),
+                        MergePolicy::Last => body,
+                    };
+                    *opt = Some(PushMessage::Custom(name, body));
+                }
+            }
+            item.received = time;
+            return None;
+        }
+
+        let max_file_ids = self.max_file_ids;
         let item = match self.item_mut(&message) {
             Some(item) => item,
             None => return Some(message),
@@ -149,7 +552,7 @@ impl SendQueue {
 
         match &mut item.message {
             Some(queued) => {
-                queued.merge(&message);
+                queued.merge(&message, max_file_ids);
             }
             opt => {
                 *opt = Some(message);
@@ -166,13 +569,13 @@ impl SendQueue {
         connection_count: usize,
         max_debounce_time: usize,
     ) -> impl Iterator<Item = PushMessage> + '_ {
-        self.items.iter_mut().filter_map(move |item| {
-            let debounce_time = item
-                .message
-                .as_ref()?
-                .debounce_time(connection_count, max_debounce_time);
-            if now.duration_since(item.sent) > debounce_time {
-                if now.duration_since(item.received) > Duration::from_millis(100) {
+        let merge_time = self.merge_time;
+        let custom = self.custom.values_mut().map(|(_, item)| item);
+        self.items.iter_mut().chain(custom).filter_map(move |item| {
+            let message = item.message.as_ref()?;
+            let debounce_time = message.debounce_time(connection_count, max_debounce_time);
+            if now.duration_since(item.sent) >= debounce_time {
+                if now.duration_since(item.received) >= merge_time.for_message(message) {
                     item.sent = now;
                     item.message.take()
                 } else {
@@ -183,6 +586,32 @@ impl SendQueue {
             }
         })
     }
+
+    /// The earliest time at which `drain` will return a queued message, if any messages are queued
+    /// Take all queued messages without waiting for their debounce time, for connections that are about to close
+    pub fn take_pending(&mut self) -> impl Iterator<Item = PushMessage> + '_ {
+        let custom = self.custom.values_mut().map(|(_, item)| item);
+        self.items
+            .iter_mut()
+            .chain(custom)
+            .filter_map(|item| item.message.take())
+    }
+
+    pub fn next_flush(&self, connection_count: usize, max_debounce_time: usize) -> Option<Instant> {
+        let custom = self.custom.values().map(|(_, item)| item);
+        self.items
+            .iter()
+            .chain(custom)
+            .filter_map(|item| {
+                let message = item.message.as_ref()?;
+                let debounce_time = message.debounce_time(connection_count, max_debounce_time);
+                Some(max(
+                    item.sent + debounce_time,
+                    item.received + self.merge_time.for_message(message),
+                ))
+            })
+            .min()
+    }
 }
 
 #[test]
@@ -316,3 +745,227 @@ fn test_send_queue_1() {
             .collect::<Vec<_>>()
     );
 }
+
+#[test]
+fn test_send_queue_next_flush() {
+    let base_time = Instant::now();
+    let mut queue = SendQueue::new();
+    assert_eq!(None, queue.next_flush(100, 15));
+
+    // the first message only waits to be merged
+    queue.push(PushMessage::Activity, base_time);
+    let next = queue.next_flush(100, 15).unwrap();
+    assert_eq!(base_time + DEFAULT_MERGE_TIME, next);
+    assert_eq!(1, queue.drain(next, 100, 15).count());
+    assert_eq!(None, queue.next_flush(100, 15));
+
+    // later messages wait for the debounce time
+    queue.push(PushMessage::Activity, base_time + Duration::from_secs(1));
+    queue.push(
+        PushMessage::Notification,
+        base_time + Duration::from_secs(1),
+    );
+    let next = queue.next_flush(100, 15).unwrap();
+    assert_eq!(base_time + Duration::from_millis(1100), next);
+    assert_eq!(
+        vec![PushMessage::Notification],
+        queue.drain(next, 100, 15).collect::<Vec<_>>()
+    );
+    let next = queue.next_flush(100, 15).unwrap();
+    assert_eq!(
+        base_time + DEFAULT_MERGE_TIME + Duration::from_secs(10),
+        next
+    );
+    assert_eq!(1, queue.drain(next, 100, 15).count());
+}
+
+#[test]
+fn test_merge_time_settings() {
+    let settings = ["50", "file=250"].map(|setting| setting.parse().unwrap());
+    let merge_time = MergeTime::from_settings(&settings);
+    assert_eq!(Duration::from_millis(250), merge_time.file);
+    assert_eq!(Duration::from_millis(50), merge_time.activity);
+    assert_eq!(Duration::from_millis(50), merge_time.custom);
+    assert_eq!("file=250", settings[1].to_string());
+    assert!("folder=250".parse::<MergeTimeSetting>().is_err());
+    assert_eq!(MergeTime::default(), MergeTime::from_settings(&[]));
+
+    let base_time = Instant::now();
+    let mut queue = SendQueue::new().with_merge_time(merge_time);
+    queue.push(PushMessage::Activity, base_time);
+    queue.push(PushMessage::File(UpdatedFiles::Unknown), base_time);
+    assert_eq!(
+        Some(base_time + Duration::from_millis(50)),
+        queue.next_flush(100, 15)
+    );
+    assert_eq!(
+        vec![PushMessage::Activity],
+        queue
+            .drain(base_time + Duration::from_millis(100), 100, 15)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![PushMessage::File(UpdatedFiles::Unknown)],
+        queue
+            .drain(base_time + Duration::from_millis(250), 100, 15)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_send_queue_take_pending() {
+    let base_time = Instant::now();
+    let mut queue = SendQueue::new();
+    queue.push(PushMessage::Activity, base_time);
+    queue.push(PushMessage::Notification, base_time);
+    assert_eq!(0, queue.drain(base_time, 100, 15).count());
+
+    assert_eq!(
+        vec![PushMessage::Activity, PushMessage::Notification],
+        queue.take_pending().collect::<Vec<_>>()
+    );
+    assert_eq!(None, queue.next_flush(100, 15));
+}
+
+#[test]
+fn test_file_ids_dedup() {
+    let mut ids = FileIds::from(vec![1, 2]);
+    for id in (0..100).chain(0..100) {
+        ids.insert(id);
+    }
+    assert!(ids.index.is_some());
+    assert_eq!(
+        [1, 2, 0].into_iter().chain(3..100).collect::<Vec<_>>(),
+        ids.to_vec()
+    );
+}
+
+#[test]
+fn test_send_queue_max_file_ids() {
+    let base_time = Instant::now();
+    let mut queue = SendQueue::new().with_max_file_ids(2);
+    for id in [1, 2, 2] {
+        queue.push(PushMessage::File(UpdatedFiles::from(id)), base_time);
+    }
+    assert_eq!(
+        vec![PushMessage::File(UpdatedFiles::Known(vec![1, 2].into()))],
+        queue
+            .drain(base_time + Duration::from_millis(200), 100, 15)
+            .collect::<Vec<_>>()
+    );
+
+    // too many files changed, the client has to check all files
+    let later = base_time + Duration::from_secs(30);
+    for id in [1, 2, 3] {
+        queue.push(PushMessage::File(UpdatedFiles::from(id)), later);
+    }
+    assert_eq!(
+        vec![PushMessage::File(UpdatedFiles::Unknown)],
+        queue
+            .drain(later + Duration::from_millis(200), 100, 15)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_send_queue_custom_debounce() {
+    let base_time = Instant::now();
+    let debounce: Vec<CustomDebounce> = vec![
+        "typing=last".parse().unwrap(),
+        "reaction=append".parse().unwrap(),
+    ];
+    let mut queue = SendQueue::new().with_custom_debounce(&debounce);
+    let custom = |name: &str, body: Value| PushMessage::Custom(name.into(), Box::new(body));
+    for i in 0..3 {
+        assert_eq!(
+            None,
+            queue.push(custom("typing", serde_json::json!(i)), base_time)
+        );
+        assert_eq!(
+            None,
+            queue.push(custom("reaction", serde_json::json!(i)), base_time)
+        );
+    }
+    // custom messages without a merge policy are passed through
+    assert_eq!(
+        Some(custom("other", serde_json::json!(1))),
+        queue.push(custom("other", serde_json::json!(1)), base_time)
+    );
+
+    assert_eq!(
+        Vec::<PushMessage>::new(),
+        queue
+            .drain(base_time + Duration::from_millis(20), 100, 15)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![
+            custom("reaction", serde_json::json!([0, 1, 2])),
+            custom("typing", serde_json::json!(2))
+        ],
+        queue
+            .drain(base_time + Duration::from_secs(2), 100, 15)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_parse_custom_debounce() {
+    assert_eq!(
+        CustomDebounce {
+            name: "typing".into(),
+            policy: MergePolicy::Last,
+        },
+        "typing=last".parse().unwrap()
+    );
+    assert_eq!(
+        "typing=append",
+        "typing=append"
+            .parse::<CustomDebounce>()
+            .unwrap()
+            .to_string()
+    );
+    assert!("typing=first".parse::<CustomDebounce>().is_err());
+}
+
+#[test]
+fn test_negotiate_subprotocol() {
+    assert_eq!(None, Subprotocol::negotiate([]));
+    assert_eq!(None, Subprotocol::negotiate(["chat"]));
+    assert_eq!(
+        Some(Subprotocol::Json),
+        Subprotocol::negotiate(["chat", "notify_push.json", "notify_push.text"])
+    );
+    assert_eq!(
+        Some(Subprotocol::Text),
+        Subprotocol::negotiate(["notify_push.text", "notify_push.json"])
+    );
+}
+
+#[test]
+fn test_cbor_message() {
+    let opts = ConnectionOptions {
+        subprotocol: Subprotocol::Cbor,
+        ..ConnectionOptions::default()
+    };
+    let message = PushMessage::File(UpdatedFiles::Known(vec![1, 2].into())).into_message(&opts);
+    let Message::Binary(data) = message else {
+        panic!("cbor messages should be binary frames");
+    };
+    let decoded: Value = ciborium::from_reader(data.as_ref()).unwrap();
+    assert_eq!(
+        serde_json::json!({"type": "notify_file_id", "file_ids": [1, 2]}),
+        decoded
+    );
+
+    let Message::Binary(data) =
+        Reply::Error("Invalid credentials".into()).into_message(Subprotocol::Cbor)
+    else {
+        panic!("cbor messages should be binary frames");
+    };
+    let decoded: Value = ciborium::from_reader(data.as_ref()).unwrap();
+    assert_eq!(
+        serde_json::json!({"type": "error", "message": "Invalid credentials"}),
+        decoded
+    );
+}
@@ -3,17 +3,116 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
  
-use crate::config::{Bind, TlsConfig};
+use crate::activity::ActivityStats;
+use crate::config::{Bind, HttpLimits, TlsConfig};
+use crate::error::WebSocketErrorKind;
+use crate::health::HealthStatus;
+use crate::http::{ClientLimits, LimitExceeded};
+use crate::metrics_format::{MetricsFormat, MetricsLabel};
+use crate::update::is_newer;
 use crate::{serve_at, Result};
-use serde::{Serialize, Serializer};
-use std::fmt::Write;
+use axum::extract::RawQuery;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::get;
+use axum::{Json, Router};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
 use std::future::Future;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot;
-use warp::Filter;
 
 pub static METRICS: Metrics = Metrics::new();
 
+/// Maximum number of distinct client names tracked, to keep the number of metric labels bounded
+const MAX_CLIENT_LABELS: usize = 16;
+
+/// Version and build options of the running binary
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn get() -> Self {
+        BuildInfo {
+            version: env!("NOTIFY_PUSH_VERSION"),
+            commit: env!("NOTIFY_PUSH_COMMIT"),
+            features: [
+                ("systemd", cfg!(feature = "systemd")),
+                ("rustls", cfg!(feature = "rustls")),
+                ("test-endpoints", cfg!(feature = "test-endpoints")),
+                ("ldap", cfg!(feature = "ldap")),
+                ("io-uring", cfg!(feature = "io-uring")),
+            ]
+            .into_iter()
+            .filter_map(|(feature, enabled)| enabled.then_some(feature))
+            .collect(),
+        }
+    }
+}
+
+/// Build details for auditing a running binary, the versions are the locked versions of the
+/// dependencies that handle untrusted input or cryptography
+#[derive(Debug, Serialize)]
+pub struct Sbom {
+    #[serde(flatten)]
+    pub build: BuildInfo,
+    pub target: &'static str,
+    pub license: &'static str,
+    pub dependencies: BTreeMap<&'static str, Vec<&'static str>>,
+}
+
+impl Sbom {
+    pub fn get() -> Self {
+        let mut dependencies = BTreeMap::<_, Vec<_>>::new();
+        for (name, version) in env!("NOTIFY_PUSH_DEPENDENCIES")
+            .split(',')
+            .filter_map(|dependency| dependency.split_once('@'))
+        {
+            dependencies.entry(name).or_default().push(version);
+        }
+        Sbom {
+            build: BuildInfo::get(),
+            target: env!("NOTIFY_PUSH_TARGET"),
+            license: "AGPL-3.0-or-later",
+            dependencies,
+        }
+    }
+}
+
+impl Display for Sbom {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "notify_push {} ({})",
+            self.build.version, self.build.commit
+        )?;
+        writeln!(f, "target: {}", self.target)?;
+        writeln!(f, "features: {}", self.build.features.join(", "))?;
+        writeln!(f, "license: {}", self.license)?;
+        writeln!(f, "dependencies:")?;
+        for (name, versions) in &self.dependencies {
+            writeln!(f, "  {} {}", name, versions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Response of the `/status` endpoint
+#[derive(Debug, Serialize)]
+struct Status {
+    #[serde(flatten)]
+    build: BuildInfo,
+    latest_version: Option<String>,
+    update_available: bool,
+}
+
 #[derive(Default)]
 pub struct Metrics {
     active_connection_count: AtomicUsize,
@@ -22,6 +121,42 @@ pub struct Metrics {
     mapping_query_count: AtomicUsize,
     events_received: AtomicUsize,
     messages_sent: AtomicUsize,
+    insecure_connection_count: AtomicUsize,
+    websocket_flush_count: AtomicUsize,
+    websocket_frame_count: AtomicUsize,
+    websocket_error_count: [AtomicUsize; WebSocketErrorKind::ALL.len()],
+    storm_count: AtomicUsize,
+    active_storm_count: AtomicUsize,
+    summarized_event_count: AtomicUsize,
+    unknown_event_count: AtomicUsize,
+    recently_active_user_count: AtomicUsize,
+    idle_connection_count: AtomicUsize,
+    client_connection_count: Lazy<DashMap<String, AtomicUsize>>,
+    rejected_request_count: Lazy<DashMap<(&'static str, LimitExceeded), AtomicUsize>>,
+    task_panic_count: Lazy<DashMap<&'static str, AtomicUsize>>,
+    shard_connection_count: Lazy<DashMap<usize, AtomicUsize>>,
+    /// Result of the last background health check
+    health: Mutex<Option<HealthStatus>>,
+    /// Set once a shutdown signal is received, while the connections are still being served
+    stopping: AtomicBool,
+    /// Version of the latest release, if update checks are enabled
+    latest_version: Mutex<Option<String>>,
+}
+
+/// The cumulative counters, which are kept across restarts if enabled, see [`crate::metrics_store`]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PersistedCounters {
+    pub total_connection_count: usize,
+    pub mapping_query_count: usize,
+    pub events_received: usize,
+    pub messages_sent: usize,
+    pub insecure_connection_count: usize,
+    pub websocket_flush_count: usize,
+    pub websocket_frame_count: usize,
+    pub storm_count: usize,
+    pub summarized_event_count: usize,
+    pub unknown_event_count: usize,
 }
 
 #[derive(Serialize)]
@@ -32,6 +167,20 @@ struct SerializeMetrics {
     mapping_query_count: usize,
     events_received: usize,
     messages_sent: usize,
+    insecure_connection_count: usize,
+    websocket_flush_count: usize,
+    websocket_frame_count: usize,
+    websocket_error_count: BTreeMap<&'static str, usize>,
+    storm_count: usize,
+    active_storm_count: usize,
+    summarized_event_count: usize,
+    unknown_event_count: usize,
+    recently_active_user_count: usize,
+    idle_connection_count: usize,
+    active_connection_count_by_client: BTreeMap<String, usize>,
+    rejected_request_count: BTreeMap<&'static str, BTreeMap<&'static str, usize>>,
+    task_panic_count: BTreeMap<&'static str, usize>,
+    active_connection_count_by_shard: BTreeMap<usize, usize>,
 }
 
 impl From<&Metrics> for SerializeMetrics {
@@ -43,6 +192,29 @@ impl From<&Metrics> for SerializeMetrics {
             mapping_query_count: metrics.mapping_query_count(),
             events_received: metrics.events_received(),
             messages_sent: metrics.messages_sent(),
+            insecure_connection_count: metrics.insecure_connection_count(),
+            websocket_flush_count: metrics.websocket_flush_count(),
+            websocket_frame_count: metrics.websocket_frame_count(),
+            websocket_error_count: metrics.websocket_error_counts().collect(),
+            storm_count: metrics.storm_count(),
+            active_storm_count: metrics.active_storm_count(),
+            summarized_event_count: metrics.summarized_event_count(),
+            unknown_event_count: metrics.unknown_event_count(),
+            recently_active_user_count: metrics.recently_active_user_count(),
+            idle_connection_count: metrics.idle_connection_count(),
+            active_connection_count_by_client: metrics.client_connection_counts(),
+            rejected_request_count: metrics.rejected_request_counts().into_iter().fold(
+                BTreeMap::new(),
+                |mut counts, ((route, limit), count)| {
+                    counts
+                        .entry(route)
+                        .or_insert_with(BTreeMap::new)
+                        .insert(limit.label(), count);
+                    counts
+                },
+            ),
+            task_panic_count: metrics.task_panic_counts(),
+            active_connection_count_by_shard: metrics.shard_connection_counts(),
         }
     }
 }
@@ -65,6 +237,71 @@ impl Metrics {
             mapping_query_count: AtomicUsize::new(0),
             events_received: AtomicUsize::new(0),
             messages_sent: AtomicUsize::new(0),
+            insecure_connection_count: AtomicUsize::new(0),
+            websocket_flush_count: AtomicUsize::new(0),
+            websocket_frame_count: AtomicUsize::new(0),
+            websocket_error_count: [
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+                AtomicUsize::new(0),
+            ],
+            storm_count: AtomicUsize::new(0),
+            active_storm_count: AtomicUsize::new(0),
+            summarized_event_count: AtomicUsize::new(0),
+            unknown_event_count: AtomicUsize::new(0),
+            recently_active_user_count: AtomicUsize::new(0),
+            idle_connection_count: AtomicUsize::new(0),
+            client_connection_count: Lazy::new(DashMap::default),
+            rejected_request_count: Lazy::new(DashMap::default),
+            task_panic_count: Lazy::new(DashMap::default),
+            shard_connection_count: Lazy::new(DashMap::default),
+            health: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+            latest_version: Mutex::new(None),
+        }
+    }
+
+    pub fn snapshot(&self) -> PersistedCounters {
+        PersistedCounters {
+            total_connection_count: self.total_connection_count(),
+            mapping_query_count: self.mapping_query_count(),
+            events_received: self.events_received(),
+            messages_sent: self.messages_sent(),
+            insecure_connection_count: self.insecure_connection_count(),
+            websocket_flush_count: self.websocket_flush_count(),
+            websocket_frame_count: self.websocket_frame_count(),
+            storm_count: self.storm_count(),
+            summarized_event_count: self.summarized_event_count(),
+            unknown_event_count: self.unknown_event_count(),
+        }
+    }
+
+    /// Add the counters of a previous instance
+    pub fn restore(&self, counters: &PersistedCounters) {
+        for (counter, value) in [
+            (
+                &self.total_connection_count,
+                counters.total_connection_count,
+            ),
+            (&self.mapping_query_count, counters.mapping_query_count),
+            (&self.events_received, counters.events_received),
+            (&self.messages_sent, counters.messages_sent),
+            (
+                &self.insecure_connection_count,
+                counters.insecure_connection_count,
+            ),
+            (&self.websocket_flush_count, counters.websocket_flush_count),
+            (&self.websocket_frame_count, counters.websocket_frame_count),
+            (&self.storm_count, counters.storm_count),
+            (
+                &self.summarized_event_count,
+                counters.summarized_event_count,
+            ),
+            (&self.unknown_event_count, counters.unknown_event_count),
+        ] {
+            counter.fetch_add(value, Ordering::Relaxed);
         }
     }
 
@@ -88,6 +325,89 @@ impl Metrics {
         self.messages_sent.load(Ordering::Relaxed)
     }
 
+    /// Websocket connections that were accepted without TLS
+    pub fn insecure_connection_count(&self) -> usize {
+        self.insecure_connection_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of times buffered websocket frames were flushed to the clients
+    pub fn websocket_flush_count(&self) -> usize {
+        self.websocket_flush_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of websocket frames written, including pings and replies
+    pub fn websocket_frame_count(&self) -> usize {
+        self.websocket_frame_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of websocket errors by category
+    pub fn websocket_error_counts(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        WebSocketErrorKind::ALL
+            .into_iter()
+            .zip(&self.websocket_error_count)
+            .map(|(kind, count)| (kind.label(), count.load(Ordering::Relaxed)))
+    }
+
+    /// Number of event storms that were summarized, see [`crate::storm`]
+    pub fn storm_count(&self) -> usize {
+        self.storm_count.load(Ordering::Relaxed)
+    }
+
+    pub fn active_storm_count(&self) -> usize {
+        self.active_storm_count.load(Ordering::Relaxed)
+    }
+
+    /// Number of updates that were summarized instead of sent, counted once their storm is over
+    pub fn summarized_event_count(&self) -> usize {
+        self.summarized_event_count.load(Ordering::Relaxed)
+    }
+
+    /// Events received on redis channels the push server doesn't handle, see [`crate::unknown_channel`]
+    pub fn unknown_event_count(&self) -> usize {
+        self.unknown_event_count.load(Ordering::Relaxed)
+    }
+
+    pub fn add_unknown_event(&self) {
+        self.unknown_event_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_storm(&self) {
+        self.storm_count.fetch_add(1, Ordering::Relaxed);
+        self.active_storm_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_storm(&self, summarized: u64) {
+        self.active_storm_count.fetch_sub(1, Ordering::Relaxed);
+        self.summarized_event_count
+            .fetch_add(summarized as usize, Ordering::Relaxed);
+    }
+
+    /// Connected users that received a message recently, see [`crate::activity`]
+    pub fn recently_active_user_count(&self) -> usize {
+        self.recently_active_user_count.load(Ordering::Relaxed)
+    }
+
+    /// Connections that haven't sent a message for a long time, see [`crate::activity`]
+    pub fn idle_connection_count(&self) -> usize {
+        self.idle_connection_count.load(Ordering::Relaxed)
+    }
+
+    pub fn set_activity(&self, stats: ActivityStats) {
+        self.recently_active_user_count
+            .store(stats.active_users, Ordering::Relaxed);
+        self.idle_connection_count
+            .store(stats.idle_connections, Ordering::Relaxed);
+    }
+
+    pub fn frames_per_flush(&self) -> f64 {
+        let flushes = self.websocket_flush_count();
+        if flushes == 0 {
+            0.0
+        } else {
+            self.websocket_frame_count() as f64 / flushes as f64
+        }
+    }
+
     pub fn add_connection(&self) {
         self.total_connection_count.fetch_add(1, Ordering::Relaxed);
         self.active_connection_count.fetch_add(1, Ordering::Relaxed);
@@ -97,6 +417,97 @@ impl Metrics {
         self.active_connection_count.fetch_sub(1, Ordering::Relaxed);
     }
 
+    /// Get the metric label for a client id like `desktop/3.14.0`
+    fn client_label(&self, client_id: &str) -> String {
+        let name = client_id
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if self.client_connection_count.contains_key(&name)
+            || self.client_connection_count.len() < MAX_CLIENT_LABELS
+        {
+            name
+        } else {
+            String::from("other")
+        }
+    }
+
+    pub fn add_client_connection(&self, client_id: &str) {
+        self.client_connection_count
+            .entry(self.client_label(client_id))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_client_connection(&self, client_id: &str) {
+        if let Some(count) = self
+            .client_connection_count
+            .get(&self.client_label(client_id))
+        {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn client_connection_counts(&self) -> BTreeMap<String, usize> {
+        self.client_connection_count
+            .iter()
+            .map(|item| (item.key().clone(), item.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn add_shard_connection(&self, shard: usize) {
+        self.shard_connection_count
+            .entry(shard)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_shard_connection(&self, shard: usize) {
+        if let Some(count) = self.shard_connection_count.get(&shard) {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Connections handled by each runtime shard, empty if the connections aren't sharded
+    pub fn shard_connection_counts(&self) -> BTreeMap<usize, usize> {
+        self.shard_connection_count
+            .iter()
+            .map(|item| (*item.key(), item.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Requests rejected by the per ip limits, by route and exceeded limit
+    pub fn rejected_request_counts(&self) -> BTreeMap<(&'static str, LimitExceeded), usize> {
+        self.rejected_request_count
+            .iter()
+            .map(|item| (*item.key(), item.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn add_rejected_request(&self, route: &'static str, limit: LimitExceeded) {
+        self.rejected_request_count
+            .entry((route, limit))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Panics in supervised tasks, by task name
+    pub fn task_panic_counts(&self) -> BTreeMap<&'static str, usize> {
+        self.task_panic_count
+            .iter()
+            .map(|item| (*item.key(), item.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn add_task_panic(&self, task: &'static str) {
+        self.task_panic_count
+            .entry(task)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn active_user_count(&self) -> usize {
         self.active_user_count.load( Ordering::Relaxed)
     }
@@ -120,47 +531,151 @@ impl Metrics {
     pub fn add_message(&self) {
         self.messages_sent.fetch_add(1, Ordering::Relaxed);
     }
+
+    pub fn add_insecure_connection(&self) {
+        self.insecure_connection_count
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_websocket_error(&self, kind: WebSocketErrorKind) {
+        self.websocket_error_count[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_flush(&self, frames: usize) {
+        self.websocket_flush_count.fetch_add(1, Ordering::Relaxed);
+        self.websocket_frame_count
+            .fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn health(&self) -> Option<HealthStatus> {
+        *self.health.lock().unwrap()
+    }
+
+    pub fn set_health(&self, status: HealthStatus) {
+        *self.health.lock().unwrap() = Some(status);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    pub fn set_stopping(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
+
+    pub fn latest_version(&self) -> Option<String> {
+        self.latest_version.lock().unwrap().clone()
+    }
+
+    pub fn set_latest_version(&self, version: String) {
+        *self.latest_version.lock().unwrap() = Some(version);
+    }
+
+    /// Whether the update check found a newer release than the running version
+    pub fn update_available(&self) -> bool {
+        self.latest_version()
+            .is_some_and(|latest| is_newer(&latest, env!("NOTIFY_PUSH_VERSION")))
+    }
 }
 
 pub fn serve_metrics(
     bind: Bind,
     cancel: oneshot::Receiver<()>,
     tls: Option<&TlsConfig>,
+    http_limits: &HttpLimits,
+    log_requests: bool,
+    labels: Vec<MetricsLabel>,
 ) -> Result<impl Future<Output = ()> + Send> {
-    let metrics = warp::path!("metrics").map(|| {
-        let mut response = String::with_capacity(128);
-        let _ = writeln!(
-            &mut response,
-            "active_connection_count {}",
-            METRICS.active_connection_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "active_user_count {}",
-            METRICS.active_user_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "total_connection_count {}",
-            METRICS.total_connection_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "mapping_query_count {}",
-            METRICS.mapping_query_count()
-        );
-        let _ = writeln!(
-            &mut response,
-            "event_count_total {}",
-            METRICS.events_received()
-        );
-        let _ = writeln!(
-            &mut response,
-            "message_count_total {}",
-            METRICS.messages_sent()
-        );
-        response
+    let labels = Arc::new(labels);
+    let metrics = get(|RawQuery(query): RawQuery, headers: HeaderMap| async move {
+        MetricsFormat::for_request(query.as_deref(), &headers).respond(&METRICS, &labels)
+    });
+    let sbom = get(|| async { Json(Sbom::get()) });
+    let status = get(|| async {
+        Json(Status {
+            build: BuildInfo::get(),
+            latest_version: METRICS.latest_version(),
+            update_available: METRICS.update_available(),
+        })
+    });
+    // unhealthy backends are reported with a 503 so the endpoint can be used by load balancers directly
+    let health = get(|| async {
+        match METRICS.health() {
+            Some(health) => (
+                if health.is_healthy() {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                },
+                Json(Some(health)),
+            ),
+            None => (StatusCode::OK, Json(None::<HealthStatus>)),
+        }
+    });
+
+    // unlike the health check this reports the shutdown, so new connections are sent elsewhere while the
+    // existing connections are still served, an unhealthy push server isn't ready either
+    let ready = get(|| async {
+        let healthy = match METRICS.health() {
+            Some(health) => health.is_healthy(),
+            None => true,
+        };
+        if METRICS.is_stopping() {
+            (StatusCode::SERVICE_UNAVAILABLE, "stopping")
+        } else if healthy {
+            (StatusCode::OK, "ready")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+        }
     });
 
-    serve_at(metrics, bind, cancel, tls)
+    let routes = Router::new()
+        .route("/metrics", metrics)
+        .route("/status", status)
+        .route("/sbom", sbom)
+        .route("/health", health)
+        .route("/ready", ready);
+
+    serve_at(
+        ClientLimits::new(http_limits).apply(routes, "metrics"),
+        bind,
+        cancel,
+        tls,
+        http_limits,
+        log_requests,
+        Default::default(),
+    )
+}
+
+#[test]
+fn test_sbom() {
+    let sbom = Sbom::get();
+    assert_eq!(env!("NOTIFY_PUSH_VERSION"), sbom.build.version);
+    assert!(!sbom.target.is_empty());
+    assert!(sbom.dependencies.contains_key("redis"));
+    assert_eq!(
+        cfg!(feature = "rustls"),
+        sbom.dependencies.contains_key("rustls")
+    );
+    assert!(sbom.to_string().contains("\n  redis "));
+}
+
+#[test]
+fn test_restore_counters() {
+    let metrics = Metrics::new();
+    metrics.add_connection();
+    metrics.add_message();
+    let counters: PersistedCounters =
+        serde_json::from_str(r#"{"total_connection_count": 10, "messages_sent": 5}"#).unwrap();
+    metrics.restore(&counters);
+    assert_eq!(
+        PersistedCounters {
+            total_connection_count: 11,
+            messages_sent: 6,
+            ..PersistedCounters::default()
+        },
+        metrics.snapshot()
+    );
+    // only the cumulative counters are restored
+    assert_eq!(1, metrics.active_connection_count());
 }
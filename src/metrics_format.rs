@@ -0,0 +1,614 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Formats of the `/metrics` endpoint, picked with the `Accept` header of the request.
+//!
+//! The metrics are collected once into metric families, which are then rendered as the plain `name value` lines the
+//! endpoint always served, as OpenMetrics for Prometheus, or as the json object the Nextcloud app reads from redis.
+
+use crate::error::MetricsLabelError;
+use crate::metrics::{BuildInfo, Metrics};
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use parse_display::{Display, FromStr};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Prefix of the metric names in the Prometheus and OpenMetrics formats
+const PREFIX: &str = "notify_push_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    fn name(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Vec<(&'static str, String)>,
+    pub value: String,
+}
+
+/// A metric with all of its samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFamily {
+    /// Name in the plain text format
+    pub name: &'static str,
+    /// Name in the Prometheus and OpenMetrics formats, without the `notify_push_` prefix and the `_total` suffix
+    pub base_name: &'static str,
+    pub kind: MetricKind,
+    pub help: &'static str,
+    pub samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    fn new(name: &'static str, kind: MetricKind, help: &'static str) -> Self {
+        let base_name = name.strip_prefix(PREFIX).unwrap_or(name);
+        MetricFamily {
+            name,
+            base_name: base_name.strip_suffix("_total").unwrap_or(base_name),
+            kind,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn single(
+        name: &'static str,
+        kind: MetricKind,
+        help: &'static str,
+        value: impl ToString,
+    ) -> Self {
+        MetricFamily::new(name, kind, help).with_sample(Vec::new(), value)
+    }
+
+    /// Use a different name in the Prometheus and OpenMetrics formats
+    fn renamed(mut self, base_name: &'static str) -> Self {
+        self.base_name = base_name;
+        self
+    }
+
+    fn with_sample(mut self, labels: Vec<(&'static str, String)>, value: impl ToString) -> Self {
+        self.samples.push(Sample {
+            labels,
+            value: value.to_string(),
+        });
+        self
+    }
+}
+
+/// Collect the current metrics
+pub fn metric_families(metrics: &Metrics) -> Vec<MetricFamily> {
+    use MetricKind::{Counter, Gauge};
+
+    let build_info = BuildInfo::get();
+    let mut families = vec![
+        MetricFamily::new(
+            "notify_push_build_info",
+            Gauge,
+            "Version, git commit and enabled features of the binary",
+        )
+        .with_sample(
+            vec![
+                ("version", build_info.version.into()),
+                ("commit", build_info.commit.into()),
+                ("features", build_info.features.join(",")),
+            ],
+            1,
+        ),
+        MetricFamily::single(
+            "active_connection_count",
+            Gauge,
+            "Number of connected clients",
+            metrics.active_connection_count(),
+        ),
+        MetricFamily::single(
+            "active_user_count",
+            Gauge,
+            "Number of users with at least one connected client",
+            metrics.active_user_count(),
+        ),
+        MetricFamily::single(
+            "total_connection_count",
+            Counter,
+            "Number of accepted connections",
+            metrics.total_connection_count(),
+        )
+        .renamed("connection_count"),
+        MetricFamily::single(
+            "mapping_query_count",
+            Counter,
+            "Number of storage mapping queries to the database",
+            metrics.mapping_query_count(),
+        ),
+        MetricFamily::single(
+            "event_count_total",
+            Counter,
+            "Number of events received from redis",
+            metrics.events_received(),
+        ),
+        MetricFamily::single(
+            "message_count_total",
+            Counter,
+            "Number of messages sent to clients",
+            metrics.messages_sent(),
+        ),
+        MetricFamily::single(
+            "insecure_connection_count_total",
+            Counter,
+            "Number of accepted plain text connections",
+            metrics.insecure_connection_count(),
+        ),
+        MetricFamily::single(
+            "recently_active_user_count",
+            Gauge,
+            "Number of connected users that received a message in the last 5 minutes",
+            metrics.recently_active_user_count(),
+        ),
+        MetricFamily::single(
+            "idle_connection_count",
+            Gauge,
+            "Number of connections without messages in the last hour",
+            metrics.idle_connection_count(),
+        ),
+        MetricFamily::single(
+            "websocket_flush_count_total",
+            Counter,
+            "Number of websocket flushes",
+            metrics.websocket_flush_count(),
+        ),
+        MetricFamily::single(
+            "websocket_frame_count_total",
+            Counter,
+            "Number of websocket frames sent",
+            metrics.websocket_frame_count(),
+        ),
+        MetricFamily::single(
+            "websocket_frames_per_flush",
+            Gauge,
+            "Average number of websocket frames per flush",
+            format!("{:.2}", metrics.frames_per_flush()),
+        ),
+        MetricFamily::single(
+            "storm_count_total",
+            Counter,
+            "Number of event storms",
+            metrics.storm_count(),
+        ),
+        MetricFamily::single(
+            "active_storm_count",
+            Gauge,
+            "Number of event storms that are being summarized",
+            metrics.active_storm_count(),
+        ),
+        MetricFamily::single(
+            "summarized_event_count_total",
+            Counter,
+            "Number of updates that were summarized during event storms",
+            metrics.summarized_event_count(),
+        ),
+        MetricFamily::single(
+            "unknown_event_count_total",
+            Counter,
+            "Number of events received on unsupported redis channels",
+            metrics.unknown_event_count(),
+        ),
+    ];
+
+    let mut errors = MetricFamily::new(
+        "websocket_error_count_total",
+        Counter,
+        "Number of websocket errors by kind",
+    );
+    for (kind, count) in metrics.websocket_error_counts() {
+        errors = errors.with_sample(vec![("kind", kind.into())], count);
+    }
+    families.push(errors);
+
+    let mut clients = MetricFamily::new(
+        "active_connection_count_by_client",
+        Gauge,
+        "Number of connected clients by client name",
+    );
+    for (client, count) in metrics.client_connection_counts() {
+        clients = clients.with_sample(vec![("client", client)], count);
+    }
+    families.push(clients);
+
+    let mut shards = MetricFamily::new(
+        "active_connection_count_by_shard",
+        Gauge,
+        "Number of connected clients by runtime shard",
+    );
+    for (shard, count) in metrics.shard_connection_counts() {
+        shards = shards.with_sample(vec![("shard", shard.to_string())], count);
+    }
+    families.push(shards);
+
+    let mut rejected = MetricFamily::new(
+        "rejected_request_count_total",
+        Counter,
+        "Number of requests rejected by the client limits",
+    );
+    for ((route, limit), count) in metrics.rejected_request_counts() {
+        rejected = rejected.with_sample(
+            vec![("route", route.into()), ("reason", limit.label().into())],
+            count,
+        );
+    }
+    families.push(rejected);
+
+    let mut panics = MetricFamily::new(
+        "task_panic_count_total",
+        Counter,
+        "Number of panics by background task",
+    );
+    for (task, count) in metrics.task_panic_counts() {
+        panics = panics.with_sample(vec![("task", task.into())], count);
+    }
+    families.push(panics);
+
+    if let Some(latest) = metrics.latest_version() {
+        families.push(
+            MetricFamily::new(
+                "notify_push_update_available",
+                Gauge,
+                "Whether a newer release is available",
+            )
+            .with_sample(vec![("latest", latest)], metrics.update_available() as u8),
+        );
+    }
+    if let Some(health) = metrics.health() {
+        let mut checks = MetricFamily::new(
+            "health_check",
+            Gauge,
+            "Result of the last health check by checked service",
+        );
+        for (check, ok) in [
+            ("database", health.database),
+            ("redis", health.redis),
+            ("nextcloud", health.nextcloud),
+        ] {
+            checks = checks.with_sample(vec![("check", check.into())], ok as u8);
+        }
+        families.push(checks);
+    }
+    families
+}
+
+/// Label added to every metric in the Prometheus and OpenMetrics formats, to tell instances apart
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+#[display("{name}={value}")]
+pub struct MetricsLabel {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for MetricsLabel {
+    type Err = MetricsLabelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| MetricsLabelError(s.into()))?;
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with("__");
+        if !valid_name {
+            return Err(MetricsLabelError(s.into()));
+        }
+        Ok(MetricsLabel {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+}
+
+/// Format of the metrics response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+#[display(style = "lowercase")]
+pub enum MetricsFormat {
+    /// Plain `name value` lines, kept for existing consumers
+    Text,
+    Prometheus,
+    OpenMetrics,
+    Json,
+}
+
+impl MetricsFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            MetricsFormat::Text => "text/plain; charset=utf-8",
+            MetricsFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            MetricsFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+            MetricsFormat::Json => "application/json",
+        }
+    }
+
+    fn for_media_type(media_type: &str, versioned: bool) -> Option<Self> {
+        match media_type {
+            // only scrapers ask for a specific version of the text format
+            "text/plain" if versioned => Some(MetricsFormat::Prometheus),
+            "text/plain" | "text/*" | "*/*" => Some(MetricsFormat::Text),
+            "application/openmetrics-text" => Some(MetricsFormat::OpenMetrics),
+            "application/json" => Some(MetricsFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// The supported format with the highest quality in the `Accept` header, the plain text format if there is none
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut best = None;
+        for range in accept.unwrap_or_default().split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut versioned = false;
+            for param in params {
+                if let Some(q) = param.strip_prefix("q=") {
+                    quality = q.parse::<f32>().unwrap_or(1.0);
+                } else if param.starts_with("version=") {
+                    versioned = true;
+                }
+            }
+            let Some(format) = MetricsFormat::for_media_type(&media_type, versioned) else {
+                continue;
+            };
+            // the first of the equally preferred formats wins
+            if quality > 0.0 && best.map_or(true, |(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map_or(MetricsFormat::Text, |(format, _)| format)
+    }
+
+    /// The format from the `format` query parameter if given, otherwise the format from the `Accept` header
+    pub fn for_request(query: Option<&str>, headers: &HeaderMap) -> Self {
+        let requested = query
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|param| param.strip_prefix("format="))
+            .and_then(|format| format.parse().ok());
+        requested.unwrap_or_else(|| {
+            let accept = headers.get(ACCEPT).and_then(|accept| accept.to_str().ok());
+            MetricsFormat::negotiate(accept)
+        })
+    }
+
+    pub fn render(&self, metrics: &Metrics, labels: &[MetricsLabel]) -> String {
+        match self {
+            MetricsFormat::Text => render_text(&metric_families(metrics)),
+            MetricsFormat::Prometheus => render_prometheus(&metric_families(metrics), labels),
+            MetricsFormat::OpenMetrics => render_open_metrics(&metric_families(metrics), labels),
+            MetricsFormat::Json => serde_json::to_string(metrics).unwrap_or_default(),
+        }
+    }
+
+    pub fn respond(&self, metrics: &Metrics, labels: &[MetricsLabel]) -> Response {
+        (
+            [(CONTENT_TYPE, self.content_type())],
+            self.render(metrics, labels),
+        )
+            .into_response()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', r"\\").replace('\n', r"\n")
+}
+
+fn write_sample<'a>(
+    out: &mut String,
+    name: &str,
+    labels: impl IntoIterator<Item = (&'a str, &'a str)>,
+    value: &str,
+) {
+    out.push_str(name);
+    let mut labels = labels.into_iter().peekable();
+    if labels.peek().is_some() {
+        let labels = labels
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+            .collect::<Vec<_>>();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn sample_labels<'a>(
+    sample: &'a Sample,
+    extra: &'a [MetricsLabel],
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    sample
+        .labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(
+            extra
+                .iter()
+                .map(|label| (label.name.as_str(), label.value.as_str())),
+        )
+}
+
+fn render_text(families: &[MetricFamily]) -> String {
+    let mut out = String::with_capacity(128);
+    for family in families {
+        for sample in &family.samples {
+            write_sample(
+                &mut out,
+                family.name,
+                sample_labels(sample, &[]),
+                &sample.value,
+            );
+        }
+    }
+    out
+}
+
+/// Render the metadata and samples of the families, `family_suffix` is added to the name of counter families and
+/// `sample_suffix` to the name of counter samples
+fn render_structured(
+    out: &mut String,
+    families: &[MetricFamily],
+    labels: &[MetricsLabel],
+    family_suffix: &str,
+    sample_suffix: &str,
+) {
+    for family in families {
+        let (family_name, sample_name) = match family.kind {
+            MetricKind::Counter => (
+                format!("{}{}{}", PREFIX, family.base_name, family_suffix),
+                format!("{}{}{}", PREFIX, family.base_name, sample_suffix),
+            ),
+            MetricKind::Gauge => (
+                format!("{}{}", PREFIX, family.base_name),
+                format!("{}{}", PREFIX, family.base_name),
+            ),
+        };
+        let _ = writeln!(out, "# HELP {} {}", family_name, escape_help(family.help));
+        let _ = writeln!(out, "# TYPE {} {}", family_name, family.kind.name());
+        for sample in &family.samples {
+            write_sample(
+                out,
+                &sample_name,
+                sample_labels(sample, labels),
+                &sample.value,
+            );
+        }
+    }
+}
+
+fn render_prometheus(families: &[MetricFamily], labels: &[MetricsLabel]) -> String {
+    let mut out = String::with_capacity(256);
+    render_structured(&mut out, families, labels, "_total", "_total");
+    out
+}
+
+fn render_open_metrics(families: &[MetricFamily], labels: &[MetricsLabel]) -> String {
+    let mut out = String::with_capacity(256);
+    // counter samples need the `_total` suffix, which isn't part of the name of the family
+    render_structured(&mut out, families, labels, "", "_total");
+    out.push_str("# EOF\n");
+    out
+}
+
+#[test]
+fn test_negotiate_metrics_format() {
+    assert_eq!(MetricsFormat::Text, MetricsFormat::negotiate(None));
+    assert_eq!(MetricsFormat::Text, MetricsFormat::negotiate(Some("*/*")));
+    assert_eq!(
+        MetricsFormat::Json,
+        MetricsFormat::negotiate(Some("application/json"))
+    );
+    // the accept header prometheus sends
+    assert_eq!(
+        MetricsFormat::OpenMetrics,
+        MetricsFormat::negotiate(Some(
+            "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+        ))
+    );
+    assert_eq!(
+        MetricsFormat::Prometheus,
+        MetricsFormat::negotiate(Some("text/plain; version=0.0.4"))
+    );
+    assert_eq!(
+        MetricsFormat::Json,
+        MetricsFormat::negotiate(Some("text/plain;q=0.5, application/json"))
+    );
+    assert_eq!(
+        MetricsFormat::Text,
+        MetricsFormat::negotiate(Some("application/json;q=0, image/png"))
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT, "application/json".parse().unwrap());
+    assert_eq!(
+        MetricsFormat::Prometheus,
+        MetricsFormat::for_request(Some("format=prometheus"), &headers)
+    );
+    assert_eq!(
+        MetricsFormat::Json,
+        MetricsFormat::for_request(Some("format=unknown"), &headers)
+    );
+}
+
+#[test]
+fn test_parse_metrics_label() {
+    let label: MetricsLabel = "instance=eu-1".parse().unwrap();
+    assert_eq!("instance", label.name);
+    assert_eq!("eu-1", label.value);
+    assert_eq!("instance=eu-1", label.to_string());
+    assert!("instance".parse::<MetricsLabel>().is_err());
+    assert!("1instance=eu".parse::<MetricsLabel>().is_err());
+    assert!("__name__=eu".parse::<MetricsLabel>().is_err());
+}
+
+#[test]
+fn test_render_metrics() {
+    let families = vec![
+        MetricFamily::single("event_count_total", MetricKind::Counter, "Events", 5),
+        MetricFamily::single(
+            "total_connection_count",
+            MetricKind::Counter,
+            "Connections",
+            2,
+        )
+        .renamed("connection_count"),
+        MetricFamily::new(
+            "active_connection_count_by_client",
+            MetricKind::Gauge,
+            "Clients",
+        )
+        .with_sample(vec![("client", "a\"b".into())], 1),
+    ];
+    let labels = ["instance=eu".parse().unwrap()];
+    assert_eq!(
+        "event_count_total 5\ntotal_connection_count 2\nactive_connection_count_by_client{client=\"a\\\"b\"} 1\n",
+        render_text(&families)
+    );
+    assert_eq!(
+        "# HELP notify_push_event_count_total Events\n\
+        # TYPE notify_push_event_count_total counter\n\
+        notify_push_event_count_total{instance=\"eu\"} 5\n\
+        # HELP notify_push_connection_count_total Connections\n\
+        # TYPE notify_push_connection_count_total counter\n\
+        notify_push_connection_count_total{instance=\"eu\"} 2\n\
+        # HELP notify_push_active_connection_count_by_client Clients\n\
+        # TYPE notify_push_active_connection_count_by_client gauge\n\
+        notify_push_active_connection_count_by_client{client=\"a\\\"b\",instance=\"eu\"} 1\n",
+        render_prometheus(&families, &labels)
+    );
+    assert_eq!(
+        "# HELP notify_push_event_count Events\n\
+        # TYPE notify_push_event_count counter\n\
+        notify_push_event_count_total 5\n\
+        # HELP notify_push_connection_count Connections\n\
+        # TYPE notify_push_connection_count counter\n\
+        notify_push_connection_count_total 2\n\
+        # HELP notify_push_active_connection_count_by_client Clients\n\
+        # TYPE notify_push_active_connection_count_by_client gauge\n\
+        notify_push_active_connection_count_by_client{client=\"a\\\"b\"} 1\n\
+        # EOF\n",
+        render_open_metrics(&families, &[])
+    );
+}
@@ -0,0 +1,141 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Keep the cumulative metrics across restarts.
+//!
+//! Counters that reset to zero on every deploy break the rate calculations of dashboards built on them, so the counters
+//! are stored in redis or a local file on shutdown and added back on startup.
+
+use crate::error::Error;
+use crate::metrics::{PersistedCounters, METRICS};
+use crate::redis::Redis;
+use crate::Result;
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const METRICS_KEY: &str = "notify_push_persisted_metrics";
+
+/// Where the counters are stored, written as `redis`, `redis:<name>` or the path of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsStore {
+    /// Redis key, a name can be given to keep instances that share a redis server apart
+    Redis(Option<String>),
+    File(PathBuf),
+}
+
+impl FromStr for MetricsStore {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("redis") {
+            Some("") => MetricsStore::Redis(None),
+            Some(name) if name.starts_with(':') => MetricsStore::Redis(Some(name[1..].into())),
+            _ => MetricsStore::File(s.into()),
+        })
+    }
+}
+
+impl Display for MetricsStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsStore::Redis(None) => write!(f, "redis"),
+            MetricsStore::Redis(Some(name)) => write!(f, "redis:{}", name),
+            MetricsStore::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl MetricsStore {
+    /// A separate store for every worker process, since each of them counts separately
+    pub fn for_worker(self, index: usize) -> Self {
+        match self {
+            MetricsStore::Redis(name) => MetricsStore::Redis(Some(match name {
+                Some(name) => format!("{}_{}", name, index),
+                None => index.to_string(),
+            })),
+            MetricsStore::File(path) => {
+                let mut path = path.into_os_string();
+                path.push(format!(".{}", index));
+                MetricsStore::File(path.into())
+            }
+        }
+    }
+
+    fn key(name: &Option<String>) -> String {
+        match name {
+            Some(name) => format!("{}_{}", METRICS_KEY, name),
+            None => METRICS_KEY.into(),
+        }
+    }
+
+    /// Store the current counters
+    pub async fn save(&self, redis: &Redis) -> Result<()> {
+        let counters = serde_json::to_string(&METRICS.snapshot()).unwrap();
+        match self {
+            MetricsStore::Redis(name) => {
+                let mut client = redis.connect().await?;
+                client.set(&Self::key(name), &counters).await
+            }
+            MetricsStore::File(path) => tokio::fs::write(path, counters)
+                .await
+                .map_err(Error::MetricsStore),
+        }
+    }
+
+    /// Add the counters stored by a previous instance to the current counters, returns whether any were stored
+    pub async fn load(&self, redis: &Redis) -> Result<bool> {
+        let counters = match self {
+            MetricsStore::Redis(name) => {
+                let mut client = redis.connect().await?;
+                client.get_optional(&Self::key(name)).await?
+            }
+            MetricsStore::File(path) => match tokio::fs::read_to_string(path).await {
+                Ok(counters) => Some(counters),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(Error::MetricsStore(e)),
+            },
+        };
+        let Some(counters) = counters else {
+            return Ok(false);
+        };
+        match serde_json::from_str::<PersistedCounters>(&counters) {
+            Ok(counters) => {
+                METRICS.restore(&counters);
+                Ok(true)
+            }
+            Err(e) => {
+                log::warn!("Ignoring invalid persisted metrics: {}", e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_metrics_store() {
+    for (input, expected) in [
+        ("redis", MetricsStore::Redis(None)),
+        ("redis:eu", MetricsStore::Redis(Some("eu".into()))),
+        (
+            "/var/lib/notify_push/metrics.json",
+            MetricsStore::File("/var/lib/notify_push/metrics.json".into()),
+        ),
+        ("redis.json", MetricsStore::File("redis.json".into())),
+    ] {
+        let parsed: MetricsStore = input.parse().unwrap();
+        assert_eq!(expected, parsed);
+        assert_eq!(input, parsed.to_string());
+    }
+    assert_eq!(
+        MetricsStore::Redis(Some("eu_1".into())),
+        MetricsStore::Redis(Some("eu".into())).for_worker(1)
+    );
+    assert_eq!(
+        MetricsStore::File("metrics.json.2".into()),
+        MetricsStore::File("metrics.json".into()).for_worker(2)
+    );
+}
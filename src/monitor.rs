@@ -0,0 +1,161 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Read-only stream of the push traffic for live monitoring dashboards, served at `/admin/monitor`.
+//!
+//! Only the metadata of the messages is mirrored, the type, a hash of the user and the time, never the contents.
+//! The user hash is only stable for the lifetime of the push server and differs between instances.
+//! Only with `UNKNOWN_CHANNELS=forward` the raw events from unknown redis channels are mirrored too.
+
+use crate::http::WebSocket;
+use crate::message::{MessageType, PushMessage};
+use crate::UserId;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Number of events a slow monitoring connection can fall behind before it misses events
+const MONITOR_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonitorEvent {
+    #[serde(rename = "type")]
+    pub message_type: MessageType,
+    /// Anonymized user the message was send to, or `None` for messages to all users
+    pub user: Option<String>,
+    /// Whether the user had any open connections
+    pub connected: bool,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Event published on a redis channel the push server doesn't handle, see [`crate::unknown_channel`]
+#[derive(Debug, Clone, Serialize)]
+pub struct UnknownChannelEvent {
+    pub channel: String,
+    pub payload: String,
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum MonitorItem {
+    Message(MonitorEvent),
+    UnknownChannel(UnknownChannelEvent),
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub struct Monitor {
+    sender: broadcast::Sender<MonitorItem>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor {
+            sender: broadcast::channel(MONITOR_BUFFER).0,
+        }
+    }
+}
+
+impl Monitor {
+    /// Mirror a message to the monitoring connections, if there are any
+    pub fn record(&self, user: Option<&UserId>, connected: bool, msg: &PushMessage) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        self.sender
+            .send(MonitorItem::Message(MonitorEvent {
+                message_type: msg.message_type(),
+                user: user.map(UserId::anonymized),
+                connected,
+                timestamp: timestamp(),
+            }))
+            .ok();
+    }
+
+    /// Mirror the raw payload of an event from an unknown channel, if there are any monitoring connections
+    pub fn record_unknown_channel(&self, channel: &str, payload: &[u8]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        self.sender
+            .send(MonitorItem::UnknownChannel(UnknownChannelEvent {
+                channel: channel.into(),
+                payload: String::from_utf8_lossy(payload).into_owned(),
+                timestamp: timestamp(),
+            }))
+            .ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitorItem> {
+        self.sender.subscribe()
+    }
+}
+
+/// Send the events to a monitoring connection until it's closed, anything the client sends is ignored
+pub async fn handle_monitor_socket(ws: WebSocket, mut events: broadcast::Receiver<MonitorItem>) {
+    log::info!("new monitoring connection");
+    let (mut tx, mut rx) = ws.split();
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        log::debug!("monitoring connection fell behind, skipped {} events", count);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let json = serde_json::to_string(&event).unwrap_or_default();
+                if tx.send(Message::text(json)).await.is_err() {
+                    break;
+                }
+            }
+            msg = rx.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+    tx.close().await.ok();
+}
+
+#[test]
+fn test_monitor_event() {
+    let monitor = Monitor::default();
+    let user = UserId::new("foo");
+    // nothing is recorded without listeners
+    monitor.record(Some(&user), true, &PushMessage::Activity);
+
+    let mut events = monitor.subscribe();
+    monitor.record(
+        Some(&user),
+        false,
+        &PushMessage::Custom(
+            "secret_type".into(),
+            Box::new(serde_json::json!({"secret": 1})),
+        ),
+    );
+    let MonitorItem::Message(event) = events.try_recv().unwrap() else {
+        panic!("expected a message event");
+    };
+    assert_eq!(MessageType::Custom, event.message_type);
+    assert_eq!(Some(user.anonymized()), event.user);
+    let json = serde_json::to_string(&event).unwrap();
+    assert!(!json.contains("secret"));
+    assert!(!json.contains("foo"));
+    assert!(events.try_recv().is_err());
+}
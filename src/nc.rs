@@ -4,28 +4,96 @@
  */
 
 use crate::error::{AuthenticationError, NextCloudError};
+use crate::remote_config::RemoteConfig;
 use crate::{Result, UserId};
 use reqwest::header::HeaderName;
 use reqwest::{Response, StatusCode, Url};
 use std::fmt::Write;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
 
 static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 
+/// Number of times an authentication request is retried when nextcloud can't be reached or returns a server error
+const MAX_RETRIES: u32 = 2;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Number of consecutive failed requests after which we stop sending requests to nextcloud for a while
+const BREAKER_THRESHOLD: usize = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Stop hammering nextcloud with authentication requests while it keeps failing
+#[derive(Default)]
+struct CircuitBreaker {
+    failures: AtomicUsize,
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self) -> bool {
+        let mut open_until = self.open_until.lock().unwrap();
+        match *open_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                // let the next request through, but open again if it fails
+                *open_until = None;
+                self.failures
+                    .store(BREAKER_THRESHOLD - 1, Ordering::Relaxed);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn failure(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 >= BREAKER_THRESHOLD {
+            log::warn!(
+                "Too many failed requests to nextcloud, pausing authentication for {}s",
+                BREAKER_COOLDOWN.as_secs()
+            );
+            self.failures.store(0, Ordering::Relaxed);
+            *self.open_until.lock().unwrap() = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
+}
+
+/// Build the http client used for nextcloud and other outgoing requests
+pub(crate) fn http_client(allow_self_signed: bool) -> Result<reqwest::Client, reqwest::Error> {
+    let builder = reqwest::Client::builder();
+    #[cfg(feature = "rustls")]
+    let builder = builder.danger_accept_invalid_certs(allow_self_signed);
+    #[cfg(not(feature = "rustls"))]
+    let _ = allow_self_signed;
+    builder.build()
+}
+
 pub struct Client {
     http: reqwest::Client,
     base_url: Url,
+    breaker: CircuitBreaker,
 }
 
 impl Client {
     pub fn new(base_url: &str, allow_self_signed: bool) -> Result<Self, NextCloudError> {
         let base_url = Url::parse(base_url)?;
-        let http = reqwest::Client::builder()
-            .danger_accept_invalid_certs(allow_self_signed)
-            .build()?;
-        Ok(Client { http, base_url })
+        let http = http_client(allow_self_signed)?;
+        Ok(Client {
+            http,
+            base_url,
+            breaker: CircuitBreaker::default(),
+        })
     }
 
+    #[cfg_attr(
+        feature = "otel",
+        tracing::instrument(skip(self, password, forwarded_for), err)
+    )]
     pub async fn verify_credentials(
         &self,
         username: &str,
@@ -33,7 +101,32 @@ impl Client {
         forwarded_for: Vec<IpAddr>,
     ) -> Result<UserId, AuthenticationError> {
         log::debug!("Verifying credentials for {}", username);
-        let response = self.auth_request(username, password, forwarded_for).await?;
+        if self.breaker.is_open() {
+            return Err(NextCloudError::Unavailable.into());
+        }
+
+        let mut attempt = 0;
+        let response = loop {
+            match self.auth_request(username, password, &forwarded_for).await {
+                Ok(response) if !response.status().is_server_error() => {
+                    self.breaker.success();
+                    break response;
+                }
+                result => {
+                    self.breaker.failure();
+                    if attempt >= MAX_RETRIES || self.breaker.is_open() {
+                        break result?;
+                    }
+                    attempt += 1;
+                    log::debug!(
+                        "Authentication request for {} failed, retrying (attempt {})",
+                        username,
+                        attempt
+                    );
+                    sleep(RETRY_DELAY * attempt).await;
+                }
+            }
+        };
 
         match response.status() {
             StatusCode::OK => Ok(response
@@ -48,11 +141,12 @@ impl Client {
         }
     }
 
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
     async fn auth_request(
         &self,
         username: &str,
         password: &str,
-        forwarded_for: Vec<IpAddr>,
+        forwarded_for: &[IpAddr],
     ) -> Result<Response, NextCloudError> {
         self.http
             .get(self.base_url.join("index.php/apps/notify_push/uid")?)
@@ -75,6 +169,7 @@ impl Client {
             .map_err(NextCloudError::NextcloudConnect)
     }
 
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
     pub async fn get_test_cookie(&self) -> Result<u32, NextCloudError> {
         let response = self
             .http
@@ -101,6 +196,7 @@ impl Client {
         }
     }
 
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
     pub async fn test_set_remote(&self, addr: IpAddr) -> Result<IpAddr, NextCloudError> {
         self.http
             .get(
@@ -117,7 +213,128 @@ impl Client {
             .map_err(NextCloudError::MalformedRemote)
     }
 
+    /// Run the remote test through the public url of the push server, so the request passes the reverse proxy
+    ///
+    /// Returns `None` if the test endpoint can't be reached through `endpoint`
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
+    pub async fn test_remote_through(
+        &self,
+        endpoint: &str,
+        addr: IpAddr,
+        secret: Option<&str>,
+    ) -> Result<Option<IpAddr>, NextCloudError> {
+        let endpoint = Url::parse(&format!("{}/", endpoint.trim_end_matches('/')))?;
+        let mut request = self
+            .http
+            .get(endpoint.join(&format!("test/remote/{}", addr))?);
+        if let Some(secret) = secret {
+            request = request.header("x-notify-push-test-secret", secret);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        response
+            .text()
+            .await?
+            .parse()
+            .map(Some)
+            .map_err(NextCloudError::MalformedRemote)
+    }
+
+    /// Check that nextcloud can be reached, only server errors are considered a failure
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
+    pub async fn ping(&self) -> Result<(), NextCloudError> {
+        let response = self
+            .http
+            .get(self.base_url.join("status.php")?)
+            .send()
+            .await
+            .map_err(NextCloudError::NextcloudConnect)?;
+        if response.status().is_server_error() {
+            Err(NextCloudError::Server(response.status()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get the push server settings managed by the app
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
+    pub async fn get_remote_config(&self) -> Result<RemoteConfig, NextCloudError> {
+        let response = self
+            .http
+            .get(self.base_url.join("index.php/apps/notify_push/config")?)
+            .send()
+            .await
+            .map_err(NextCloudError::NextcloudConnect)?;
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            status if status.is_server_error() => Err(NextCloudError::Server(status)),
+            status if status.is_client_error() => Err(NextCloudError::Client(status)),
+            status => Err(NextCloudError::Other(status)),
+        }
+    }
+
+    /// Store the public url of the push server in the app config, this requires the credentials of an admin user
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
+    pub async fn set_base_endpoint(
+        &self,
+        username: &str,
+        password: &str,
+        endpoint: &str,
+    ) -> Result<(), NextCloudError> {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("value", endpoint)
+            .finish();
+        let response = self
+            .http
+            .post(self.base_url.join(
+                "ocs/v2.php/apps/provisioning_api/api/v1/config/apps/notify_push/base_endpoint",
+            )?)
+            .basic_auth(username, Some(password))
+            .header("OCS-APIREQUEST", "true")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .map_err(NextCloudError::NextcloudConnect)?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status if status.is_server_error() => Err(NextCloudError::Server(status)),
+            status if status.is_client_error() => Err(NextCloudError::Client(status)),
+            status => Err(NextCloudError::Other(status)),
+        }
+    }
+
+    /// Register the public url of the push server with the app, authenticated with the test secret
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
+    pub async fn register_endpoint(
+        &self,
+        endpoint: &str,
+        secret: &str,
+    ) -> Result<(), NextCloudError> {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("endpoint", endpoint)
+            .finish();
+        let response = self
+            .http
+            .post(self.base_url.join("index.php/apps/notify_push/endpoint")?)
+            .header("x-notify-push-test-secret", secret)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .map_err(NextCloudError::NextcloudConnect)?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status if status.is_server_error() => Err(NextCloudError::Server(status)),
+            status if status.is_client_error() => Err(NextCloudError::Client(status)),
+            status => Err(NextCloudError::Other(status)),
+        }
+    }
+
     /// Ask the app to put it's version number into redis under 'notify_push_app_version'
+    #[cfg_attr(feature = "otel", tracing::instrument(skip_all))]
     pub async fn request_app_version(&self) -> Result<(), NextCloudError> {
         self.http
             .get(
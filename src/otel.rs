@@ -0,0 +1,63 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Export of tracing spans to an OpenTelemetry collector.
+//!
+//! The handling of redis events, the storage mapping lookups, the sends to the users and the authentication of
+//! clients (including the requests to nextcloud) are instrumented with `tracing` spans, which are exported over
+//! OTLP/HTTP when an endpoint is configured. The spans of a single event are nested, so a "message never arrived"
+//! report can be followed from the event to the users it was sent to.
+
+use crate::error::Error;
+use crate::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Keeps the exporter running, the remaining spans are sent on shutdown
+pub struct OtelExporter {
+    provider: TracerProvider,
+}
+
+impl OtelExporter {
+    /// Start exporting spans to the OTLP endpoint, has to be called from within the tokio runtime
+    pub fn start(endpoint: &str, worker: Option<usize>) -> Result<Self> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| Error::Otel(e.to_string()))?;
+        let mut attributes = vec![
+            KeyValue::new("service.name", "notify_push"),
+            KeyValue::new("service.version", env!("NOTIFY_PUSH_VERSION")),
+        ];
+        if let Some(worker) = worker {
+            attributes.push(KeyValue::new("service.instance.id", worker as i64));
+        }
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, Tokio)
+            .with_resource(Resource::new(attributes))
+            .build();
+
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("notify_push"));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+            .map_err(|e| Error::Otel(e.to_string()))?;
+        log::info!("Exporting spans to {}", endpoint);
+        Ok(OtelExporter { provider })
+    }
+
+    /// Send the remaining spans and stop the exporter
+    pub async fn shutdown(self) {
+        // the provider waits for the export to finish, blocking the thread
+        let result = tokio::task::spawn_blocking(move || self.provider.shutdown()).await;
+        if let Ok(Err(e)) = result {
+            log::warn!("Failed to export the remaining spans: {}", e);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Long-polling transport at `/poll`, for clients on networks where websockets are blocked or killed by proxies.
+//!
+//! The first poll authenticates with basic auth and returns a session token and a cursor. Following polls only send the
+//! session and the cursor of the previous response, and block until there are messages after the cursor or the timeout
+//! is reached. The messages of every polling user are kept in a short buffer, so nothing is lost between two polls.
+//! If a client falls further behind than the buffer, it's told to check for every type of update instead.
+
+use crate::auth::Credentials;
+use crate::error::AuthenticationError;
+use crate::forwarded::Forwarded;
+use crate::message::{MessageType, PushMessage};
+use crate::passthru_hasher::PassthruHasher;
+use crate::{App, UserId};
+use ahash::RandomState;
+use axum::extract::{RawQuery, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use dashmap::DashMap;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::time::timeout_at;
+
+/// Number of messages kept for a polling user
+const BUFFER_SIZE: usize = 64;
+/// Time after the last poll after which the session and the buffered messages of a user are removed
+const SESSION_EXPIRY: Duration = Duration::from_secs(120);
+/// Time a poll waits for messages if the client doesn't specify a timeout
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest time a poll can wait for messages, proxies tend to close requests that take much longer
+const MAX_POLL_TIMEOUT: Duration = Duration::from_secs(60);
+const SESSION_TOKEN_LENGTH: usize = 32;
+
+struct Buffer {
+    messages: VecDeque<PushMessage>,
+    /// Cursor of the next message, the cursor of the first buffered message is `next - messages.len()`
+    next: u64,
+    last_poll: Instant,
+}
+
+/// Recent messages of a user for the polling clients
+struct UserBuffer {
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+}
+
+impl UserBuffer {
+    fn new(now: Instant) -> Self {
+        UserBuffer {
+            buffer: Mutex::new(Buffer {
+                messages: VecDeque::with_capacity(BUFFER_SIZE),
+                next: 0,
+                last_poll: now,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    fn push(&self, msg: PushMessage) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.messages.len() == BUFFER_SIZE {
+            buffer.messages.pop_front();
+        }
+        buffer.messages.push_back(msg);
+        buffer.next += 1;
+        drop(buffer);
+        self.notify.notify_waiters();
+    }
+
+    /// The messages after the cursor, or `None` if nothing was sent yet
+    fn read(&self, cursor: u64, now: Instant) -> Option<(Vec<PushMessage>, u64)> {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.last_poll = now;
+        if cursor == buffer.next {
+            return None;
+        }
+        let first = buffer.next - buffer.messages.len() as u64;
+        let messages = if cursor < first || cursor > buffer.next {
+            // the messages after the cursor are no longer buffered, or the cursor is from a previous buffer
+            PushMessage::resync().into()
+        } else {
+            buffer
+                .messages
+                .iter()
+                .skip((cursor - first) as usize)
+                .cloned()
+                .collect()
+        };
+        Some((messages, buffer.next))
+    }
+
+    fn cursor(&self, now: Instant) -> u64 {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.last_poll = now;
+        buffer.next
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.buffer.lock().unwrap().last_poll) > SESSION_EXPIRY
+    }
+
+    /// Wait for messages after the cursor until the deadline
+    async fn wait(&self, cursor: u64, deadline: Instant) -> (Vec<PushMessage>, u64) {
+        loop {
+            // register for notifications before checking, so messages pushed in between wake us up
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(result) = self.read(cursor, Instant::now()) {
+                return result;
+            }
+            if timeout_at(deadline.into(), notified).await.is_err() {
+                return (Vec::new(), self.cursor(Instant::now()));
+            }
+        }
+    }
+}
+
+/// Message buffers and sessions of the polling clients
+#[derive(Default)]
+pub struct PollBuffers {
+    users: DashMap<UserId, Arc<UserBuffer>, PassthruHasher>,
+    sessions: DashMap<String, UserId, RandomState>,
+}
+
+impl PollBuffers {
+    /// Buffer a message for the polling clients of the user, returns whether the user has any
+    pub fn send_to_user(&self, user: &UserId, msg: &PushMessage) -> bool {
+        match self.users.get(user) {
+            Some(buffer) => {
+                buffer.push(msg.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn send_to_all(&self, msg: &PushMessage) {
+        for buffer in self.users.iter() {
+            buffer.push(msg.clone());
+        }
+    }
+
+    /// Whether the user polled within the session expiry
+    pub fn is_polling(&self, user: &UserId) -> bool {
+        self.users
+            .get(user)
+            .is_some_and(|buffer| !buffer.is_expired(Instant::now()))
+    }
+
+    /// The users that polled within the session expiry
+    pub fn polling_users(&self) -> Vec<UserId> {
+        let now = Instant::now();
+        self.users
+            .iter()
+            .filter(|buffer| !buffer.is_expired(now))
+            .map(|buffer| buffer.key().clone())
+            .collect()
+    }
+
+    /// Start a new session for an authenticated user, returns the session token and the current cursor
+    fn create_session(&self, user: UserId) -> (String, u64) {
+        let now = Instant::now();
+        self.cleanup(now);
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), SESSION_TOKEN_LENGTH);
+        let cursor = self
+            .users
+            .entry(user.clone())
+            .or_insert_with(|| Arc::new(UserBuffer::new(now)))
+            .cursor(now);
+        self.sessions.insert(token.clone(), user);
+        (token, cursor)
+    }
+
+    fn session(&self, token: &str) -> Option<Arc<UserBuffer>> {
+        let user = self.sessions.get(token)?;
+        let buffer = self.users.get(user.value())?;
+        Some(buffer.clone())
+    }
+
+    /// Remove the buffers and sessions of users that stopped polling
+    fn cleanup(&self, now: Instant) {
+        self.users.retain(|_, buffer| !buffer.is_expired(now));
+        self.sessions
+            .retain(|_, user| self.users.contains_key(user));
+    }
+}
+
+/// Combine the messages of the same type, like the debouncing of the websocket connections
+fn merge_messages(messages: Vec<PushMessage>, max_file_ids: usize) -> Vec<PushMessage> {
+    let mut merged: Vec<PushMessage> = Vec::with_capacity(messages.len());
+    for msg in messages {
+        let message_type = msg.message_type();
+        match merged
+            .iter_mut()
+            .find(|existing| existing.message_type() == message_type)
+        {
+            Some(existing) if message_type != MessageType::Custom => {
+                existing.merge(&msg, max_file_ids)
+            }
+            _ => merged.push(msg),
+        }
+    }
+    merged
+}
+
+#[derive(Debug, Serialize)]
+struct PollResponse {
+    session: String,
+    /// Cursor to send with the next poll
+    cursor: u64,
+    messages: Vec<Value>,
+}
+
+#[derive(Debug, Default)]
+struct PollQuery {
+    session: Option<String>,
+    cursor: Option<u64>,
+    timeout: Option<Duration>,
+}
+
+impl PollQuery {
+    fn parse(query: Option<&str>) -> Self {
+        let mut parsed = PollQuery::default();
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match key.as_ref() {
+                "session" => parsed.session = Some(value.into_owned()),
+                "cursor" => parsed.cursor = value.parse().ok(),
+                "timeout" => parsed.timeout = value.parse().ok().map(Duration::from_secs),
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+/// Username and password from a basic auth header
+fn basic_auth(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.into(), password.into()))
+}
+
+/// Handle a `/poll` request, see the module documentation
+pub async fn poll(
+    State(app): State<Arc<App>>,
+    mut forwarded: Forwarded,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if let Some(depth) = app.forwarded_for_depth {
+        forwarded = forwarded.at_depth(depth);
+    }
+    if app.require_secure && !forwarded.is_secure(app.forwarded_for_depth) {
+        return (StatusCode::FORBIDDEN, "polling requires TLS").into_response();
+    }
+    let query = PollQuery::parse(query.as_deref());
+    let polls = app.connections.polls();
+
+    let Some(session) = query.session else {
+        let Some((username, password)) = basic_auth(&headers) else {
+            return (StatusCode::UNAUTHORIZED, "missing credentials").into_response();
+        };
+        let verify = app.auth.verify(Credentials {
+            username: &username,
+            password: &password,
+            forwarded_for: &forwarded.hops,
+        });
+        let user = match app.limit_auth(&forwarded, &username, verify).await {
+            Ok(user) => user,
+            Err(AuthenticationError::Invalid) => {
+                return (StatusCode::UNAUTHORIZED, "invalid credentials").into_response();
+            }
+            Err(AuthenticationError::TooManyAttempts) => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many failed login attempts",
+                )
+                    .into_response();
+            }
+            Err(e) => {
+                log::warn!("Failed to authenticate poll: {}", e);
+                return (StatusCode::BAD_GATEWAY, "failed to verify credentials").into_response();
+            }
+        };
+        log::info!("new polling session for {}", user);
+        let (session, cursor) = polls.create_session(user);
+        return Json(PollResponse {
+            session,
+            cursor,
+            messages: Vec::new(),
+        })
+        .into_response();
+    };
+
+    let Some(buffer) = polls.session(&session) else {
+        return (StatusCode::UNAUTHORIZED, "unknown or expired session").into_response();
+    };
+    let wait = query
+        .timeout
+        .unwrap_or(DEFAULT_POLL_TIMEOUT)
+        .min(MAX_POLL_TIMEOUT);
+    let (messages, cursor) = buffer
+        .wait(query.cursor.unwrap_or(0), Instant::now() + wait)
+        .await;
+    let messages = merge_messages(messages, app.connections.max_file_ids())
+        .iter()
+        .map(PushMessage::to_json)
+        .collect();
+    Json(PollResponse {
+        session,
+        cursor,
+        messages,
+    })
+    .into_response()
+}
+
+#[test]
+fn test_poll_buffer() {
+    let now = Instant::now();
+    let buffer = UserBuffer::new(now);
+    assert!(buffer.read(0, now).is_none());
+
+    buffer.push(PushMessage::Activity);
+    buffer.push(PushMessage::Notification);
+    assert_eq!(
+        Some((vec![PushMessage::Activity, PushMessage::Notification], 2)),
+        buffer.read(0, now)
+    );
+    assert_eq!(
+        Some((vec![PushMessage::Notification], 2)),
+        buffer.read(1, now)
+    );
+    assert!(buffer.read(2, now).is_none());
+
+    // a cursor from before the buffered messages needs a resync
+    for _ in 0..BUFFER_SIZE {
+        buffer.push(PushMessage::Activity);
+    }
+    assert_eq!(
+        Some((PushMessage::resync().into(), BUFFER_SIZE as u64 + 2)),
+        buffer.read(1, now)
+    );
+    assert_eq!(BUFFER_SIZE, buffer.read(2, now).unwrap().0.len());
+
+    assert!(!buffer.is_expired(now + SESSION_EXPIRY));
+    assert!(buffer.is_expired(now + SESSION_EXPIRY + Duration::from_secs(1)));
+}
+
+#[test]
+fn test_merge_poll_messages() {
+    use crate::message::UpdatedFiles;
+
+    let custom = |body: i32| PushMessage::Custom("foo".into(), Box::new(body.into()));
+    let merged = merge_messages(
+        vec![
+            PushMessage::File(UpdatedFiles::from(1)),
+            PushMessage::Activity,
+            custom(1),
+            PushMessage::File(UpdatedFiles::from(2)),
+            PushMessage::Activity,
+            custom(2),
+        ],
+        10,
+    );
+    let mut files = UpdatedFiles::from(1);
+    files.extend(&UpdatedFiles::from(2), 10);
+    assert_eq!(
+        vec![
+            PushMessage::File(files),
+            PushMessage::Activity,
+            custom(1),
+            custom(2)
+        ],
+        merged
+    );
+}
+
+#[tokio::test]
+async fn test_poll_wait() {
+    let polls = PollBuffers::default();
+    let user = UserId::new("foo");
+    let (token, cursor) = polls.create_session(user.clone());
+    assert_eq!(0, cursor);
+    let buffer = polls.session(&token).unwrap();
+    assert!(polls.session("other").is_none());
+    assert!(polls.is_polling(&user));
+
+    // times out without messages
+    let (messages, cursor) = buffer
+        .wait(0, Instant::now() + Duration::from_millis(10))
+        .await;
+    assert!(messages.is_empty());
+    assert_eq!(0, cursor);
+
+    let wait = buffer.wait(0, Instant::now() + Duration::from_secs(10));
+    let send = async {
+        tokio::task::yield_now().await;
+        assert!(polls.send_to_user(&user, &PushMessage::Activity));
+    };
+    let ((messages, cursor), _) = futures::join!(wait, send);
+    assert_eq!(vec![PushMessage::Activity], messages);
+    assert_eq!(1, cursor);
+
+    assert!(!polls.send_to_user(&UserId::new("bar"), &PushMessage::Activity));
+}
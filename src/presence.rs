@@ -0,0 +1,69 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use crate::error::NextCloudError;
+use crate::nc::http_client;
+use crate::user::keep_user_names;
+use crate::UserId;
+use reqwest::Url;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    /// The first connection for the user has been opened
+    Connected,
+    /// The last connection for the user has been closed
+    Disconnected,
+}
+
+#[derive(Serialize)]
+struct PresenceUpdate<'a> {
+    user: &'a str,
+    state: PresenceState,
+}
+
+/// Posts a json message to the configured url whenever a user comes online or goes offline
+pub struct PresenceWebhook {
+    http: reqwest::Client,
+    url: Url,
+}
+
+impl PresenceWebhook {
+    pub fn new(url: Url, allow_self_signed: bool) -> Result<Self, NextCloudError> {
+        let http = http_client(allow_self_signed)?;
+        keep_user_names();
+        Ok(PresenceWebhook { http, url })
+    }
+
+    pub fn notify(&self, user: &UserId, state: PresenceState) {
+        let Some(user) = user.name() else {
+            log::warn!(
+                "Can't send presence update for {:?}, user name not known",
+                user
+            );
+            return;
+        };
+        let request = self
+            .http
+            .post(self.url.clone())
+            .json(&PresenceUpdate { user: &user, state });
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    log::warn!(
+                        "Presence webhook returned status {} for {}",
+                        response.status(),
+                        user
+                    );
+                }
+                Ok(_) => {
+                    log::debug!("Sent presence update {:?} for {}", state, user);
+                }
+                Err(e) => log::warn!("Failed to send presence update for {}: {}", user, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,312 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Machine readable description of the websocket protocol, served at `/protocol`.
+//!
+//! The frames are rendered by the same code that sends and parses them on a connection,
+//! so the description always matches what this build supports.
+
+use crate::affinity::{AFFINITY_HEADER, AFFINITY_QUERY};
+use crate::connection::{ClientCommand, ConnectionOptions};
+use crate::error::AuthenticationError;
+#[cfg(test)]
+use crate::message::DEFAULT_MAX_FILE_IDS;
+use crate::message::{MergeTime, PushMessage, Reply, Subprotocol, UpdatedFiles};
+use crate::{App, Error};
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Placeholder used in the example frames for values that differ per message
+const PLACEHOLDER: &str = "<value>";
+
+#[derive(Debug, Serialize)]
+pub struct Protocol {
+    pub version: &'static str,
+    /// Maximum number of ids in a `notify_file_id` message, `notify_file` is sent instead if more files changed
+    pub max_file_ids: usize,
+    /// Milliseconds the server waits for more messages of the same type to merge with before sending
+    pub merge_time: MergeTime,
+    /// Values for the `Sec-WebSocket-Protocol` header, without it the text protocol is used which all examples are in
+    pub subprotocols: Vec<&'static str>,
+    /// Query parameter of the websocket url to pass the key from the `affinity` message in when reconnecting
+    pub affinity_query: &'static str,
+    /// Header in the handshake response that repeats the key passed in the query, for sticky-session load balancers
+    pub affinity_header: &'static str,
+    /// Frames the client sends after connecting, in order
+    pub authentication: Vec<Frame>,
+    /// Frames the server sends
+    pub messages: Vec<Frame>,
+    /// Frames the client can send once authenticated
+    pub commands: Vec<Frame>,
+    /// Frames the server sends when something goes wrong, the connection is closed after authentication errors
+    pub errors: Vec<Frame>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Frame {
+    pub name: &'static str,
+    /// Example text of the frame
+    pub example: String,
+    pub description: &'static str,
+    /// Command the client needs to send before receiving this message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requires: Option<String>,
+}
+
+impl Frame {
+    fn new(name: &'static str, example: impl Into<String>, description: &'static str) -> Self {
+        Frame {
+            name,
+            example: example.into(),
+            description,
+            requires: None,
+        }
+    }
+
+    fn requires(mut self, command: ClientCommand) -> Self {
+        self.requires = Some(command.to_string());
+        self
+    }
+}
+
+fn frame_text(msg: Message) -> String {
+    match msg {
+        Message::Text(text) => text.as_str().into(),
+        _ => String::new(),
+    }
+}
+
+/// Replies are documented in the text protocol
+fn reply_text(reply: Reply) -> String {
+    frame_text(reply.into_message(Subprotocol::Text))
+}
+
+fn message_frame(msg: PushMessage, opts: &ConnectionOptions) -> Frame {
+    let listen_file_id = opts.listen_file_id.load(Ordering::Relaxed);
+    // matching every variant makes sure new messages get described here
+    let frame = match &msg {
+        PushMessage::File(UpdatedFiles::Known(_)) if listen_file_id => Frame::new(
+            "notify_file_id",
+            "",
+            "Files with the listed ids have changed, if too many files changed `notify_file` is sent instead",
+        )
+        .requires(ClientCommand::ListenFileId),
+        PushMessage::File(_) => Frame::new(
+            "notify_file",
+            "",
+            "Files of the user have changed, the client should check for updates",
+        ),
+        PushMessage::Activity => Frame::new(
+            "notify_activity",
+            "",
+            "There is a new activity for the user",
+        ),
+        PushMessage::Notification => Frame::new(
+            "notify_notification",
+            "",
+            "There is a new notification for the user",
+        ),
+        PushMessage::Custom(..) => Frame::new(
+            "custom",
+            "",
+            "Message send by an app, consisting of the message type optionally followed by a json body",
+        ),
+    };
+    Frame {
+        example: frame_text(msg.into_message(opts)),
+        ..frame
+    }
+}
+
+fn command_frame(command: ClientCommand) -> Frame {
+    let frame = match &command {
+        ClientCommand::ListenFileId => Frame::new(
+            "listen_file_id",
+            "",
+            "Receive the ids of changed files with `notify_file_id` instead of `notify_file`",
+        ),
+        ClientCommand::ClientId(_) => Frame::new(
+            "client_id",
+            "",
+            "Identify the client by name and version, used in the logs and metrics",
+        ),
+        ClientCommand::RequestTransferToken => Frame::new(
+            "request_transfer_token",
+            "",
+            "Request a single use token that can be used to authenticate a new connection as the same user",
+        ),
+        ClientCommand::RequestAffinity => Frame::new(
+            "request_affinity",
+            "",
+            "Request the routing key of the user, to pass when reconnecting",
+        ),
+    };
+    Frame {
+        example: command.to_string(),
+        ..frame
+    }
+}
+
+fn error_frame(error: AuthenticationError) -> Frame {
+    let name = match &error {
+        AuthenticationError::Socket(_) => "socket",
+        AuthenticationError::InvalidMessage => "invalid_message",
+        AuthenticationError::Nextcloud(_) => "nextcloud",
+        AuthenticationError::Ldap(_) => "ldap",
+        AuthenticationError::Invalid => "invalid_credentials",
+        AuthenticationError::TooManyAttempts => "too_many_attempts",
+        AuthenticationError::LimitExceeded => "limit_exceeded",
+    };
+    // the connection limit is only checked once authenticated, and reported without the error prefix
+    let example = match error {
+        AuthenticationError::LimitExceeded => {
+            reply_text(Reply::UnprefixedError(Error::from(error).to_string()))
+        }
+        error => reply_text(Reply::Error(error.to_string())),
+    };
+    Frame::new(
+        name,
+        example,
+        "Authentication failed, the connection is closed",
+    )
+}
+
+impl Protocol {
+    pub fn new(max_file_ids: usize, merge_time: MergeTime) -> Self {
+        let opts = ConnectionOptions::default();
+        let mut messages = vec![Frame::new(
+            "authenticated",
+            reply_text(Reply::Authenticated),
+            "The credentials were accepted",
+        )];
+        messages.extend(
+            [
+                PushMessage::File(UpdatedFiles::Unknown),
+                PushMessage::Activity,
+                PushMessage::Notification,
+                PushMessage::Custom("custom_type".into(), Box::new(json!({"key": PLACEHOLDER}))),
+            ]
+            .map(|msg| message_frame(msg, &opts)),
+        );
+        opts.listen_file_id.store(true, Ordering::Relaxed);
+        messages.push(message_frame(
+            PushMessage::File(UpdatedFiles::Known(vec![1, 2].into())),
+            &opts,
+        ));
+        messages.push(
+            Frame::new(
+                "transfer_token",
+                reply_text(Reply::TransferToken(PLACEHOLDER.into())),
+                "Token to authenticate a new connection with, instead of the username",
+            )
+            .requires(ClientCommand::RequestTransferToken),
+        );
+        messages.push(
+            Frame::new(
+                "affinity",
+                reply_text(Reply::Affinity(PLACEHOLDER.into())),
+                "Routing key of the user, the same for all connections of the user to any instance",
+            )
+            .requires(ClientCommand::RequestAffinity),
+        );
+
+        Protocol {
+            version: env!("NOTIFY_PUSH_VERSION"),
+            max_file_ids,
+            merge_time,
+            subprotocols: Subprotocol::ALL
+                .iter()
+                .map(Subprotocol::name)
+                .collect(),
+            affinity_query: AFFINITY_QUERY,
+            affinity_header: AFFINITY_HEADER,
+            authentication: vec![
+                Frame::new(
+                    "username",
+                    PLACEHOLDER,
+                    "The username, empty when authenticating with a transfer token",
+                ),
+                Frame::new(
+                    "password",
+                    PLACEHOLDER,
+                    "The password, app password, pre-auth token or transfer token",
+                ),
+            ],
+            messages,
+            commands: [
+                ClientCommand::ListenFileId,
+                ClientCommand::ClientId(format!("desktop/{}", PLACEHOLDER)),
+                ClientCommand::RequestTransferToken,
+                ClientCommand::RequestAffinity,
+            ]
+            .into_iter()
+            .map(command_frame)
+            .collect(),
+            errors: [
+                AuthenticationError::InvalidMessage,
+                AuthenticationError::Invalid,
+                AuthenticationError::TooManyAttempts,
+                AuthenticationError::LimitExceeded,
+            ]
+            .into_iter()
+            .map(error_frame)
+            .chain([
+                Frame::new(
+                    "authentication_timeout",
+                    reply_text(Reply::UnprefixedError("Authentication timeout".into())),
+                    "The credentials were not send within 15 seconds, the connection is closed",
+                ),
+                Frame::new(
+                    "transfer_token_failed",
+                    reply_text(Reply::Error("Failed to create transfer token".into())),
+                    "A transfer token was requested but transfer tokens are disabled or it could not be created, the connection stays open",
+                ),
+            ])
+            .collect(),
+        }
+    }
+}
+
+pub async fn protocol(State(app): State<Arc<App>>) -> Json<Protocol> {
+    Json(Protocol::new(
+        app.connections.max_file_ids(),
+        app.connections.merge_time(),
+    ))
+}
+
+#[test]
+fn test_protocol_frames_parse() {
+    let protocol = Protocol::new(DEFAULT_MAX_FILE_IDS, MergeTime::default());
+    // every documented command is understood by the connection
+    for command in &protocol.commands {
+        assert!(
+            ClientCommand::parse(&command.example).is_some(),
+            "{}",
+            command.example
+        );
+    }
+    let file_id = protocol
+        .messages
+        .iter()
+        .find(|msg| msg.name == "notify_file_id")
+        .unwrap();
+    assert_eq!("notify_file_id [1,2]", file_id.example);
+    assert_eq!(Some("listen notify_file_id"), file_id.requires.as_deref());
+    let custom = protocol
+        .messages
+        .iter()
+        .find(|msg| msg.name == "custom")
+        .unwrap();
+    assert_eq!(r#"custom_type {"key":"<value>"}"#, custom.example);
+    assert_eq!(
+        100,
+        serde_json::to_value(&protocol).unwrap()["merge_time"]["file"]
+    );
+}
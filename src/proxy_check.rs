@@ -0,0 +1,98 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Verify that nextcloud trusts the push server as a proxy.
+//!
+//! The push server forwards the address of connecting clients to nextcloud, which only uses it when the
+//! push server is listed in the `trusted_proxies`. Otherwise nextcloud sees all clients coming from the
+//! push server, which breaks brute force protection and rate limiting.
+
+use crate::{App, Result, SELF_TEST_WINDOW};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+/// Address from the documentation range that is send as forwarded client address
+const TEST_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyCheck {
+    /// The public url the check went through, `None` if nextcloud was asked directly
+    pub endpoint: Option<String>,
+    /// The client address nextcloud saw
+    pub remote: IpAddr,
+}
+
+impl ProxyCheck {
+    /// Whether nextcloud used the address forwarded by the push server
+    pub fn is_trusted(&self) -> bool {
+        self.remote == TEST_ADDR
+    }
+
+    pub fn log(&self) {
+        let through = self
+            .endpoint
+            .as_deref()
+            .map(|endpoint| format!(" (checked through {})", endpoint))
+            .unwrap_or_default();
+        if self.is_trusted() {
+            log::info!("Nextcloud trusts the push server as proxy{}", through);
+        } else {
+            log::warn!(
+                "Nextcloud doesn't trust the push server as proxy{}, add {} to the `trusted_proxies` in the nextcloud config",
+                through,
+                self.remote
+            );
+        }
+    }
+}
+
+impl App {
+    /// Check if nextcloud accepts the client addresses forwarded by the push server
+    ///
+    /// If a public url is configured, either for the push server or in the app, the check goes through the reverse proxy
+    /// in front of the push server, otherwise, or if the push server can't be reached that way, nextcloud is asked directly.
+    pub async fn verify_proxy(&self) -> Result<ProxyCheck> {
+        let endpoint = match &self.public_url {
+            Some(public_url) => Some(public_url.to_string()),
+            None => self.storage_mapping.get_base_endpoint().await?,
+        };
+        if let Some(endpoint) = endpoint {
+            // the request comes back to us, make sure the test endpoints accept it
+            if self.production {
+                *self.self_test_until.lock().unwrap() = Some(Instant::now() + SELF_TEST_WINDOW);
+            }
+            let result = self
+                .nc_client
+                .test_remote_through(&endpoint, TEST_ADDR, self.test_secret.as_deref())
+                .await;
+            if self.production {
+                *self.self_test_until.lock().unwrap() = None;
+            }
+            match result {
+                Ok(Some(remote)) => {
+                    return Ok(ProxyCheck {
+                        endpoint: Some(endpoint),
+                        remote,
+                    })
+                }
+                Ok(None) => log::warn!(
+                    "The test endpoints of the push server can't be reached through {}, checking nextcloud directly",
+                    endpoint
+                ),
+                Err(e) => log::warn!(
+                    "Failed to reach the push server through {}, checking nextcloud directly: {:#}",
+                    endpoint,
+                    e
+                ),
+            }
+        }
+
+        let remote = self.nc_client.test_set_remote(TEST_ADDR).await?;
+        Ok(ProxyCheck {
+            endpoint: None,
+            remote,
+        })
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Answers to the queries send by the Nextcloud app.
+//!
+//! Since every push server instance receives the query, the answers are stored in redis under a key
+//! that includes the id of the instance, so the app can collect the answers of all instances.
+
+use crate::config::Config;
+use crate::event::Query;
+use crate::metrics::METRICS;
+use crate::redis::RedisConnection;
+use crate::Result;
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+
+/// Answers are only kept for long enough for the app to read them
+const ANSWER_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Information about the running push server instance
+pub struct Instance {
+    /// Random id to tell the answers of different instances apart
+    pub id: String,
+    pub started: Instant,
+    /// Hash of the redacted config, to find instances running with a different config
+    pub config_hash: String,
+}
+
+impl Instance {
+    pub fn new(config: &Config) -> Self {
+        Instance {
+            id: Alphanumeric.sample_string(&mut rand::thread_rng(), 12),
+            started: Instant::now(),
+            config_hash: config_hash(config),
+        }
+    }
+
+    pub fn answer(&self, query: &Query) -> Value {
+        match query {
+            Query::Metrics => serde_json::to_value(&METRICS).unwrap(),
+            Query::Uptime => json!(self.started.elapsed().as_secs()),
+            Query::Version => json!(env!("NOTIFY_PUSH_VERSION")),
+            Query::ConfigHash => json!(self.config_hash),
+            Query::Connections => json!({
+                "active_connection_count": METRICS.active_connection_count(),
+                "active_user_count": METRICS.active_user_count(),
+                "total_connection_count": METRICS.total_connection_count(),
+            }),
+        }
+    }
+
+    /// Store the answer for the query under `notify_push_<query>_<instance id>`
+    pub async fn write_answer(&self, redis: &mut RedisConnection, query: &Query) -> Result<()> {
+        let answer = self.answer(query).to_string();
+        let key = format!("notify_push_{}_{}", query, self.id);
+        redis.set_ex(&key, &answer, ANSWER_EXPIRY.as_secs()).await?;
+        // older versions of the app only read the metrics from a single key
+        if matches!(query, Query::Metrics) {
+            redis.set("notify_push_metrics", &answer).await?;
+        }
+        Ok(())
+    }
+}
+
+fn config_hash(config: &Config) -> String {
+    let config = config.redacted().to_json().to_string();
+    format!("{:x}", Sha256::digest(config.as_bytes()))
+}
+
+#[test]
+fn test_query_key_matches_event() {
+    for name in ["metrics", "uptime", "version", "config_hash", "connections"] {
+        let query: Query = serde_json::from_value(json!(name)).unwrap();
+        assert_eq!(name, query.to_string());
+    }
+}
@@ -4,14 +4,34 @@
  */
 
 use crate::error::ConfigError;
+#[cfg(feature = "rustls")]
+use crate::error::TlsError;
 use crate::Result;
 use redis::aio::{MultiplexedConnection, PubSub};
 use redis::cluster::ClusterClient;
 use redis::cluster_async::ClusterConnection;
-use redis::{AsyncCommands, Client, ConnectionInfo, RedisError};
+use redis::{AsyncCommands, Client, ConnectionInfo, RedisError, SetExpiry, SetOptions};
+#[cfg(feature = "rustls")]
+use redis::{ClientTlsConfig, ConnectionAddr, TlsCertificates};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+
+/// Number of databases a redis server has unless configured otherwise
+const DEFAULT_DATABASE_COUNT: i64 = 16;
 
 pub struct Redis {
+    /// Nodes to subscribe to the events on
     config: Vec<ConnectionInfo>,
+    /// Nodes for the key-value commands, if they are configured separately from the pubsub nodes
+    commands: Option<Vec<ConnectionInfo>>,
+    /// Client certificate and CA for the TLS connections
+    #[cfg(feature = "rustls")]
+    certificates: Option<TlsCertificates>,
+    /// Index of the node the last pubsub connection was made to
+    pubsub_node: AtomicUsize,
+    /// Connection shared by the commands send while handling events, so answering a query doesn't need a new connection
+    shared: Mutex<Option<RedisConnection>>,
 }
 
 impl Redis {
@@ -19,36 +39,198 @@ impl Redis {
         if config.is_empty() {
             return Err(ConfigError::NoRedis.into());
         }
-        Ok(Redis { config })
+        Ok(Redis {
+            config,
+            commands: None,
+            #[cfg(feature = "rustls")]
+            certificates: None,
+            pubsub_node: AtomicUsize::new(0),
+            shared: Mutex::default(),
+        })
+    }
+
+    /// Send the key-value commands to different nodes or with different credentials than the subscription
+    ///
+    /// Without command nodes, the pubsub nodes are used for both.
+    pub fn with_command_servers(mut self, commands: Vec<ConnectionInfo>) -> Self {
+        if !commands.is_empty() {
+            self.commands = Some(commands);
+        }
+        self
+    }
+
+    fn commands(&self) -> &[ConnectionInfo] {
+        self.commands.as_deref().unwrap_or(&self.config)
+    }
+
+    /// Use a client certificate or a custom CA for the TLS connections to redis
+    #[cfg(feature = "rustls")]
+    pub fn with_certificates(
+        mut self,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        ca: Option<&Path>,
+    ) -> Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| TlsError::Read(e, path.display().to_string()))
+        };
+        let client_tls = match (cert, key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read(cert)?,
+                client_key: read(key)?,
+            }),
+            _ => None,
+        };
+        let root_cert = ca.map(read).transpose()?;
+        if client_tls.is_some() || root_cert.is_some() {
+            self.certificates = Some(TlsCertificates {
+                client_tls,
+                root_cert,
+            });
+        }
+        Ok(self)
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    pub fn with_certificates(
+        self,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        ca: Option<&Path>,
+    ) -> Result<Self> {
+        if cert.is_some() || key.is_some() || ca.is_some() {
+            return Err(ConfigError::TlsDisabled.into());
+        }
+        Ok(self)
+    }
+
+    /// Client for a single node, with the certificates if the node uses TLS
+    fn client(&self, info: &ConnectionInfo) -> Result<Client, RedisError> {
+        #[cfg(feature = "rustls")]
+        if let (Some(certificates), ConnectionAddr::TcpTls { .. }) =
+            (&self.certificates, &info.addr)
+        {
+            return Client::build_with_tls(info.clone(), certificates.clone());
+        }
+        Client::open(info.clone())
+    }
+
+    fn cluster_client(&self) -> Result<ClusterClient, RedisError> {
+        let builder = ClusterClient::builder(self.commands().to_vec());
+        #[cfg(feature = "rustls")]
+        let builder = match &self.certificates {
+            Some(certificates) => builder.certs(certificates.clone()),
+            None => builder,
+        };
+        builder.build()
     }
 
     /// Get an async pubsub connection
+    ///
+    /// Since pubsub performs a multicast for all nodes in a cluster, listening to a single node is sufficient.
+    /// We keep using the node from the last connection while it's available, and move on to the next node once it fails.
     pub async fn pubsub(&self) -> Result<PubSub, RedisError> {
-        // since pubsub performs a multicast for all nodes in a cluster,
-        // listening to a single server in the cluster is sufficient for cluster setups
-        let client = Client::open(self.config.first().unwrap().clone())?;
-        client.get_async_pubsub().await
+        let start = self.pubsub_node.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for index in (start..self.config.len()).chain(0..start) {
+            let node = &self.config[index];
+            match self.client(node)?.get_async_pubsub().await {
+                Ok(pubsub) => {
+                    if index != start {
+                        log::info!("Subscribing to events from redis node {}", node.addr);
+                    }
+                    self.pubsub_node.store(index, Ordering::Relaxed);
+                    return Ok(pubsub);
+                }
+                Err(e) => {
+                    if self.config.len() > 1 {
+                        log::warn!("Failed to connect to redis node {}: {}", node.addr, e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        // the config is never empty, so there was at least one error
+        Err(last_error.unwrap())
+    }
+
+    /// Subscribe to the keyspace notifications for a key in the database of the commands
+    ///
+    /// Notifications are only sent if `notify-keyspace-events` is enabled on the redis server.
+    pub async fn watch_key(&self, key: &str) -> Result<PubSub, RedisError> {
+        let mut pubsub = self.pubsub().await?;
+        pubsub
+            .subscribe(format!("__keyspace@{}__:{}", self.db(), key))
+            .await?;
+        Ok(pubsub)
     }
 
     pub async fn connect(&self) -> Result<RedisConnection, RedisError> {
-        let connection = match self.config.as_slice() {
+        let connection = match self.commands() {
             [single] => {
-                let client = Client::open(single.clone())?
+                let client = self
+                    .client(single)?
                     .get_multiplexed_async_connection()
                     .await?;
                 RedisConnection::Single(client)
             }
-            config => {
-                let client = ClusterClient::new(config.to_vec())?
-                    .get_async_connection()
-                    .await?;
+            _ => {
+                let client = self.cluster_client()?.get_async_connection().await?;
                 RedisConnection::Cluster(client)
             }
         };
         Ok(connection)
     }
+
+    /// Get the shared command connection, connecting if there is no open connection yet
+    pub async fn shared(&self) -> Result<RedisConnection, RedisError> {
+        let mut shared = self.shared.lock().await;
+        if let Some(connection) = shared.as_ref() {
+            return Ok(connection.clone());
+        }
+        let connection = self.connect().await?;
+        *shared = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Whether the commands are sent to other nodes or with other credentials than the subscription
+    pub fn separate_commands(&self) -> bool {
+        self.commands.is_some()
+    }
+
+    /// Database index the commands are sent to, a cluster only has database 0
+    pub fn db(&self) -> i64 {
+        match self.commands() {
+            [single] => single.redis.db,
+            _ => 0,
+        }
+    }
+
+    /// Connect to all other databases of the server, to detect nextcloud writing to a different database than the push server reads
+    pub async fn other_databases(&self) -> Result<Vec<(i64, RedisConnection)>, RedisError> {
+        let [single] = self.commands() else {
+            return Ok(Vec::new());
+        };
+        let mut connections = Vec::new();
+        for db in (0..DEFAULT_DATABASE_COUNT).filter(|db| *db != single.redis.db) {
+            let mut info = single.clone();
+            info.redis.db = db;
+            // selecting a database fails once we're past the number of databases of the server
+            match self.client(&info)?.get_multiplexed_async_connection().await {
+                Ok(connection) => connections.push((db, RedisConnection::Single(connection))),
+                Err(_) => break,
+            }
+        }
+        Ok(connections)
+    }
+
+    /// Close the shared connection after an error, the next command will open a new connection
+    pub async fn reset_shared(&self) {
+        self.shared.lock().await.take();
+    }
 }
 
+#[derive(Clone)]
 pub enum RedisConnection {
     Single(MultiplexedConnection),
     Cluster(ClusterConnection),
@@ -67,6 +249,13 @@ impl RedisConnection {
         Ok(())
     }
 
+    pub async fn exists(&mut self, key: &str) -> Result<bool, RedisError> {
+        match self {
+            RedisConnection::Single(client) => client.exists(key).await,
+            RedisConnection::Cluster(client) => client.exists(key).await,
+        }
+    }
+
     pub async fn get(&mut self, key: &str) -> Result<String> {
         Ok(match self {
             RedisConnection::Single(client) => client.get(key).await?,
@@ -74,6 +263,46 @@ impl RedisConnection {
         })
     }
 
+    pub async fn get_optional(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(match self {
+            RedisConnection::Single(client) => client.get(key).await?,
+            RedisConnection::Cluster(client) => client.get(key).await?,
+        })
+    }
+
+    /// Get the value of a key and remove it atomically
+    pub async fn get_del(&mut self, key: &str) -> Result<Option<String>> {
+        Ok(match self {
+            RedisConnection::Single(client) => client.get_del(key).await?,
+            RedisConnection::Cluster(client) => client.get_del(key).await?,
+        })
+    }
+
+    pub async fn set_ex(&mut self, key: &str, value: &str, seconds: u64) -> Result<()> {
+        let options = SetOptions::default().with_expiration(SetExpiry::EX(seconds));
+        match self {
+            RedisConnection::Single(client) => {
+                client.set_options::<_, _, ()>(key, value, options).await?;
+            }
+            RedisConnection::Cluster(client) => {
+                client.set_options::<_, _, ()>(key, value, options).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn publish(&mut self, channel: &str, message: &str) -> Result<()> {
+        match self {
+            RedisConnection::Single(client) => {
+                client.publish::<_, _, ()>(channel, message).await?;
+            }
+            RedisConnection::Cluster(client) => {
+                client.publish::<_, _, ()>(channel, message).await?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn set(&mut self, key: &str, value: &str) -> Result<()> {
         match self {
             RedisConnection::Single(client) => {
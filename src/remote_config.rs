@@ -0,0 +1,42 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Push server settings managed from Nextcloud with `occ notify_push:remote-config`.
+//!
+//! When enabled, the settings are loaded from the app at startup and whenever the app sends a `remote_config` event.
+//! Settings the app doesn't provide fall back to the local configuration.
+
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct RemoteConfig {
+    pub max_debounce_time: Option<usize>,
+    pub max_connection_time: Option<usize>,
+    /// Storage updates for paths starting with any of these prefixes are not send to the clients
+    #[serde(default)]
+    pub excluded_paths: Vec<String>,
+}
+
+impl RemoteConfig {
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.excluded_paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+#[test]
+fn test_excluded_paths() {
+    let config: RemoteConfig = serde_json::from_str(
+        r#"{"max_debounce_time": 30, "excluded_paths": ["appdata_", "files_trashbin/"]}"#,
+    )
+    .unwrap();
+    assert_eq!(Some(30), config.max_debounce_time);
+    assert_eq!(None, config.max_connection_time);
+    assert!(config.is_excluded("appdata_abc/preview/1"));
+    assert!(config.is_excluded("files_trashbin/files/foo"));
+    assert!(!config.is_excluded("files/appdata_"));
+    assert!(!RemoteConfig::default().is_excluded("appdata_abc"));
+}
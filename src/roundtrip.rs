@@ -0,0 +1,163 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Verification of the path from nextcloud through redis to the push server, using the test cookie.
+//!
+//! The self test of the Nextcloud app stores a random cookie, publishes it as `notify_test_cookie` event and reads it
+//! back from `/test/cookie`, while `/test/reverse_cookie` has the push server read the cookie from nextcloud.
+//! [`App::verify_roundtrip`] runs both legs from within the push server and times them: it reads the current cookie
+//! from nextcloud and publishes it again, waiting until the listener received it.
+
+use crate::message::serialize_millis;
+use crate::App;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// Maximum time to wait for the published cookie to come back from redis
+const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The last test cookie received from redis
+pub struct TestCookie {
+    value: watch::Sender<u32>,
+    /// Cookie published by [`App::verify_roundtrip`] that hasn't come back yet
+    own: Mutex<Option<u32>>,
+}
+
+impl Default for TestCookie {
+    fn default() -> Self {
+        TestCookie {
+            value: watch::Sender::new(0),
+            own: Mutex::default(),
+        }
+    }
+}
+
+impl TestCookie {
+    pub fn get(&self) -> u32 {
+        *self.value.borrow()
+    }
+
+    /// Store a cookie received from redis, returns whether it was published by nextcloud instead of the push server
+    pub fn receive(&self, cookie: u32) -> bool {
+        let own = {
+            let mut own = self.own.lock().unwrap();
+            if *own == Some(cookie) {
+                own.take().is_some()
+            } else {
+                false
+            }
+        };
+        // sent even if the value doesn't change, so a waiting roundtrip sees it
+        self.value.send_replace(cookie);
+        !own
+    }
+}
+
+/// Result of a single leg of the roundtrip
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoundtripLeg {
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+impl RoundtripLeg {
+    fn new<E: std::fmt::Display>(start: Instant, result: Result<(), E>) -> Self {
+        RoundtripLeg {
+            latency: start.elapsed(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Roundtrip {
+    /// The test cookie as stored in nextcloud
+    pub cookie: Option<u32>,
+    /// Reading the test cookie from nextcloud
+    pub nextcloud: RoundtripLeg,
+    /// Publishing the cookie to redis until it's received by the push server, skipped if nextcloud failed
+    pub redis: Option<RoundtripLeg>,
+}
+
+impl Roundtrip {
+    pub fn is_ok(&self) -> bool {
+        self.nextcloud.is_ok() && self.redis.as_ref().is_some_and(RoundtripLeg::is_ok)
+    }
+}
+
+impl App {
+    /// Verify that the push server can reach nextcloud and receives the events published in redis
+    pub async fn verify_roundtrip(&self) -> Roundtrip {
+        let start = Instant::now();
+        let cookie = self.nc_client.get_test_cookie().await;
+        let nextcloud = RoundtripLeg::new(start, cookie.as_ref().map(|_| ()));
+        let Ok(cookie) = cookie else {
+            return Roundtrip {
+                cookie: None,
+                nextcloud,
+                redis: None,
+            };
+        };
+
+        let start = Instant::now();
+        let redis = self.publish_test_cookie(cookie).await;
+        Roundtrip {
+            cookie: Some(cookie),
+            nextcloud,
+            redis: Some(RoundtripLeg::new(start, redis)),
+        }
+    }
+
+    async fn publish_test_cookie(&self, cookie: u32) -> Result<(), String> {
+        let mut received = self.test_cookie.value.subscribe();
+        received.mark_unchanged();
+        *self.test_cookie.own.lock().unwrap() = Some(cookie);
+
+        let mut client = self.redis.connect().await.map_err(|e| e.to_string())?;
+        client
+            .publish("notify_test_cookie", &cookie.to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+        let wait = async {
+            while received.changed().await.is_ok() {
+                if *received.borrow_and_update() == cookie {
+                    return true;
+                }
+            }
+            false
+        };
+        match timeout(REDIS_TIMEOUT, wait).await {
+            Ok(true) => Ok(()),
+            _ => {
+                self.test_cookie.own.lock().unwrap().take();
+                Err(format!(
+                    "the published cookie wasn't received within {}s",
+                    REDIS_TIMEOUT.as_secs()
+                ))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_receive_own_cookie() {
+    let test_cookie = TestCookie::default();
+    assert!(test_cookie.receive(5));
+    assert_eq!(5, test_cookie.get());
+
+    *test_cookie.own.lock().unwrap() = Some(6);
+    assert!(test_cookie.receive(7));
+    assert!(!test_cookie.receive(6));
+    assert_eq!(6, test_cookie.get());
+    assert!(test_cookie.receive(6));
+}
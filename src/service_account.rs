@@ -0,0 +1,175 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Service accounts receive the messages for a declared set of users and groups, for consumers like a digital signage
+//! dashboard that watches a shared folder without needing the app password of a real user.
+//!
+//! The accounts are defined in the token file and connect as a pseudo user that can't clash with a Nextcloud user,
+//! messages are copied to them when they are send to one of the users in their scope.
+
+use crate::error::DatabaseError;
+use crate::storage_mapping::StorageMapping;
+use crate::UserId;
+use ahash::RandomState;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Nextcloud user ids can't contain a colon
+const SERVICE_ACCOUNT_PREFIX: &str = "service:";
+
+/// The pseudo user a service account connects as
+pub fn service_account_id(name: &str) -> UserId {
+    UserId::new(&format!("{}{}", SERVICE_ACCOUNT_PREFIX, name))
+}
+
+/// Users and groups a service account receives the messages for
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceScope {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceAccount {
+    id: UserId,
+    scope: ServiceScope,
+}
+
+impl ServiceAccount {
+    pub fn new(name: &str, scope: ServiceScope) -> Self {
+        ServiceAccount {
+            id: service_account_id(name),
+            scope,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ServiceAccounts {
+    accounts: Vec<ServiceAccount>,
+    /// The service accounts watching each user, including the members of the watched groups
+    watchers: RwLock<HashMap<UserId, Vec<UserId>, RandomState>>,
+}
+
+impl ServiceAccounts {
+    pub fn new(accounts: Vec<ServiceAccount>) -> Self {
+        let service_accounts = ServiceAccounts {
+            accounts,
+            watchers: RwLock::default(),
+        };
+        service_accounts.set_group_members(&HashMap::new());
+        service_accounts
+    }
+
+    fn has_groups(&self) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| !account.scope.groups.is_empty())
+    }
+
+    /// Whether the members of the group need to be loaded again when the group changes
+    pub fn watches_group(&self, group: &str) -> bool {
+        self.accounts
+            .iter()
+            .any(|account| account.scope.groups.iter().any(|watched| watched == group))
+    }
+
+    /// Load the members of the watched groups from the database
+    pub async fn load_groups(&self, mapping: &StorageMapping) -> Result<(), DatabaseError> {
+        if !self.has_groups() {
+            return Ok(());
+        }
+        let mut members = HashMap::new();
+        for account in &self.accounts {
+            for group in &account.scope.groups {
+                if !members.contains_key(group) {
+                    members.insert(group.clone(), mapping.get_group_members(group).await?);
+                }
+            }
+        }
+        self.set_group_members(&members);
+        Ok(())
+    }
+
+    fn set_group_members(&self, members: &HashMap<String, Vec<UserId>>) {
+        let mut watchers: HashMap<UserId, Vec<UserId>, RandomState> = HashMap::default();
+        for account in &self.accounts {
+            let users = account.scope.users.iter().map(|user| UserId::new(user));
+            let group_members = account
+                .scope
+                .groups
+                .iter()
+                .filter_map(|group| members.get(group))
+                .flatten()
+                .cloned();
+            for user in users.chain(group_members) {
+                let watching = watchers.entry(user).or_default();
+                if !watching.contains(&account.id) {
+                    watching.push(account.id.clone());
+                }
+            }
+        }
+        *self.watchers.write().unwrap() = watchers;
+    }
+
+    /// The service accounts that receive the messages for the user
+    pub fn watchers(&self, user: &UserId) -> Vec<UserId> {
+        if self.accounts.is_empty() {
+            return Vec::new();
+        }
+        self.watchers
+            .read()
+            .unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[test]
+fn test_service_account_watchers() {
+    let accounts = ServiceAccounts::new(vec![
+        ServiceAccount::new(
+            "signage",
+            ServiceScope {
+                users: vec!["alice".into()],
+                groups: vec!["lobby".into()],
+            },
+        ),
+        ServiceAccount::new(
+            "wall",
+            ServiceScope {
+                users: vec!["alice".into(), "bob".into()],
+                groups: vec![],
+            },
+        ),
+    ]);
+    let signage = service_account_id("signage");
+    let wall = service_account_id("wall");
+    assert_eq!(
+        vec![signage.clone(), wall.clone()],
+        accounts.watchers(&UserId::new("alice"))
+    );
+    assert_eq!(vec![wall.clone()], accounts.watchers(&UserId::new("bob")));
+    assert!(accounts.watchers(&UserId::new("carol")).is_empty());
+    assert!(accounts.watches_group("lobby"));
+    assert!(!accounts.watches_group("admin"));
+
+    accounts.set_group_members(&HashMap::from([(
+        "lobby".to_string(),
+        vec![UserId::new("carol"), UserId::new("alice")],
+    )]));
+    assert_eq!(
+        vec![signage.clone()],
+        accounts.watchers(&UserId::new("carol"))
+    );
+    assert_eq!(
+        vec![signage, wall],
+        accounts.watchers(&UserId::new("alice"))
+    );
+
+    // the pseudo user of a service account is never a nextcloud user
+    assert_ne!(UserId::new("signage"), service_account_id("signage"));
+}
@@ -0,0 +1,99 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use crate::connection::ActiveConnections;
+use crate::redis::Redis;
+use crate::{Result, UserId};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_KEY: &str = "notify_push_session_snapshot";
+/// How long a snapshot can be used to resume sessions, both for the snapshot stored in redis
+/// and for clients reconnecting after the restart
+pub const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default, Serialize, Deserialize)]
+struct SessionSnapshot {
+    users: Vec<String>,
+}
+
+/// Key the snapshot is stored under, instances sharing a redis server are told apart by their name
+/// (or host name) and worker processes by their index
+pub fn snapshot_key(instance_name: Option<&str>, worker: Option<usize>) -> String {
+    let mut key = String::from(SNAPSHOT_KEY);
+    if let Some(name) = instance_name.map(String::from).or_else(host_name) {
+        key.push('_');
+        key.push_str(&name);
+    }
+    if let Some(index) = worker {
+        key.push_str(&format!("_{}", index));
+    }
+    key
+}
+
+fn host_name() -> Option<String> {
+    dotenvy::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Store the currently connected users in redis
+pub async fn save_snapshot(
+    redis: &Redis,
+    key: &str,
+    connections: &ActiveConnections,
+) -> Result<usize> {
+    let snapshot = SessionSnapshot {
+        users: connections.users().filter_map(|user| user.name()).collect(),
+    };
+    let mut client = redis.connect().await?;
+    client
+        .set_ex(
+            key,
+            &serde_json::to_string(&snapshot).unwrap(),
+            RESUME_WINDOW.as_secs(),
+        )
+        .await?;
+    Ok(snapshot.users.len())
+}
+
+/// Load the snapshot stored by a previous instance during shutdown
+pub async fn load_snapshot(
+    redis: &Redis,
+    key: &str,
+    connections: &ActiveConnections,
+) -> Result<usize> {
+    let mut client = redis.connect().await?;
+    let Some(snapshot) = client.get_del(key).await? else {
+        return Ok(0);
+    };
+    let snapshot: SessionSnapshot = match serde_json::from_str(&snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            log::warn!("Ignoring invalid session snapshot: {}", e);
+            return Ok(0);
+        }
+    };
+    let count = snapshot.users.len();
+    connections.resume(
+        snapshot.users.into_iter().map(UserId::from),
+        Instant::now() + RESUME_WINDOW,
+    );
+    Ok(count)
+}
+
+#[test]
+fn test_snapshot_key() {
+    assert_eq!(
+        "notify_push_session_snapshot_push1",
+        snapshot_key(Some("push1"), None)
+    );
+    assert_eq!(
+        "notify_push_session_snapshot_push1_2",
+        snapshot_key(Some("push1"), Some(2))
+    );
+}
@@ -0,0 +1,269 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Guided setup, checking that nextcloud, the database and redis can be reached before
+//! installing the push server as a systemd service and registering it with the app.
+//!
+//! All questions can be answered with flags, when not running in a terminal the defaults are used.
+
+use crate::config::{Bind, Config};
+use crate::error::SetupError;
+use crate::nc::Client;
+use crate::redis::Redis;
+use crate::storage_mapping::StorageMapping;
+use crate::Result;
+use clap::Args;
+use std::fmt::Write as _;
+use std::fs::{metadata, read_to_string, write};
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Number of ports after the configured one that are tried when it's already in use
+const PORT_ATTEMPTS: u16 = 20;
+
+#[derive(Args, Debug, Clone)]
+pub struct SetupOpt {
+    /// The public url of the push server, defaults to the nextcloud url with `push` appended
+    #[clap(long)]
+    pub endpoint: Option<Url>,
+    /// Path to write the systemd unit to
+    #[clap(long, default_value = "/etc/systemd/system/notify_push.service")]
+    pub unit_file: PathBuf,
+    /// Write the environment variables to a separate file that is loaded by the unit
+    #[clap(long)]
+    pub env_file: Option<PathBuf>,
+    /// The system user to run the push server as, defaults to the owner of the nextcloud config
+    #[clap(long)]
+    pub user: Option<String>,
+    /// Nextcloud admin user to register the endpoint with, the password is read from `NEXTCLOUD_ADMIN_PASSWORD`
+    #[clap(long)]
+    pub admin_user: Option<String>,
+    /// Don't ask for confirmation
+    #[clap(long, short)]
+    pub yes: bool,
+}
+
+/// Ask a question on the terminal, returns `None` for an empty answer or when not running interactively
+fn prompt(question: &str) -> Result<Option<String>, SetupError> {
+    if !stdin().is_terminal() {
+        return Ok(None);
+    }
+    print!("{}: ", question);
+    stdout().flush().map_err(SetupError::Input)?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).map_err(SetupError::Input)?;
+    let answer = answer.trim();
+    Ok((!answer.is_empty()).then(|| answer.to_string()))
+}
+
+fn confirm(question: &str, yes: bool) -> Result<bool, SetupError> {
+    if yes {
+        return Ok(true);
+    }
+    Ok(prompt(&format!("{} [Y/n]", question))?
+        .map_or(true, |answer| answer.eq_ignore_ascii_case("y")))
+}
+
+/// Name of the user owning `path`, looked up in `/etc/passwd`
+fn file_owner(path: &Path) -> Option<String> {
+    let uid = metadata(path).ok()?.uid().to_string();
+    read_to_string("/etc/passwd")
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            (fields.nth(1)? == uid).then(|| name.to_string())
+        })
+}
+
+/// The configured address if it's free, or the next free port
+fn free_address(addr: SocketAddr) -> Option<SocketAddr> {
+    (0..PORT_ATTEMPTS)
+        .filter_map(|offset| addr.port().checked_add(offset))
+        .map(|port| SocketAddr::new(addr.ip(), port))
+        .find(|addr| TcpListener::bind(addr).is_ok())
+}
+
+fn systemd_unit(
+    binary: &Path,
+    config_file: &Path,
+    user: &str,
+    env: &[(&str, String)],
+    env_file: Option<&Path>,
+) -> String {
+    let mut unit = String::from(
+        "[Unit]\nDescription = Push daemon for Nextcloud clients\nDocumentation = https://github.com/nextcloud/notify_push\n\n[Service]\n",
+    );
+    match env_file {
+        Some(env_file) => {
+            let _ = writeln!(unit, "EnvironmentFile = {}", env_file.display());
+        }
+        None => {
+            for (name, value) in env {
+                let _ = writeln!(unit, "Environment = {}={}", name, value);
+            }
+        }
+    }
+    let _ = writeln!(
+        unit,
+        "ExecStart = {} {}",
+        binary.display(),
+        config_file.display()
+    );
+    if cfg!(feature = "systemd") {
+        unit.push_str("Type = notify\n");
+    }
+    let _ = writeln!(unit, "User = {}", user);
+    unit.push_str("Restart = always\nRestartSec = 60\n\n[Install]\nWantedBy = multi-user.target\n");
+    unit
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), SetupError> {
+    write(path, content).map_err(|e| SetupError::Write(e, path.display().to_string()))?;
+    println!("✓ Wrote {}", path.display());
+    Ok(())
+}
+
+pub async fn setup(config: Config, config_file: Option<PathBuf>, opt: SetupOpt) -> Result<()> {
+    let config_file = config_file.ok_or(SetupError::NoConfigFile)?;
+
+    println!("Checking nextcloud at {}", config.nextcloud_url);
+    let client = Client::new(&config.nextcloud_url, config.allow_self_signed)?;
+    client.ping().await?;
+    client.get_test_cookie().await?;
+    println!("✓ Nextcloud is reachable and the notify_push app is enabled");
+
+    StorageMapping::new(config.database.clone(), config.database_prefix.clone())
+        .await?
+        .ping()
+        .await?;
+    println!("✓ Connected to the database");
+
+    let mut redis = Redis::new(config.redis.clone())?
+        .with_command_servers(config.redis_command.clone())
+        .with_certificates(
+            config.redis_tls_cert.as_deref(),
+            config.redis_tls_key.as_deref(),
+            config.redis_tls_ca.as_deref(),
+        )?
+        .connect()
+        .await?;
+    redis.set_ex("notify_push_setup", "1", 10).await?;
+    println!("✓ Connected to redis");
+
+    let env = match &config.bind {
+        Bind::Tcp(addr) => {
+            let free = free_address(*addr).ok_or(SetupError::NoFreePort(*addr))?;
+            if free.port() != addr.port() {
+                println!(
+                    "! Port {} is already in use, using port {} instead",
+                    addr.port(),
+                    free.port()
+                );
+            } else {
+                println!("✓ Port {} is available", free.port());
+            }
+            vec![("PORT", free.port().to_string())]
+        }
+        Bind::Unix(path, permissions) => vec![
+            ("SOCKET_PATH", path.display().to_string()),
+            ("SOCKET_PERMISSIONS", format!("0{:o}", permissions)),
+        ],
+    };
+
+    let default_endpoint = format!("{}push", config.nextcloud_url);
+    let endpoint = match opt.endpoint {
+        Some(endpoint) => endpoint.to_string(),
+        None => prompt(&format!(
+            "Public url of the push server [{}]",
+            default_endpoint
+        ))?
+        .unwrap_or(default_endpoint),
+    };
+    let user = opt
+        .user
+        .or_else(|| file_owner(&config_file))
+        .unwrap_or_else(|| String::from("www-data"));
+    let binary = std::env::current_exe()
+        .unwrap_or_else(|_| PathBuf::from(std::env::args().next().unwrap_or_default()));
+
+    let unit = systemd_unit(&binary, &config_file, &user, &env, opt.env_file.as_deref());
+    if confirm(
+        &format!("Write systemd unit to {}?", opt.unit_file.display()),
+        opt.yes,
+    )? {
+        if let Some(env_file) = &opt.env_file {
+            let content = env
+                .iter()
+                .map(|(name, value)| format!("{}={}\n", name, value))
+                .collect::<String>();
+            write_file(env_file, &content)?;
+        }
+        write_file(&opt.unit_file, &unit)?;
+    } else {
+        println!("\n{}", unit);
+    }
+
+    let password = std::env::var("NEXTCLOUD_ADMIN_PASSWORD").ok();
+    match (opt.admin_user, password) {
+        (Some(admin), Some(password)) => {
+            client
+                .set_base_endpoint(&admin, &password, &endpoint)
+                .await?;
+            println!("✓ Registered {} as push server endpoint", endpoint);
+        }
+        _ => println!(
+            "! Not registering the endpoint, run `occ notify_push:setup {}` to register it",
+            endpoint
+        ),
+    }
+
+    println!("\nStart the push server with `systemctl daemon-reload && systemctl enable --now notify_push`");
+    println!(
+        "and make sure your reverse proxy forwards {} to the push server",
+        endpoint
+    );
+    Ok(())
+}
+
+#[test]
+fn test_systemd_unit() {
+    let env = [("PORT", String::from("7867"))];
+    let unit = systemd_unit(
+        Path::new("/usr/bin/notify_push"),
+        Path::new("/var/www/nextcloud/config/config.php"),
+        "www-data",
+        &env,
+        None,
+    );
+    assert!(unit.contains("Environment = PORT=7867\n"));
+    assert!(
+        unit.contains("ExecStart = /usr/bin/notify_push /var/www/nextcloud/config/config.php\n")
+    );
+    assert!(unit.contains("User = www-data\n"));
+
+    let unit = systemd_unit(
+        Path::new("/usr/bin/notify_push"),
+        Path::new("/var/www/nextcloud/config/config.php"),
+        "www-data",
+        &env,
+        Some(Path::new("/etc/notify_push.env")),
+    );
+    assert!(unit.contains("EnvironmentFile = /etc/notify_push.env\n"));
+    assert!(!unit.contains("PORT"));
+}
+
+#[test]
+fn test_free_address() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken = listener.local_addr().unwrap();
+    let free = free_address(taken).unwrap();
+    assert_ne!(taken, free);
+    assert!(free.port() > taken.port());
+}
@@ -0,0 +1,166 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Pinning the runtime threads to cpus and sharding the connections over multiple runtimes, for large NUMA servers.
+//!
+//! At very high connection counts, the memory traffic between the cpu sockets becomes a bottleneck when the tasks of a
+//! connection move freely between all cores. With a shard per socket, each pinned to the cpus of that socket, the work
+//! of every connection stays on a single socket. Connections are assigned to a shard by the stable hash of their user,
+//! so all connections of a user share the same shard.
+
+use crate::error::CpuSetError;
+use crate::metrics::METRICS;
+use crate::UserId;
+use core_affinity::CoreId;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+/// A set of cpus, written as a list of cpu numbers and ranges like `0-15,32-47`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(Vec<usize>);
+
+impl FromStr for CpuSet {
+    type Err = CpuSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || CpuSetError(s.into());
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start: usize = start.trim().parse().map_err(|_| error())?;
+            let end: usize = end.trim().parse().map_err(|_| error())?;
+            if start > end {
+                return Err(error());
+            }
+            cpus.extend(start..=end);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet(cpus))
+    }
+}
+
+impl Display for CpuSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        let mut cpus = self.0.iter().copied().peekable();
+        while let Some(start) = cpus.next() {
+            let mut end = start;
+            while cpus.peek() == Some(&(end + 1)) {
+                end = cpus.next().unwrap();
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CpuSet {
+    /// Start a worker thread for every cpu in the set, each pinned to one of the cpus
+    pub fn pin(&self, builder: &mut Builder) {
+        let cpus = Arc::new(self.0.clone());
+        let next = AtomicUsize::new(0);
+        builder
+            .worker_threads(self.0.len())
+            .on_thread_start(move || {
+                let cpu = cpus[next.fetch_add(1, Ordering::Relaxed) % cpus.len()];
+                if !core_affinity::set_for_current(CoreId { id: cpu }) {
+                    log::warn!("Failed to pin runtime thread to cpu {}", cpu);
+                }
+            });
+    }
+}
+
+/// Runtimes the connections are distributed over after they are authenticated
+#[derive(Default)]
+pub struct Shards {
+    runtimes: Vec<Runtime>,
+}
+
+impl Shards {
+    pub fn new(cpu_sets: &[CpuSet]) -> std::io::Result<Self> {
+        let runtimes = cpu_sets
+            .iter()
+            .enumerate()
+            .map(|(index, cpus)| {
+                let mut builder = Builder::new_multi_thread();
+                cpus.pin(&mut builder);
+                builder
+                    .thread_name(format!("notify_push-shard-{}", index))
+                    .enable_all()
+                    .build()
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Shards { runtimes })
+    }
+
+    /// Run the connection of the user on its shard, or on the current runtime when not sharding
+    pub async fn run<F>(&self, user: &UserId, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if self.runtimes.is_empty() {
+            return connection.await;
+        }
+        let shard = user.shard(self.runtimes.len());
+        let result = self.runtimes[shard]
+            .spawn(async move {
+                METRICS.add_shard_connection(shard);
+                connection.await;
+                METRICS.remove_shard_connection(shard);
+            })
+            .await;
+        if let Err(e) = result {
+            log::error!("Connection for {} on shard {} failed: {}", user, shard, e);
+        }
+    }
+}
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        // dropping a runtime blocks until its tasks are done, which isn't allowed from within the main runtime
+        for runtime in self.runtimes.drain(..) {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[test]
+fn test_parse_cpu_set() {
+    let set: CpuSet = "0-3, 8,10-11,2".parse().unwrap();
+    assert_eq!(CpuSet(vec![0, 1, 2, 3, 8, 10, 11]), set);
+    assert_eq!("0-3,8,10-11", set.to_string());
+    assert_eq!(set, set.to_string().parse().unwrap());
+    assert!("3-1".parse::<CpuSet>().is_err());
+    assert!("".parse::<CpuSet>().is_err());
+    assert!("a-b".parse::<CpuSet>().is_err());
+}
+
+#[tokio::test]
+async fn test_shard_connections() {
+    let shards = Shards::new(&["0".parse().unwrap(), "0".parse().unwrap()]).unwrap();
+    let user = UserId::new("foo");
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    shards
+        .run(&user, async move {
+            tx.send(std::thread::current().name().map(String::from))
+                .ok();
+        })
+        .await;
+    let thread = rx.await.unwrap().unwrap();
+    assert_eq!(format!("notify_push-shard-{}", user.shard(2)), thread);
+}
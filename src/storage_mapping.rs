@@ -7,15 +7,23 @@ use crate::error::DatabaseError;
 use crate::metrics::METRICS;
 use crate::{Result, UserId};
 use ahash::RandomState;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
+use futures::{stream, StreamExt};
 use log::debug;
 use rand::{thread_rng, Rng};
 use sqlx::any::AnyConnectOptions;
-use sqlx::{query_as, Any, AnyPool, FromRow};
+use sqlx::{query, query_as, Any, AnyPool, FromRow};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 use tokio::time::Duration;
 
+/// Number of storage mappings that are loaded in parallel while warming up the cache
+const WARM_UP_CONCURRENCY: usize = 4;
+
 #[derive(Debug, Clone, FromRow)]
 pub struct UserStorageAccess {
     #[sqlx(rename = "user_id")]
@@ -27,15 +35,18 @@ pub struct UserStorageAccess {
 struct CachedAccess {
     access: Vec<UserStorageAccess>,
     valid_till: Instant,
+    /// Position of the last lookup for this storage, higher is more recent
+    last_used: AtomicU64,
 }
 
 impl CachedAccess {
-    pub fn new(access: Vec<UserStorageAccess>) -> Self {
+    pub fn new(access: Vec<UserStorageAccess>, last_used: u64) -> Self {
         let mut rng = thread_rng();
         Self {
             access,
             valid_till: Instant::now()
                 + Duration::from_millis(rng.gen_range((4 * 60 * 1000)..(5 * 60 * 1000))),
+            last_used: AtomicU64::new(last_used),
         }
     }
 
@@ -44,18 +55,49 @@ impl CachedAccess {
     }
 }
 
+/// Shared database pool that can be replaced when the database credentials change
+#[derive(Clone)]
+pub struct DatabasePool(Arc<RwLock<AnyPool>>);
+
+impl DatabasePool {
+    pub fn new(pool: AnyPool) -> Self {
+        DatabasePool(Arc::new(RwLock::new(pool)))
+    }
+
+    pub fn get(&self) -> AnyPool {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Connect with the new options and switch all new queries over to the new pool
+    ///
+    /// Queries that are already running on the old pool are allowed to finish before it is closed
+    pub async fn replace(&self, options: AnyConnectOptions) -> Result<(), DatabaseError> {
+        let pool = AnyPool::connect_with(options)
+            .await
+            .map_err(DatabaseError::Connect)?;
+        let old = std::mem::replace(&mut *self.0.write().unwrap(), pool);
+        tokio::spawn(async move {
+            old.close().await;
+            debug!("old database pool closed");
+        });
+        Ok(())
+    }
+}
+
 pub struct StorageMapping {
     cache: DashMap<u32, CachedAccess, RandomState>,
-    connection: AnyPool,
+    connection: DatabasePool,
     prefix: String,
+    lookups: AtomicU64,
 }
 
 impl StorageMapping {
     pub fn from_connection(connection: AnyPool, prefix: String) -> Self {
         Self {
             cache: Default::default(),
-            connection,
+            connection: DatabasePool::new(connection),
             prefix,
+            lookups: AtomicU64::new(0),
         }
     }
 
@@ -67,20 +109,132 @@ impl StorageMapping {
         Ok(Self::from_connection(connection, prefix))
     }
 
+    pub fn connection(&self) -> &DatabasePool {
+        &self.connection
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Check that the database is still reachable
+    pub async fn ping(&self) -> Result<(), DatabaseError> {
+        query("SELECT 1")
+            .execute(&self.connection.get())
+            .await
+            .map_err(DatabaseError::Query)?;
+        Ok(())
+    }
+
+    /// The public url of the push server as configured in the app
+    pub async fn get_base_endpoint(&self) -> Result<Option<String>, DatabaseError> {
+        let endpoint = query_as::<Any, (String,)>(&format!(
+            "\
+                SELECT configvalue \
+                FROM {prefix}appconfig \
+                WHERE appid = 'notify_push' AND configkey = 'base_endpoint'",
+            prefix = self.prefix,
+        ))
+        .fetch_optional(&self.connection.get())
+        .await
+        .map_err(DatabaseError::Query)?;
+        Ok(endpoint
+            .map(|(endpoint,)| endpoint)
+            .filter(|endpoint| !endpoint.is_empty()))
+    }
+
+    /// The users that are a member of a group
+    pub async fn get_group_members(&self, group: &str) -> Result<Vec<UserId>, DatabaseError> {
+        let connection = self.connection.get();
+        let placeholder = if connection
+            .connect_options()
+            .database_url
+            .scheme()
+            .starts_with("postgres")
+        {
+            "$1"
+        } else {
+            "?"
+        };
+        let members = query_as::<Any, (UserId,)>(&format!(
+            "SELECT uid FROM {prefix}group_user WHERE gid = {placeholder}",
+            prefix = self.prefix,
+        ))
+        .bind(group)
+        .fetch_all(&connection)
+        .await
+        .map_err(DatabaseError::Query)?;
+        Ok(members.into_iter().map(|(user,)| user).collect())
+    }
+
     async fn get_storage_mapping(
         &self,
         storage: u32,
     ) -> Result<Ref<'_, u32, CachedAccess>, DatabaseError> {
+        let lookup = self.lookups.fetch_add(1, Ordering::Relaxed);
         if let Some(cached) = self.cache.get(&storage).filter(|cached| cached.is_valid()) {
+            cached.last_used.store(lookup, Ordering::Relaxed);
             Ok(cached)
         } else {
             let users = self.load_storage_mapping(storage).await?;
 
-            self.cache.insert(storage, CachedAccess::new(users));
+            self.cache.insert(storage, CachedAccess::new(users, lookup));
             Ok(self.cache.get(&storage).unwrap())
         }
     }
 
+    /// The `count` most recently used storages in the cache
+    pub fn recent_storages(&self, count: usize) -> Vec<u32> {
+        let mut storages = self
+            .cache
+            .iter()
+            .map(|cached| (*cached.key(), cached.last_used.load(Ordering::Relaxed)))
+            .collect::<Vec<_>>();
+        storages.sort_unstable_by_key(|(_, last_used)| Reverse(*last_used));
+        storages
+            .into_iter()
+            .take(count)
+            .map(|(storage, _)| storage)
+            .collect()
+    }
+
+    /// Load the mappings for the storages into the cache, returns the number of storages that were loaded
+    ///
+    /// The storages are loaded in order, so the ones that should be available first should come first.
+    pub async fn warm_up(&self, storages: Vec<u32>) -> usize {
+        stream::iter(storages)
+            .map(|storage| async move {
+                if self
+                    .cache
+                    .get(&storage)
+                    .is_some_and(|cached| cached.is_valid())
+                {
+                    return true;
+                }
+                match self.load_storage_mapping(storage).await {
+                    Ok(users) => {
+                        // storages that were looked up in the meantime are more recent than the ones we load
+                        match self.cache.entry(storage) {
+                            Entry::Occupied(cached) if cached.get().is_valid() => {}
+                            entry => {
+                                entry.insert(CachedAccess::new(users, 0));
+                            }
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        debug!("failed to warm up storage mapping for {}: {}", storage, e);
+                        false
+                    }
+                }
+            })
+            .buffered(WARM_UP_CONCURRENCY)
+            .filter(|loaded| futures::future::ready(*loaded))
+            .count()
+            .await
+    }
+
+    #[cfg_attr(feature = "otel", tracing::instrument(skip(self)))]
     pub async fn get_users_for_storage_path(
         &self,
         storage: u32,
@@ -115,7 +269,7 @@ impl StorageMapping {
             prefix = self.prefix,
             storage = storage
         ))
-        .fetch_all(&self.connection)
+        .fetch_all(&self.connection.get())
         .await
         .map_err(DatabaseError::Query)?;
         METRICS.add_mapping_query();
@@ -0,0 +1,147 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Event counts per storage, to find the storages responsible for a flood of storage updates.
+//!
+//! Only a bounded number of storages is tracked. Counts decay over time so storages that were busy a while ago
+//! make room for the ones that are busy now.
+
+use ahash::RandomState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time after which half of the counted events are forgotten
+const HALF_LIFE: Duration = Duration::from_secs(5 * 60);
+
+struct Entry {
+    score: f64,
+    total: u64,
+    updated: Instant,
+}
+
+impl Entry {
+    fn score_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.score * 0.5f64.powf(elapsed.as_secs_f64() / HALF_LIFE.as_secs_f64())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StorageActivity {
+    pub storage: u32,
+    /// Number of recent events, with older events counting less
+    pub score: f64,
+    /// Number of events since the storage started being tracked
+    pub total: u64,
+}
+
+pub struct StorageStats {
+    capacity: usize,
+    entries: Mutex<HashMap<u32, Entry, RandomState>>,
+}
+
+impl StorageStats {
+    /// Track up to `capacity` storages
+    pub fn new(capacity: usize) -> Self {
+        StorageStats {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn record(&self, storage: u32) {
+        self.record_at(storage, Instant::now());
+    }
+
+    fn record_at(&self, storage: u32, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(storage).or_insert(Entry {
+            score: 0.0,
+            total: 0,
+            updated: now,
+        });
+        entry.score = entry.score_at(now) + 1.0;
+        entry.total += 1;
+        entry.updated = now;
+
+        // allow some slack so we don't have to prune on every new storage
+        if entries.len() > self.capacity * 2 {
+            let mut scores = entries
+                .iter()
+                .map(|(storage, entry)| (*storage, entry.score_at(now)))
+                .collect::<Vec<_>>();
+            scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+            for (storage, _) in &scores[self.capacity..] {
+                entries.remove(storage);
+            }
+        }
+    }
+
+    /// The `count` storages with the most recent events, busiest first
+    pub fn top(&self, count: usize) -> Vec<StorageActivity> {
+        self.top_at(count, Instant::now())
+    }
+
+    fn top_at(&self, count: usize, now: Instant) -> Vec<StorageActivity> {
+        let entries = self.entries.lock().unwrap();
+        let mut top = entries
+            .iter()
+            .map(|(storage, entry)| StorageActivity {
+                storage: *storage,
+                score: entry.score_at(now),
+                total: entry.total,
+            })
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.score.total_cmp(&a.score));
+        top.truncate(count.min(self.capacity));
+        top
+    }
+}
+
+#[test]
+fn test_storage_stats_top() {
+    let stats = StorageStats::new(2);
+    let start = Instant::now();
+    for _ in 0..10 {
+        stats.record_at(1, start);
+    }
+    for _ in 0..5 {
+        stats.record_at(2, start);
+    }
+    stats.record_at(3, start);
+
+    let top = stats.top_at(10, start);
+    assert_eq!(
+        vec![1, 2],
+        top.iter().map(|s| s.storage).collect::<Vec<_>>()
+    );
+    assert_eq!(10.0, top[0].score);
+    assert_eq!(10, top[0].total);
+
+    // after a half life the old events count for half
+    let later = start + HALF_LIFE;
+    for _ in 0..6 {
+        stats.record_at(2, later);
+    }
+    let top = stats.top_at(10, later);
+    assert_eq!(2, top[0].storage);
+    assert_eq!(8.5, top[0].score);
+    assert_eq!(11, top[0].total);
+    assert_eq!(5.0, top[1].score);
+}
+
+#[test]
+fn test_storage_stats_capacity() {
+    let stats = StorageStats::new(2);
+    let now = Instant::now();
+    for storage in 0..100 {
+        stats.record_at(storage, now);
+        stats.record_at(1000, now);
+    }
+    assert!(stats.entries.lock().unwrap().len() <= 4);
+    assert_eq!(1000, stats.top_at(1, now)[0].storage);
+}
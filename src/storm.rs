@@ -0,0 +1,259 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Detection of event storms, where a single storage or user receives file updates faster than clients can use them.
+//!
+//! Mass migrations or large scripted changes can cause millions of storage updates in a short time. Once the update
+//! rate of a storage or user goes over the threshold it's switched to summary mode, where every affected user gets a
+//! single `notify_file` without file ids and a `notify_storm` message, instead of a message for every update.
+//! When the storm is over, the users get one more `notify_file` to pick up the changes made during the storm.
+
+use crate::message::PushMessage;
+use crate::metrics::METRICS;
+use crate::UserId;
+use ahash::RandomState;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the custom message sent to the users when their updates are summarized
+pub const STORM_MESSAGE: &str = "notify_storm";
+/// Length of the window the update rate is measured over
+const WINDOW: Duration = Duration::from_secs(10);
+/// Number of parts the window is split in, the window moves forward one part at a time
+const BUCKETS: u64 = 10;
+
+/// Source of the updates that are counted
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StormTarget {
+    Storage(u32),
+    User(UserId),
+}
+
+impl StormTarget {
+    /// Informational message for the users affected by the storm, with the type of the target
+    pub fn message(&self) -> PushMessage {
+        let kind = match self {
+            StormTarget::Storage(_) => "storage",
+            StormTarget::User(_) => "user",
+        };
+        PushMessage::Custom(STORM_MESSAGE.into(), Box::new(json!({ "type": kind })))
+    }
+}
+
+impl Display for StormTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StormTarget::Storage(storage) => write!(f, "storage {}", storage),
+            StormTarget::User(user) => write!(f, "user {}", user),
+        }
+    }
+}
+
+/// A storm that is currently being summarized
+struct Storm {
+    started: Instant,
+    /// Users that already received the summary
+    users: HashSet<UserId, RandomState>,
+    /// Number of updates that weren't sent individually
+    summarized: u64,
+}
+
+struct Counter {
+    buckets: [u64; BUCKETS as usize],
+    /// Index of the most recent bucket that was counted in
+    last: u64,
+    storm: Option<Storm>,
+}
+
+impl Counter {
+    fn new(now: u64) -> Self {
+        Counter {
+            buckets: [0; BUCKETS as usize],
+            last: now,
+            storm: None,
+        }
+    }
+
+    /// Clear the buckets that fell out of the window
+    fn advance(&mut self, now: u64) {
+        if now.saturating_sub(self.last) >= BUCKETS {
+            self.buckets = [0; BUCKETS as usize];
+        } else {
+            for bucket in self.last + 1..=now {
+                self.buckets[(bucket % BUCKETS) as usize] = 0;
+            }
+        }
+        self.last = self.last.max(now);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// A storm that ended, the users need to be notified once more
+pub struct EndedStorm {
+    pub target: StormTarget,
+    pub users: Vec<UserId>,
+}
+
+pub struct StormDetector {
+    /// Number of updates within the window after which a target is summarized
+    threshold: u64,
+    start: Instant,
+    counters: Mutex<HashMap<StormTarget, Counter, RandomState>>,
+}
+
+impl StormDetector {
+    /// Summarize the updates for targets that receive more than `rate` updates per second
+    pub fn new(rate: u64) -> Self {
+        StormDetector {
+            threshold: rate.saturating_mul(WINDOW.as_secs()),
+            start: Instant::now(),
+            counters: Mutex::default(),
+        }
+    }
+
+    fn bucket(&self, now: Instant) -> u64 {
+        let bucket_length = WINDOW / BUCKETS as u32;
+        (now.saturating_duration_since(self.start).as_millis() / bucket_length.as_millis()) as u64
+    }
+
+    /// Count an update for the target, returns whether the updates for the target are being summarized
+    pub fn record(&self, target: &StormTarget) -> bool {
+        self.record_at(target, Instant::now())
+    }
+
+    fn record_at(&self, target: &StormTarget, now: Instant) -> bool {
+        let bucket = self.bucket(now);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters
+            .entry(target.clone())
+            .or_insert_with(|| Counter::new(bucket));
+        counter.advance(bucket);
+        counter.buckets[(bucket % BUCKETS) as usize] += 1;
+
+        if let Some(storm) = &mut counter.storm {
+            storm.summarized += 1;
+            return true;
+        }
+        if counter.count() > self.threshold {
+            log::warn!(
+                "Received more than {} updates for {} in the last {}s, summarizing the updates until it calms down",
+                self.threshold,
+                target,
+                WINDOW.as_secs()
+            );
+            METRICS.add_storm();
+            counter.storm = Some(Storm {
+                started: now,
+                users: HashSet::default(),
+                summarized: 1,
+            });
+            return true;
+        }
+        false
+    }
+
+    /// Filter out the users that already received the summary for the storm of the target
+    pub fn summarize(
+        &self,
+        target: &StormTarget,
+        users: impl IntoIterator<Item = UserId>,
+    ) -> Vec<UserId> {
+        let mut counters = self.counters.lock().unwrap();
+        let Some(storm) = counters
+            .get_mut(target)
+            .and_then(|counter| counter.storm.as_mut())
+        else {
+            return Vec::new();
+        };
+        users
+            .into_iter()
+            .filter(|user| storm.users.insert(user.clone()))
+            .collect()
+    }
+
+    /// End the storms for targets that are back under the threshold, and forget the targets without recent updates
+    pub fn expire(&self) -> Vec<EndedStorm> {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) -> Vec<EndedStorm> {
+        let bucket = self.bucket(now);
+        let mut ended = Vec::new();
+        let mut counters = self.counters.lock().unwrap();
+        counters.retain(|target, counter| {
+            counter.advance(bucket);
+            if counter.count() > self.threshold {
+                return true;
+            }
+            if let Some(storm) = counter.storm.take() {
+                METRICS.remove_storm(storm.summarized);
+                log::info!(
+                    "Updates for {} calmed down after {}s, {} updates were summarized",
+                    target,
+                    now.saturating_duration_since(storm.started).as_secs(),
+                    storm.summarized
+                );
+                ended.push(EndedStorm {
+                    target: target.clone(),
+                    users: storm.users.into_iter().collect(),
+                });
+            }
+            counter.count() > 0
+        });
+        ended
+    }
+}
+
+#[test]
+fn test_storm_detection() {
+    let detector = StormDetector::new(1);
+    let start = detector.start;
+    let storage = StormTarget::Storage(1);
+    let other = StormTarget::Storage(2);
+    let foo = UserId::new("foo");
+    let bar = UserId::new("bar");
+
+    for _ in 0..10 {
+        assert!(!detector.record_at(&storage, start));
+    }
+    assert!(!detector.record_at(&other, start));
+    assert!(detector.record_at(&storage, start));
+    assert!(detector.record_at(&storage, start + Duration::from_secs(1)));
+
+    assert_eq!(
+        vec![foo.clone()],
+        detector.summarize(&storage, [foo.clone(), foo.clone()])
+    );
+    assert_eq!(
+        vec![bar.clone()],
+        detector.summarize(&storage, [foo.clone(), bar.clone()])
+    );
+    assert!(detector.summarize(&other, [foo.clone()]).is_empty());
+
+    // the storm continues while the rate is over the threshold
+    assert!(detector
+        .expire_at(start + Duration::from_secs(5))
+        .is_empty());
+
+    let ended = detector.expire_at(start + Duration::from_secs(10));
+    assert_eq!(1, ended.len());
+    assert_eq!(storage, ended[0].target);
+    assert_eq!(2, ended[0].users.len());
+    assert!(ended[0].users.contains(&foo));
+    assert!(ended[0].users.contains(&bar));
+
+    // without updates in the window the targets are forgotten
+    assert!(detector
+        .expire_at(start + Duration::from_secs(20))
+        .is_empty());
+    assert!(detector.counters.lock().unwrap().is_empty());
+    assert!(!detector.record_at(&storage, start + Duration::from_secs(20)));
+}
@@ -0,0 +1,127 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Supervision for spawned tasks.
+//!
+//! A panic in a task spawned with `tokio::spawn` only ends up in the `JoinHandle`, which we don't keep around,
+//! so supervised tasks log the panic with the name of the task and count it in the metrics instead.
+
+use crate::metrics::METRICS;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+pub static SUPERVISOR: Supervisor = Supervisor::new();
+
+/// Window in which panics are counted against the limit
+const PANIC_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct Supervisor {
+    /// Number of panics within the window after which the push server shuts down, zero to never shut down
+    max_panics: AtomicUsize,
+    recent_panics: Mutex<VecDeque<Instant>>,
+    limit_reached: Lazy<Notify>,
+}
+
+impl Supervisor {
+    pub const fn new() -> Self {
+        Supervisor {
+            max_panics: AtomicUsize::new(0),
+            recent_panics: Mutex::new(VecDeque::new()),
+            limit_reached: Lazy::new(Notify::new),
+        }
+    }
+
+    pub fn set_max_panics(&self, max_panics: usize) {
+        self.max_panics.store(max_panics, Ordering::Relaxed);
+    }
+
+    /// Completes once the configured number of panics happened within a minute
+    pub async fn panic_limit_reached(&self) {
+        self.limit_reached.notified().await
+    }
+
+    fn record_panic(&self, task: &'static str, payload: &(dyn Any + Send)) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        log::error!("Task {} panicked: {}", task, message);
+        METRICS.add_task_panic(task);
+
+        let max_panics = self.max_panics.load(Ordering::Relaxed);
+        if max_panics == 0 {
+            return;
+        }
+        let mut recent = self.recent_panics.lock().unwrap();
+        let now = Instant::now();
+        recent.push_back(now);
+        while recent
+            .front()
+            .is_some_and(|time| now.duration_since(*time) > PANIC_WINDOW)
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= max_panics {
+            log::error!(
+                "{} tasks panicked within {}s, shutting down",
+                recent.len(),
+                PANIC_WINDOW.as_secs()
+            );
+            self.limit_reached.notify_one();
+        }
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spawn a task that has its panics logged and counted as `task`
+pub fn spawn_supervised<F>(task: &'static str, future: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(payload) = AssertUnwindSafe(future).catch_unwind().await {
+            SUPERVISOR.record_panic(task, &*payload);
+        }
+    })
+}
+
+#[tokio::test]
+async fn test_supervised_panic() {
+    let supervisor = Supervisor::new();
+    supervisor.set_max_panics(2);
+
+    let before = METRICS
+        .task_panic_counts()
+        .get("test")
+        .copied()
+        .unwrap_or(0);
+    let result = spawn_supervised("test", async { panic!("oops") }).await;
+    assert!(result.is_ok());
+    assert_eq!(before + 1, METRICS.task_panic_counts()["test"]);
+
+    supervisor.record_panic("test", &"first");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(10), supervisor.panic_limit_reached())
+            .await
+            .is_err()
+    );
+    supervisor.record_panic("test", &"second");
+    supervisor.panic_limit_reached().await;
+}
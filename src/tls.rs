@@ -0,0 +1,201 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! TLS listener for the push and metrics server.
+//!
+//! We do the handshake ourselves to control the protocol versions and cipher suites,
+//! and hand the established streams to the http server.
+
+use crate::config::{TlsConfig, TlsOptions, TlsVersion};
+use crate::error::TlsError;
+use crate::http::Connection;
+use futures::{Stream, StreamExt};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::version::{TLS12, TLS13};
+use tokio_rustls::rustls::{ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Connections that don't complete the handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Read(e, path.display().to_string()))
+}
+
+pub fn server_config(tls: &TlsConfig) -> Result<ServerConfig, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(&tls.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Read(e, tls.cert.display().to_string()))?;
+    let key = rustls_pemfile::private_key(&mut open(&tls.key)?)
+        .map_err(|e| TlsError::Read(e, tls.key.display().to_string()))?
+        .ok_or_else(|| TlsError::NoKey(tls.key.display().to_string()))?;
+
+    let provider = CryptoProvider {
+        cipher_suites: cipher_suites(&tls.options)?,
+        ..default_provider()
+    };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(&protocol_versions(&tls.options))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// The allowed cipher suites in order of preference, the configured names are matched against the IANA names
+fn cipher_suites(options: &TlsOptions) -> Result<Vec<SupportedCipherSuite>, TlsError> {
+    let supported = default_provider().cipher_suites;
+    if options.cipher_suites.is_empty() {
+        return Ok(supported);
+    }
+    options
+        .cipher_suites
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| TlsError::UnknownCipherSuite(name.clone()))
+        })
+        .collect()
+}
+
+fn protocol_versions(options: &TlsOptions) -> Vec<&'static SupportedProtocolVersion> {
+    [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+        .into_iter()
+        .filter(|(version, _)| {
+            options.min_version.map_or(true, |min| *version >= min)
+                && options.max_version.map_or(true, |max| *version <= max)
+        })
+        .map(|(_, version)| version)
+        .collect()
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.peer_addr().ok()
+    }
+
+    fn is_secure(&self) -> bool {
+        true
+    }
+}
+
+/// Stream of connections that completed the TLS handshake
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(accept(listener, acceptor, tx));
+    ReceiverStream::new(rx).map(Ok)
+}
+
+/// Accept connections and perform the handshakes in the background, so slow clients don't block new connections
+async fn accept(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    connections: mpsc::Sender<TlsStream<TcpStream>>,
+) {
+    loop {
+        let result = tokio::select! {
+            _ = connections.closed() => return,
+            result = listener.accept() => result,
+        };
+        match result {
+            Ok((stream, remote)) => {
+                let acceptor = acceptor.clone();
+                let connections = connections.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            connections.send(stream).await.ok();
+                        }
+                        Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", remote, e),
+                        Err(_) => log::debug!("TLS handshake with {} timed out", remote),
+                    }
+                });
+            }
+            Err(e) => {
+                log::warn!("Failed to accept connection: {}", e);
+                // most likely out of file descriptors, give the server some time to close connections
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cipher_suites() {
+    let options = |cipher_suites: &[&str]| TlsOptions {
+        cipher_suites: cipher_suites
+            .iter()
+            .map(|suite| suite.to_string())
+            .collect(),
+        ..TlsOptions::default()
+    };
+    let names = |options: TlsOptions| {
+        cipher_suites(&options).map(|suites| {
+            suites
+                .iter()
+                .map(|suite| format!("{:?}", suite.suite()))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    assert_eq!(
+        default_provider().cipher_suites.len(),
+        names(options(&[])).unwrap().len()
+    );
+    assert_eq!(
+        vec![
+            "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+            "TLS13_AES_128_GCM_SHA256"
+        ],
+        names(options(&[
+            "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+            "tls13_aes_128_gcm_sha256"
+        ]))
+        .unwrap()
+    );
+    assert!(matches!(
+        names(options(&["TLS_RSA_WITH_AES_128_CBC_SHA"])),
+        Err(TlsError::UnknownCipherSuite(_))
+    ));
+}
+
+#[test]
+fn test_protocol_versions() {
+    let versions = |min_version, max_version| {
+        protocol_versions(&TlsOptions {
+            min_version,
+            max_version,
+            ..TlsOptions::default()
+        })
+        .iter()
+        .map(|version| version.version)
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(vec![TLS12.version, TLS13.version], versions(None, None));
+    assert_eq!(vec![TLS13.version], versions(Some(TlsVersion::Tls13), None));
+    assert_eq!(vec![TLS12.version], versions(None, Some(TlsVersion::Tls12)));
+}
@@ -0,0 +1,219 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Detailed logging of the messages for a single user, for a limited time.
+//!
+//! A trace is started with the `trace_user` config event, after which every event routed to the user is logged
+//! at each stage it passes through. Once the trace ends a summary is stored in redis.
+
+use crate::UserId;
+use ahash::RandomState;
+use parse_display::Display;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Traces are stopped after this long, even if a longer duration was requested
+pub const MAX_TRACE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+static NEXT_TRACE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Display)]
+#[display(style = "lowercase")]
+pub enum Stage {
+    /// The event for the user was received, for storage updates this is after the users are resolved
+    Received,
+    /// The message was passed to the connections of the user
+    Queued,
+    /// The user had no open connections
+    Dropped,
+    /// The message was written to a connection of the user
+    Sent,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct StageSummary {
+    pub count: u64,
+    /// Total time in milliseconds since the previous stage
+    pub total_ms: u64,
+    /// Longest time in milliseconds since the previous stage
+    pub max_ms: u64,
+}
+
+impl StageSummary {
+    fn add(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_millis() as u64;
+        self.count += 1;
+        self.total_ms += elapsed;
+        self.max_ms = self.max_ms.max(elapsed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceSummary {
+    pub user: String,
+    /// Unix timestamp of the start of the trace
+    pub started: u64,
+    /// Length of the trace in seconds
+    pub duration: u64,
+    pub received: StageSummary,
+    pub queued: StageSummary,
+    pub dropped: StageSummary,
+    pub sent: StageSummary,
+}
+
+struct Trace {
+    id: u64,
+    start: Instant,
+    /// Last time a message for the user was queued, to time the delivery to the connections
+    last_queued: Option<Instant>,
+    summary: TraceSummary,
+}
+
+#[derive(Default)]
+pub struct Tracer {
+    /// Number of running traces, so the message path doesn't need the lock when nothing is traced
+    active: AtomicUsize,
+    traces: Mutex<HashMap<UserId, Trace, RandomState>>,
+}
+
+impl Tracer {
+    /// Start tracing `user`, replacing any running trace for the same user
+    ///
+    /// Returns the id of the trace, to be passed to [`Tracer::finish`] once the duration has passed.
+    pub fn start(&self, user: &str) -> u64 {
+        let id = NEXT_TRACE_ID.fetch_add(1, Ordering::Relaxed);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let trace = Trace {
+            id,
+            start: Instant::now(),
+            last_queued: None,
+            summary: TraceSummary {
+                user: user.into(),
+                started,
+                duration: 0,
+                received: StageSummary::default(),
+                queued: StageSummary::default(),
+                dropped: StageSummary::default(),
+                sent: StageSummary::default(),
+            },
+        };
+        let mut traces = self.traces.lock().unwrap();
+        traces.insert(UserId::new(user), trace);
+        self.active.store(traces.len(), Ordering::Relaxed);
+        id
+    }
+
+    /// Stop the trace and get its summary, if it hasn't been replaced by a newer trace for the same user
+    pub fn finish(&self, user: &str, id: u64) -> Option<TraceSummary> {
+        let mut traces = self.traces.lock().unwrap();
+        let user = UserId::new(user);
+        if traces.get(&user)?.id != id {
+            return None;
+        }
+        let mut trace = traces.remove(&user)?;
+        self.active.store(traces.len(), Ordering::Relaxed);
+        trace.summary.duration = trace.start.elapsed().as_secs();
+        Some(trace.summary)
+    }
+
+    pub fn is_traced(&self, user: &UserId) -> bool {
+        self.active.load(Ordering::Relaxed) > 0 && self.traces.lock().unwrap().contains_key(user)
+    }
+
+    /// Log a stage for a message of a traced user, does nothing if the user isn't traced
+    ///
+    /// For the `sent` stage the time is measured from when the last message was queued, otherwise from `since`.
+    pub fn record(&self, user: &UserId, stage: Stage, since: Instant, message: &dyn Display) {
+        self.record_at(user, stage, since, message, Instant::now())
+    }
+
+    fn record_at(
+        &self,
+        user: &UserId,
+        stage: Stage,
+        since: Instant,
+        message: &dyn Display,
+        now: Instant,
+    ) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut traces = self.traces.lock().unwrap();
+        let Some(trace) = traces.get_mut(user) else {
+            return;
+        };
+        let since = match stage {
+            Stage::Sent => trace.last_queued.unwrap_or(since),
+            _ => since,
+        };
+        let elapsed = now.saturating_duration_since(since);
+        let summary = &mut trace.summary;
+        match stage {
+            Stage::Received => summary.received.add(elapsed),
+            Stage::Queued => {
+                trace.last_queued = Some(now);
+                summary.queued.add(elapsed);
+            }
+            Stage::Dropped => summary.dropped.add(elapsed),
+            Stage::Sent => summary.sent.add(elapsed),
+        }
+        log::info!(
+            target: "notify_push::trace",
+            "{}: {} {} at +{}ms, {}ms after the previous stage",
+            summary.user,
+            stage,
+            message,
+            now.saturating_duration_since(trace.start).as_millis(),
+            elapsed.as_millis()
+        );
+    }
+}
+
+#[test]
+fn test_trace_stages() {
+    let tracer = Tracer::default();
+    let foo = UserId::new("foo");
+    let bar = UserId::new("bar");
+    let id = tracer.start("foo");
+    assert!(tracer.is_traced(&foo));
+    assert!(!tracer.is_traced(&bar));
+
+    let received = Instant::now();
+    let later = |ms| received + Duration::from_millis(ms);
+    tracer.record_at(&foo, Stage::Received, received, &"activity", later(0));
+    tracer.record_at(&foo, Stage::Queued, received, &"activity", later(5));
+    tracer.record_at(&foo, Stage::Sent, received, &"activity", later(105));
+    tracer.record_at(&foo, Stage::Sent, received, &"activity", later(125));
+    tracer.record_at(&bar, Stage::Received, received, &"activity", later(0));
+
+    let summary = tracer.finish("foo", id).unwrap();
+    assert_eq!("foo", summary.user);
+    assert_eq!(1, summary.received.count);
+    assert_eq!(5, summary.queued.total_ms);
+    assert_eq!(2, summary.sent.count);
+    assert_eq!(220, summary.sent.total_ms);
+    assert_eq!(120, summary.sent.max_ms);
+    assert_eq!(0, summary.dropped.count);
+    assert!(!tracer.is_traced(&foo));
+}
+
+#[test]
+fn test_trace_restart() {
+    let tracer = Tracer::default();
+    let first = tracer.start("foo");
+    let second = tracer.start("foo");
+
+    // the first trace ending doesn't stop the trace that replaced it
+    assert!(tracer.finish("foo", first).is_none());
+    assert!(tracer.is_traced(&UserId::new("foo")));
+    assert!(tracer.finish("foo", second).is_some());
+}
@@ -0,0 +1,99 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Handling of events published on redis channels the push server doesn't know.
+//!
+//! Every such event is counted in the metrics, what else happens with it depends on the configured policy.
+
+use crate::metrics::METRICS;
+use crate::monitor::Monitor;
+use parse_display::{Display, FromStr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Minimum time between two warnings about unknown channels with the `sample` policy
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Display, FromStr)]
+#[display(style = "snake_case")]
+pub enum UnknownChannelPolicy {
+    /// Only count the events
+    Ignore,
+    /// Log a warning for the first event, and at most once a minute after that
+    #[default]
+    Sample,
+    /// Mirror the raw events to the `/admin/monitor` stream
+    Forward,
+}
+
+pub struct UnknownChannels {
+    policy: UnknownChannelPolicy,
+    last_warning: Mutex<Option<Instant>>,
+    /// Events that weren't logged since the last warning
+    suppressed: AtomicUsize,
+}
+
+impl UnknownChannels {
+    pub fn new(policy: UnknownChannelPolicy) -> Self {
+        UnknownChannels {
+            policy,
+            last_warning: Mutex::new(None),
+            suppressed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn handle(&self, channel: &str, payload: &[u8], monitor: &Monitor) {
+        METRICS.add_unknown_event();
+        match self.policy {
+            UnknownChannelPolicy::Ignore => {}
+            UnknownChannelPolicy::Sample => {
+                if let Some(suppressed) = self.sample(Instant::now()) {
+                    log::warn!(
+                        "Received event on unsupported channel {}, {} more events on unsupported channels since the last warning",
+                        channel,
+                        suppressed
+                    );
+                }
+            }
+            UnknownChannelPolicy::Forward => monitor.record_unknown_channel(channel, payload),
+        }
+    }
+
+    /// Whether a warning should be logged now, with the number of events that weren't logged since the last warning
+    fn sample(&self, now: Instant) -> Option<usize> {
+        let mut last_warning = self.last_warning.lock().unwrap();
+        match *last_warning {
+            Some(last) if now.duration_since(last) < WARNING_INTERVAL => {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            _ => {
+                *last_warning = Some(now);
+                Some(self.suppressed.swap(0, Ordering::Relaxed))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sample_warnings() {
+    let unknown = UnknownChannels::new(UnknownChannelPolicy::Sample);
+    let start = Instant::now();
+    assert_eq!(Some(0), unknown.sample(start));
+    assert_eq!(None, unknown.sample(start + Duration::from_secs(1)));
+    assert_eq!(None, unknown.sample(start + Duration::from_secs(59)));
+    assert_eq!(Some(2), unknown.sample(start + Duration::from_secs(60)));
+    assert_eq!(None, unknown.sample(start + Duration::from_secs(61)));
+}
+
+#[test]
+fn test_parse_policy() {
+    assert_eq!(
+        UnknownChannelPolicy::Forward,
+        "forward".parse::<UnknownChannelPolicy>().unwrap()
+    );
+    assert!("warn".parse::<UnknownChannelPolicy>().is_err());
+}
@@ -0,0 +1,231 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Check for new releases and optionally replace the running binary.
+//!
+//! The binary is only replaced with a release asset for the same target that matches the published sha256 checksum,
+//! after which the push server shuts down like it would on `SIGTERM` and starts the new binary in its place.
+//!
+//! The checksum is downloaded from the same release as the binary, so it only protects against corrupted downloads,
+//! not against a tampered release.
+
+use crate::error::UpdateError;
+use crate::metrics::METRICS;
+use once_cell::sync::Lazy;
+use reqwest::header::USER_AGENT;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::{rename, set_permissions, write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{interval, MissedTickBehavior};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/nextcloud/notify_push/releases/latest";
+const AGENT: &str = concat!("notify_push/", env!("NOTIFY_PUSH_VERSION"));
+
+/// Notified once a new binary is installed and the push server should restart
+pub static UPDATE_INSTALLED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+/// Whether `latest` is a higher version than `current`, pre-release suffixes are ignored
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    let parts = |version: &str| {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    parts(latest) > parts(current)
+}
+
+/// Check that `data` matches a checksum file in the `sha256sum` format
+fn verify_checksum(data: &[u8], checksum_file: &str) -> Result<(), UpdateError> {
+    let expected = checksum_file
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let actual = Sha256::digest(data)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        });
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(UpdateError::ChecksumMismatch { expected, actual })
+    }
+}
+
+pub struct Updater {
+    http: reqwest::Client,
+    /// The binary to replace, resolved at startup since the path of the running binary changes once it's replaced
+    binary: PathBuf,
+}
+
+impl Updater {
+    pub fn new(binary: PathBuf) -> Result<Self, UpdateError> {
+        Ok(Updater {
+            http: crate::nc::http_client(false)?,
+            binary,
+        })
+    }
+
+    async fn get(&self, url: &str) -> Result<reqwest::Response, UpdateError> {
+        let response = self.http.get(url).header(USER_AGENT, AGENT).send().await?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(UpdateError::Status(response.status(), url.into()))
+        }
+    }
+
+    pub async fn latest_release(&self) -> Result<Release, UpdateError> {
+        Ok(self.get(RELEASES_URL).await?.json().await?)
+    }
+
+    /// Download the binary for this target from the release and replace the running binary with it
+    pub async fn install(&self, release: &Release) -> Result<(), UpdateError> {
+        let name = format!("notify_push-{}", env!("NOTIFY_PUSH_TARGET"));
+        let checksum_name = format!("{}.sha256", name);
+        let (Some(binary), Some(checksum)) = (release.asset(&name), release.asset(&checksum_name))
+        else {
+            return Err(UpdateError::NoAsset(name));
+        };
+
+        let data = self
+            .get(&binary.browser_download_url)
+            .await?
+            .bytes()
+            .await?;
+        let checksum = self
+            .get(&checksum.browser_download_url)
+            .await?
+            .text()
+            .await?;
+        verify_checksum(&data, &checksum)?;
+
+        // write next to the binary and move it into place, so we never leave a partially written binary behind
+        let install = |target: &Path| {
+            let temp = target.with_extension("update");
+            write(&temp, &data)?;
+            set_permissions(&temp, PermissionsExt::from_mode(0o755))?;
+            rename(&temp, target)
+        };
+        install(&self.binary)
+            .map_err(|e| UpdateError::Install(e, self.binary.display().to_string()))
+    }
+
+    /// Path of the binary the update is installed to
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+}
+
+/// Check for a new release every `period`, if `self_update` is set the new release is installed
+/// and [`UPDATE_INSTALLED`] is notified
+pub async fn update_check_loop(updater: Updater, period: Duration, self_update: bool) {
+    let mut interval = interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let release = match updater.latest_release().await {
+            Ok(release) => release,
+            Err(e) => {
+                log::warn!("Failed to check for updates: {:#}", e);
+                continue;
+            }
+        };
+        METRICS.set_latest_version(release.version().into());
+        if !is_newer(release.version(), env!("NOTIFY_PUSH_VERSION")) {
+            continue;
+        }
+
+        if !self_update {
+            log::info!(
+                "notify_push {} is available, running {}",
+                release.version(),
+                env!("NOTIFY_PUSH_VERSION")
+            );
+            continue;
+        }
+        log::info!("Installing notify_push {}", release.version());
+        match updater.install(&release).await {
+            Ok(()) => {
+                log::info!(
+                    "Installed notify_push {} to {}, restarting",
+                    release.version(),
+                    updater.binary().display()
+                );
+                UPDATE_INSTALLED.notify_one();
+                return;
+            }
+            Err(e) => log::error!("Failed to install update: {:#}", e),
+        }
+    }
+}
+
+#[test]
+fn test_is_newer() {
+    assert!(is_newer("1.1.0", "1.0.0"));
+    assert!(is_newer("1.0.10", "1.0.9"));
+    assert!(is_newer("2.0.0", "1.9.9"));
+    assert!(is_newer("1.1", "1.0.5"));
+    assert!(!is_newer("1.0.0", "1.0.0"));
+    assert!(!is_newer("1.0.0-beta1", "1.0.0"));
+    assert!(!is_newer("0.9.0", "1.0.0"));
+}
+
+#[test]
+fn test_verify_checksum() {
+    let checksum =
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  notify_push\n";
+    assert!(verify_checksum(b"hello", checksum).is_ok());
+    assert!(verify_checksum(b"hello", &checksum.to_uppercase()).is_ok());
+    assert!(matches!(
+        verify_checksum(b"hello!", checksum),
+        Err(UpdateError::ChecksumMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_release_version() {
+    let release: Release =
+        serde_json::from_str(r#"{"tag_name": "v1.2.3", "assets": [{"name": "notify_push-x86_64-unknown-linux-musl", "browser_download_url": "https://example.com/notify_push"}]}"#)
+            .unwrap();
+    assert_eq!("1.2.3", release.version());
+    assert!(release
+        .asset("notify_push-x86_64-unknown-linux-musl")
+        .is_some());
+}
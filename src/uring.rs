@@ -0,0 +1,195 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Experimental listener that accepts and reads the client connections with io_uring instead of epoll.
+//!
+//! The sockets are handled by a tokio-uring runtime on a separate thread, which copies the data from and to an in-memory
+//! stream that is served by the main runtime like any other connection. This keeps the rest of the server unchanged,
+//! at the cost of a copy in both directions. `benches/io_uring.rs` compares the latency and syscalls of both listeners.
+
+use crate::http::{Connection, Listener};
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use tokio::io::{
+    duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+};
+use tokio::sync::{mpsc, Mutex};
+use tokio_uring::net::{TcpListener, TcpStream};
+
+/// Size of the buffers used for reading from and writing to a socket
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Accepted connections that haven't been picked up by the server yet
+const ACCEPT_BACKLOG: usize = 128;
+
+pub struct UringListener {
+    connections: Mutex<mpsc::Receiver<io::Result<UringConnection>>>,
+    local_addr: SocketAddr,
+}
+
+impl UringListener {
+    /// Bind to the address and start accepting connections on the io_uring thread
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let (connections_tx, connections) = mpsc::channel(ACCEPT_BACKLOG);
+        let (bound_tx, bound) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("notify_push-io-uring".into())
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        bound_tx.send(Err(e)).ok();
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let listener = match TcpListener::bind(addr) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            bound_tx.send(Err(e)).ok();
+                            return;
+                        }
+                    };
+                    bound_tx.send(listener.local_addr()).ok();
+                    accept_loop(listener, connections_tx).await;
+                })
+            })?;
+        let local_addr = bound
+            .recv()
+            .map_err(|_| io::Error::other("io_uring thread stopped"))??;
+        Ok(UringListener {
+            connections: Mutex::new(connections),
+            local_addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Listener for UringListener {
+    type Connection = UringConnection;
+
+    async fn accept(&self) -> io::Result<UringConnection> {
+        self.connections
+            .lock()
+            .await
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(io::Error::other("io_uring thread stopped")))
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    connections: mpsc::Sender<io::Result<UringConnection>>,
+) {
+    loop {
+        // stop listening once the server is stopped
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = connections.closed() => return,
+        };
+        let connection = accepted.map(|(socket, peer)| {
+            let (stream, bridged) = duplex(BUFFER_SIZE);
+            tokio_uring::spawn(bridge(socket, bridged));
+            UringConnection { stream, peer }
+        });
+        if connections.send(connection).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Copy the data between the socket and the stream served by the main runtime until either side is closed
+async fn bridge(socket: TcpStream, stream: DuplexStream) {
+    let socket = Rc::new(socket);
+    let (mut stream_rx, mut stream_tx) = tokio::io::split(stream);
+
+    let incoming = {
+        let socket = socket.clone();
+        async move {
+            let mut buf = Vec::with_capacity(BUFFER_SIZE);
+            loop {
+                let (result, read) = socket.read(buf).await;
+                buf = read;
+                match result {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if stream_tx.write_all(&buf).await.is_err() {
+                    break;
+                }
+                buf.clear();
+            }
+            stream_tx.shutdown().await.ok();
+        }
+    };
+
+    let outgoing = async move {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let read = match stream_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            buf.truncate(read);
+            let (result, written) = socket.write_all(buf).await;
+            buf = written;
+            if result.is_err() {
+                break;
+            }
+            buf.resize(BUFFER_SIZE, 0);
+        }
+        // also wakes up the pending read, so the connection is closed once the server is done with it
+        socket.shutdown(Shutdown::Both).ok();
+    };
+
+    futures::future::join(incoming, outgoing).await;
+}
+
+/// A connection accepted by the io_uring listener
+pub struct UringConnection {
+    stream: DuplexStream,
+    peer: SocketAddr,
+}
+
+impl Connection for UringConnection {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer)
+    }
+}
+
+impl AsyncRead for UringConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UringConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -3,6 +3,7 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
 
+use crate::affinity::affinity_hash;
 use crate::passthru_hasher::PassthruHasher;
 use ahash::RandomState;
 use dashmap::DashMap;
@@ -14,16 +15,37 @@ use serde::{Deserialize, Deserializer};
 use sqlx::error::BoxDynError;
 use sqlx::{Database, Decode, Type};
 use std::fmt;
-use std::hash::{BuildHasher, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 static USER_NAMES: Lazy<DashMap<u64, String, PassthruHasher>> = Lazy::new(DashMap::default);
 
+// Features that report user names outside of logging (e.g. webhooks) need the names regardless of log level
+static KEEP_USER_NAMES: AtomicBool = AtomicBool::new(false);
+
+/// Always keep track of the plain user names, instead of only when logging at INFO or higher
+pub fn keep_user_names() {
+    KEEP_USER_NAMES.store(true, Ordering::Relaxed);
+}
+
+fn user_names_enabled() -> bool {
+    KEEP_USER_NAMES.load(Ordering::Relaxed) || log::max_level() >= LevelFilter::Info
+}
+
 // Use the same hash state for generating user hash for every instance
 static RANDOM_STATE: OnceBox<RandomState> = OnceBox::new();
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct UserId {
     hash: u64,
+    /// Stable hash of the user name, the hash above differs between instances
+    affinity: u64,
+}
+
+impl Hash for UserId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
 }
 
 impl UserId {
@@ -33,13 +55,36 @@ impl UserId {
         hash.write(user_id.as_bytes());
         let hash = hash.finish();
 
-        if log::max_level() >= LevelFilter::Info {
+        if user_names_enabled() {
             USER_NAMES
                 .entry(hash)
                 .or_insert_with(|| user_id.to_string());
         }
 
-        UserId { hash }
+        UserId {
+            hash,
+            affinity: affinity_hash(user_id),
+        }
+    }
+
+    /// Hash of the user that doesn't reveal the name, it differs between instances and restarts
+    pub fn anonymized(&self) -> String {
+        format!("{:016x}", self.hash)
+    }
+
+    /// Routing key of the user, see [`crate::affinity`]
+    pub fn affinity_key(&self) -> String {
+        format!("{:016x}", self.affinity)
+    }
+
+    /// Index of the runtime shard the connections of the user are handled on, the same for every connection of the user
+    pub fn shard(&self, shard_count: usize) -> usize {
+        (self.affinity % shard_count as u64) as usize
+    }
+
+    /// Get the plain user name, if user names are being tracked
+    pub fn name(&self) -> Option<String> {
+        USER_NAMES.get(&self.hash).map(|name| name.value().clone())
     }
 }
 
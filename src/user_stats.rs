@@ -0,0 +1,252 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Messages sent per user over a sliding window, to find users that receive an unusual amount of messages.
+//!
+//! A single user receiving a constant stream of messages is usually caused by a sync loop, where one
+//! client keeps rewriting files that the other clients of the user then get notified about.
+
+use crate::error::NextCloudError;
+use crate::nc::http_client;
+use crate::user::keep_user_names;
+use crate::UserId;
+use ahash::RandomState;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of parts the window is split in, the window moves forward one part at a time
+const BUCKETS: u64 = 10;
+
+struct Counter {
+    buckets: [u64; BUCKETS as usize],
+    /// Index of the most recent bucket that was counted in
+    last: u64,
+    /// Whether the user is currently over the threshold
+    alerted: bool,
+}
+
+impl Counter {
+    fn new(now: u64) -> Self {
+        Counter {
+            buckets: [0; BUCKETS as usize],
+            last: now,
+            alerted: false,
+        }
+    }
+
+    /// Clear the buckets that fell out of the window
+    fn advance(&mut self, now: u64) {
+        if now.saturating_sub(self.last) >= BUCKETS {
+            self.buckets = [0; BUCKETS as usize];
+        } else {
+            for bucket in self.last + 1..=now {
+                self.buckets[(bucket % BUCKETS) as usize] = 0;
+            }
+        }
+        self.last = self.last.max(now);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UserActivity {
+    pub user: String,
+    /// Number of messages within the window
+    pub messages: u64,
+}
+
+#[derive(Serialize)]
+struct Alert<'a> {
+    user: &'a str,
+    messages: u64,
+    /// Length of the window in seconds
+    window: u64,
+}
+
+pub struct UserMessageStats {
+    window: Duration,
+    /// Number of messages within the window after which an alert is send
+    threshold: Option<u64>,
+    webhook: Option<(reqwest::Client, Url)>,
+    start: Instant,
+    counters: Mutex<HashMap<UserId, Counter, RandomState>>,
+    /// Bucket at which the counters of inactive users were last removed
+    last_cleanup: Mutex<u64>,
+}
+
+impl UserMessageStats {
+    pub fn new(
+        window: Duration,
+        threshold: Option<u64>,
+        webhook: Option<Url>,
+        allow_self_signed: bool,
+    ) -> Result<Self, NextCloudError> {
+        // the stats are reported by user name
+        keep_user_names();
+        let webhook = webhook
+            .map(|url| http_client(allow_self_signed).map(|http| (http, url)))
+            .transpose()?;
+        Ok(UserMessageStats {
+            window,
+            threshold,
+            webhook,
+            start: Instant::now(),
+            counters: Mutex::default(),
+            last_cleanup: Mutex::new(0),
+        })
+    }
+
+    fn bucket(&self, now: Instant) -> u64 {
+        let bucket_length = (self.window / BUCKETS as u32).max(Duration::from_millis(1));
+        (now.saturating_duration_since(self.start).as_millis() / bucket_length.as_millis()) as u64
+    }
+
+    pub fn record(&self, user: &UserId) {
+        self.record_at(user, Instant::now());
+    }
+
+    fn record_at(&self, user: &UserId, now: Instant) {
+        let bucket = self.bucket(now);
+        let mut counters = self.counters.lock().unwrap();
+        self.cleanup(&mut counters, bucket);
+
+        let counter = counters
+            .entry(user.clone())
+            .or_insert_with(|| Counter::new(bucket));
+        counter.advance(bucket);
+        counter.buckets[(bucket % BUCKETS) as usize] += 1;
+
+        let count = counter.count();
+        match self.threshold {
+            Some(threshold) if count > threshold && !counter.alerted => {
+                counter.alerted = true;
+                self.alert(user, count);
+            }
+            Some(threshold) if count <= threshold => counter.alerted = false,
+            _ => {}
+        }
+    }
+
+    /// Remove the users that didn't receive any messages within the window, at most once per bucket
+    fn cleanup(&self, counters: &mut HashMap<UserId, Counter, RandomState>, bucket: u64) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+        if *last_cleanup == bucket {
+            return;
+        }
+        *last_cleanup = bucket;
+        counters.retain(|_, counter| bucket.saturating_sub(counter.last) < BUCKETS);
+    }
+
+    fn alert(&self, user: &UserId, messages: u64) {
+        let name = user.name().unwrap_or_else(|| String::from("unknown user"));
+        log::warn!(
+            "{} received {} messages in the last {}s, this might be caused by a client that keeps modifying files",
+            name,
+            messages,
+            self.window.as_secs()
+        );
+        let Some((http, url)) = &self.webhook else {
+            return;
+        };
+        let request = http.post(url.clone()).json(&Alert {
+            user: &name,
+            messages,
+            window: self.window.as_secs(),
+        });
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    log::warn!(
+                        "Message alert webhook returned status {} for {}",
+                        response.status(),
+                        name
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Failed to send message alert for {}: {}", name, e),
+            }
+        });
+    }
+
+    /// The `count` users that received the most messages within the window
+    pub fn top(&self, count: usize) -> Vec<UserActivity> {
+        self.top_at(count, Instant::now())
+    }
+
+    fn top_at(&self, count: usize, now: Instant) -> Vec<UserActivity> {
+        let bucket = self.bucket(now);
+        let mut counters = self.counters.lock().unwrap();
+        let mut top = counters
+            .iter_mut()
+            .map(|(user, counter)| {
+                counter.advance(bucket);
+                (user, counter.count())
+            })
+            .filter(|(_, messages)| *messages > 0)
+            .map(|(user, messages)| UserActivity {
+                user: user.name().unwrap_or_else(|| String::from("unknown user")),
+                messages,
+            })
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.user.cmp(&b.user)));
+        top.truncate(count);
+        top
+    }
+}
+
+#[test]
+fn test_user_message_window() {
+    let stats = UserMessageStats::new(Duration::from_secs(10), None, None, false).unwrap();
+    let start = stats.start;
+    let foo = UserId::new("foo");
+    let bar = UserId::new("bar");
+    for _ in 0..5 {
+        stats.record_at(&foo, start);
+    }
+    for _ in 0..3 {
+        stats.record_at(&bar, start + Duration::from_secs(6));
+    }
+
+    let top = stats.top_at(10, start + Duration::from_secs(6));
+    assert_eq!(
+        vec![("foo", 5), ("bar", 3)],
+        top.iter()
+            .map(|activity| (activity.user.as_str(), activity.messages))
+            .collect::<Vec<_>>()
+    );
+
+    // the messages for foo are now outside of the window
+    let top = stats.top_at(1, start + Duration::from_secs(11));
+    assert_eq!(1, top.len());
+    assert_eq!("bar", top[0].user);
+    assert_eq!(3, top[0].messages);
+
+    let top = stats.top_at(10, start + Duration::from_secs(20));
+    assert!(top.is_empty());
+}
+
+#[test]
+fn test_user_message_threshold() {
+    let stats = UserMessageStats::new(Duration::from_secs(10), Some(2), None, false).unwrap();
+    let start = stats.start;
+    let foo = UserId::new("foo");
+    let alerted = |stats: &UserMessageStats| stats.counters.lock().unwrap()[&foo].alerted;
+
+    stats.record_at(&foo, start);
+    stats.record_at(&foo, start);
+    assert!(!alerted(&stats));
+    stats.record_at(&foo, start);
+    assert!(alerted(&stats));
+
+    // once the messages are outside the window the user can trigger a new alert
+    stats.record_at(&foo, start + Duration::from_secs(10));
+    assert!(!alerted(&stats));
+}
@@ -0,0 +1,52 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Warm up the storage mapping cache after a restart.
+//!
+//! Right after a restart every client reconnects at once and every storage update needs a database query,
+//! by loading the storages that were used most recently before the restart we can spread that load out.
+
+use crate::redis::Redis;
+use crate::storage_mapping::StorageMapping;
+use crate::Result;
+use std::time::Duration;
+
+const RECENT_STORAGES_KEY: &str = "notify_push_recent_storages";
+/// The list is only useful for a restart, not when the push server has been stopped for a while
+const RECENT_STORAGES_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Store the most recently used storages in redis
+pub async fn save_recent_storages(
+    redis: &Redis,
+    storage_mapping: &StorageMapping,
+    count: usize,
+) -> Result<usize> {
+    let storages = storage_mapping.recent_storages(count);
+    let mut client = redis.connect().await?;
+    client
+        .set_ex(
+            RECENT_STORAGES_KEY,
+            &serde_json::to_string(&storages).unwrap(),
+            RECENT_STORAGES_EXPIRY.as_secs(),
+        )
+        .await?;
+    Ok(storages.len())
+}
+
+/// Load the storages saved by a previous instance into the cache
+pub async fn warm_up(redis: &Redis, storage_mapping: &StorageMapping) -> Result<usize> {
+    let mut client = redis.connect().await?;
+    let Some(storages) = client.get_optional(RECENT_STORAGES_KEY).await? else {
+        return Ok(0);
+    };
+    let storages: Vec<u32> = match serde_json::from_str(&storages) {
+        Ok(storages) => storages,
+        Err(e) => {
+            log::warn!("Ignoring invalid list of recent storages: {}", e);
+            return Ok(0);
+        }
+    };
+    Ok(storage_mapping.warm_up(storages).await)
+}
@@ -0,0 +1,271 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+use crate::config::WebPushConfig;
+use crate::error::{ConfigError, WebPushError};
+use crate::nc::http_client;
+use crate::storage_mapping::DatabasePool;
+use crate::user::keep_user_names;
+use crate::UserId;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use p256::ecdh::EphemeralSecret;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{query_as, Any, FromRow};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Record size advertised in the encrypted payload header, we only ever send a single record
+const RECORD_SIZE: u32 = 4096;
+/// How long the push service should keep the message around if the browser isn't reachable
+const MESSAGE_TTL: Duration = Duration::from_secs(60 * 60);
+const VAPID_VALIDITY: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug, FromRow)]
+struct Subscription {
+    endpoint: String,
+    p256dh: String,
+    auth: String,
+}
+
+/// Delivers notifications to users without an active websocket connection using the
+/// Web Push subscriptions that are registered in the Nextcloud database
+pub struct WebPush {
+    http: reqwest::Client,
+    signing_key: SigningKey,
+    public_key: String,
+    subject: String,
+    connection: DatabasePool,
+    prefix: String,
+}
+
+impl WebPush {
+    pub fn new(
+        config: WebPushConfig,
+        connection: DatabasePool,
+        prefix: String,
+        allow_self_signed: bool,
+    ) -> Result<Self, WebPushError> {
+        let key_bytes = URL_SAFE_NO_PAD
+            .decode(config.vapid_key.trim())
+            .map_err(|_| ConfigError::VapidKey)?;
+        let secret = SecretKey::from_slice(&key_bytes).map_err(|_| ConfigError::VapidKey)?;
+        let public_key =
+            URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes());
+        let http = http_client(allow_self_signed)?;
+        keep_user_names();
+
+        Ok(WebPush {
+            http,
+            signing_key: SigningKey::from(&secret),
+            public_key,
+            subject: config.subject,
+            connection,
+            prefix,
+        })
+    }
+
+    /// Send a push message to all subscriptions of the user
+    pub async fn send(&self, user: &UserId, message: &str) -> Result<(), WebPushError> {
+        let Some(user) = user.name() else {
+            return Ok(());
+        };
+        for subscription in self.get_subscriptions(&user).await? {
+            if let Err(e) = self.send_to_subscription(&subscription, message).await {
+                log::warn!("Failed to send web push message for {}: {}", user, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_subscriptions(&self, user: &str) -> Result<Vec<Subscription>, WebPushError> {
+        let connection = self.connection.get();
+        let placeholder = if connection
+            .connect_options()
+            .database_url
+            .scheme()
+            .starts_with("postgres")
+        {
+            "$1"
+        } else {
+            "?"
+        };
+        query_as::<Any, Subscription>(&format!(
+            "SELECT endpoint, p256dh, auth FROM {prefix}notifications_webpush WHERE uid = {placeholder}",
+            prefix = self.prefix,
+        ))
+        .bind(user)
+        .fetch_all(&connection)
+        .await
+        .map_err(WebPushError::Database)
+    }
+
+    async fn send_to_subscription(
+        &self,
+        subscription: &Subscription,
+        message: &str,
+    ) -> Result<(), WebPushError> {
+        let endpoint = Url::parse(&subscription.endpoint)?;
+        let body = encrypt(subscription, message.as_bytes())?;
+        let response = self
+            .http
+            .post(endpoint.clone())
+            .header("Content-Encoding", "aes128gcm")
+            .header("Content-Type", "application/octet-stream")
+            .header("TTL", MESSAGE_TTL.as_secs())
+            .header(
+                "Authorization",
+                format!(
+                    "vapid t={}, k={}",
+                    self.vapid_token(&endpoint),
+                    self.public_key
+                ),
+            )
+            .body(body)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            // we only have read access to the subscriptions, cleanup is left to the notifications app
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                log::debug!("Web push subscription {} has expired", endpoint);
+                Ok(())
+            }
+            status => Err(WebPushError::Status(status)),
+        }
+    }
+
+    fn vapid_token(&self, endpoint: &Url) -> String {
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            + VAPID_VALIDITY;
+        let header = URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            json!({
+                "aud": endpoint.origin().ascii_serialization(),
+                "exp": expires.as_secs(),
+                "sub": self.subject,
+            })
+            .to_string(),
+        );
+        let unsigned = format!("{}.{}", header, claims);
+        let signature: Signature = self.signing_key.sign(unsigned.as_bytes());
+        format!(
+            "{}.{}",
+            unsigned,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+}
+
+/// Encrypt the message following RFC 8291 using the `aes128gcm` content encoding from RFC 8188
+fn encrypt(subscription: &Subscription, message: &[u8]) -> Result<Vec<u8>, WebPushError> {
+    let ua_public = URL_SAFE_NO_PAD
+        .decode(subscription.p256dh.trim_end_matches('='))
+        .ok()
+        .and_then(|key| PublicKey::from_sec1_bytes(&key).ok())
+        .ok_or(WebPushError::InvalidSubscription)?;
+    let auth_secret = URL_SAFE_NO_PAD
+        .decode(subscription.auth.trim_end_matches('='))
+        .map_err(|_| WebPushError::InvalidSubscription)?;
+
+    let as_secret = EphemeralSecret::random(&mut OsRng);
+    let as_public = as_secret.public_key().to_encoded_point(false);
+    let ua_public_bytes = ua_public.to_encoded_point(false);
+    let ecdh_secret = as_secret.diffie_hellman(&ua_public);
+
+    let mut key_info = Vec::with_capacity(144);
+    key_info.extend_from_slice(b"WebPush: info\0");
+    key_info.extend_from_slice(ua_public_bytes.as_bytes());
+    key_info.extend_from_slice(as_public.as_bytes());
+    let mut ikm = [0; 32];
+    Hkdf::<Sha256>::new(Some(&auth_secret), ecdh_secret.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .map_err(|_| WebPushError::Encryption)?;
+
+    let mut salt = [0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let prk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+    let mut cek = [0; 16];
+    let mut nonce = [0; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .map_err(|_| WebPushError::Encryption)?;
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .map_err(|_| WebPushError::Encryption)?;
+
+    // single record, terminated by the last-record padding delimiter
+    let mut plaintext = Vec::with_capacity(message.len() + 1);
+    plaintext.extend_from_slice(message);
+    plaintext.push(2);
+    let ciphertext = Aes128Gcm::new_from_slice(&cek)
+        .map_err(|_| WebPushError::Encryption)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| WebPushError::Encryption)?;
+
+    let mut body = Vec::with_capacity(16 + 4 + 1 + 65 + ciphertext.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(as_public.len() as u8);
+    body.extend_from_slice(as_public.as_bytes());
+    body.extend_from_slice(&ciphertext);
+    Ok(body)
+}
+
+#[test]
+fn test_encrypt_roundtrip() {
+    // decrypt the message the same way the user agent would
+    let ua_secret = SecretKey::random(&mut OsRng);
+    let ua_public = ua_secret.public_key().to_encoded_point(false);
+    let mut auth_secret = [0; 16];
+    OsRng.fill_bytes(&mut auth_secret);
+    let subscription = Subscription {
+        endpoint: String::from("https://push.example.com/foo"),
+        p256dh: URL_SAFE_NO_PAD.encode(ua_public.as_bytes()),
+        auth: URL_SAFE_NO_PAD.encode(auth_secret),
+    };
+
+    let body = encrypt(&subscription, b"notify_notification").unwrap();
+
+    let (salt, rest) = body.split_at(16);
+    assert_eq!(&rest[0..4], &RECORD_SIZE.to_be_bytes());
+    let key_length = rest[4] as usize;
+    let (as_public, ciphertext) = rest[5..].split_at(key_length);
+
+    let as_public = PublicKey::from_sec1_bytes(as_public).unwrap();
+    let ecdh_secret =
+        p256::ecdh::diffie_hellman(ua_secret.to_nonzero_scalar(), as_public.as_affine());
+    let mut key_info = Vec::new();
+    key_info.extend_from_slice(b"WebPush: info\0");
+    key_info.extend_from_slice(ua_public.as_bytes());
+    key_info.extend_from_slice(as_public.to_encoded_point(false).as_bytes());
+    let mut ikm = [0; 32];
+    Hkdf::<Sha256>::new(Some(&auth_secret), ecdh_secret.raw_secret_bytes())
+        .expand(&key_info, &mut ikm)
+        .unwrap();
+    let prk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let mut cek = [0; 16];
+    let mut nonce = [0; 12];
+    prk.expand(b"Content-Encoding: aes128gcm\0", &mut cek)
+        .unwrap();
+    prk.expand(b"Content-Encoding: nonce\0", &mut nonce)
+        .unwrap();
+
+    let plaintext = Aes128Gcm::new_from_slice(&cek)
+        .unwrap()
+        .decrypt(Nonce::from_slice(&nonce), ciphertext)
+        .unwrap();
+    assert_eq!(b"notify_notification\x02", plaintext.as_slice());
+}
@@ -0,0 +1,201 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Pre-fork mode, running multiple worker processes that share the listening port with `SO_REUSEPORT`.
+//!
+//! The parent process only starts the workers and restarts them when they exit, every worker is a complete push server
+//! with its own redis subscription. The workers are stopped by closing their stdin, so a worker also shuts down when the
+//! parent dies without getting the chance to stop them.
+
+use crate::config::Bind;
+use crate::Error;
+use std::env::{args_os, current_exe};
+use std::future::pending;
+use std::io::ErrorKind;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::net::unix::pipe;
+use tokio::process::Command;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::spawn;
+use tokio::time::sleep;
+
+/// Environment variable containing the index of a worker process
+pub const WORKER_ENV: &str = "NOTIFY_PUSH_WORKER";
+
+/// Delay before a worker that exited is started again
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether the listening socket is bound with `SO_REUSEPORT`, so the other workers can bind to the same port
+pub static REUSE_PORT: AtomicBool = AtomicBool::new(false);
+
+/// The index of this process if it's started as a worker
+pub fn worker_index() -> Option<usize> {
+    dotenvy::var(WORKER_ENV).ok()?.parse().ok()
+}
+
+/// The address a worker serves on for sockets that aren't shared between the workers
+///
+/// Tcp ports are offset by the index of the worker, unix sockets get the index as extension.
+pub fn worker_bind(bind: Bind, index: usize) -> Bind {
+    match bind {
+        Bind::Tcp(mut addr) => {
+            addr.set_port(addr.port().saturating_add(index as u16));
+            Bind::Tcp(addr)
+        }
+        Bind::Unix(path, permissions) => {
+            let mut path = path.into_os_string();
+            path.push(format!(".{}", index));
+            Bind::Unix(path.into(), permissions)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WorkerState {
+    Running,
+    /// Let the workers finish their shutdown
+    Stopping,
+    /// Stop the workers without waiting for them
+    Killing,
+}
+
+/// Start the workers and keep them running until the parent receives a shutdown signal
+pub async fn run_workers(count: usize) -> Result<(), Error> {
+    let binary = current_exe().map_err(Error::Workers)?;
+    let mut term = signal(SignalKind::terminate()).map_err(Error::SignalHook)?;
+    let mut int = signal(SignalKind::interrupt()).map_err(Error::SignalHook)?;
+
+    let (state, state_rx) = watch::channel(WorkerState::Running);
+    let workers = (0..count)
+        .map(|index| spawn(supervise_worker(binary.clone(), index, state_rx.clone())))
+        .collect::<Vec<_>>();
+    log::info!("Started {} workers", count);
+
+    // SystemD only accepts notifications from the main process by default, so the workers don't send them
+    #[cfg(feature = "systemd")]
+    sd_notify::notify(true, &[sd_notify::NotifyState::Ready]).map_err(Error::SystemD)?;
+
+    select! {
+        _ = term.recv() => {},
+        _ = int.recv() => {},
+    }
+    log::info!("shutdown signal received, stopping workers");
+    state.send(WorkerState::Stopping).ok();
+
+    let stopped = futures::future::join_all(workers);
+    tokio::pin!(stopped);
+    // a second signal stops the workers without waiting for their pre-stop delay
+    select! {
+        _ = &mut stopped => return Ok(()),
+        _ = term.recv() => {},
+        _ = int.recv() => {},
+    }
+    log::info!("stopping workers immediately");
+    state.send(WorkerState::Killing).ok();
+    stopped.await;
+    Ok(())
+}
+
+async fn supervise_worker(binary: PathBuf, index: usize, mut state: watch::Receiver<WorkerState>) {
+    loop {
+        match start_worker(&binary, index) {
+            Ok(mut child) => {
+                // dropping our end of the pipe tells the worker to stop
+                let stdin = child.stdin.take();
+                log::debug!(
+                    "Started worker {} with pid {}",
+                    index,
+                    child.id().unwrap_or_default()
+                );
+                let stopping = select! {
+                    status = child.wait() => {
+                        match status {
+                            Ok(status) => log::error!("Worker {} exited with {}", index, status),
+                            Err(e) => log::error!("Failed to wait for worker {}: {}", index, e),
+                        }
+                        false
+                    },
+                    _ = state.wait_for(|state| *state != WorkerState::Running) => true,
+                };
+                if stopping {
+                    drop(stdin);
+                    let killing = select! {
+                        _ = child.wait() => false,
+                        _ = state.wait_for(|state| *state == WorkerState::Killing) => true,
+                    };
+                    if killing {
+                        child.kill().await.ok();
+                    }
+                    return;
+                }
+            }
+            Err(e) => log::error!("Failed to start worker {}: {}", index, e),
+        }
+
+        select! {
+            _ = sleep(RESTART_DELAY) => {},
+            _ = state.wait_for(|state| *state != WorkerState::Running) => return,
+        }
+    }
+}
+
+fn start_worker(binary: &Path, index: usize) -> std::io::Result<tokio::process::Child> {
+    Command::new(binary)
+        .args(args_os().skip(1))
+        .env(WORKER_ENV, index.to_string())
+        .stdin(Stdio::piped())
+        .spawn()
+}
+
+/// Resolves once the parent closes the stdin of this worker, never resolves when not running as a worker
+pub async fn parent_exited() {
+    if worker_index().is_none() {
+        return pending().await;
+    }
+    let receiver = std::io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .and_then(pipe::Receiver::from_owned_fd);
+    let receiver = match receiver {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            log::warn!(
+                "Failed to watch the connection to the parent process: {}",
+                e
+            );
+            return pending().await;
+        }
+    };
+    let mut buf = [0; 64];
+    loop {
+        if receiver.readable().await.is_err() {
+            return;
+        }
+        match receiver.try_read(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => return,
+        }
+    }
+}
+
+#[test]
+fn test_worker_bind() {
+    assert_eq!(
+        "127.0.0.1:7869",
+        worker_bind(Bind::Tcp(([127, 0, 0, 1], 7867).into()), 2).to_string()
+    );
+    assert_eq!(
+        "/run/metrics.sock.1",
+        worker_bind(Bind::Unix("/run/metrics.sock".into(), 0o666), 1).to_string()
+    );
+}
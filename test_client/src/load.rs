@@ -0,0 +1,219 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Publish a steady stream of generated events, to profile the push server under a controlled load.
+//!
+//! The events are targeted at the users from `LOAD_USERS` (comma separated, defaults to `admin`)
+//! and storage updates are send for the storage id from `LOAD_STORAGE` (defaults to `1`).
+
+use crate::redis_client;
+use log::info;
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use serde_json::json;
+use std::env::var;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Events are published in batches every tick to keep up with high rates
+const TICK: Duration = Duration::from_millis(10);
+const DEFAULT_MIX: &str = "storage=8,activity=1,notification=1";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EventType {
+    Storage,
+    Activity,
+    Notification,
+    Custom,
+}
+
+impl EventType {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "storage" => Ok(EventType::Storage),
+            "activity" => Ok(EventType::Activity),
+            "notification" => Ok(EventType::Notification),
+            "custom" => Ok(EventType::Custom),
+            _ => Err(Report::msg(format!(
+                "unknown event type {}, expected storage, activity, notification or custom",
+                name
+            ))),
+        }
+    }
+}
+
+/// Parse a mix like `storage=8,activity=1` into event types with their weights
+fn parse_mix(mix: &str) -> Result<Vec<(EventType, u32)>> {
+    let mix = mix
+        .split(',')
+        .map(|part| {
+            let (name, weight) = part.split_once('=').ok_or_else(|| {
+                Report::msg(format!("invalid mix {:?}, expected type=weight", part))
+            })?;
+            let weight = weight
+                .trim()
+                .parse()
+                .into_diagnostic()
+                .wrap_err_with(|| format!("invalid weight for {}", name))?;
+            Ok((EventType::parse(name.trim())?, weight))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err(Report::msg(
+            "at least one event type needs a non-zero weight",
+        ));
+    }
+    Ok(mix)
+}
+
+/// Generates the events, spreading the types according to their weights
+struct Generator {
+    mix: Vec<(EventType, u32)>,
+    total_weight: u32,
+    users: Vec<String>,
+    storage: u32,
+    count: u64,
+}
+
+impl Generator {
+    fn new(mix: Vec<(EventType, u32)>, users: Vec<String>, storage: u32) -> Self {
+        Generator {
+            total_weight: mix.iter().map(|(_, weight)| weight).sum(),
+            mix,
+            users,
+            storage,
+            count: 0,
+        }
+    }
+
+    fn event_type(&self) -> EventType {
+        let mut slot = (self.count % self.total_weight as u64) as u32;
+        for (ty, weight) in &self.mix {
+            if slot < *weight {
+                return *ty;
+            }
+            slot -= weight;
+        }
+        unreachable!("slot is always smaller than the total weight")
+    }
+
+    fn next(&mut self) -> (&'static str, String) {
+        let user = &self.users[self.count as usize % self.users.len()];
+        let event = match self.event_type() {
+            EventType::Storage => (
+                "notify_storage_update",
+                json!({
+                    "storage": self.storage,
+                    "path": format!("files/load-test/file-{}", self.count),
+                    "file_id": self.count,
+                }),
+            ),
+            EventType::Activity => ("notify_activity", json!({ "user": user })),
+            EventType::Notification => ("notify_notification", json!({ "user": user })),
+            EventType::Custom => (
+                "notify_custom",
+                json!({ "user": user, "message": "load_test", "body": self.count }),
+            ),
+        };
+        self.count += 1;
+        (event.0, event.1.to_string())
+    }
+}
+
+pub fn load(rate: &str, duration: &str, mix: Option<&str>) -> Result<()> {
+    let rate: f64 = rate
+        .parse()
+        .into_diagnostic()
+        .wrap_err("Invalid rate, expected the number of events per second")?;
+    let duration = Duration::from_secs(
+        duration
+            .parse()
+            .into_diagnostic()
+            .wrap_err("Invalid duration, expected the number of seconds")?,
+    );
+    let mix = parse_mix(mix.unwrap_or(DEFAULT_MIX))?;
+    let users = var("LOAD_USERS")
+        .unwrap_or_else(|_| String::from("admin"))
+        .split(',')
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+        .collect::<Vec<_>>();
+    if users.is_empty() {
+        return Err(Report::msg("LOAD_USERS doesn't contain any users"));
+    }
+    let storage = match var("LOAD_STORAGE") {
+        Ok(storage) => storage
+            .parse()
+            .into_diagnostic()
+            .wrap_err("Invalid LOAD_STORAGE")?,
+        Err(_) => 1,
+    };
+
+    let mut connection = redis_client()?
+        .get_connection()
+        .into_diagnostic()
+        .wrap_err("Failed to connect to redis")?;
+    let mut generator = Generator::new(mix, users, storage);
+    info!(
+        "Publishing {} events per second for {}s",
+        rate,
+        duration.as_secs()
+    );
+
+    let start = Instant::now();
+    let mut last_report = start;
+    let mut reported = 0;
+    while start.elapsed() < duration {
+        let target = (start.elapsed().as_secs_f64() * rate) as u64;
+        if target > generator.count {
+            let mut pipe = redis::pipe();
+            while generator.count < target {
+                let (channel, payload) = generator.next();
+                pipe.cmd("PUBLISH").arg(channel).arg(payload).ignore();
+            }
+            pipe.query::<()>(&mut connection)
+                .into_diagnostic()
+                .wrap_err("Failed to publish events")?;
+        }
+
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            info!(
+                "Published {} events ({:.0} per second)",
+                generator.count,
+                (generator.count - reported) as f64 / last_report.elapsed().as_secs_f64()
+            );
+            reported = generator.count;
+            last_report = Instant::now();
+        }
+        sleep(TICK);
+    }
+
+    info!(
+        "Published {} events in {:.1}s",
+        generator.count,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_generator_mix() {
+    let mix = parse_mix("storage=2, activity=1,notification=0").unwrap();
+    let mut generator = Generator::new(mix, vec![String::from("a"), String::from("b")], 5);
+    let channels = (0..6).map(|_| generator.next().0).collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "notify_storage_update",
+            "notify_storage_update",
+            "notify_activity",
+            "notify_storage_update",
+            "notify_storage_update",
+            "notify_activity"
+        ],
+        channels
+    );
+    assert!(parse_mix("storage=0").is_err());
+    assert!(parse_mix("unknown=1").is_err());
+    assert!(parse_mix("storage").is_err());
+}
@@ -3,13 +3,21 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
 
+mod load;
+mod record;
+mod tui;
+mod users;
+
 use base64::Engine;
 use flexi_logger::{AdaptiveFormat, Logger};
 use log::{debug, info, trace, warn};
 use miette::{IntoDiagnostic, Report, Result, WrapErr};
 use serde_json::Value;
 use std::env::var;
-use tungstenite::{connect, Message};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{connect, Message, WebSocket};
 use url::Url;
 
 fn main() -> Result<()> {
@@ -23,18 +31,319 @@ fn main() -> Result<()> {
     let mut args = std::env::args();
 
     let bin = args.next().unwrap();
-    let (nc_url, username, password) = match (args.next(), args.next(), args.next()) {
-        (Some(host), Some(username), Some(password)) => (host, username, password),
+    let (flags, args): (Vec<_>, Vec<_>) = args.partition(|arg| arg.starts_with("--"));
+
+    if args.first().map(String::as_str) == Some("publish") {
+        return match args.as_slice() {
+            [_, channel, payload] => publish(channel, payload),
+            _ => {
+                eprintln!("usage {} publish <channel> <json>", bin);
+                Ok(())
+            }
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("load") {
+        return match args.as_slice() {
+            [_, rate, duration] => load::load(rate, duration, None),
+            [_, rate, duration, mix] => load::load(rate, duration, Some(mix)),
+            _ => {
+                eprintln!(
+                    "usage {} load <events per second> <seconds> [<type>=<weight>,...]",
+                    bin
+                );
+                Ok(())
+            }
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("record") {
+        return match args.as_slice() {
+            [_, file] => record::record(Path::new(file)),
+            _ => {
+                eprintln!("usage {} record <file>", bin);
+                Ok(())
+            }
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("replay") {
+        return match args.as_slice() {
+            [_, file] => record::replay(Path::new(file)),
+            _ => {
+                eprintln!("usage {} replay <file>", bin);
+                Ok(())
+            }
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("diagnose") {
+        return match args.as_slice() {
+            [_, nc_url, username, password] => diagnose(nc_url, username, password),
+            _ => {
+                eprintln!(
+                    "usage {} diagnose <nextcloud url> <username> <password>",
+                    bin
+                );
+                Ok(())
+            }
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("users") {
+        return match args.as_slice() {
+            [_, nc_url, users_file] => users::listen_all(nc_url, Path::new(users_file)),
+            _ => {
+                eprintln!("usage {} users <nextcloud url> <users csv>", bin);
+                Ok(())
+            }
+        };
+    }
+
+    let create_app_password = flags.iter().any(|flag| flag == "--app-password");
+    let tui = flags.iter().any(|flag| flag == "--tui");
+    let delete_app_password = flags.iter().any(|flag| flag == "--delete-app-password");
+    let (nc_url, username, password) = match args.as_slice() {
+        [host, username, password] => (host.clone(), username.clone(), password.clone()),
         _ => {
-            eprintln!("usage {} <nextcloud url> <username> <password>", bin);
+            eprintln!(
+                "usage {} [--tui] [--app-password [--delete-app-password]] <nextcloud url> <username> <password>",
+                bin
+            );
             return Ok(());
         }
     };
 
-    let ws_url = if nc_url.starts_with("ws") {
+    if nc_url.starts_with("ws") && create_app_password {
+        return Err(Report::msg(
+            "an app password can only be created when connecting through the nextcloud url",
+        ));
+    }
+    let password = if create_app_password {
+        let app_password = get_app_password(&nc_url, &username, &password)?;
+        info!("Created app password");
+        app_password
+    } else {
+        password
+    };
+
+    let result = listen(&nc_url, &username, &password, tui);
+
+    if create_app_password && delete_app_password {
+        match delete_app_password_request(&nc_url, &username, &password) {
+            Ok(()) => info!("Deleted app password"),
+            Err(e) => warn!("Failed to delete app password: {:?}", e),
+        }
+    }
+
+    result
+}
+
+/// The redis channels the push server listens to
+const CHANNELS: [&str; 11] = [
+    "notify_storage_update",
+    "notify_group_membership_update",
+    "notify_user_share_created",
+    "notify_test_cookie",
+    "notify_activity",
+    "notify_notification",
+    "notify_pre_auth",
+    "notify_custom",
+    "notify_config",
+    "notify_query",
+    "notify_signal",
+];
+
+/// Publish an event to the redis server from the Nextcloud config, like the app would
+fn publish(channel: &str, payload: &str) -> Result<()> {
+    if !CHANNELS.contains(&channel) {
+        return Err(Report::msg(format!(
+            "unknown channel {}, the push server listens to {}",
+            channel,
+            CHANNELS.join(", ")
+        )));
+    }
+    // allow `publish notify_signal reset` instead of having to quote the json string
+    let payload = match serde_json::from_str::<Value>(payload) {
+        Ok(_) => payload.to_string(),
+        Err(_)
+            if payload
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Value::from(payload).to_string()
+        }
+        Err(e) => return Err(e).into_diagnostic().wrap_err("Invalid json payload"),
+    };
+
+    let mut connection = redis_client()?
+        .get_connection()
+        .into_diagnostic()
+        .wrap_err("Failed to connect to redis")?;
+    let receivers: usize = redis::cmd("PUBLISH")
+        .arg(channel)
+        .arg(&payload)
+        .query(&mut connection)
+        .into_diagnostic()
+        .wrap_err("Failed to publish event")?;
+    info!(
+        "Published {} to {}, received by {} subscribers",
+        payload, channel, receivers
+    );
+    if receivers == 0 {
+        warn!("No push server is listening to the redis server");
+    }
+    Ok(())
+}
+
+/// The redis server from the Nextcloud config
+///
+/// The config is found the same way as the push server does, from `CONFIG_FILE` or `NEXTCLOUD_CONFIG_DIR`,
+/// `REDIS_URL` can be set to connect to a different redis server.
+fn redis_client() -> Result<redis::Client> {
+    match var("REDIS_URL") {
+        Ok(url) => redis::Client::open(url.as_str()),
+        Err(_) => {
+            let config_file = var("CONFIG_FILE")
+                .map(PathBuf::from)
+                .or_else(|_| {
+                    var("NEXTCLOUD_CONFIG_DIR").map(|dir| Path::new(&dir).join("config.php"))
+                })
+                .map_err(|_| {
+                    Report::msg(
+                        "set CONFIG_FILE to the path of the nextcloud config.php, or REDIS_URL",
+                    )
+                })?;
+            let config = nextcloud_config_parser::parse(&config_file)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to parse {}", config_file.display()))?;
+            let info = config
+                .redis
+                .into_vec()
+                .into_iter()
+                .next()
+                .ok_or(Report::msg("no redis configured in the nextcloud config"))?;
+            redis::Client::open(info)
+        }
+    }
+    .into_diagnostic()
+    .wrap_err("Invalid redis configuration")
+}
+
+/// Plain text response of a test endpoint, `TEST_SECRET` is send along if set
+fn get_test_endpoint(url: &str) -> Result<String, Box<ureq::Error>> {
+    let request = ureq::get(url);
+    let request = match var("TEST_SECRET") {
+        Ok(secret) => request.set("x-notify-push-test-secret", &secret),
+        Err(_) => request,
+    };
+    let response = request.call()?;
+    Ok(response
+        .into_string()
+        .map_err(ureq::Error::from)?
+        .trim()
+        .to_string())
+}
+
+/// Check the path from the client, through the reverse proxy, to the push server and on to nextcloud,
+/// using the same test endpoints as the self test of the app
+fn diagnose(nc_url: &str, user: &str, password: &str) -> Result<()> {
+    let nc_url = nc_url.trim_end_matches('/');
+    let ws_url = get_endpoint(nc_url, user, password)?;
+    info!("Found push server at {}", ws_url);
+    let push_url = ws_url
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let push_url = push_url.trim_end_matches("/ws");
+
+    let client_ip = get_test_endpoint(&format!(
+        "{}/index.php/apps/notify_push/test/remote",
         nc_url
+    ))
+    .into_diagnostic()
+    .wrap_err("Failed to get the client address from nextcloud")?;
+    info!("Nextcloud sees the client as {}", client_ip);
+
+    let push_cookie = match get_test_endpoint(&format!("{}/test/cookie", push_url)) {
+        Ok(cookie) => cookie,
+        Err(e) if matches!(*e, ureq::Error::Status(404, _)) => {
+            return Err(Report::msg(
+                "the test endpoints of the push server are disabled, \
+                they are only available in production mode while the self test is running and might require TEST_SECRET to be set",
+            ))
+        }
+        Err(e) => {
+            return Err(e)
+                .into_diagnostic()
+                .wrap_err("Failed to reach the push server through the reverse proxy")
+        }
+    };
+    info!("Client → proxy → push server: ok");
+
+    let nc_cookie = get_test_endpoint(&format!(
+        "{}/index.php/apps/notify_push/test/cookie",
+        nc_url
+    ))
+    .into_diagnostic()
+    .wrap_err("Failed to get the test cookie from nextcloud")?;
+    let reverse_cookie = get_test_endpoint(&format!("{}/test/reverse_cookie", push_url))
+        .into_diagnostic()
+        .wrap_err("Failed to get the test cookie from nextcloud through the push server")?;
+    if reverse_cookie == nc_cookie {
+        info!("Push server → nextcloud: ok");
+    } else {
+        warn!(
+            "Push server → nextcloud: the push server got {:?} instead of the test cookie {}, check the nextcloud url of the push server",
+            reverse_cookie, nc_cookie
+        );
+    }
+    if push_cookie != nc_cookie {
+        warn!(
+            "The push server has test cookie {} instead of {}, either the self test wasn't run yet or the push server doesn't receive events from redis",
+            push_cookie, nc_cookie
+        );
+    }
+
+    let forwarded = get_test_endpoint(&format!("{}/test/forwarded", push_url))
+        .into_diagnostic()
+        .wrap_err("Failed to get the forwarded addresses from the push server")?;
+    if forwarded.split(", ").any(|hop| hop == client_ip) {
+        info!(
+            "Client → proxy → push server forwarding: ok ({})",
+            forwarded
+        );
+    } else {
+        warn!(
+            "Client → proxy → push server forwarding: the push server sees {} instead of {}, make sure the reverse proxy sets the X-Forwarded-For header",
+            forwarded, client_ip
+        );
+    }
+
+    let remote = get_test_endpoint(&format!("{}/test/remote/{}", push_url, client_ip))
+        .into_diagnostic()
+        .wrap_err("Failed to test the forwarding from the push server to nextcloud")?;
+    if remote == client_ip {
+        info!("Push server → nextcloud forwarding: ok");
+    } else {
+        warn!(
+            "Push server → nextcloud forwarding: nextcloud sees {} instead of {}, add the push server address to the trusted proxies of nextcloud",
+            remote, client_ip
+        );
+    }
+
+    Ok(())
+}
+
+/// Connect to the push server, authenticate and subscribe to file id notifications
+fn connect_socket(
+    nc_url: &str,
+    username: &str,
+    password: &str,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>> {
+    let ws_url = if nc_url.starts_with("ws") {
+        nc_url.to_string()
     } else {
-        get_endpoint(&nc_url, &username, &password)?
+        get_endpoint(nc_url, username, password)?
     };
     info!("Found push server at {}", ws_url);
 
@@ -58,6 +367,16 @@ fn main() -> Result<()> {
         .into_diagnostic()
         .wrap_err("Failed to send username")?;
 
+    Ok(socket)
+}
+
+fn listen(nc_url: &str, username: &str, password: &str, tui: bool) -> Result<()> {
+    let mut socket = connect_socket(nc_url, username, password)?;
+
+    if tui {
+        return tui::run(socket);
+    }
+
     loop {
         if let Message::Text(text) = socket.read().into_diagnostic()? {
             if let Some(err) = text.strip_prefix("err: ") {
@@ -78,8 +397,8 @@ fn main() -> Result<()> {
     }
 }
 
-fn get_endpoint(nc_url: &str, user: &str, password: &str) -> Result<String> {
-    let raw = ureq::get(&format!("{}/ocs/v2.php/cloud/capabilities", nc_url))
+fn ocs_request(method: &str, url: &str, user: &str, password: &str) -> ureq::Request {
+    ureq::request(method, url)
         .set(
             "Authorization",
             &format!(
@@ -89,10 +408,62 @@ fn get_endpoint(nc_url: &str, user: &str, password: &str) -> Result<String> {
         )
         .set("Accept", "application/json")
         .set("OCS-APIREQUEST", "true")
-        .call()
-        .into_diagnostic()?
-        .into_string()
-        .into_diagnostic()?;
+}
+
+/// Exchange the login credentials for a dedicated app password
+fn get_app_password(nc_url: &str, user: &str, password: &str) -> Result<String> {
+    let response = ocs_request(
+        "GET",
+        &format!("{}/ocs/v2.php/core/getapppassword", nc_url),
+        user,
+        password,
+    )
+    .call();
+    let raw = match response {
+        Ok(response) => response.into_string().into_diagnostic()?,
+        Err(ureq::Error::Status(403, _)) => {
+            return Err(Report::msg(
+                "can't create an app password when logging in with an app password",
+            ))
+        }
+        Err(e) => {
+            return Err(e)
+                .into_diagnostic()
+                .wrap_err("Failed to create app password")
+        }
+    };
+    let json: Value = serde_json::from_str(&raw)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to decode json app password response: {}", raw))?;
+    json["ocs"]["data"]["apppassword"]
+        .as_str()
+        .map(|password| password.to_string())
+        .ok_or(Report::msg("invalid app password response"))
+}
+
+fn delete_app_password_request(nc_url: &str, user: &str, app_password: &str) -> Result<()> {
+    ocs_request(
+        "DELETE",
+        &format!("{}/ocs/v2.php/core/apppassword", nc_url),
+        user,
+        app_password,
+    )
+    .call()
+    .into_diagnostic()?;
+    Ok(())
+}
+
+fn get_endpoint(nc_url: &str, user: &str, password: &str) -> Result<String> {
+    let raw = ocs_request(
+        "GET",
+        &format!("{}/ocs/v2.php/cloud/capabilities", nc_url),
+        user,
+        password,
+    )
+    .call()
+    .into_diagnostic()?
+    .into_string()
+    .into_diagnostic()?;
     trace!("Capabilities response: {}", raw);
     let json: Value = serde_json::from_str(&raw)
         .into_diagnostic()
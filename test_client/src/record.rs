@@ -0,0 +1,152 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Record the events send to the push server, and replay them later against a different server.
+//!
+//! Recordings are stored as one json object per line, containing the channel, the payload and
+//! the time in milliseconds since the recording started.
+
+use crate::{redis_client, CHANNELS};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use serde_json::{json, Value};
+use std::fs::{read_to_string, File};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+struct RecordedEvent {
+    time: Duration,
+    channel: String,
+    payload: String,
+}
+
+impl RecordedEvent {
+    fn to_json(&self) -> Value {
+        json!({
+            "time": self.time.as_millis() as u64,
+            "channel": self.channel,
+            "payload": self.payload,
+        })
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let json: Value = serde_json::from_str(line).into_diagnostic()?;
+        match (
+            json["time"].as_u64(),
+            json["channel"].as_str(),
+            json["payload"].as_str(),
+        ) {
+            (Some(time), Some(channel), Some(payload)) => Ok(RecordedEvent {
+                time: Duration::from_millis(time),
+                channel: channel.to_string(),
+                payload: payload.to_string(),
+            }),
+            _ => Err(Report::msg("expected time, channel and payload")),
+        }
+    }
+}
+
+/// Write all events published to the push server channels to `file` until interrupted
+pub fn record(file: &Path) -> Result<()> {
+    let mut out = File::create(file)
+        .map(LineWriter::new)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", file.display()))?;
+    let mut connection = redis_client()?
+        .get_connection()
+        .into_diagnostic()
+        .wrap_err("Failed to connect to redis")?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub
+        .subscribe(&CHANNELS[..])
+        .into_diagnostic()
+        .wrap_err("Failed to subscribe to events")?;
+    info!("Recording events to {}", file.display());
+
+    let start = Instant::now();
+    let mut count = 0;
+    loop {
+        let msg = pubsub
+            .get_message()
+            .into_diagnostic()
+            .wrap_err("Failed to receive event")?;
+        let payload = match msg.get_payload::<String>() {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Skipping event with invalid payload: {}", e);
+                continue;
+            }
+        };
+        let event = RecordedEvent {
+            time: start.elapsed(),
+            channel: msg.get_channel_name().to_string(),
+            payload,
+        };
+        writeln!(out, "{}", event.to_json())
+            .into_diagnostic()
+            .wrap_err("Failed to write event")?;
+        count += 1;
+        info!(
+            "Recorded {} on {} ({} total)",
+            event.payload, event.channel, count
+        );
+    }
+}
+
+/// Publish the events from a recording with the same timing as they were recorded
+pub fn replay(file: &Path) -> Result<()> {
+    let content = read_to_string(file)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+    let events = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            RecordedEvent::parse(line).wrap_err_with(|| format!("Invalid event on line {}", i + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut connection = redis_client()?
+        .get_connection()
+        .into_diagnostic()
+        .wrap_err("Failed to connect to redis")?;
+    info!("Replaying {} events", events.len());
+
+    let start = Instant::now();
+    for event in &events {
+        if let Some(wait) = event.time.checked_sub(start.elapsed()) {
+            sleep(wait);
+        }
+        let receivers: usize = redis::cmd("PUBLISH")
+            .arg(&event.channel)
+            .arg(&event.payload)
+            .query(&mut connection)
+            .into_diagnostic()
+            .wrap_err("Failed to publish event")?;
+        info!(
+            "Published {} to {}, received by {} subscribers",
+            event.payload, event.channel, receivers
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_recorded_event_roundtrip() {
+    let event = RecordedEvent {
+        time: Duration::from_millis(1500),
+        channel: String::from("notify_custom"),
+        payload: String::from(r#"{"user":"foo","message":"bar"}"#),
+    };
+    assert_eq!(
+        event,
+        RecordedEvent::parse(&event.to_json().to_string()).unwrap()
+    );
+    assert!(RecordedEvent::parse(r#"{"channel":"notify_custom"}"#).is_err());
+}
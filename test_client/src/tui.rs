@@ -0,0 +1,173 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Live overview of the received events, redrawn in place with plain ANSI escape codes.
+
+use miette::{IntoDiagnostic, Report, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Error, Message, WebSocket};
+
+/// Window over which the event rates are calculated
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct EventStats {
+    count: usize,
+    last: Option<Instant>,
+}
+
+struct Stats {
+    started: Instant,
+    events: BTreeMap<String, EventStats>,
+    recent: VecDeque<(Instant, String)>,
+    /// Round trip time of the last answered ping
+    latency: Option<Duration>,
+    last_message: Option<String>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            events: BTreeMap::new(),
+            recent: VecDeque::new(),
+            latency: None,
+            last_message: None,
+        }
+    }
+
+    fn add(&mut self, message: &str) {
+        let now = Instant::now();
+        // file notifications include the file ids, only the type is counted
+        let ty = message.split(' ').next().unwrap_or_default().to_string();
+        let event = self.events.entry(ty.clone()).or_default();
+        event.count += 1;
+        event.last = Some(now);
+        self.recent.push_back((now, ty));
+        self.last_message = Some(message.to_string());
+    }
+
+    fn rate(&mut self, ty: &str) -> f64 {
+        let now = Instant::now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        let window = RATE_WINDOW
+            .min(now.duration_since(self.started))
+            .max(Duration::from_secs(1));
+        self.recent.iter().filter(|(_, event)| event == ty).count() as f64 / window.as_secs_f64()
+    }
+
+    fn render(&mut self, out: &mut impl Write) -> io::Result<()> {
+        // clear the screen and move the cursor to the top left
+        write!(out, "\x1b[2J\x1b[H")?;
+        writeln!(
+            out,
+            "connected for {}s, latency {}",
+            self.started.elapsed().as_secs(),
+            self.latency.map_or_else(
+                || String::from("-"),
+                |latency| format!("{}ms", latency.as_millis())
+            )
+        )?;
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<24} {:>8} {:>10} {:>12}",
+            "event", "count", "per sec", "last (s ago)"
+        )?;
+        let types = self.events.keys().cloned().collect::<Vec<_>>();
+        for ty in types {
+            let rate = self.rate(&ty);
+            let event = &self.events[&ty];
+            writeln!(
+                out,
+                "{:<24} {:>8} {:>10.2} {:>12}",
+                ty,
+                event.count,
+                rate,
+                event.last.map_or_else(
+                    || String::from("-"),
+                    |last| last.elapsed().as_secs().to_string()
+                )
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(
+            out,
+            "last message: {}",
+            self.last_message.as_deref().unwrap_or("-")
+        )?;
+        out.flush()
+    }
+}
+
+/// Reads need to time out so the screen is redrawn and pings are sent while no events are received
+fn set_read_timeout(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    timeout: Duration,
+) -> io::Result<()> {
+    match socket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(Some(timeout)),
+        MaybeTlsStream::Rustls(stream) => stream.get_mut().set_read_timeout(Some(timeout)),
+        _ => Ok(()),
+    }
+}
+
+pub fn run(mut socket: WebSocket<MaybeTlsStream<TcpStream>>) -> Result<()> {
+    set_read_timeout(&mut socket, REDRAW_INTERVAL).into_diagnostic()?;
+    let mut stats = Stats::new();
+    let mut stdout = io::stdout();
+    let mut last_redraw = Instant::now();
+    let mut next_ping = Instant::now();
+
+    loop {
+        if Instant::now() >= next_ping {
+            let sent = stats.started.elapsed().as_nanos() as u64;
+            socket
+                .send(Message::Ping(sent.to_le_bytes().to_vec().into()))
+                .into_diagnostic()?;
+            next_ping = Instant::now() + PING_INTERVAL;
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Some(err) = text.strip_prefix("err: ") {
+                    return Err(Report::msg(format!("Received error: {}", err)));
+                }
+                if text.as_str() != "authenticated" {
+                    stats.add(&text);
+                }
+            }
+            Ok(Message::Pong(data)) => {
+                if let Ok(sent) = <[u8; 8]>::try_from(data.as_ref()) {
+                    let sent = Duration::from_nanos(u64::from_le_bytes(sent));
+                    stats.latency = Some(stats.started.elapsed().saturating_sub(sent));
+                }
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e).into_diagnostic(),
+        }
+
+        if last_redraw.elapsed() >= REDRAW_INTERVAL {
+            stats.render(&mut stdout).into_diagnostic()?;
+            last_redraw = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Listen for multiple users at once and report which of them received each event.
+//!
+//! Useful for debugging cases where only some of the users with access to a file get notified.
+
+use crate::connect_socket;
+use log::{info, warn};
+use miette::{IntoDiagnostic, Report, Result, WrapErr};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// How long to wait for other users to receive an event before reporting it
+const COLLECT_TIME: Duration = Duration::from_secs(2);
+
+enum UserEvent {
+    Connected(String),
+    Message(String, String),
+    Closed(String, Option<Report>),
+}
+
+/// Parse `username,password` lines, empty lines and lines starting with `#` are ignored
+fn parse_users(content: &str) -> Result<Vec<(String, String)>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(',')
+                .map(|(user, password)| (user.trim().to_string(), password.trim().to_string()))
+                .ok_or_else(|| {
+                    Report::msg(format!(
+                        "invalid line {:?}, expected username,password",
+                        line
+                    ))
+                })
+        })
+        .collect()
+}
+
+fn listen_user(
+    nc_url: &str,
+    username: &str,
+    password: &str,
+    events: &Sender<UserEvent>,
+) -> Result<()> {
+    let mut socket = connect_socket(nc_url, username, password)?;
+    loop {
+        match socket.read().into_diagnostic()? {
+            Message::Text(text) => {
+                if let Some(err) = text.strip_prefix("err: ") {
+                    return Err(Report::msg(format!("Received error: {}", err)));
+                } else if text.as_str() == "authenticated" {
+                    events.send(UserEvent::Connected(username.to_string())).ok();
+                } else {
+                    events
+                        .send(UserEvent::Message(username.to_string(), text.to_string()))
+                        .ok();
+                }
+            }
+            Message::Close(_) => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+pub fn listen_all(nc_url: &str, users_file: &Path) -> Result<()> {
+    let content = read_to_string(users_file)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", users_file.display()))?;
+    let users = parse_users(&content)?;
+    if users.is_empty() {
+        return Err(Report::msg(format!(
+            "no users found in {}",
+            users_file.display()
+        )));
+    }
+
+    let (tx, rx) = channel();
+    for (username, password) in users {
+        let tx = tx.clone();
+        let nc_url = nc_url.to_string();
+        thread::spawn(move || {
+            let result = listen_user(&nc_url, &username, &password, &tx);
+            tx.send(UserEvent::Closed(username, result.err())).ok();
+        });
+    }
+    drop(tx);
+
+    let mut connected = BTreeSet::new();
+    // events that are still waiting for other users to receive them, by message
+    let mut pending: HashMap<String, (Instant, BTreeSet<String>)> = HashMap::new();
+
+    loop {
+        let timeout = pending
+            .values()
+            .map(|(first, _)| (*first + COLLECT_TIME).saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(COLLECT_TIME);
+        match rx.recv_timeout(timeout) {
+            Ok(UserEvent::Connected(user)) => {
+                info!("{} authenticated", user);
+                connected.insert(user);
+            }
+            Ok(UserEvent::Message(user, message)) => {
+                pending
+                    .entry(message)
+                    .or_insert_with(|| (Instant::now(), BTreeSet::new()))
+                    .1
+                    .insert(user);
+            }
+            Ok(UserEvent::Closed(user, err)) => {
+                match err {
+                    Some(err) => warn!("Connection for {} failed: {:?}", user, err),
+                    None => warn!("Connection for {} closed", user),
+                }
+                connected.remove(&user);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Report::msg("all connections closed"))
+            }
+        }
+
+        let done = pending
+            .iter()
+            .filter(|(_, (first, _))| first.elapsed() >= COLLECT_TIME)
+            .map(|(message, _)| message.clone())
+            .collect::<Vec<_>>();
+        for message in done {
+            let (_, received) = pending.remove(&message).unwrap();
+            let missed = connected.difference(&received).cloned().collect::<Vec<_>>();
+            let received = received.into_iter().collect::<Vec<_>>();
+            if missed.is_empty() {
+                info!("{} received by all {} users", message, received.len());
+            } else {
+                warn!(
+                    "{} received by {} but not by {}",
+                    message,
+                    received.join(", "),
+                    missed.join(", ")
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_users() {
+    let users = parse_users("# user,app password\nalice, a,b\n\n  bob,secret  \n").unwrap();
+    assert_eq!(
+        vec![
+            (String::from("alice"), String::from("a,b")),
+            (String::from("bob"), String::from("secret"))
+        ],
+        users
+    );
+    assert!(parse_users("alice").is_err());
+}
@@ -0,0 +1,599 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Utilities for running a push server against a mock redis server, Nextcloud instance and database in tests
+
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use dashmap::DashMap;
+use flexi_logger::{Logger, LoggerHandle};
+use futures::future::select;
+use futures::{pin_mut, FutureExt};
+use futures::{SinkExt, StreamExt};
+use http_auth_basic::Credentials;
+use notify_push::auth::AuthProviderKind;
+use notify_push::config::{Bind, Config, HttpLimits};
+use notify_push::message::{MergeTime, DEBOUNCE_ENABLE, DEFAULT_MAX_FILE_IDS};
+use notify_push::unknown_channel::UnknownChannelPolicy;
+use notify_push::{listen_loop, serve, App};
+use once_cell::sync::Lazy;
+use sqlx::AnyPool;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::spawn;
+use tokio::time::timeout;
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+mod mock_redis;
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+static LAST_PORT: AtomicU16 = AtomicU16::new(1024);
+
+pub async fn listen_available_port() -> Option<TcpListener> {
+    for _ in LAST_PORT.load(Ordering::SeqCst)..65535 {
+        let port = LAST_PORT.fetch_add(1, Ordering::SeqCst);
+        if let Ok(tcp) = TcpListener::bind(("127.0.0.1", port)).await {
+            return Some(tcp);
+        }
+    }
+
+    None
+}
+
+/// Faults injected into the responses of the mock Nextcloud instance
+#[derive(Default)]
+struct NextcloudFaults {
+    latency_ms: AtomicU64,
+    failures: AtomicUsize,
+    untrusted_domain: AtomicBool,
+    untrusted_proxy: AtomicBool,
+    requests: AtomicUsize,
+}
+
+/// The test cookie as stored in the mock Nextcloud instance
+pub const TEST_COOKIE: u32 = 1234;
+
+/// Mock redis server, Nextcloud instance and database for a push server to connect to
+pub struct Services {
+    redis: SocketAddr,
+    nextcloud: SocketAddr,
+    redis_shutdown: oneshot::Sender<()>,
+    _nextcloud_shutdown: oneshot::Sender<()>,
+    users: Arc<DashMap<String, String>>,
+    presence: Arc<DashMap<String, String>>,
+    remote_config: Arc<Mutex<serde_json::Value>>,
+    registered_endpoint: Arc<Mutex<Option<(String, String)>>>,
+    faults: Arc<NextcloudFaults>,
+    db: AnyPool,
+}
+
+static LOG_HANDLE: Lazy<LoggerHandle> =
+    Lazy::new(|| Logger::try_with_str("").unwrap().start().unwrap());
+
+impl Services {
+    pub async fn new() -> Self {
+        sqlx::any::install_default_drivers();
+        DEBOUNCE_ENABLE.store(false, Ordering::SeqCst);
+        let redis_tcp = listen_available_port()
+            .await
+            .expect("Can't find open port for redis");
+        let nextcloud_tcp = listen_available_port()
+            .await
+            .expect("Can't find open port for nextcloud mock");
+
+        let redis_addr = redis_tcp
+            .local_addr()
+            .expect("Failed to get redis socket address");
+        let nextcloud_addr = nextcloud_tcp
+            .local_addr()
+            .expect("Failed to get nextcloud mock socket address");
+
+        // use the port in the db name to prevent collisions
+        let db = AnyPool::connect(&format!(
+            "sqlite:file:memory{}?mode=memory&cache=shared",
+            nextcloud_addr.port()
+        ))
+        .await
+        .expect("Failed to connect sqlite database");
+
+        sqlx::query("CREATE TABLE oc_filecache(fileid BIGINT, path TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE INDEX fc_id ON oc_filecache (fileid)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE INDEX mount_storage ON oc_mounts (storage_id)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE INDEX mount_root ON oc_mounts (root_id)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE oc_appconfig(appid TEXT, configkey TEXT, configvalue TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE oc_group_user(gid TEXT, uid TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let users: Arc<DashMap<String, String>> = Arc::default();
+
+        async fn uid(
+            State(users): State<Arc<DashMap<String, String>>>,
+            headers: HeaderMap,
+        ) -> Response {
+            let Some(auth) = headers
+                .get(AUTHORIZATION)
+                .and_then(|auth| auth.to_str().ok())
+            else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            let credentials = match Credentials::from_header(auth.to_string()) {
+                Ok(credentials) => credentials,
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            };
+            match users.get(&credentials.user_id) {
+                Some(pass) if pass.value() == &credentials.password => {
+                    credentials.user_id.into_response()
+                }
+                _ => StatusCode::UNAUTHORIZED.into_response(),
+            }
+        }
+
+        let presence: Arc<DashMap<String, String>> = Arc::default();
+
+        let presence_state = presence.clone();
+        let presence_update = post(move |Json(update): Json<serde_json::Value>| async move {
+            presence_state.insert(
+                update["user"].as_str().unwrap_or_default().into(),
+                update["state"].as_str().unwrap_or_default().into(),
+            );
+            StatusCode::OK
+        });
+
+        let remote_config = Arc::new(Mutex::new(serde_json::json!({})));
+
+        let remote_config_state = remote_config.clone();
+        let get_remote_config =
+            get(move || async move { Json(remote_config_state.lock().unwrap().clone()) });
+
+        let registered_endpoint: Arc<Mutex<Option<(String, String)>>> = Arc::default();
+
+        let registered_endpoint_state = registered_endpoint.clone();
+        let register_endpoint = post(
+            move |headers: HeaderMap, Form(form): Form<HashMap<String, String>>| async move {
+                let secret = headers
+                    .get("x-notify-push-test-secret")
+                    .and_then(|secret| secret.to_str().ok())
+                    .unwrap_or_default();
+                *registered_endpoint_state.lock().unwrap() = Some((
+                    form.get("endpoint").cloned().unwrap_or_default(),
+                    secret.to_string(),
+                ));
+                StatusCode::OK
+            },
+        );
+
+        let faults: Arc<NextcloudFaults> = Arc::default();
+
+        // delays every request and, if a fault is configured, responds with an error instead of passing on to the other routes
+        async fn inject_faults(
+            State(faults): State<Arc<NextcloudFaults>>,
+            request: Request,
+            next: Next,
+        ) -> Response {
+            faults.requests.fetch_add(1, Ordering::SeqCst);
+            let latency = faults.latency_ms.load(Ordering::SeqCst);
+            if latency > 0 {
+                sleep(Duration::from_millis(latency)).await;
+            }
+            if faults.untrusted_domain.load(Ordering::SeqCst) {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Access through untrusted domain, see admin-trusted-domains",
+                )
+                    .into_response();
+            }
+            match faults
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                    count.checked_sub(1)
+                }) {
+                Ok(_) => (StatusCode::SERVICE_UNAVAILABLE, "Service unavailable").into_response(),
+                Err(_) => next.run(request).await,
+            }
+        }
+
+        // like nextcloud, only use the forwarded address if the request comes from a trusted proxy
+        let remote_faults = faults.clone();
+        let remote_test = get(move |headers: HeaderMap| async move {
+            match headers.get("x-forwarded-for") {
+                Some(forwarded) if !remote_faults.untrusted_proxy.load(Ordering::SeqCst) => {
+                    forwarded.to_str().unwrap_or_default().to_string()
+                }
+                _ => String::from("127.0.0.1"),
+            }
+        });
+
+        let router = Router::new()
+            .route("/presence", presence_update)
+            .route("/index.php/apps/notify_push/config", get_remote_config)
+            .route("/index.php/apps/notify_push/endpoint", register_endpoint)
+            .route("/index.php/apps/notify_push/test/remote", remote_test)
+            .route(
+                "/index.php/apps/notify_push/test/cookie",
+                get(|| async { TEST_COOKIE.to_string() }),
+            )
+            .fallback(uid)
+            .with_state(users.clone())
+            .layer(middleware::from_fn_with_state(
+                faults.clone(),
+                inject_faults,
+            ));
+
+        let (redis_shutdown, redis_shutdown_rx) = oneshot::channel();
+        let (nextcloud_shutdown, nextcloud_shutdown_rx) = oneshot::channel();
+
+        spawn(async move {
+            axum::serve(nextcloud_tcp, router)
+                .with_graceful_shutdown(nextcloud_shutdown_rx.map(|_| ()))
+                .await
+                .ok();
+        });
+        spawn(async move {
+            mock_redis::run(redis_tcp, redis_shutdown_rx).await;
+        });
+
+        Self {
+            redis: redis_addr,
+            nextcloud: nextcloud_addr,
+            redis_shutdown,
+            _nextcloud_shutdown: nextcloud_shutdown,
+            users,
+            presence,
+            remote_config,
+            registered_endpoint,
+            faults,
+            db,
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            database: "sqlite::memory:?cache=shared".parse().unwrap(),
+            database_prefix: "oc_".to_string(),
+            redis: vec![format!("redis://{}", self.redis).parse().unwrap()],
+            redis_command: Vec::new(),
+            nextcloud_url: format!("http://{}/", self.nextcloud),
+            metrics_bind: None,
+            log_level: "".to_string(),
+            bind: Bind::Tcp(self.nextcloud),
+            allow_self_signed: false,
+            no_ansi: false,
+            tls: None,
+            metrics_tls: None,
+            max_debounce_time: 15,
+            max_connection_time: 0,
+            presence_webhook: None,
+            web_push: None,
+            resume_sessions: false,
+            instance_name: None,
+            production: false,
+            test_secret: None,
+            forwarded_for_depth: None,
+            require_secure: false,
+            log_requests: false,
+            strict_version: false,
+            health_check_interval: Duration::ZERO,
+            wait_for_backends: Duration::ZERO,
+            pre_stop_delay: Duration::ZERO,
+            connection_ramp_rate: 0,
+            connection_ramp_duration: Duration::ZERO,
+            warm_up_storages: 0,
+            persist_metrics: None,
+            otlp_endpoint: None,
+            per_user_delivery: false,
+            transfer_tokens: false,
+            remote_config: false,
+            max_task_panics: 0,
+            crash_dump_dir: None,
+            verify_proxy: false,
+            public_url: None,
+            update_check_interval: Duration::ZERO,
+            self_update: false,
+            admin_token: None,
+            push_token: None,
+            storage_stats: 0,
+            user_message_window: Duration::ZERO,
+            user_message_threshold: None,
+            user_message_webhook: None,
+            storm_threshold: None,
+            unknown_channels: UnknownChannelPolicy::Sample,
+            max_file_ids: DEFAULT_MAX_FILE_IDS,
+            auth_providers: vec![AuthProviderKind::Nextcloud],
+            auth_token_file: None,
+            ldap_url: None,
+            ldap_bind_dn: None,
+            auth_failure_limit: 0,
+            auth_failure_cooldown: Duration::from_secs(300),
+            workers: 1,
+            cpu_set: None,
+            io_uring: false,
+            runtime_shards: Vec::new(),
+            redis_tls_cert: None,
+            redis_tls_key: None,
+            redis_tls_ca: None,
+            merge_time: MergeTime::default(),
+            custom_debounce: Vec::new(),
+            metrics_labels: Vec::new(),
+            http_limits: HttpLimits::default(),
+        }
+    }
+
+    pub async fn app(&self, config: Config) -> App {
+        App::with_connection(self.db.clone(), config, LOG_HANDLE.clone(), false)
+            .await
+            .unwrap()
+    }
+
+    pub async fn spawn_server(&self) -> ServerHandle {
+        self.spawn_server_with_config(self.config()).await
+    }
+
+    pub async fn spawn_server_with_config(&self, config: Config) -> ServerHandle {
+        let http_limits = config.http_limits.clone();
+        self.spawn_server_with_limits(Arc::new(self.app(config).await), http_limits)
+            .await
+    }
+
+    pub async fn spawn_server_with_app(&self, app: Arc<App>) -> ServerHandle {
+        self.spawn_server_with_limits(app, HttpLimits::default())
+            .await
+    }
+
+    async fn spawn_server_with_limits(
+        &self,
+        app: Arc<App>,
+        http_limits: HttpLimits,
+    ) -> ServerHandle {
+        let addr = async {
+            let tcp = listen_available_port().await.unwrap();
+            tcp.local_addr()
+        }
+        .await
+        .unwrap();
+
+        let (serve_tx, serve_rx) = oneshot::channel();
+        let (listen_tx, listen_rx) = oneshot::channel();
+
+        let bind = Bind::Tcp(addr);
+        spawn(async move {
+            let serve = serve(app.clone(), bind, serve_rx, None, &http_limits, 15, 0).unwrap();
+            let listen = listen_loop(app.clone(), listen_rx);
+
+            pin_mut!(serve);
+            pin_mut!(listen);
+
+            select(serve, listen).await;
+        });
+
+        sleep(Duration::from_millis(10)).await;
+
+        ServerHandle {
+            _serve_handle: serve_tx,
+            _listen_handle: listen_tx,
+            port: addr.port(),
+        }
+    }
+
+    /// Stop the redis server and start a new, empty one on the same address after `downtime`
+    ///
+    /// This waits until running push servers had the chance to re-subscribe
+    pub async fn restart_redis(&mut self, downtime: Duration) {
+        let (redis_shutdown, redis_shutdown_rx) = oneshot::channel();
+        drop(std::mem::replace(&mut self.redis_shutdown, redis_shutdown));
+        sleep(downtime).await;
+
+        let redis_tcp = TcpListener::bind(self.redis).await.unwrap();
+        spawn(async move {
+            mock_redis::run(redis_tcp, redis_shutdown_rx).await;
+        });
+        sleep(Duration::from_millis(1500)).await;
+    }
+
+    /// Delay every response from the mock Nextcloud server
+    pub fn set_nextcloud_latency(&self, latency: Duration) {
+        self.faults
+            .latency_ms
+            .store(latency.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Respond to the next `count` requests to the mock Nextcloud server with a `503` error
+    pub fn fail_nextcloud_requests(&self, count: usize) {
+        self.faults.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Respond to all requests with the error Nextcloud gives when it's accessed through an untrusted domain
+    pub fn set_untrusted_domain(&self, untrusted: bool) {
+        self.faults
+            .untrusted_domain
+            .store(untrusted, Ordering::SeqCst);
+    }
+
+    /// Ignore the forwarded client address, like Nextcloud does when the push server isn't a trusted proxy
+    pub fn set_untrusted_proxy(&self, untrusted: bool) {
+        self.faults
+            .untrusted_proxy
+            .store(untrusted, Ordering::SeqCst);
+    }
+
+    /// Number of requests the mock Nextcloud server has received
+    pub fn nextcloud_requests(&self) -> usize {
+        self.faults.requests.load(Ordering::SeqCst)
+    }
+
+    /// Address of the mock Nextcloud server, additional routes like `/presence` are served here
+    pub fn nextcloud_addr(&self) -> SocketAddr {
+        self.nextcloud
+    }
+
+    /// Last state received by the mock presence webhook for a user
+    pub fn presence(&self, user: &str) -> Option<String> {
+        self.presence.get(user).map(|state| state.clone())
+    }
+
+    /// Settings returned by the mock app for the push server
+    pub fn set_remote_config(&self, config: serde_json::Value) {
+        *self.remote_config.lock().unwrap() = config;
+    }
+
+    /// The last endpoint registered with the mock app, with the secret it was registered with
+    pub fn registered_endpoint(&self) -> Option<(String, String)> {
+        self.registered_endpoint.lock().unwrap().clone()
+    }
+
+    pub async fn redis_client(&self) -> redis::aio::MultiplexedConnection {
+        let client = redis::Client::open(self.config().redis.first().unwrap().clone()).unwrap();
+        client.get_multiplexed_async_connection().await.unwrap()
+    }
+
+    pub fn add_user(&self, username: &str, password: &str) {
+        self.users.insert(username.into(), password.into());
+    }
+
+    pub async fn add_storage_mapping(&self, username: &str, storage: u32, root: u32) {
+        sqlx::query("INSERT INTO oc_mounts(storage_id, root_id, user_id) VALUES(?, ?, ?)")
+            .bind(storage as i64)
+            .bind(root as i64)
+            .bind(username)
+            .execute(&self.db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn add_group_member(&self, group: &str, username: &str) {
+        sqlx::query("INSERT INTO oc_group_user(gid, uid) VALUES(?, ?)")
+            .bind(group)
+            .bind(username)
+            .execute(&self.db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn set_app_value(&self, app: &str, key: &str, value: &str) {
+        sqlx::query("INSERT INTO oc_appconfig(appid, configkey, configvalue) VALUES(?, ?, ?)")
+            .bind(app)
+            .bind(key)
+            .bind(value)
+            .execute(&self.db)
+            .await
+            .unwrap();
+    }
+
+    pub async fn add_filecache_item(&self, fileid: u32, path: &str) {
+        sqlx::query("INSERT INTO oc_filecache(fileid, path) VALUES(?, ?)")
+            .bind(fileid as i64)
+            .bind(path)
+            .execute(&self.db)
+            .await
+            .unwrap();
+    }
+}
+
+/// A running push server, the server is stopped when the handle is dropped
+pub struct ServerHandle {
+    _serve_handle: oneshot::Sender<()>,
+    _listen_handle: oneshot::Sender<()>,
+    port: u16,
+}
+
+impl ServerHandle {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn connect(&self) -> Client {
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", self.port))
+            .await
+            .unwrap()
+            .0
+    }
+
+    /// Connect to the push server requesting the subprotocols, returns the subprotocol selected by the server
+    pub async fn connect_with_protocol(&self, protocols: &str) -> (Client, Option<String>) {
+        let mut request = format!("ws://127.0.0.1:{}/ws", self.port)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocols.parse().unwrap());
+        let (client, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let protocol = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|protocol| protocol.to_str().ok())
+            .map(String::from);
+        (client, protocol)
+    }
+
+    /// Connect to the push server and authenticate with the provided credentials
+    pub async fn connect_auth(&self, username: &str, password: &str) -> Client {
+        let mut client =
+            tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", self.port))
+                .await
+                .unwrap()
+                .0;
+
+        client.send(Message::Text(username.into())).await.unwrap();
+        client.send(Message::Text(password.into())).await.unwrap();
+
+        assert_next_message(&mut client, "authenticated").await;
+
+        client
+    }
+}
+
+/// Assert that the next message received by the client is `expected`
+pub async fn assert_next_message(client: &mut Client, expected: &str) {
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        timeout(Duration::from_millis(200), client.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap(),
+        Message::Text(expected.into())
+    );
+}
+
+/// Assert that the client didn't receive any message
+pub async fn assert_no_message(client: &mut Client) {
+    sleep(Duration::from_millis(5)).await;
+    assert!(timeout(Duration::from_millis(10), client.next())
+        .await
+        .is_err());
+}
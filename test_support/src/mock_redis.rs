@@ -0,0 +1,148 @@
+/*
+ * SPDX-FileCopyrightText: 2026 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Mock redis server
+//!
+//! Pub/sub is handled by `mini-redis`, the key-value commands are handled here since `mini-redis` lacks `DEL` and `GETDEL`
+
+use crate::listen_available_port;
+use bytes::Bytes;
+use mini_redis::{Connection, Frame};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::{spawn, JoinSet};
+use tokio::time::Duration;
+
+type Store = Arc<Mutex<HashMap<Bytes, (Bytes, Option<Instant>)>>>;
+
+/// Run the mock redis server until `shutdown` completes, closing all open connections
+pub async fn run(listener: TcpListener, shutdown: impl Future) {
+    let backend = listen_available_port()
+        .await
+        .expect("Can't find open port for redis");
+    let backend_addr = backend.local_addr().unwrap();
+    let (_backend_shutdown, backend_shutdown_rx) = oneshot::channel::<()>();
+    spawn(async move {
+        mini_redis::server::run(backend, backend_shutdown_rx)
+            .await
+            .ok();
+    });
+
+    let store = Store::default();
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Ok((socket, _)) = listener.accept() => {
+                connections.spawn(handle(socket, backend_addr, store.clone()));
+            }
+        }
+    }
+}
+
+async fn handle(socket: TcpStream, backend: SocketAddr, store: Store) -> mini_redis::Result<()> {
+    let mut client = Connection::new(socket);
+    let mut backend = Connection::new(TcpStream::connect(backend).await?);
+
+    while let Some(frame) = client.read_frame().await? {
+        if let Some(reply) = apply(&store, &frame) {
+            client.write_frame(&reply).await?;
+            continue;
+        }
+
+        let subscribe = command(&frame).is_some_and(|(command, _)| command == "subscribe");
+        backend.write_frame(&frame).await?;
+        if subscribe {
+            // once subscribed, messages are pushed without being requested
+            loop {
+                tokio::select! {
+                    frame = client.read_frame() => match frame? {
+                        Some(frame) => backend.write_frame(&frame).await?,
+                        None => return Ok(()),
+                    },
+                    frame = backend.read_frame() => match frame? {
+                        Some(frame) => client.write_frame(&frame).await?,
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+        match backend.read_frame().await? {
+            Some(reply) => client.write_frame(&reply).await?,
+            None => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// Split a frame into the lowercase command name and its arguments
+fn command(frame: &Frame) -> Option<(String, Vec<Bytes>)> {
+    let Frame::Array(parts) = frame else {
+        return None;
+    };
+    let mut parts = parts.iter().map(|part| match part {
+        Frame::Bulk(bytes) => Some(bytes.clone()),
+        _ => None,
+    });
+    let command = String::from_utf8(parts.next()??.to_vec()).ok()?;
+    Some((command.to_ascii_lowercase(), parts.collect::<Option<_>>()?))
+}
+
+/// Apply the key-value commands, returns `None` for commands that should be handled by `mini-redis`
+fn apply(store: &Store, frame: &Frame) -> Option<Frame> {
+    let (command, args) = command(frame)?;
+    let mut store = store.lock().unwrap();
+    let now = Instant::now();
+    store.retain(|_, (_, expire)| !expire.is_some_and(|expire| expire <= now));
+
+    let reply = match (command.as_str(), args.as_slice()) {
+        ("get", [key]) => store
+            .get(key)
+            .map_or(Frame::Null, |(value, _)| Frame::Bulk(value.clone())),
+        ("getdel", [key]) => store
+            .remove(key)
+            .map_or(Frame::Null, |(value, _)| Frame::Bulk(value)),
+        ("del", keys) if !keys.is_empty() => Frame::Integer(
+            keys.iter()
+                .filter(|key| store.remove(*key).is_some())
+                .count() as u64,
+        ),
+        ("set", [key, value, options @ ..]) => {
+            let expire = match options {
+                [] => None,
+                [unit, amount] => {
+                    let Some(amount) = std::str::from_utf8(amount)
+                        .ok()
+                        .and_then(|amount| amount.parse().ok())
+                    else {
+                        return Some(Frame::Error(
+                            "ERR value is not an integer or out of range".into(),
+                        ));
+                    };
+                    match unit.to_ascii_lowercase().as_slice() {
+                        b"ex" => Some(now + Duration::from_secs(amount)),
+                        b"px" => Some(now + Duration::from_millis(amount)),
+                        _ => return Some(Frame::Error("ERR syntax error".into())),
+                    }
+                }
+                _ => return Some(Frame::Error("ERR syntax error".into())),
+            };
+            store.insert(key.clone(), (value.clone(), expire));
+            Frame::Simple("OK".into())
+        }
+        ("get" | "getdel" | "del" | "set", _) => Frame::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command
+        )),
+        _ => return None,
+    };
+    Some(reply)
+}
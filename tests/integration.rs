@@ -3,335 +3,295 @@
  * SPDX-License-Identifier: AGPL-3.0-or-later
  */
 
-use dashmap::DashMap;
-use flexi_logger::{Logger, LoggerHandle};
-use futures::future::select;
-use futures::{pin_mut, FutureExt};
 use futures::{SinkExt, StreamExt};
-use http_auth_basic::Credentials;
-use notify_push::config::{Bind, Config};
-use notify_push::message::DEBOUNCE_ENABLE;
-use notify_push::{listen_loop, serve, App};
-use once_cell::sync::Lazy;
+use notify_push::auth::AuthProviderKind;
+use notify_push::event::RelayedMessage;
+use notify_push::metrics::METRICS;
+use notify_push_test_support::{
+    assert_next_message, assert_no_message, Client, Services, TEST_COOKIE,
+};
 use redis::AsyncCommands;
-use smallvec::alloc::sync::Arc;
 use sqlx::AnyPool;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU16, Ordering};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::oneshot;
-use tokio::task::spawn;
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::time::timeout;
 use tokio::time::{sleep, Duration};
-use tokio_stream::wrappers::TcpListenerStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-use warp::http::StatusCode;
-use warp::{Filter, Reply};
 
-static LAST_PORT: AtomicU16 = AtomicU16::new(1024);
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
 
-async fn listen_available_port() -> Option<TcpListener> {
-    for _ in LAST_PORT.load(Ordering::SeqCst)..65535 {
-        let port = LAST_PORT.fetch_add(1, Ordering::SeqCst);
-        if let Ok(tcp) = TcpListener::bind(("127.0.0.1", port)).await {
-            return Some(tcp);
-        }
-    }
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
 
-    None
+    assert_next_message(&mut client, "authenticated").await;
 }
 
-struct Services {
-    redis: SocketAddr,
-    nextcloud: SocketAddr,
-    _redis_shutdown: oneshot::Sender<()>,
-    _nextcloud_shutdown: oneshot::Sender<()>,
-    users: Arc<DashMap<String, String>>,
-    db: AnyPool,
-}
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_failure() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
 
-static LOG_HANDLE: Lazy<LoggerHandle> =
-    Lazy::new(|| Logger::try_with_str("").unwrap().start().unwrap());
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect().await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("not_bar".into())).await.unwrap();
 
-impl Services {
-    pub async fn new() -> Self {
-        sqlx::any::install_default_drivers();
-        DEBOUNCE_ENABLE.store(false, Ordering::SeqCst);
-        let redis_tcp = listen_available_port()
-            .await
-            .expect("Can't find open port for redis");
-        let nextcloud_tcp = listen_available_port()
-            .await
-            .expect("Can't find open port for nextcloud mock");
-
-        let redis_addr = redis_tcp
-            .local_addr()
-            .expect("Failed to get redis socket address");
-        let nextcloud_addr = nextcloud_tcp
-            .local_addr()
-            .expect("Failed to get nextcloud mock socket address");
-
-        // use the port in the db name to prevent collisions
-        let db = AnyPool::connect(&format!(
-            "sqlite:file:memory{}?mode=memory&cache=shared",
-            nextcloud_addr.port()
-        ))
-        .await
-        .expect("Failed to connect sqlite database");
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
 
-        sqlx::query("CREATE TABLE oc_filecache(fileid BIGINT, path TEXT)")
-            .execute(&db)
-            .await
-            .unwrap();
-        sqlx::query("CREATE INDEX fc_id ON oc_filecache (fileid)")
-            .execute(&db)
-            .await
-            .unwrap();
-        sqlx::query("CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT)")
-            .execute(&db)
-            .await
-            .unwrap();
-        sqlx::query("CREATE INDEX mount_storage ON oc_mounts (storage_id)")
-            .execute(&db)
-            .await
-            .unwrap();
-        sqlx::query("CREATE INDEX mount_root ON oc_mounts (root_id)")
-            .execute(&db)
-            .await
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_failure_limit() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.auth_failure_limit = 2;
+    // the requests come from a reverse proxy that adds the client address
+    config.forwarded_for_depth = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let connect = |client: &str| {
+        let mut request = format!("ws://127.0.0.1:{}/ws", server_handle.port())
+            .into_client_request()
             .unwrap();
-
-        let users: Arc<DashMap<String, String>> = Arc::default();
-
-        let users_filter = users.clone();
-        let users_filter = warp::any().map(move || users_filter.clone());
-
-        let uid = warp::any()
-            .and(warp::header::<String>("authorization"))
-            .and(users_filter)
-            .map(|auth, users: Arc<DashMap<String, String>>| {
-                let credentials = match Credentials::from_header(auth) {
-                    Ok(credentials) => credentials,
-                    Err(_) => return Box::new(StatusCode::BAD_REQUEST) as Box<dyn Reply>,
-                };
-                match users.get(&credentials.user_id) {
-                    Some(pass) if pass.value() == &credentials.password => {
-                        Box::new(credentials.user_id)
-                    }
-                    _ => Box::new(StatusCode::UNAUTHORIZED),
-                }
-            });
-
-        let (redis_shutdown, redis_shutdown_rx) = oneshot::channel();
-        let (nextcloud_shutdown, nextcloud_shutdown_rx) = oneshot::channel();
-
-        spawn(async move {
-            warp::serve(uid)
-                .serve_incoming_with_graceful_shutdown(
-                    TcpListenerStream::new(nextcloud_tcp),
-                    nextcloud_shutdown_rx.map(|_| ()),
-                )
-                .await;
-        });
-        spawn(async move {
-            mini_redis::server::run(redis_tcp, redis_shutdown_rx)
-                .await
-                .ok();
-        });
-
-        Self {
-            redis: redis_addr,
-            nextcloud: nextcloud_addr,
-            _redis_shutdown: redis_shutdown,
-            _nextcloud_shutdown: nextcloud_shutdown,
-            users,
-            db,
-        }
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", client.parse().unwrap());
+        async move { tokio_tungstenite::connect_async(request).await.unwrap().0 }
+    };
+
+    for _ in 0..2 {
+        let mut client = connect("10.0.0.1").await;
+        client.send(Message::Text("foo".into())).await.unwrap();
+        client.send(Message::Text("not_bar".into())).await.unwrap();
+        assert_next_message(&mut client, "err: Invalid credentials").await;
     }
 
-    fn config(&self) -> Config {
-        Config {
-            database: "sqlite::memory:?cache=shared".parse().unwrap(),
-            database_prefix: "oc_".to_string(),
-            redis: vec![format!("redis://{}", self.redis).parse().unwrap()],
-            nextcloud_url: format!("http://{}/", self.nextcloud),
-            metrics_bind: None,
-            log_level: "".to_string(),
-            bind: Bind::Tcp(self.nextcloud),
-            allow_self_signed: false,
-            no_ansi: false,
-            tls: None,
-            max_debounce_time: 15,
-            max_connection_time: 0,
-        }
-    }
+    // even the right password is rejected for the client until the cooldown is over
+    let mut client = connect("10.0.0.1").await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "err: Too many failed login attempts").await;
 
-    async fn app(&self) -> App {
-        let config = self.config();
-        App::with_connection(self.db.clone(), config, LOG_HANDLE.clone(), false)
+    // other clients behind the same proxy can still login, the user is only delayed
+    let start = Instant::now();
+    let mut client = connect("10.0.0.2").await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_eq!(
+        Message::Text("authenticated".into()),
+        timeout(Duration::from_secs(3), client.next())
             .await
             .unwrap()
-    }
-
-    async fn spawn_server(&self) -> ServerHandle {
-        let app = Arc::new(self.app().await);
-        let addr = async {
-            let tcp = listen_available_port().await.unwrap();
-            tcp.local_addr()
-        }
-        .await
-        .unwrap();
-
-        let (serve_tx, serve_rx) = oneshot::channel();
-        let (listen_tx, listen_rx) = oneshot::channel();
-
-        let bind = Bind::Tcp(addr);
-        spawn(async move {
-            let serve = serve(app.clone(), bind, serve_rx, None, 15, 0).unwrap();
-            let listen = listen_loop(app.clone(), listen_rx);
-
-            pin_mut!(serve);
-            pin_mut!(listen);
-
-            select(serve, listen).await;
-        });
-
-        sleep(Duration::from_millis(10)).await;
-
-        ServerHandle {
-            _serve_handle: serve_tx,
-            _listen_handle: listen_tx,
-            port: addr.port(),
-        }
-    }
-
-    async fn redis_client(&self) -> redis::aio::MultiplexedConnection {
-        let client = redis::Client::open(self.config().redis.first().unwrap().clone()).unwrap();
-        client.get_multiplexed_async_connection().await.unwrap()
-    }
-
-    fn add_user(&self, username: &str, password: &str) {
-        self.users.insert(username.into(), password.into());
-    }
+            .unwrap()
+            .unwrap()
+    );
+    assert!(start.elapsed() >= Duration::from_secs(1));
+}
 
-    async fn add_storage_mapping(&self, username: &str, storage: u32, root: u32) {
-        sqlx::query("INSERT INTO oc_mounts(storage_id, root_id, user_id) VALUES(?, ?, ?)")
-            .bind(storage as i64)
-            .bind(root as i64)
-            .bind(username)
-            .execute(&self.db)
-            .await
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_auth_failure_limit_forwarded_for() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.auth_failure_limit = 2;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let connect = |forwarded_for: String| {
+        let mut request = format!("ws://127.0.0.1:{}/ws", server_handle.port())
+            .into_client_request()
             .unwrap();
-    }
-
-    async fn add_filecache_item(&self, fileid: u32, path: &str) {
-        sqlx::query("INSERT INTO oc_filecache(fileid, path) VALUES(?, ?)")
-            .bind(fileid as i64)
-            .bind(path)
-            .execute(&self.db)
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        async move { tokio_tungstenite::connect_async(request).await.unwrap().0 }
+    };
+
+    // without trusted proxies the header is set by the client, so a new address for every attempt doesn't help
+    for i in 0..2 {
+        let mut client = connect(format!("10.0.0.{}", i)).await;
+        client
+            .send(Message::Text(format!("user{}", i).into()))
             .await
             .unwrap();
+        client.send(Message::Text("not_bar".into())).await.unwrap();
+        assert_next_message(&mut client, "err: Invalid credentials").await;
     }
-}
 
-struct ServerHandle {
-    _serve_handle: oneshot::Sender<()>,
-    _listen_handle: oneshot::Sender<()>,
-    port: u16,
+    let mut client = connect("10.0.0.99".into()).await;
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, "err: Too many failed login attempts").await;
 }
 
-impl ServerHandle {
-    async fn connect(&self) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
-        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", self.port))
-            .await
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_websocket_error_kind() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let client = server_handle.connect_auth("foo", "bar").await;
+    let reset_count = || {
+        METRICS
+            .websocket_error_counts()
+            .find(|(kind, _)| *kind == "reset")
             .unwrap()
-            .0
-    }
+            .1
+    };
+    let before = reset_count();
 
-    async fn connect_auth(
-        &self,
-        username: &str,
-        password: &str,
-    ) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
-        let mut client =
-            tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", self.port))
-                .await
-                .unwrap()
-                .0;
+    // dropping the connection without a close frame
+    drop(client);
+    sleep(Duration::from_millis(100)).await;
+    assert!(reset_count() > before);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_disconnect_signal() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
 
-        client.send(Message::Text(username.into())).await.unwrap();
-        client.send(Message::Text(password.into())).await.unwrap();
+    let server_handle = services.spawn_server().await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
 
-        assert_next_message(&mut client, "authenticated").await;
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_signal", r#"{"disconnect":{"user":"foo"}}"#)
+        .await
+        .unwrap();
 
-        client
-    }
+    let closed = timeout(Duration::from_millis(500), client1.next())
+        .await
+        .unwrap();
+    assert!(matches!(closed, Some(Ok(Message::Close(_))) | None));
+    assert_no_message(&mut client2).await;
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_auth() {
+async fn test_notify_activity() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
 
     let server_handle = services.spawn_server().await;
-    let mut client = server_handle.connect().await;
-    client.send(Message::Text("foo".into())).await.unwrap();
-    client.send(Message::Text("bar".into())).await.unwrap();
+    let mut client = server_handle.connect_auth("foo", "bar").await;
 
-    assert_next_message(&mut client, "authenticated").await;
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+    std::mem::forget(services);
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_auth_failure() {
+async fn test_token_file_auth() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
 
-    let server_handle = services.spawn_server().await;
-    let mut client = server_handle.connect().await;
-    client.send(Message::Text("foo".into())).await.unwrap();
-    client.send(Message::Text("not_bar".into())).await.unwrap();
+    let token_file =
+        std::env::temp_dir().join(format!("notify_push_tokens_{}", std::process::id()));
+    std::fs::write(&token_file, "# dashboards\ndashboard:secret\n").unwrap();
+    let mut config = services.config();
+    config.auth_providers = vec![AuthProviderKind::Nextcloud, AuthProviderKind::TokenFile];
+    config.auth_token_file = Some(token_file.clone());
+    let server_handle = services.spawn_server_with_config(config).await;
+    std::fs::remove_file(token_file).ok();
+
+    // nextcloud users can still login next to the service accounts
+    server_handle.connect_auth("foo", "bar").await;
+    let mut client = server_handle.connect_auth("dashboard", "secret").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"dashboard"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
 
+    let mut client = server_handle.connect().await;
+    client
+        .send(Message::Text("dashboard".into()))
+        .await
+        .unwrap();
+    client.send(Message::Text("wrong".into())).await.unwrap();
     assert_next_message(&mut client, "err: Invalid credentials").await;
 }
 
-async fn assert_next_message(
-    client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-    expected: &str,
-) {
-    sleep(Duration::from_millis(100)).await;
-    assert_eq!(
-        timeout(Duration::from_millis(200), client.next())
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_service_account() {
+    let services = Services::new().await;
+    services.add_group_member("lobby", "carol").await;
+
+    let token_file =
+        std::env::temp_dir().join(format!("notify_push_service_tokens_{}", std::process::id()));
+    std::fs::write(&token_file, "signage:secret users=alice groups=lobby\n").unwrap();
+    let mut config = services.config();
+    config.auth_providers = vec![AuthProviderKind::TokenFile];
+    config.auth_token_file = Some(token_file.clone());
+    let server_handle = services.spawn_server_with_config(config).await;
+    std::fs::remove_file(token_file).ok();
+
+    let mut client = server_handle.connect_auth("signage", "secret").await;
+
+    let mut redis = services.redis_client().await;
+    for user in ["alice", "bob", "carol", "signage"] {
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(r#"{{"user":"{}","message":"for_{}"}}"#, user, user),
+            )
             .await
-            .unwrap()
-            .unwrap()
-            .unwrap(),
-        Message::Text(expected.into())
-    );
-}
+            .unwrap();
+    }
+
+    // only the messages for the users in the scope are received, not the ones for a user with the same name
+    assert_next_message(&mut client, "for_alice").await;
+    assert_next_message(&mut client, "for_carol").await;
+    assert_no_message(&mut client).await;
 
-async fn assert_no_message(client: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) {
-    sleep(Duration::from_millis(5)).await;
-    assert!(timeout(Duration::from_millis(10), client.next())
+    services.add_group_member("lobby", "bob").await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_group_membership_update",
+            r#"{"user":"bob","group":"lobby"}"#,
+        )
         .await
-        .is_err());
+        .unwrap();
+    assert_next_message(&mut client, "notify_file").await;
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn test_notify_activity() {
+async fn test_per_user_delivery() {
     let services = Services::new().await;
     services.add_user("foo", "bar");
 
-    let server_handle = services.spawn_server().await;
-    let mut client = server_handle.connect_auth("foo", "bar").await;
+    let mut config = services.config();
+    config.per_user_delivery = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle.connect_auth("foo", "bar").await;
 
     let mut redis = services.redis_client().await;
-    redis
-        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
-        .await
-        .unwrap();
+    for _ in 0..2 {
+        redis
+            .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+            .await
+            .unwrap();
+    }
 
-    assert_next_message(&mut client, "notify_activity").await;
-    std::mem::forget(services);
+    // every connection gets the messages from the shared delivery task
+    for client in [&mut client1, &mut client2] {
+        assert_next_message(client, "notify_activity").await;
+        assert_next_message(client, "notify_activity").await;
+        assert_no_message(client).await;
+    }
 }
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -431,6 +391,125 @@ async fn test_notify_file_multiple() {
     assert_no_message(&mut client3).await;
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_storm_summary() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_storage_mapping("foo", 10, 10).await;
+
+    let mut config = services.config();
+    // more than 10 updates within 10 seconds
+    config.storm_threshold = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for file_id in 0..100 {
+        redis
+            .publish::<_, _, ()>(
+                "notify_storage_update",
+                format!(
+                    r#"{{"storage":10, "path":"foo/bar", "file_id":{}}}"#,
+                    file_id
+                ),
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut messages = Vec::new();
+    while let Ok(Some(Ok(Message::Text(text)))) =
+        timeout(Duration::from_millis(1500), client.next()).await
+    {
+        messages.push(text.to_string());
+    }
+    assert_eq!(
+        1,
+        messages
+            .iter()
+            .filter(|msg| *msg == r#"notify_storm {"type":"storage"}"#)
+            .count()
+    );
+    // the updates below the threshold and a single summary
+    assert!(messages.iter().filter(|msg| *msg == "notify_file").count() <= 11);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_long_polling() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let server_handle = services.spawn_server().await;
+    let url = format!("http://127.0.0.1:{}/poll", server_handle.port());
+    let http = reqwest::Client::new();
+
+    let response = http
+        .get(&url)
+        .basic_auth("foo", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, response.status());
+
+    let session: serde_json::Value = http
+        .get(&url)
+        .basic_auth("foo", Some("bar"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let token = session["session"].as_str().unwrap();
+    assert_eq!(0, session["cursor"]);
+
+    let poll = http
+        .get(&url)
+        .query(&[("session", token), ("cursor", "0"), ("timeout", "5")])
+        .send();
+    let publish = async {
+        sleep(Duration::from_millis(100)).await;
+        let mut redis = services.redis_client().await;
+        redis
+            .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+            .await
+            .unwrap();
+    };
+    let (response, _) = futures::join!(poll, publish);
+    let response: serde_json::Value = response.unwrap().json().await.unwrap();
+    assert_eq!(
+        serde_json::json!([{"type": "notify_activity"}]),
+        response["messages"]
+    );
+    let cursor = response["cursor"].as_u64().unwrap();
+    assert!(cursor > 0);
+
+    // nothing new after the cursor
+    let response: serde_json::Value = http
+        .get(&url)
+        .query(&[
+            ("session", token),
+            ("cursor", &cursor.to_string()),
+            ("timeout", "0"),
+        ])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(serde_json::json!([]), response["messages"]);
+    assert_eq!(cursor, response["cursor"]);
+
+    let response = http
+        .get(&url)
+        .query(&[("session", "unknown")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, response.status());
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_pre_auth() {
     let services = Services::new().await;
@@ -566,3 +645,1556 @@ async fn test_notify_custom_body() {
     assert_next_message(&mut client1, "my_custom_message [1,2,3]").await;
     assert_no_message(&mut client2).await;
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_dynamic_channel() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_config",
+            r#"{"add_channel":{"channel":"notify_deck","message":"deck_update"}}"#,
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    redis
+        .publish::<_, _, ()>("notify_deck", r#"{"user":"foo", "body": 1}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "deck_update 1").await;
+
+    redis
+        .publish::<_, _, ()>("notify_config", r#"{"remove_channel":"notify_deck"}"#)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    redis
+        .publish::<_, _, ()>("notify_deck", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_no_message(&mut client).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_presence_webhook() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.presence_webhook = Some(
+        format!("http://{}/presence", services.nextcloud_addr())
+            .parse()
+            .unwrap(),
+    );
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(services.presence("foo").as_deref(), Some("connected"));
+
+    client.close(None).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(services.presence("foo").as_deref(), Some("disconnected"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_transfer_token() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.transfer_tokens = true;
+
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client1 = server_handle.connect_auth("foo", "bar").await;
+
+    client1
+        .send(Message::Text("request_transfer_token".into()))
+        .await
+        .unwrap();
+    let reply = timeout(Duration::from_millis(200), client1.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let token = reply
+        .to_text()
+        .unwrap()
+        .strip_prefix("transfer_token ")
+        .unwrap()
+        .to_string();
+
+    let mut client2 = server_handle.connect_auth("", &token).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client1, "notify_activity").await;
+    assert_next_message(&mut client2, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_transfer_token_single_use() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.transfer_tokens = true;
+
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    client
+        .send(Message::Text("request_transfer_token".into()))
+        .await
+        .unwrap();
+    let reply = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let token = reply
+        .to_text()
+        .unwrap()
+        .strip_prefix("transfer_token ")
+        .unwrap()
+        .to_string();
+
+    let redeem = || async {
+        let mut client = server_handle.connect().await;
+        client.send(Message::Text("".into())).await.unwrap();
+        client
+            .send(Message::Text(token.clone().into()))
+            .await
+            .unwrap();
+        timeout(Duration::from_millis(500), client.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+            .into_text()
+            .unwrap()
+            .to_string()
+    };
+    let (first, second) = futures::join!(redeem(), redeem());
+    let mut replies = [first, second];
+    replies.sort();
+    assert_eq!(replies, ["authenticated", "err: Invalid credentials"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_transfer_token_disabled() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    client
+        .send(Message::Text("request_transfer_token".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "err: Failed to create transfer token").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_affinity() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    client
+        .send(Message::Text("request_affinity".into()))
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "affinity dcb27518fed9d577").await;
+
+    let (_, response) = tokio_tungstenite::connect_async(format!(
+        "ws://127.0.0.1:{}/ws?affinity=dcb27518fed9d577",
+        server_handle.port()
+    ))
+    .await
+    .unwrap();
+    assert_eq!(
+        "dcb27518fed9d577",
+        response.headers()["x-notify-push-affinity"]
+    );
+
+    let (_, response) =
+        tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port()))
+            .await
+            .unwrap();
+    assert!(!response.headers().contains_key("x-notify-push-affinity"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_runtime_shards() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("baz", "qux");
+
+    let mut config = services.config();
+    config.runtime_shards = vec!["0".parse().unwrap(), "0".parse().unwrap()];
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut foo = server_handle.connect_auth("foo", "bar").await;
+    let mut baz = server_handle.connect_auth("baz", "qux").await;
+
+    let mut redis = services.redis_client().await;
+    for user in ["foo", "baz"] {
+        redis
+            .publish::<_, _, ()>("notify_activity", format!(r#"{{"user":"{}"}}"#, user))
+            .await
+            .unwrap();
+    }
+
+    assert_next_message(&mut foo, "notify_activity").await;
+    assert_next_message(&mut baz, "notify_activity").await;
+    assert_eq!(2, METRICS.shard_connection_counts().values().sum::<usize>());
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_io_uring_listener() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let mut config = services.config();
+    config.io_uring = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+
+    assert_next_message(&mut client, "notify_activity").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_relay() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let app1 = Arc::new(services.app(services.config()).await);
+    let app2 = Arc::new(services.app(services.config()).await);
+    let server_handle1 = services.spawn_server_with_app(app1.clone()).await;
+    let server_handle2 = services.spawn_server_with_app(app2).await;
+    let mut client1 = server_handle1.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle2.connect_auth("foo", "bar").await;
+
+    app1.relay(
+        "foo",
+        RelayedMessage::Custom {
+            message: "my_message".into(),
+            body: Box::new(serde_json::json!({"foo": "bar"})),
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_next_message(&mut client1, r#"my_message {"foo":"bar"}"#).await;
+    assert_next_message(&mut client2, r#"my_message {"foo":"bar"}"#).await;
+    // the origin instance doesn't deliver the message again when it receives it back from redis
+    assert_no_message(&mut client1).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_resume_sessions() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let app = Arc::new(services.app(services.config()).await);
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let _client = server_handle.connect_auth("foo", "bar").await;
+    assert_eq!(1, app.save_sessions().await.unwrap());
+    drop(server_handle);
+
+    let app = Arc::new(services.app(services.config()).await);
+    assert_eq!(1, app.resume_sessions().await.unwrap());
+    let server_handle = services.spawn_server_with_app(app).await;
+
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client, "notify_file").await;
+    assert_next_message(&mut client, "notify_activity").await;
+    assert_next_message(&mut client, "notify_notification").await;
+
+    // users that weren't connected before the restart don't get any resync messages
+    let mut client2 = server_handle.connect_auth("foo2", "bar").await;
+    assert_no_message(&mut client2).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_resume_sessions_per_instance() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    let instance_config = |name: &str| {
+        let mut config = services.config();
+        config.resume_sessions = true;
+        config.instance_name = Some(name.into());
+        config
+    };
+
+    let app1 = Arc::new(services.app(instance_config("push1")).await);
+    let app2 = Arc::new(services.app(instance_config("push2")).await);
+    let server_handle1 = services.spawn_server_with_app(app1.clone()).await;
+    let server_handle2 = services.spawn_server_with_app(app2.clone()).await;
+    let _client1 = server_handle1.connect_auth("foo", "bar").await;
+    let _client2 = server_handle2.connect_auth("foo2", "bar").await;
+    assert_eq!(1, app1.save_sessions().await.unwrap());
+    assert_eq!(1, app2.save_sessions().await.unwrap());
+    drop(server_handle1);
+    drop(server_handle2);
+
+    // every instance only resumes the sessions it saved itself
+    let app1 = Arc::new(services.app(instance_config("push1")).await);
+    assert_eq!(1, app1.resume_sessions().await.unwrap());
+    let server_handle1 = services.spawn_server_with_app(app1).await;
+    let mut client1 = server_handle1.connect_auth("foo", "bar").await;
+    assert_next_message(&mut client1, "notify_file").await;
+    let mut client2 = server_handle1.connect_auth("foo2", "bar").await;
+    assert_no_message(&mut client2).await;
+
+    let app2 = Arc::new(services.app(instance_config("push2")).await);
+    assert_eq!(1, app2.resume_sessions().await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_warm_up_storage_cache() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(11, "foo").await;
+    services.add_filecache_item(13, "foo").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+    services.add_storage_mapping("foo", 12, 13).await;
+
+    let app = Arc::new(services.app(services.config()).await);
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for storage in [10, 12] {
+        redis
+            .publish::<_, _, ()>(
+                "notify_storage_update",
+                format!(
+                    r#"{{"storage":{}, "path":"foo/bar", "file_id":5}}"#,
+                    storage
+                ),
+            )
+            .await
+            .unwrap();
+    }
+    assert_next_message(&mut client, "notify_file").await;
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(1, app.save_recent_storages(1).await.unwrap());
+    drop(server_handle);
+
+    let app = services.app(services.config()).await;
+    assert_eq!(1, app.warm_up_storage_cache().await.unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_remote_config() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(11, "foo").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+    services.set_remote_config(serde_json::json!({"excluded_paths": ["foo/excluded/"]}));
+
+    let mut config = services.config();
+    config.remote_config = true;
+    let app = Arc::new(services.app(config).await);
+    app.load_remote_config().await.unwrap();
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let redis = services.redis_client().await;
+    let publish_update = |path: &'static str| {
+        let mut redis = redis.clone();
+        async move {
+            redis
+                .publish::<_, _, ()>(
+                    "notify_storage_update",
+                    format!(r#"{{"storage":10, "path":"{}", "file_id":5}}"#, path),
+                )
+                .await
+                .unwrap();
+        }
+    };
+
+    publish_update("foo/excluded/bar").await;
+    assert_no_message(&mut client).await;
+    publish_update("foo/bar").await;
+    assert_next_message(&mut client, "notify_file").await;
+
+    // the app tells the push server when the settings have changed
+    services.set_remote_config(serde_json::json!({}));
+    redis
+        .clone()
+        .publish::<_, _, ()>("notify_config", r#""remote_config""#)
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    publish_update("foo/excluded/bar").await;
+    assert_next_message(&mut client, "notify_file").await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_query() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let app = Arc::new(services.app(services.config()).await);
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let _client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for query in ["version", "connections", "metrics"] {
+        redis
+            .publish::<_, _, ()>("notify_query", format!(r#""{}""#, query))
+            .await
+            .unwrap();
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let instance = app.instance_id();
+    let version: String = redis
+        .get(format!("notify_push_version_{}", instance))
+        .await
+        .unwrap();
+    assert_eq!(format!(r#""{}""#, env!("NOTIFY_PUSH_VERSION")), version);
+    let connections: String = redis
+        .get(format!("notify_push_connections_{}", instance))
+        .await
+        .unwrap();
+    let connections: serde_json::Value = serde_json::from_str(&connections).unwrap();
+    assert!(connections["active_connection_count"].as_u64().unwrap() > 0);
+    // the metrics are also stored under the key used by older versions of the app
+    let metrics: Option<String> = redis.get("notify_push_metrics").await.unwrap();
+    assert!(metrics.is_some());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_replace_database() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    // a second database that only contains the storage mapping for storage 20
+    let path = std::env::temp_dir().join(format!("notify_push_replace_{}.db", std::process::id()));
+    let database_url = format!("sqlite://{}?mode=rwc", path.display());
+    let db = AnyPool::connect(&database_url).await.unwrap();
+    for query in [
+        "CREATE TABLE oc_filecache(fileid BIGINT, path TEXT)",
+        "CREATE TABLE oc_mounts(storage_id BIGINT, root_id BIGINT, user_id TEXT)",
+        "INSERT INTO oc_filecache(fileid, path) VALUES(21, 'foo')",
+        "INSERT INTO oc_mounts(storage_id, root_id, user_id) VALUES(20, 21, 'foo')",
+    ] {
+        sqlx::query(query).execute(&db).await.unwrap();
+    }
+    db.close().await;
+
+    let app = Arc::new(services.app(services.config()).await);
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    app.replace_database(database_url.parse().unwrap())
+        .await
+        .unwrap();
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":20, "path":"foo/bar", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_file").await;
+
+    std::fs::remove_file(path).ok();
+}
+
+async fn authenticate(client: &mut Client, username: &str, password: &str) -> String {
+    client.send(Message::Text(username.into())).await.unwrap();
+    client.send(Message::Text(password.into())).await.unwrap();
+    timeout(Duration::from_secs(2), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+        .to_text()
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_latency() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.set_nextcloud_latency(Duration::from_millis(300));
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect().await;
+
+    let start = Instant::now();
+    assert_eq!(
+        "authenticated",
+        authenticate(&mut client, "foo", "bar").await
+    );
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_retry() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let requests = services.nextcloud_requests();
+    services.fail_nextcloud_requests(2);
+
+    let mut client = server_handle.connect().await;
+    assert_eq!(
+        "authenticated",
+        authenticate(&mut client, "foo", "bar").await
+    );
+    assert_eq!(requests + 3, services.nextcloud_requests());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_failure() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let requests = services.nextcloud_requests();
+    services.fail_nextcloud_requests(3);
+
+    let mut client = server_handle.connect().await;
+    assert_eq!(
+        "err: Error while sending authentication request to nextcloud: Server error: 503 Service Unavailable",
+        authenticate(&mut client, "foo", "bar").await
+    );
+    assert_eq!(requests + 3, services.nextcloud_requests());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_circuit_breaker() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let requests = services.nextcloud_requests();
+    services.fail_nextcloud_requests(100);
+
+    for _ in 0..2 {
+        let mut client = server_handle.connect().await;
+        assert!(authenticate(&mut client, "foo", "bar")
+            .await
+            .starts_with("err: "));
+    }
+    assert_eq!(requests + 5, services.nextcloud_requests());
+
+    // once the breaker is open, requests aren't sent to nextcloud anymore
+    let mut client = server_handle.connect().await;
+    assert_eq!(
+        "err: Error while sending authentication request to nextcloud: Too many failed requests to nextcloud, try again later",
+        authenticate(&mut client, "foo", "bar").await
+    );
+    assert_eq!(requests + 5, services.nextcloud_requests());
+}
+
+#[cfg(feature = "test-endpoints")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_nextcloud_untrusted_domain() {
+    let services = Services::new().await;
+    services.set_untrusted_domain(true);
+
+    let server_handle = services.spawn_server().await;
+    let response = reqwest::get(format!(
+        "http://127.0.0.1:{}/test/reverse_cookie",
+        server_handle.port()
+    ))
+    .await
+    .unwrap()
+    .text()
+    .await
+    .unwrap();
+    assert_eq!(
+        "127.0.0.1 is not configured as a trusted domain for the nextcloud server",
+        response
+    );
+}
+
+#[cfg(feature = "test-endpoints")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_forwarded_test_endpoint() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+    let url = format!("http://127.0.0.1:{}/test/forwarded", server_handle.port());
+
+    let response = reqwest::Client::new()
+        .get(&url)
+        .header("x-forwarded-for", "192.0.2.60")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!("192.0.2.60, 127.0.0.1", response.text().await.unwrap());
+}
+
+#[cfg(feature = "test-endpoints")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_verify_proxy() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.production = true;
+    let app = Arc::new(services.app(config).await);
+
+    // without a public url nextcloud is asked directly
+    let check = app.verify_proxy().await.unwrap();
+    assert_eq!(None, check.endpoint);
+    assert!(check.is_trusted());
+
+    // the test endpoints are closed in production, but the check opens them for itself
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let endpoint = format!("http://127.0.0.1:{}", server_handle.port());
+    services
+        .set_app_value("notify_push", "base_endpoint", &endpoint)
+        .await;
+    let check = app.verify_proxy().await.unwrap();
+    assert_eq!(Some(endpoint), check.endpoint);
+    assert!(check.is_trusted());
+
+    services.set_untrusted_proxy(true);
+    let check = app.verify_proxy().await.unwrap();
+    assert!(!check.is_trusted());
+    assert_eq!("127.0.0.1", check.remote.to_string());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_storage_stats() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    config.storage_stats = 10;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("http://127.0.0.1:{}/admin/storages", server_handle.port());
+    let http = reqwest::Client::new();
+
+    let response = http.get(&url).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::UNAUTHORIZED, response.status());
+
+    let mut redis = services.redis_client().await;
+    for (storage, count) in [(10, 3), (11, 5), (12, 1)] {
+        for _ in 0..count {
+            redis
+                .publish::<_, _, ()>(
+                    "notify_storage_update",
+                    format!(
+                        r#"{{"storage":{}, "path":"foo/bar", "file_id":5}}"#,
+                        storage
+                    ),
+                )
+                .await
+                .unwrap();
+        }
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let top: serde_json::Value = http
+        .get(format!("{}?limit=2", url))
+        .bearer_auth("admin_token")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(2, top.as_array().unwrap().len());
+    assert_eq!(11, top[0]["storage"]);
+    assert_eq!(5, top[0]["total"]);
+    assert_eq!(10, top[1]["storage"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_user_stats() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    config.user_message_window = Duration::from_secs(60);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    for _ in 0..3 {
+        redis
+            .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+            .await
+            .unwrap();
+    }
+    assert_next_message(&mut client, "notify_activity").await;
+
+    let top: serde_json::Value = reqwest::Client::new()
+        .get(format!(
+            "http://127.0.0.1:{}/admin/users",
+            server_handle.port()
+        ))
+        .bearer_auth("admin_token")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(serde_json::json!([{"user": "foo", "messages": 3}]), top);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_connections() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    let server_handle = services.spawn_server_with_config(config).await;
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+    let _client2 = server_handle.connect_auth("foo", "bar").await;
+    let _client3 = server_handle.connect_auth("foo2", "bar").await;
+
+    let url = format!(
+        "http://127.0.0.1:{}/admin/connections",
+        server_handle.port()
+    );
+    let http = reqwest::Client::new();
+    let get = |query: &'static str| {
+        http.get(format!("{}{}", url, query))
+            .bearer_auth("admin_token")
+            .send()
+    };
+
+    let connected: serde_json::Value = get("").await.unwrap().json().await.unwrap();
+    assert_eq!(
+        serde_json::json!([
+            {"user": "foo", "connections": 2, "queued_messages": 0, "polling": false},
+            {"user": "foo2", "connections": 1, "queued_messages": 0, "polling": false},
+        ]),
+        connected
+    );
+
+    let connected: serde_json::Value = get("?user=foo2").await.unwrap().json().await.unwrap();
+    assert_eq!(1, connected.as_array().unwrap().len());
+    assert_eq!("foo2", connected[0]["user"]);
+
+    let connected: serde_json::Value = get("?user=other").await.unwrap().json().await.unwrap();
+    assert_eq!(serde_json::json!([]), connected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_roundtrip() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("http://127.0.0.1:{}/admin/roundtrip", server_handle.port());
+    let http = reqwest::Client::new();
+    let get = || http.get(&url).bearer_auth("admin_token").send();
+
+    let response = get().await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let roundtrip: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(TEST_COOKIE, roundtrip["cookie"]);
+    assert!(roundtrip["nextcloud"]["error"].is_null());
+    assert!(roundtrip["redis"]["error"].is_null());
+    assert!(roundtrip["redis"]["latency_ms"].is_u64());
+
+    services.set_untrusted_domain(true);
+    let response = get().await.unwrap();
+    assert_eq!(503, response.status().as_u16());
+    let roundtrip: serde_json::Value = response.json().await.unwrap();
+    assert!(roundtrip["cookie"].is_null());
+    assert!(roundtrip["nextcloud"]["error"].is_string());
+    assert!(roundtrip["redis"].is_null());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_push() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    config.push_token = Some("push_token".into());
+    let server_handle = services.spawn_server_with_config(config).await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let url = format!("http://127.0.0.1:{}/admin/push", server_handle.port());
+    let http = reqwest::Client::new();
+    let push = |token: &'static str| {
+        http.post(&url)
+            .bearer_auth(token)
+            .json(&serde_json::json!({"user": "foo", "message": "my_message", "body": [1, 2]}))
+            .send()
+    };
+
+    assert_eq!(401, push("wrong").await.unwrap().status().as_u16());
+    assert_no_message(&mut client).await;
+
+    assert_eq!(202, push("push_token").await.unwrap().status().as_u16());
+    assert_next_message(&mut client, "my_message [1,2]").await;
+    assert_eq!(202, push("admin_token").await.unwrap().status().as_u16());
+    assert_next_message(&mut client, "my_message [1,2]").await;
+
+    // the push token doesn't give access to the other admin endpoints
+    let response = http
+        .get(format!(
+            "http://127.0.0.1:{}/admin/connections",
+            server_handle.port()
+        ))
+        .bearer_auth("push_token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_push_other_instance() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.push_token = Some("push_token".into());
+
+    let server_handle1 = services.spawn_server_with_config(config.clone()).await;
+    let server_handle2 = services.spawn_server_with_config(config).await;
+    let mut client1 = server_handle1.connect_auth("foo", "bar").await;
+    let mut client2 = server_handle2.connect_auth("foo", "bar").await;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://127.0.0.1:{}/admin/push",
+            server_handle1.port()
+        ))
+        .bearer_auth("push_token")
+        .json(&serde_json::json!({"user": "foo", "message": "my_message"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(202, response.status().as_u16());
+
+    assert_next_message(&mut client1, "my_message").await;
+    assert_next_message(&mut client2, "my_message").await;
+    assert_no_message(&mut client1).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_monitor() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.admin_token = Some("admin_token".into());
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("ws://127.0.0.1:{}/admin/monitor", server_handle.port());
+
+    assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+
+    let mut request = url.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("authorization", "Bearer admin_token".parse().unwrap());
+    let mut monitor = tokio_tungstenite::connect_async(request).await.unwrap().0;
+    let _client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_custom",
+            r#"{"user":"foo","message":"secret_message","body":{"secret":1}}"#,
+        )
+        .await
+        .unwrap();
+
+    let event = timeout(Duration::from_millis(200), monitor.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let event: serde_json::Value = serde_json::from_str(event.to_text().unwrap()).unwrap();
+    assert_eq!("custom", event["type"]);
+    assert_eq!(true, event["connected"]);
+    assert!(event["user"].is_string());
+    assert!(!event.to_string().contains("secret"));
+    assert!(!event.to_string().contains("foo"));
+
+    // commands are ignored, the connection only receives the monitoring events
+    monitor
+        .send(Message::Text("listen notify_file_id".into()))
+        .await
+        .unwrap();
+    assert_no_message(&mut monitor).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_admin_disabled() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+    let response = reqwest::Client::new()
+        .get(format!(
+            "http://127.0.0.1:{}/admin/storages",
+            server_handle.port()
+        ))
+        .bearer_auth("")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_register_public_url() {
+    let services = Services::new().await;
+    let app = services.app(services.config()).await;
+    app.register_public_url().await.unwrap();
+    assert_eq!(None, services.registered_endpoint());
+
+    let mut config = services.config();
+    config.public_url = Some("https://cloud.example.com/push".parse().unwrap());
+    config.test_secret = Some("secret".into());
+    let app = services.app(config).await;
+    app.register_public_url().await.unwrap();
+    assert_eq!(
+        Some((
+            "https://cloud.example.com/push".to_string(),
+            "secret".to_string()
+        )),
+        services.registered_endpoint()
+    );
+}
+
+#[cfg(feature = "test-endpoints")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_production_test_endpoints() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.production = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = |path: &str| format!("http://127.0.0.1:{}/test/{}", server_handle.port(), path);
+    let http = reqwest::Client::new();
+
+    let response = http.get(url("cookie")).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+
+    // starting the self test opens the test endpoints
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_test_cookie", "12")
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let response = http.get(url("cookie")).send().await.unwrap();
+    assert_eq!("12", response.text().await.unwrap());
+
+    // and the version check at the end of the self test closes them
+    let response = http.post(url("version")).send().await.unwrap();
+    assert_eq!("set", response.text().await.unwrap());
+    let response = http.get(url("cookie")).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+}
+
+#[cfg(feature = "test-endpoints")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_test_endpoints_secret() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.test_secret = Some("secret".into());
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("http://127.0.0.1:{}/test/cookie", server_handle.port());
+    let http = reqwest::Client::new();
+
+    let response = http.get(&url).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+    let response = http
+        .get(&url)
+        .header("X-Notify-Push-Test-Secret", "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::NOT_FOUND, response.status());
+    let response = http
+        .get(&url)
+        .header("X-Notify-Push-Test-Secret", "secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(reqwest::StatusCode::OK, response.status());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_http_limits() {
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.http_limits.max_headers = 20;
+    config.http_limits.max_body_size = 16;
+    config.http_limits.rate_limit = 3;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("http://127.0.0.1:{}/test/cookie", server_handle.port());
+    let http = reqwest::Client::new();
+
+    let mut request = http.get(&url);
+    for i in 0..30 {
+        request = request.header(format!("x-header-{}", i), "value");
+    }
+    let response = request.send().await.unwrap();
+    assert_eq!(
+        reqwest::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        response.status()
+    );
+
+    let response = http.post(&url).body("x".repeat(32)).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::PAYLOAD_TOO_LARGE, response.status());
+
+    for _ in 0..3 {
+        let response = http.get(&url).send().await.unwrap();
+        assert_eq!(reqwest::StatusCode::OK, response.status());
+    }
+    let response = http.get(&url).send().await.unwrap();
+    assert_eq!(reqwest::StatusCode::TOO_MANY_REQUESTS, response.status());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_concurrency_limit() {
+    use notify_push::http::LimitExceeded;
+    use tokio_tungstenite::tungstenite::Error;
+
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.http_limits.max_concurrent_per_ip = 2;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("ws://127.0.0.1:{}/ws", server_handle.port());
+
+    let first = server_handle.connect().await;
+    let _second = server_handle.connect().await;
+
+    match tokio_tungstenite::connect_async(&url).await {
+        Err(Error::Http(response)) => {
+            assert_eq!(
+                reqwest::StatusCode::TOO_MANY_REQUESTS.as_u16(),
+                response.status().as_u16()
+            )
+        }
+        result => panic!(
+            "connection over the limit wasn't rejected: {:?}",
+            result.map(|_| ())
+        ),
+    }
+    // the websocket connections also count for the other routes
+    let response = reqwest::get(format!(
+        "http://127.0.0.1:{}/test/cookie",
+        server_handle.port()
+    ))
+    .await
+    .unwrap();
+    assert_eq!(reqwest::StatusCode::TOO_MANY_REQUESTS, response.status());
+    assert!(METRICS
+        .rejected_request_counts()
+        .get(&("ws", LimitExceeded::Concurrency))
+        .is_some_and(|count| *count > 0));
+
+    drop(first);
+    sleep(Duration::from_millis(100)).await;
+    assert!(tokio_tungstenite::connect_async(&url).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_require_secure() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Error;
+
+    let services = Services::new().await;
+    let mut config = services.config();
+    config.require_secure = true;
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("ws://127.0.0.1:{}/ws", server_handle.port());
+
+    match tokio_tungstenite::connect_async(&url).await {
+        Err(Error::Http(response)) => {
+            assert_eq!(
+                reqwest::StatusCode::FORBIDDEN.as_u16(),
+                response.status().as_u16()
+            )
+        }
+        result => panic!(
+            "insecure connection wasn't rejected: {:?}",
+            result.map(|_| ())
+        ),
+    }
+
+    // without trusted proxies, the forwarded protocol can be spoofed by the client
+    let mut request = url.clone().into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("x-forwarded-proto", "https".parse().unwrap());
+    match tokio_tungstenite::connect_async(request).await {
+        Err(Error::Http(response)) => {
+            assert_eq!(
+                reqwest::StatusCode::FORBIDDEN.as_u16(),
+                response.status().as_u16()
+            )
+        }
+        result => panic!(
+            "spoofed forwarded protocol was accepted: {:?}",
+            result.map(|_| ())
+        ),
+    }
+
+    let mut config = services.config();
+    config.require_secure = true;
+    config.forwarded_for_depth = Some(1);
+    let server_handle = services.spawn_server_with_config(config).await;
+    let url = format!("ws://127.0.0.1:{}/ws", server_handle.port());
+    let mut request = url.into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("x-forwarded-proto", "https".parse().unwrap());
+    assert!(tokio_tungstenite::connect_async(request).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let services = Services::new().await;
+    let app = services.app(services.config()).await;
+
+    let status = app.health_check().await;
+    assert!(status.is_healthy(), "{:?}", status);
+
+    services.fail_nextcloud_requests(1);
+    let status = app.health_check().await;
+    assert!(status.database);
+    assert!(status.redis);
+    assert!(!status.nextcloud);
+}
+
+#[tokio::test]
+async fn test_diagnostics() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_user("foo2", "bar");
+
+    let app = Arc::new(services.app(services.config()).await);
+    let server_handle = services.spawn_server_with_app(app.clone()).await;
+    let _client1 = server_handle.connect_auth("foo", "bar").await;
+    let _client2 = server_handle.connect_auth("foo", "bar").await;
+    let _client3 = server_handle.connect_auth("foo2", "bar").await;
+
+    let report = app.diagnostics();
+    assert!(report.contains("users: 2, connections: 3"), "{}", report);
+    assert!(report.contains(": 2 connections"), "{}", report);
+}
+
+#[tokio::test]
+async fn test_connection_ramp() {
+    use tokio_tungstenite::tungstenite::Error;
+
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    let mut config = services.config();
+    config.connection_ramp_rate = 1;
+    config.connection_ramp_duration = Duration::from_secs(60);
+    let server_handle = services.spawn_server_with_config(config).await;
+
+    let _client = server_handle.connect_auth("foo", "bar").await;
+    match tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", server_handle.port()))
+        .await
+    {
+        Err(Error::Http(response)) => {
+            assert_eq!(503, response.status().as_u16());
+            assert!(response.headers().contains_key("retry-after"));
+        }
+        result => panic!("connection wasn't rejected: {:?}", result.map(|_| ())),
+    }
+}
+
+/// Tests that verify the delivery guarantees while redis restarts and the connection to the client is unreliable
+///
+/// These are slow and timing dependent, run them with `cargo test --features chaos-tests`
+#[cfg(feature = "chaos-tests")]
+mod chaos {
+    use super::*;
+    use futures::future::select;
+    use futures::pin_mut;
+    use notify_push_test_support::{listen_available_port, ServerHandle};
+    use rand::Rng;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use tokio::sync::oneshot;
+    use tokio::task::spawn;
+
+    /// Prefix for the messages the proxy is allowed to drop
+    const CHAOS_PREFIX: &str = "chaos_";
+
+    async fn publish_custom(services: &Services, user: &str, message: &str) {
+        let mut redis = services.redis_client().await;
+        redis
+            .publish::<_, _, ()>(
+                "notify_custom",
+                format!(r#"{{"user":"{}", "message":"{}"}}"#, user, message),
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Websocket proxy between the client and the push server that delays messages sent to the client
+    /// and drops every `drop_every`th chaos message
+    struct ChaosProxy {
+        port: u16,
+        dropped: Arc<Mutex<Vec<String>>>,
+        _shutdown: oneshot::Sender<()>,
+    }
+
+    impl ChaosProxy {
+        async fn new(server: &ServerHandle, max_delay: Duration, drop_every: usize) -> Self {
+            let tcp = listen_available_port().await.unwrap();
+            let port = tcp.local_addr().unwrap().port();
+            let server_port = server.port();
+            let dropped: Arc<Mutex<Vec<String>>> = Arc::default();
+            let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+
+            let proxy_dropped = dropped.clone();
+            let accept = async move {
+                while let Ok((stream, _)) = tcp.accept().await {
+                    let dropped = proxy_dropped.clone();
+                    spawn(async move {
+                        let client = tokio_tungstenite::accept_async(stream).await.unwrap();
+                        let server = tokio_tungstenite::connect_async(format!(
+                            "ws://127.0.0.1:{}/ws",
+                            server_port
+                        ))
+                        .await
+                        .unwrap()
+                        .0;
+                        let (mut client_tx, mut client_rx) = client.split();
+                        let (mut server_tx, mut server_rx) = server.split();
+
+                        spawn(async move {
+                            while let Some(Ok(msg)) = client_rx.next().await {
+                                if server_tx.send(msg).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+
+                        let mut count = 0;
+                        while let Some(Ok(msg)) = server_rx.next().await {
+                            if let Message::Text(text) = &msg {
+                                if text.starts_with(CHAOS_PREFIX) {
+                                    count += 1;
+                                    if count % drop_every == 0 {
+                                        dropped.lock().unwrap().push(text.to_string());
+                                        continue;
+                                    }
+                                }
+                            }
+                            let delay = rand::thread_rng().gen_range(0..=max_delay.as_millis());
+                            sleep(Duration::from_millis(delay as u64)).await;
+                            if client_tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            };
+            spawn(async move {
+                pin_mut!(accept);
+                select(accept, shutdown_rx).await;
+            });
+
+            ChaosProxy {
+                port,
+                dropped,
+                _shutdown: shutdown,
+            }
+        }
+
+        async fn connect_auth(&self, username: &str, password: &str) -> Client {
+            let mut client =
+                tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/ws", self.port))
+                    .await
+                    .unwrap()
+                    .0;
+
+            client.send(Message::Text(username.into())).await.unwrap();
+            client.send(Message::Text(password.into())).await.unwrap();
+
+            assert_next_message(&mut client, "authenticated").await;
+
+            client
+        }
+
+        fn dropped(&self) -> Vec<String> {
+            self.dropped.lock().unwrap().clone()
+        }
+    }
+
+    /// Collect all text messages until the client has been quiet for `idle`
+    async fn collect_messages(client: &mut Client, idle: Duration) -> Vec<String> {
+        let mut messages = Vec::new();
+        while let Ok(Some(Ok(msg))) = timeout(idle, client.next()).await {
+            if let Message::Text(text) = msg {
+                messages.push(text.to_string());
+            }
+        }
+        messages
+    }
+
+    fn assert_no_duplicates(messages: &[String]) {
+        let mut seen = HashSet::new();
+        for message in messages.iter().filter(|msg| msg.starts_with(CHAOS_PREFIX)) {
+            assert!(seen.insert(message), "{} was delivered twice", message);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_redis_cluster_pubsub_failover() {
+        let mut services = Services::new().await;
+        // a second redis server standing in for another node of the cluster
+        let standby = Services::new().await;
+        services.add_user("foo", "bar");
+
+        let mut config = services.config();
+        config.redis.extend(standby.config().redis);
+        let server_handle = services.spawn_server_with_config(config).await;
+        let mut client = server_handle.connect_auth("foo", "bar").await;
+
+        publish_custom(&services, "foo", "chaos_first_node").await;
+        assert_next_message(&mut client, "chaos_first_node").await;
+
+        // the first node is down for longer than the reconnect delay, so the events are received from the other node
+        services.restart_redis(Duration::from_secs(3)).await;
+        let messages = collect_messages(&mut client, Duration::from_millis(300)).await;
+        assert!(messages.contains(&"notify_file".to_string()));
+
+        publish_custom(&standby, "foo", "chaos_second_node").await;
+        let messages = collect_messages(&mut client, Duration::from_millis(300)).await;
+        assert_eq!(vec!["chaos_second_node".to_string()], messages);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_redis_restart_reports_gap() {
+        let mut services = Services::new().await;
+        services.add_user("foo", "bar");
+
+        let server_handle = services.spawn_server().await;
+        let mut client = server_handle.connect_auth("foo", "bar").await;
+
+        publish_custom(&services, "foo", "chaos_before").await;
+        assert_next_message(&mut client, "chaos_before").await;
+
+        services.restart_redis(Duration::from_millis(100)).await;
+
+        assert_next_message(&mut client, "notify_file").await;
+        assert_next_message(&mut client, "notify_activity").await;
+        assert_next_message(&mut client, "notify_notification").await;
+
+        publish_custom(&services, "foo", "chaos_after").await;
+        let messages = collect_messages(&mut client, Duration::from_millis(300)).await;
+        assert_eq!(vec!["chaos_after".to_string()], messages);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_lossy_delayed_connection() {
+        let mut services = Services::new().await;
+        services.add_user("foo", "bar");
+
+        let server_handle = services.spawn_server().await;
+        let proxy = ChaosProxy::new(&server_handle, Duration::from_millis(20), 4).await;
+        let mut client = proxy.connect_auth("foo", "bar").await;
+
+        let mut sent = HashSet::new();
+        for i in 0..10 {
+            let message = format!("{}{}", CHAOS_PREFIX, i);
+            publish_custom(&services, "foo", &message).await;
+            sent.insert(message);
+        }
+        services.restart_redis(Duration::from_millis(100)).await;
+        for i in 10..20 {
+            let message = format!("{}{}", CHAOS_PREFIX, i);
+            publish_custom(&services, "foo", &message).await;
+            sent.insert(message);
+        }
+
+        let mut messages = collect_messages(&mut client, Duration::from_millis(500)).await;
+        assert_no_duplicates(&messages);
+
+        // the gap caused by the restart is reported to the client
+        assert!(messages.iter().any(|msg| msg == "notify_file"));
+
+        // everything that wasn't dropped by the proxy is delivered
+        let dropped = proxy.dropped();
+        assert!(!dropped.is_empty());
+        messages.retain(|msg| msg.starts_with(CHAOS_PREFIX));
+        messages.extend(dropped);
+        assert_eq!(sent, messages.into_iter().collect::<HashSet<_>>());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_trace_user() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let mut client = server_handle.connect_auth("foo", "bar").await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_config",
+            r#"{"trace_user":{"user":"foo","minutes":0.01}}"#,
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(50)).await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_next_message(&mut client, "notify_activity").await;
+
+    // the summary is stored once the trace ends
+    sleep(Duration::from_millis(1000)).await;
+    let summary: String = redis.get("notify_push_trace_foo").await.unwrap();
+    let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+    assert_eq!("foo", summary["user"]);
+    assert_eq!(1, summary["received"]["count"]);
+    assert_eq!(1, summary["queued"]["count"]);
+    assert_eq!(1, summary["sent"]["count"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_protocol() {
+    let services = Services::new().await;
+    let server_handle = services.spawn_server().await;
+    let protocol: serde_json::Value = reqwest::get(format!(
+        "http://127.0.0.1:{}/push/protocol",
+        server_handle.port()
+    ))
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(env!("NOTIFY_PUSH_VERSION"), protocol["version"]);
+    let messages = protocol["messages"].as_array().unwrap();
+    assert!(messages
+        .iter()
+        .any(|message| message["example"] == "notify_activity"));
+    let commands = protocol["commands"].as_array().unwrap();
+    assert!(commands
+        .iter()
+        .any(|command| command["example"] == "listen notify_file_id"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_json_subprotocol() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+    services.add_filecache_item(10, "foo").await;
+    services.add_filecache_item(11, "foo/bar").await;
+    services.add_storage_mapping("foo", 10, 11).await;
+
+    let server_handle = services.spawn_server().await;
+    // unknown protocols are skipped
+    let (mut client, protocol) = server_handle
+        .connect_with_protocol("notify_push.future, notify_push.json")
+        .await;
+    assert_eq!(Some("notify_push.json"), protocol.as_deref());
+
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_next_message(&mut client, r#"{"type":"authenticated"}"#).await;
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>(
+            "notify_storage_update",
+            r#"{"storage":10, "path":"foo/bar", "file_id":5}"#,
+        )
+        .await
+        .unwrap();
+    // file ids are send without having to enable them first
+    assert_next_message(&mut client, r#"{"type":"notify_file_id","file_ids":[5]}"#).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_text_subprotocol() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let (mut client, protocol) = server_handle
+        .connect_with_protocol("notify_push.text")
+        .await;
+    assert_eq!(Some("notify_push.text"), protocol.as_deref());
+
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("wrong".into())).await.unwrap();
+    assert_next_message(&mut client, "err: Invalid credentials").await;
+}
+
+async fn next_cbor_message(client: &mut Client) -> serde_json::Value {
+    let message = timeout(Duration::from_millis(200), client.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let Message::Binary(data) = message else {
+        panic!("expected a binary message, got {:?}", message);
+    };
+    ciborium::from_reader(data.as_ref()).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_cbor_subprotocol() {
+    let services = Services::new().await;
+    services.add_user("foo", "bar");
+
+    let server_handle = services.spawn_server().await;
+    let (mut client, protocol) = server_handle
+        .connect_with_protocol("notify_push.cbor")
+        .await;
+    assert_eq!(Some("notify_push.cbor"), protocol.as_deref());
+
+    client.send(Message::Text("foo".into())).await.unwrap();
+    client.send(Message::Text("bar".into())).await.unwrap();
+    assert_eq!(
+        serde_json::json!({"type": "authenticated"}),
+        next_cbor_message(&mut client).await
+    );
+
+    let mut redis = services.redis_client().await;
+    redis
+        .publish::<_, _, ()>("notify_activity", r#"{"user":"foo"}"#)
+        .await
+        .unwrap();
+    assert_eq!(
+        serde_json::json!({"type": "notify_activity"}),
+        next_cbor_message(&mut client).await
+    );
+}
@@ -0,0 +1,71 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Tests;
+
+use OCA\NotifyPush\Controller\EndpointController;
+use OCP\AppFramework\Http;
+use OCP\IConfig;
+use OCP\IRequest;
+use Test\TestCase;
+
+class EndpointControllerTest extends TestCase {
+	private function getController(string $secret, string $providedSecret, ?string &$endpoint): EndpointController {
+		$config = $this->createMock(IConfig::class);
+		$config->method('getAppValue')->willReturnMap([
+			['notify_push', 'test_secret', '', $secret],
+		]);
+		$config->method('setAppValue')->willReturnCallback(function ($app, $key, $value) use (&$endpoint) {
+			$this->assertEquals('base_endpoint', $key);
+			$endpoint = $value;
+		});
+		$request = $this->createMock(IRequest::class);
+		$request->method('getHeader')->willReturnMap([
+			['x-notify-push-test-secret', $providedSecret],
+		]);
+		return new EndpointController($request, $config);
+	}
+
+	public function testRoute() {
+		// the push server posts to `index.php/apps/notify_push/endpoint`
+		$routes = require __DIR__ . '/../../appinfo/routes.php';
+		$this->assertContains([
+			'name' => 'endpoint#register',
+			'url' => '/endpoint',
+			'verb' => 'POST',
+		], $routes['routes']);
+	}
+
+	public function testRegister() {
+		$endpoint = null;
+		$response = $this->getController('secret', 'secret', $endpoint)->register('https://cloud.example.com/push/');
+		$this->assertEquals(Http::STATUS_OK, $response->getStatus());
+		$this->assertEquals('https://cloud.example.com/push', $endpoint);
+	}
+
+	public function testWrongSecret() {
+		$endpoint = null;
+		$response = $this->getController('secret', 'wrong', $endpoint)->register('https://evil.example.com/push');
+		$this->assertEquals(Http::STATUS_FORBIDDEN, $response->getStatus());
+		$this->assertNull($endpoint);
+	}
+
+	public function testNoSecretConfigured() {
+		$endpoint = null;
+		$response = $this->getController('', '', $endpoint)->register('https://evil.example.com/push');
+		$this->assertEquals(Http::STATUS_FORBIDDEN, $response->getStatus());
+		$this->assertNull($endpoint);
+	}
+
+	public function testInvalidEndpoint() {
+		$endpoint = null;
+		$response = $this->getController('secret', 'secret', $endpoint)->register('javascript:alert(1)');
+		$this->assertEquals(Http::STATUS_BAD_REQUEST, $response->getStatus());
+		$this->assertNull($endpoint);
+	}
+}
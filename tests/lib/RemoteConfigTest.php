@@ -0,0 +1,57 @@
+<?php
+
+declare(strict_types=1);
+/**
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+namespace OCA\NotifyPush\Tests;
+
+use OCA\NotifyPush\Queue\IQueue;
+use OCA\NotifyPush\RemoteConfig;
+use OCP\IConfig;
+use Test\TestCase;
+
+class RemoteConfigTest extends TestCase {
+	public function testSettings() {
+		$values = [];
+		$config = $this->createMock(IConfig::class);
+		$config->method('getAppValue')->willReturnCallback(function ($app, $key, $default) use (&$values) {
+			return $values[$key] ?? $default;
+		});
+		$config->method('setAppValue')->willReturnCallback(function ($app, $key, $value) use (&$values) {
+			$values[$key] = $value;
+		});
+		$config->method('deleteAppValue')->willReturnCallback(function ($app, $key) use (&$values) {
+			unset($values[$key]);
+		});
+		$events = [];
+		$queue = $this->createMock(IQueue::class);
+		$queue->method('push')->willReturnCallback(function ($channel, $event) use (&$events) {
+			$events[] = [$channel, $event];
+		});
+		$remoteConfig = new RemoteConfig($config, $queue);
+
+		$this->assertEquals([
+			'max_debounce_time' => null,
+			'max_connection_time' => null,
+			'excluded_paths' => [],
+		], $remoteConfig->getSettings());
+
+		$remoteConfig->setTime('max_debounce_time', 30);
+		$remoteConfig->setExcludedPaths(['appdata_', 'files_trashbin/']);
+		$remoteConfig->publish();
+
+		$this->assertEquals([
+			'max_debounce_time' => 30,
+			'max_connection_time' => null,
+			'excluded_paths' => ['appdata_', 'files_trashbin/'],
+		], $remoteConfig->getSettings());
+		$this->assertEquals([['notify_config', 'remote_config']], $events);
+
+		$remoteConfig->setTime('max_debounce_time', null);
+		$remoteConfig->setExcludedPaths([]);
+		$this->assertEquals([], $values);
+	}
+}
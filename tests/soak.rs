@@ -0,0 +1,166 @@
+/*
+ * SPDX-FileCopyrightText: 2025 Nextcloud GmbH and Nextcloud contributors
+ * SPDX-License-Identifier: AGPL-3.0-or-later
+ */
+
+//! Long running test that holds a large number of connections while events are send and clients come and go,
+//! checking that the memory usage and number of tasks don't grow over time.
+//!
+//! Ignored by default, run with `cargo test --release --test soak -- --ignored --nocapture`.
+//! The load can be tuned with `SOAK_CONNECTIONS`, `SOAK_DURATION` and `SOAK_INTERVAL` (both in seconds)
+//! and `SOAK_MAX_RSS_MB`. Each connection uses two file descriptors, so `ulimit -n` might need to be raised.
+
+use futures::StreamExt;
+use notify_push::metrics::METRICS;
+use notify_push_test_support::{ServerHandle, Services};
+use redis::AsyncCommands;
+use std::env::var;
+use std::fs::read_to_string;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+const USERS: usize = 100;
+/// Part of the connections that is closed and re-opened every interval
+const CHURN_PERCENT: usize = 5;
+/// Allowed variation in the number of tasks, for the background tasks that run periodically
+const TASK_SLACK: usize = 16;
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Resident memory of the test process in MB, the push server runs in the same process
+fn rss_mb() -> Option<usize> {
+    let statm = read_to_string("/proc/self/statm").ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096 / 1024 / 1024)
+}
+
+/// A connected client that reads messages in the background until it's dropped
+struct SoakClient {
+    reader: JoinHandle<()>,
+}
+
+impl SoakClient {
+    async fn connect(server: &ServerHandle, user: usize, received: Arc<AtomicUsize>) -> Self {
+        let mut client = server.connect_auth(&format!("user{}", user), "pass").await;
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(_)) = client.next().await {
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        SoakClient { reader }
+    }
+}
+
+impl Drop for SoakClient {
+    fn drop(&mut self) {
+        // drops the socket without a close frame, like a client that goes away
+        self.reader.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore]
+async fn soak_test() {
+    let connections: usize = env_or("SOAK_CONNECTIONS", 2000);
+    let duration = Duration::from_secs(env_or("SOAK_DURATION", 3 * 60 * 60));
+    let interval = Duration::from_secs(env_or("SOAK_INTERVAL", 60));
+    let max_rss: usize = env_or("SOAK_MAX_RSS_MB", 512);
+
+    let services = Services::new().await;
+    services.add_filecache_item(10, "files").await;
+    for user in 0..USERS {
+        services.add_user(&format!("user{}", user), "pass");
+        services
+            .add_storage_mapping(&format!("user{}", user), 10, 10)
+            .await;
+    }
+
+    let server = services.spawn_server().await;
+    let received = Arc::new(AtomicUsize::new(0));
+    let mut clients = Vec::with_capacity(connections);
+    for i in 0..connections {
+        clients.push(SoakClient::connect(&server, i % USERS, received.clone()).await);
+    }
+    assert_eq!(connections, METRICS.active_connection_count());
+
+    // let the startup settle before taking the baseline
+    sleep(Duration::from_secs(5)).await;
+    let baseline_tasks = Handle::current().metrics().num_alive_tasks();
+    let baseline_rss = rss_mb();
+    println!(
+        "{} connections open, {} tasks, {:?}MB resident",
+        connections, baseline_tasks, baseline_rss
+    );
+
+    let mut redis = services.redis_client().await;
+    let start = Instant::now();
+    let mut round = 0;
+    while start.elapsed() < duration {
+        round += 1;
+        for user in 0..USERS {
+            redis
+                .publish::<_, _, ()>("notify_activity", format!(r#"{{"user":"user{}"}}"#, user))
+                .await
+                .unwrap();
+        }
+        redis
+            .publish::<_, _, ()>(
+                "notify_storage_update",
+                format!(
+                    r#"{{"storage":10, "path":"files/{}", "file_id":{}}}"#,
+                    round, round
+                ),
+            )
+            .await
+            .unwrap();
+
+        let churn = connections * CHURN_PERCENT / 100;
+        for i in 0..churn {
+            let index = (round * churn + i) % connections;
+            clients[index] = SoakClient::connect(&server, index % USERS, received.clone()).await;
+        }
+
+        sleep(interval).await;
+
+        let tasks = Handle::current().metrics().num_alive_tasks();
+        let rss = rss_mb();
+        println!(
+            "round {} after {}s: {} connections, {} tasks, {:?}MB resident, {} messages received",
+            round,
+            start.elapsed().as_secs(),
+            METRICS.active_connection_count(),
+            tasks,
+            rss,
+            received.load(Ordering::Relaxed)
+        );
+        assert_eq!(connections, METRICS.active_connection_count());
+        assert!(
+            tasks <= baseline_tasks + TASK_SLACK,
+            "task count grew from {} to {}",
+            baseline_tasks,
+            tasks
+        );
+        if let Some(rss) = rss {
+            assert!(
+                rss <= max_rss,
+                "resident memory of {}MB exceeds the ceiling of {}MB",
+                rss,
+                max_rss
+            );
+        }
+    }
+
+    drop(clients);
+    sleep(Duration::from_secs(1)).await;
+    assert_eq!(0, METRICS.active_connection_count());
+}